@@ -0,0 +1,1159 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::{CellIdentifier, Command};
+
+use crate::references::{a1_name, parse_cell, parse_column, parse_reference, Reference};
+use crate::spreadsheet::{AggKind, AggTarget, HealthReport, SheetStats};
+
+// Every command keyword the server understands, as listed by capabilities
+pub const COMMAND_KEYWORDS: &[&str] = &[
+    "get",
+    "set",
+    "list",
+    "workers",
+    "stats",
+    "stat",
+    "health",
+    "reset",
+    "sheets",
+    "extent",
+    "errorsin",
+    "watch",
+    "unwatch",
+    "export",
+    "presence",
+    "expr",
+    "formula",
+    "copy",
+    "move",
+    "insertrow",
+    "deleterow",
+    "insertcol",
+    "deletecol",
+    "name",
+    "unname",
+    "dropsheet",
+    "deps",
+    "rdeps",
+    "undo",
+    "redo",
+    "history",
+    "cas",
+    "eval",
+    "evaluate",
+    "whatif",
+    "find",
+    "findexpr",
+    "dump",
+    "colsum",
+    "rowsum",
+    "colstats",
+    "changes",
+    "version",
+    "capabilities",
+];
+
+/**
+ * A request read from a client connection
+ * Wraps the get/set commands from rsheet_lib with the server's own commands
+ */
+pub enum ServerCommand {
+    Sheet(Command), // A get or set handled by rsheet_lib's parser
+    GetRange(CellIdentifier, CellIdentifier), // "get A1_C3": every value in a rectangle
+    ListCells(Option<(CellIdentifier, CellIdentifier)>), // "list" or "list A1_C10": populated cells, with expressions and values
+    Workers, // "workers": the update queue depth of each worker
+    Stats,   // "stats" or "stat": sheet size, errors and worker activity
+    Health,  // "health": OK or DEGRADED, with worker and lock details
+    Reset,   // "reset": clears every cell
+    ErrorsIn(CellIdentifier, CellIdentifier), // "errorsin A1_Z50": error cells in a region
+    Export(PathBuf), // "export foo.csv": writes the evaluated grid as CSV into the export directory
+    Presence(CellIdentifier), // "presence A1": whether a cell was ever set, and its value
+    Expression(CellIdentifier), // "expr A1" or "formula A1": the expression a cell holds, as typed
+    SetBatch(Vec<(CellIdentifier, String)>), // "set A1 1; B1 2": sets applied as one update
+    Copy(CellIdentifier, CellIdentifier, CellIdentifier), // "copy A1_B2 D5": copies a region
+    Move(CellIdentifier, CellIdentifier, bool), // "move A1 D4 [overwrite]": moves a cell, repointing its readers
+    InsertRow(u32), // "insertrow 2": inserts a row before row 2 (0-based index 1)
+    DeleteRow(u32), // "deleterow 2": deletes row 2 (0-based index 1)
+    InsertCol(u32), // "insertcol B": inserts a column before column B
+    DeleteCol(u32), // "deletecol B": deletes column B
+    DefineName(String, String), // "name revenue A1_A12": defines a name for a cell or range
+    RemoveName(String), // "unname revenue": removes a defined name
+    Sheets,         // "sheets": the name of every sheet
+    Extent,         // "extent": the smallest region holding every populated cell
+    Capabilities,   // "version" or "capabilities": the server's version and command keywords
+    Dependencies(CellIdentifier), // "deps A1": the cells A1's expression reads
+    Dependents(CellIdentifier, bool), // "rdeps A1" or "rdeps A1 transitive": cells reading A1
+    Watch(Vec<(CellIdentifier, CellIdentifier)>), // "watch A1 B1_B5": pushes each new value in the regions
+    Unwatch(Vec<(CellIdentifier, CellIdentifier)>), // "unwatch A1 B1_B5": stops watching the regions
+    DropSheet(String),    // "dropsheet Sheet2": removes a sheet and its cells
+    Undo(CellIdentifier), // "undo A1": restores the expression A1 held before its last set
+    Redo(CellIdentifier), // "redo A1": restores the expression the last undo of A1 replaced
+    History(CellIdentifier, Option<usize>), // "history A1 [N]": the last N sets of A1, with times
+    CompareAndSet(CellIdentifier, CellValue, String), // "cas A1 5 6": sets A1 to 6 only if it holds 5
+    Eval(String), // "eval sum(A1_A3) * 2" or "evaluate ...": evaluates an expression without storing it
+    WhatIf(CellIdentifier, Vec<(CellIdentifier, CellValue)>), // "whatif D1 A1=100": D1's value if A1 held 100
+    Find(CellValue),                                          // "find 42": cells whose value is 42
+    FindExpression(String), // "findexpr A1": cells whose expression contains "A1"
+    Dump(CellIdentifier, CellIdentifier), // "dump A1_D5": a region as an aligned text table
+    Aggregate(AggTarget, Vec<AggKind>, bool), // "colsum B", "rowsum 3" or "colstats B", optionally followed by "skiperrors"
+    Changes(u64), // "changes 12": cells whose value changed after version 12
+}
+
+impl ServerCommand {
+    /**
+     * HELPER FUNCTION
+     * Checks whether the command sets cells, so it counts toward a
+     * connection's set rate limit: set, a batch of sets, or cas
+     */
+    pub fn is_set(&self) -> bool {
+        matches!(
+            self,
+            ServerCommand::Sheet(Command::Set { .. })
+                | ServerCommand::SetBatch(_)
+                | ServerCommand::CompareAndSet(..)
+        )
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Lists every cell the command names, a region by its corners and a
+     * whole row or column by its first cell, so each can be checked against
+     * the sheet's bounds before the command runs
+     * A copy also names the far corner of its destination
+     */
+    pub fn cells(&self) -> Vec<CellIdentifier> {
+        let row = |row: u32| CellIdentifier { col: 0, row };
+        let col = |col: u32| CellIdentifier { col, row: 0 };
+        match self {
+            ServerCommand::Sheet(Command::Get { cell_identifier })
+            | ServerCommand::Sheet(Command::Set {
+                cell_identifier, ..
+            }) => vec![*cell_identifier],
+            ServerCommand::Presence(cell_id)
+            | ServerCommand::Expression(cell_id)
+            | ServerCommand::Dependencies(cell_id)
+            | ServerCommand::Dependents(cell_id, _)
+            | ServerCommand::Undo(cell_id)
+            | ServerCommand::Redo(cell_id)
+            | ServerCommand::History(cell_id, _)
+            | ServerCommand::CompareAndSet(cell_id, ..) => vec![*cell_id],
+            ServerCommand::Move(src, dst, _) => vec![*src, *dst],
+            ServerCommand::GetRange(start, end)
+            | ServerCommand::ListCells(Some((start, end)))
+            | ServerCommand::ErrorsIn(start, end)
+            | ServerCommand::Dump(start, end)
+            | ServerCommand::Aggregate(AggTarget::Range(start, end), ..) => vec![*start, *end],
+            ServerCommand::Watch(regions) | ServerCommand::Unwatch(regions) => regions
+                .iter()
+                .flat_map(|(start, end)| [*start, *end])
+                .collect(),
+            ServerCommand::SetBatch(assignments) => {
+                assignments.iter().map(|(cell_id, _)| *cell_id).collect()
+            }
+            ServerCommand::WhatIf(target, overrides) => std::iter::once(*target)
+                .chain(overrides.iter().map(|(cell_id, _)| *cell_id))
+                .collect(),
+            ServerCommand::Copy(start, end, dest) => {
+                let far_corner = CellIdentifier {
+                    col: dest.col.saturating_add(start.col.abs_diff(end.col)),
+                    row: dest.row.saturating_add(start.row.abs_diff(end.row)),
+                };
+                vec![*start, *end, *dest, far_corner]
+            }
+            ServerCommand::InsertRow(index) | ServerCommand::DeleteRow(index) => vec![row(*index)],
+            ServerCommand::InsertCol(index) | ServerCommand::DeleteCol(index) => vec![col(*index)],
+            ServerCommand::Aggregate(AggTarget::Row(index), ..) => vec![row(*index)],
+            ServerCommand::Aggregate(AggTarget::Column(index), ..) => vec![col(*index)],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl FromStr for ServerCommand {
+    type Err = String;
+
+    /**
+     * HELPER FUNCTION
+     * Parses a client message
+     *
+     * Procedure:
+     * 1. Matches the server's own single-word commands
+     * 2. Matches the server's commands that take an argument: a region for
+     *    errorsin, a file path for export, a cell for presence and expr (or
+     *    its alias formula), a closed range for get and list, ;-separated assignments
+     *    for set, a region and destination cell for copy, a source and
+     *    destination cell for move (optionally followed by "overwrite"), a row number or
+     *    column name for the row and column commands, a name (with its cell
+     *    or range, when defining) for name and unname, a sheet name for
+     *    dropsheet, a cell for deps and rdeps (optionally followed by
+     *    "transitive"), one or more regions for watch and unwatch, a cell for undo and
+     *    redo, a cell for history (optionally followed by a count), and a
+     *    cell, expected value and expression for cas, an expression for
+     *    eval (or its alias evaluate), a cell followed by one or more
+     *    cell=value overrides for whatif, a value for find, the text to look
+     *    for for findexpr, a region for dump, and a column name for colsum and
+     *    colstats or a row number for rowsum (optionally followed by
+     *    "skiperrors"), and a version for changes
+     * 3. Falls back to rsheet_lib's get/set parser for everything else
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Step 1: Single-word commands
+        match s.trim() {
+            "list" => return Ok(ServerCommand::ListCells(None)),
+            "workers" => return Ok(ServerCommand::Workers),
+            "stats" | "stat" => return Ok(ServerCommand::Stats),
+            "health" => return Ok(ServerCommand::Health),
+            "reset" => return Ok(ServerCommand::Reset),
+            "sheets" => return Ok(ServerCommand::Sheets),
+            "extent" => return Ok(ServerCommand::Extent),
+            "version" | "capabilities" => return Ok(ServerCommand::Capabilities),
+            _ => {}
+        }
+
+        // Step 2: Commands with an argument
+        let (keyword, argument) = s.trim().split_once(char::is_whitespace).unwrap_or_default();
+        let argument = argument.trim();
+        let cell_of =
+            |text: &str| parse_cell(text).ok_or(format!("Error parsing cell position: {text}"));
+        let cell = || cell_of(argument);
+        let row_of = |text: &str| match text.parse::<u32>() {
+            Ok(row) if row > 0 => Ok(row - 1),
+            _ => Err(format!("Error parsing row: {text}")),
+        };
+        let row = || row_of(argument);
+        let col_of = |text: &str| parse_column(text).ok_or(format!("Error parsing column: {text}"));
+        let col = || col_of(argument);
+        let region_of = |text: &str| match parse_reference(text) {
+            Some(Reference::Cell(cell_id)) => Ok((cell_id, cell_id)),
+            Some(Reference::Range(start, end)) => Ok((start, end)),
+            _ => Err(format!("Error parsing region: {text}")),
+        };
+        let region = || region_of(argument);
+        let regions = || {
+            let regions = argument
+                .split_whitespace()
+                .map(region_of)
+                .collect::<Result<Vec<(CellIdentifier, CellIdentifier)>, String>>()?;
+            if regions.is_empty() {
+                return Err(format!("Error parsing region: {argument}"));
+            }
+            Ok(regions)
+        };
+        match keyword {
+            "errorsin" => region().map(|(start, end)| ServerCommand::ErrorsIn(start, end)),
+            "watch" => regions().map(ServerCommand::Watch),
+            "unwatch" => regions().map(ServerCommand::Unwatch),
+            "export" => Ok(ServerCommand::Export(PathBuf::from(argument))),
+            "presence" => cell().map(ServerCommand::Presence),
+            "expr" | "formula" => cell().map(ServerCommand::Expression),
+            "dump" => region().map(|(start, end)| ServerCommand::Dump(start, end)),
+            "colsum" | "rowsum" | "colstats" => {
+                let (line, flag) = argument
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((argument, ""));
+                let skip_errors = match flag.trim() {
+                    "" => false,
+                    "skiperrors" => true,
+                    flag => return Err(format!("Error parsing {keyword} flag: {flag}")),
+                };
+                let target = match keyword {
+                    "rowsum" => AggTarget::Row(row_of(line)?),
+                    _ => AggTarget::Column(col_of(line)?),
+                };
+                let kinds = match keyword {
+                    "colstats" => vec![AggKind::Min, AggKind::Max, AggKind::Avg, AggKind::Count],
+                    _ => vec![AggKind::Sum],
+                };
+                Ok(ServerCommand::Aggregate(target, kinds, skip_errors))
+            }
+            "list" => match parse_reference(argument) {
+                Some(Reference::Range(start, end)) => {
+                    Ok(ServerCommand::ListCells(Some((start, end))))
+                }
+                _ => Err(format!("Error parsing range: {argument}")),
+            },
+            "get" if argument.contains('_') => match parse_reference(argument) {
+                Some(Reference::Range(start, end)) => Ok(ServerCommand::GetRange(start, end)),
+                _ => Err(format!("Error parsing range: {argument}")),
+            },
+            "insertrow" => row().map(ServerCommand::InsertRow),
+            "deleterow" => row().map(ServerCommand::DeleteRow),
+            "insertcol" => col().map(ServerCommand::InsertCol),
+            "deletecol" => col().map(ServerCommand::DeleteCol),
+            "name" => match argument.split_once(char::is_whitespace) {
+                Some((name, target)) => Ok(ServerCommand::DefineName(
+                    name.to_string(),
+                    target.trim().to_string(),
+                )),
+                None => Err(format!("Error parsing name definition: {argument}")),
+            },
+            "unname" => Ok(ServerCommand::RemoveName(argument.to_string())),
+            "dropsheet" => Ok(ServerCommand::DropSheet(argument.to_string())),
+            "deps" => cell().map(ServerCommand::Dependencies),
+            "undo" => cell().map(ServerCommand::Undo),
+            "redo" => cell().map(ServerCommand::Redo),
+            "eval" | "evaluate" if !argument.is_empty() => {
+                Ok(ServerCommand::Eval(argument.to_string()))
+            }
+            "changes" => argument
+                .parse()
+                .map(ServerCommand::Changes)
+                .map_err(|_| format!("Error parsing version: {argument}")),
+            "find" => parse_value(argument)
+                .map(ServerCommand::Find)
+                .ok_or_else(|| format!("Error parsing value: {argument}")),
+            "findexpr" if !argument.is_empty() => {
+                Ok(ServerCommand::FindExpression(argument.to_string()))
+            }
+            "whatif" => {
+                let mut parts = argument.split_whitespace();
+                let target = parts.next().unwrap_or_default();
+                let target = cell_of(target)?;
+                let overrides = parts
+                    .map(|part| {
+                        part.split_once('=')
+                            .and_then(|(cell, value)| {
+                                Some((parse_cell(cell)?, parse_value(value)?))
+                            })
+                            .ok_or_else(|| format!("Error parsing override: {part}"))
+                    })
+                    .collect::<Result<Vec<(CellIdentifier, CellValue)>, String>>()?;
+                if overrides.is_empty() {
+                    return Err(format!("Error parsing overrides: {argument}"));
+                }
+                Ok(ServerCommand::WhatIf(target, overrides))
+            }
+            "cas" => {
+                let mut parts = argument.splitn(3, char::is_whitespace);
+                let (cell, expected) = (parts.next().unwrap_or_default(), parts.next());
+                let cell_id = cell_of(cell)?;
+                let expected = expected
+                    .and_then(parse_value)
+                    .ok_or_else(|| format!("Error parsing expected value: {argument}"))?;
+                match parts.next().map(str::trim) {
+                    Some(expression) if !expression.is_empty() => Ok(ServerCommand::CompareAndSet(
+                        cell_id,
+                        expected,
+                        expression.to_string(),
+                    )),
+                    _ => Err(format!("Error parsing cas expression: {argument}")),
+                }
+            }
+            "history" => match argument.split_once(char::is_whitespace) {
+                Some((cell, count)) => match (cell_of(cell), count.trim().parse()) {
+                    (Ok(cell_id), Ok(count)) => Ok(ServerCommand::History(cell_id, Some(count))),
+                    (Err(e), _) => Err(e),
+                    (_, Err(_)) => Err(format!("Error parsing count: {}", count.trim())),
+                },
+                None => cell().map(|cell_id| ServerCommand::History(cell_id, None)),
+            },
+            "rdeps" => match argument.split_once(char::is_whitespace) {
+                Some((cell, "transitive")) => {
+                    cell_of(cell).map(|cell_id| ServerCommand::Dependents(cell_id, true))
+                }
+                Some((_, flag)) => Err(format!("Error parsing rdeps flag: {}", flag.trim())),
+                None => cell().map(|cell_id| ServerCommand::Dependents(cell_id, false)),
+            },
+            "copy" => {
+                let (region, dest) = argument.split_once(char::is_whitespace).unwrap_or_default();
+                let dest = cell_of(dest.trim())?;
+                match parse_reference(region) {
+                    Some(Reference::Cell(cell_id)) => {
+                        Ok(ServerCommand::Copy(cell_id, cell_id, dest))
+                    }
+                    Some(Reference::Range(start, end)) => Ok(ServerCommand::Copy(start, end, dest)),
+                    _ => Err(format!("Error parsing region: {region}")),
+                }
+            }
+            "move" => {
+                let mut parts = argument.split_whitespace();
+                let src = cell_of(parts.next().unwrap_or_default())?;
+                let dst = cell_of(parts.next().unwrap_or_default())?;
+                match parts.next() {
+                    None => Ok(ServerCommand::Move(src, dst, false)),
+                    Some("overwrite") if parts.next().is_none() => {
+                        Ok(ServerCommand::Move(src, dst, true))
+                    }
+                    Some(_) => Err(format!("Error parsing move flag: {argument}")),
+                }
+            }
+            "set" if split_assignments(argument).len() > 1 => split_assignments(argument)
+                .into_iter()
+                .map(
+                    |assignment| match parse_sheet_command(&format!("set {assignment}"))? {
+                        Command::Set {
+                            cell_identifier,
+                            cell_expr,
+                        } => Ok((cell_identifier, cell_expr)),
+                        Command::Get { .. } => {
+                            Err(format!("Error parsing assignment: {assignment}"))
+                        }
+                    },
+                )
+                .collect::<Result<Vec<(CellIdentifier, String)>, String>>()
+                .map(ServerCommand::SetBatch),
+
+            // Step 3: Everything else
+            _ => parse_sheet_command(s).map(ServerCommand::Sheet),
+        }
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses a get or set with rsheet_lib's parser, first rejecting a cell name
+ * whose column is too long to number, which that parser would overflow on
+ */
+fn parse_sheet_command(text: &str) -> Result<Command, String> {
+    let cell = text.split_whitespace().nth(1).unwrap_or_default();
+    if !cell.is_empty() && parse_cell(cell).is_none() {
+        return Err(format!("Error parsing cell position: {cell}"));
+    }
+    text.parse::<Command>()
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses a value written on its own, as cas, whatif and find expect it: an integer, a
+ * string literal without whitespace or escapes, e.g. "yes", or None
+ */
+fn parse_value(text: &str) -> Option<CellValue> {
+    match text {
+        "None" => Some(CellValue::None),
+        _ if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') => {
+            Some(CellValue::String(text[1..text.len() - 1].to_string()))
+        }
+        _ => text.parse::<i64>().ok().map(CellValue::Int),
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Splits a sheet prefix off a message's first argument, so "get Sheet2!A1"
+ * gives (Some("Sheet2"), "get A1")
+ * Messages without a prefix are returned whole; a prefix is a run of
+ * letters, digits and underscores ending in "!"
+ */
+pub fn split_sheet(message: &str) -> (Option<&str>, String) {
+    let message = message.trim();
+    let Some((keyword, argument)) = message.split_once(char::is_whitespace) else {
+        return (None, message.to_string());
+    };
+    let argument = argument.trim_start();
+
+    match argument.split_once('!') {
+        Some((sheet, rest))
+            if !sheet.is_empty()
+                && sheet.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            (Some(sheet), format!("{keyword} {rest}"))
+        }
+        _ => (None, message.to_string()),
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Splits the argument of a set into its ;-separated assignments
+ *
+ * Procedure:
+ * 1. Walks the text, tracking whether it is inside a string literal so a
+ *    ';' in a string doesn't split the assignment
+ * 2. Splits at every other ';'
+ * 3. Drops assignments that are empty once trimmed, e.g. after a trailing ';'
+ */
+fn split_assignments(argument: &str) -> Vec<&str> {
+    let mut assignments: Vec<&str> = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    // Steps 1-2: Split outside string literals
+    for (i, c) in argument.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => {
+                assignments.push(&argument[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    assignments.push(&argument[start..]);
+
+    // Step 3: Drop empty assignments
+    assignments
+        .into_iter()
+        .map(str::trim)
+        .filter(|assignment| !assignment.is_empty())
+        .collect()
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::list_cells for the list command
+ *
+ * Procedure:
+ * 1. Writes one line per cell
+ * 2. Separates the cell name, expression and value with tabs, since
+ *    expressions may themselves contain spaces
+ * 3. Joins the lines with newlines so a client can split them back apart
+ */
+pub fn format_cell_list(cells: &[(CellIdentifier, String, CellValue)]) -> String {
+    cells
+        .iter()
+        .map(|(cell_id, expression, value)| {
+            format!("{}\t{}\t{}", a1_name(cell_id), expression, value)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::changed_since for the changes command,
+ * one "cell<TAB>value<TAB>version" line per changed cell, oldest first, so
+ * a client can take its next version from the last line
+ */
+pub fn format_changes(changes: &[(CellIdentifier, CellValue, u64)]) -> String {
+    changes
+        .iter()
+        .map(|(cell_id, value, version)| format!("{}\t{}\t{}", a1_name(cell_id), value, version))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::error_cells_in_range for the errorsin
+ * command, one "cell<TAB>message" line per error cell
+ */
+pub fn format_error_cells(cells: &[(CellIdentifier, String)]) -> String {
+    cells
+        .iter()
+        .map(|(cell_id, message)| format!("{}\t{}", a1_name(cell_id), message))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats a list of cells for the deps and rdeps commands, one A1 name per
+ * line, so an empty list gives an empty string
+ */
+pub fn format_cell_names(cells: &[CellIdentifier]) -> String {
+    cells
+        .iter()
+        .map(a1_name)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the matches of find and findexpr, one A1 name per line
+ * Only the first limit matches are named; a last "+N more" line counts the
+ * rest
+ */
+pub fn format_matches(cells: &[CellIdentifier], limit: usize) -> String {
+    let mut lines: Vec<String> = cells.iter().take(limit).map(a1_name).collect();
+    if cells.len() > limit {
+        lines.push(format!("+{} more", cells.len() - limit));
+    }
+    lines.join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Prepares a cell value for a reply
+ * rsheet_lib prints a string value between double quotes, so backslashes
+ * and quotes inside it are escaped, making the printed value a literal that
+ * set accepts back unchanged. A cell never set, or cleared, prints as
+ * None, e.g. "C9 = None"
+ */
+pub fn reply_value(value: CellValue) -> CellValue {
+    match value {
+        CellValue::String(text) => {
+            CellValue::String(text.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+        value => value,
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::history for the history command, one
+ * "unix_ms<TAB>expression" line per set, oldest first
+ */
+pub fn format_history(entries: &[(SystemTime, String)]) -> String {
+    entries
+        .iter()
+        .map(|(time, expression)| {
+            let unix_ms = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            format!("{}\t{}", unix_ms.as_millis(), expression)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::get_with_presence for the presence
+ * command, "set<TAB>value" for a cell that exists and "unset" otherwise
+ */
+pub fn format_presence(present: bool, value: &CellValue) -> String {
+    if present {
+        format!("set\t{value}")
+    } else {
+        "unset".to_string()
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the values of a ranged get, one "cell=value" line per cell in
+ * the order given
+ */
+pub fn format_range_values(values: &[(CellIdentifier, String)]) -> String {
+    values
+        .iter()
+        .map(|(cell_id, value)| format!("{}={}", a1_name(cell_id), value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::worker_backlogs for the workers command,
+ * one "index<TAB>depth" line per worker
+ */
+pub fn format_worker_backlogs(backlogs: &[usize]) -> String {
+    backlogs
+        .iter()
+        .enumerate()
+        .map(|(index, depth)| format!("{index}\t{depth}"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::stats for the stats command,
+ * one "name<TAB>value" line per statistic
+ */
+pub fn format_stats(stats: &SheetStats) -> String {
+    [
+        ("cells", stats.cells),
+        ("errors", stats.errors),
+        ("edges", stats.edges),
+        ("max_chain_depth", stats.max_chain_depth),
+        ("queue_depth", stats.queue_depth),
+        ("evaluations", stats.evaluations),
+        ("evaluator_threads", stats.evaluator_threads),
+        ("abandoned_evaluations", stats.abandoned_evaluations),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{name}\t{value}"))
+    .chain([format!("uptime_ms\t{}", stats.uptime.as_millis())])
+    .collect::<Vec<String>>()
+    .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the results of colstats, one "name<TAB>value" line per
+ * aggregate, leaving the value blank when there was nothing to aggregate
+ */
+pub fn format_aggregates(kinds: &[AggKind], values: &[CellValue]) -> String {
+    kinds
+        .iter()
+        .zip(values)
+        .map(|(kind, value)| match value {
+            CellValue::Int(number) => format!("{}\t{}", kind.name(), number),
+            _ => format!("{}\t", kind.name()),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the reply to version and capabilities: a "version<TAB>x.y.z" line
+ * with the crate version, then a "commands<TAB>..." line listing every
+ * command keyword, separated by spaces, so clients can check for a command
+ * before sending it
+ */
+pub fn format_capabilities() -> String {
+    format!(
+        "version\t{}\ncommands\t{}",
+        env!("CARGO_PKG_VERSION"),
+        COMMAND_KEYWORDS.join(" ")
+    )
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats the output of Spreadsheet::health for the health command
+ *
+ * Procedure:
+ * 1. Writes OK or DEGRADED on the first line
+ * 2. Follows it with one "name<TAB>value" line per detail
+ */
+pub fn format_health(report: &HealthReport) -> String {
+    let status = if report.is_ok() { "OK" } else { "DEGRADED" };
+    [
+        status.to_string(),
+        format!("worker_alive\t{}", report.worker_alive),
+        format!("cells_poisoned\t{}", report.cells_poisoned),
+        format!("graph_poisoned\t{}", report.graph_poisoned),
+        format!("pending_updates\t{}", report.pending_updates),
+        format!("uptime_ms\t{}", report.uptime.as_millis()),
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_commands() {
+        assert!(matches!(
+            "list".parse::<ServerCommand>(),
+            Ok(ServerCommand::ListCells(None))
+        ));
+        assert!(matches!(
+            "list A1_C10".parse::<ServerCommand>(),
+            Ok(ServerCommand::ListCells(Some((start, end))))
+                if start == CellIdentifier { col: 0, row: 0 } && end == CellIdentifier { col: 2, row: 9 }
+        ));
+        assert!(matches!(
+            "get A1".parse::<ServerCommand>(),
+            Ok(ServerCommand::Sheet(Command::Get { .. }))
+        ));
+        assert!(matches!(
+            "workers".parse::<ServerCommand>(),
+            Ok(ServerCommand::Workers)
+        ));
+        assert!(matches!(
+            "stats".parse::<ServerCommand>(),
+            Ok(ServerCommand::Stats)
+        ));
+        assert!(matches!(
+            "stat".parse::<ServerCommand>(),
+            Ok(ServerCommand::Stats)
+        ));
+        assert!(matches!(
+            "health".parse::<ServerCommand>(),
+            Ok(ServerCommand::Health)
+        ));
+        assert!(matches!(
+            "errorsin A1_Z50".parse::<ServerCommand>(),
+            Ok(ServerCommand::ErrorsIn(
+                CellIdentifier { col: 0, row: 0 },
+                CellIdentifier { col: 25, row: 49 }
+            ))
+        ));
+        assert!("errorsin A1_Z".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "export out/grid.csv".parse::<ServerCommand>(),
+            Ok(ServerCommand::Export(path)) if path.as_os_str() == "out/grid.csv"
+        ));
+        assert!(matches!(
+            "presence B3".parse::<ServerCommand>(),
+            Ok(ServerCommand::Presence(CellIdentifier { col: 1, row: 2 }))
+        ));
+        assert!("presence B".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "reset".parse::<ServerCommand>(),
+            Ok(ServerCommand::Reset)
+        ));
+        assert!(matches!(
+            "expr  C10 ".parse::<ServerCommand>(),
+            Ok(ServerCommand::Expression(CellIdentifier { col: 2, row: 9 }))
+        ));
+        assert!(matches!(
+            "formula A1".parse::<ServerCommand>(),
+            Ok(ServerCommand::Expression(CellIdentifier { col: 0, row: 0 }))
+        ));
+        // Corners given in any order are normalized to top-left first
+        assert!(matches!(
+            "get B2_A1".parse::<ServerCommand>(),
+            Ok(ServerCommand::GetRange(
+                CellIdentifier { col: 0, row: 0 },
+                CellIdentifier { col: 1, row: 1 }
+            ))
+        ));
+        assert!("get A1_B".parse::<ServerCommand>().is_err());
+        match "set A1 1; B1 \"a;b\" ;".parse::<ServerCommand>() {
+            Ok(ServerCommand::SetBatch(assignments)) => assert_eq!(
+                assignments,
+                vec![
+                    (CellIdentifier { col: 0, row: 0 }, "1".to_string()),
+                    (CellIdentifier { col: 1, row: 0 }, "\"a;b\"".to_string()),
+                ]
+            ),
+            _ => panic!("expected a batch of two sets"),
+        }
+        assert!(matches!(
+            "set A1 \"a;b\"".parse::<ServerCommand>(),
+            Ok(ServerCommand::Sheet(Command::Set { .. }))
+        ));
+        assert!("set A1 1; B1".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "copy A1_B2  D5".parse::<ServerCommand>(),
+            Ok(ServerCommand::Copy(
+                CellIdentifier { col: 0, row: 0 },
+                CellIdentifier { col: 1, row: 1 },
+                CellIdentifier { col: 3, row: 4 }
+            ))
+        ));
+        assert!("copy A1_B D5".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "move A1 D4".parse::<ServerCommand>(),
+            Ok(ServerCommand::Move(
+                CellIdentifier { col: 0, row: 0 },
+                CellIdentifier { col: 3, row: 3 },
+                false
+            ))
+        ));
+        assert!(matches!(
+            "move A1 D4 overwrite".parse::<ServerCommand>(),
+            Ok(ServerCommand::Move(_, _, true))
+        ));
+        assert!("move A1".parse::<ServerCommand>().is_err());
+        assert!("move A1 D4 force".parse::<ServerCommand>().is_err());
+        assert!("copy A1".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "insertrow 2".parse::<ServerCommand>(),
+            Ok(ServerCommand::InsertRow(1))
+        ));
+        assert!(matches!(
+            "deletecol AA".parse::<ServerCommand>(),
+            Ok(ServerCommand::DeleteCol(26))
+        ));
+        assert!("deleterow 0".parse::<ServerCommand>().is_err());
+        assert!("insertcol b".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "name revenue  A1_A12".parse::<ServerCommand>(),
+            Ok(ServerCommand::DefineName(name, target)) if name == "revenue" && target == "A1_A12"
+        ));
+        assert!("name revenue".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "unname revenue".parse::<ServerCommand>(),
+            Ok(ServerCommand::RemoveName(name)) if name == "revenue"
+        ));
+        assert!(matches!(
+            "sheets".parse::<ServerCommand>(),
+            Ok(ServerCommand::Sheets)
+        ));
+        assert!(matches!(
+            "extent".parse::<ServerCommand>(),
+            Ok(ServerCommand::Extent)
+        ));
+        assert!(matches!(
+            "dropsheet Sheet2".parse::<ServerCommand>(),
+            Ok(ServerCommand::DropSheet(name)) if name == "Sheet2"
+        ));
+        let b2 = CellIdentifier { col: 1, row: 1 };
+        assert!(matches!(
+            "undo B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Undo(cell_id)) if cell_id == b2
+        ));
+        assert!(matches!(
+            "redo B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Redo(cell_id)) if cell_id == b2
+        ));
+        assert!("undo".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "history B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::History(cell_id, None)) if cell_id == b2
+        ));
+        assert!(matches!(
+            "history B2 5".parse::<ServerCommand>(),
+            Ok(ServerCommand::History(cell_id, Some(5))) if cell_id == b2
+        ));
+        assert!("history B2 five".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "cas B2 5 B1 + 1".parse::<ServerCommand>(),
+            Ok(ServerCommand::CompareAndSet(cell_id, CellValue::Int(5), expression))
+                if cell_id == b2 && expression == "B1 + 1"
+        ));
+        assert!(matches!(
+            r#"cas B2 "no" "yes""#.parse::<ServerCommand>(),
+            Ok(ServerCommand::CompareAndSet(_, CellValue::String(expected), _)) if expected == "no"
+        ));
+        assert!(matches!(
+            "cas B2 None 1".parse::<ServerCommand>(),
+            Ok(ServerCommand::CompareAndSet(_, CellValue::None, _))
+        ));
+        assert!(matches!(
+            "eval  sum(A1_B2) * 2 ".parse::<ServerCommand>(),
+            Ok(ServerCommand::Eval(expression)) if expression == "sum(A1_B2) * 2"
+        ));
+        assert!(matches!(
+            "evaluate A1 + B1".parse::<ServerCommand>(),
+            Ok(ServerCommand::Eval(expression)) if expression == "A1 + B1"
+        ));
+        assert!(matches!(
+            r#"whatif D1 A1=100 B2="x""#.parse::<ServerCommand>(),
+            Ok(ServerCommand::WhatIf(target, overrides))
+                if target == "D1".parse().unwrap()
+                    && overrides == [
+                        (CellIdentifier { col: 0, row: 0 }, CellValue::Int(100)),
+                        (b2, CellValue::String("x".into())),
+                    ]
+        ));
+        assert!("whatif D1".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "find 42".parse::<ServerCommand>(),
+            Ok(ServerCommand::Find(CellValue::Int(42)))
+        ));
+        assert!(matches!(
+            "findexpr A1 ".parse::<ServerCommand>(),
+            Ok(ServerCommand::FindExpression(needle)) if needle == "A1"
+        ));
+        assert!("find forty".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "dump A1_D5".parse::<ServerCommand>(),
+            Ok(ServerCommand::Dump(start, end))
+                if start == CellIdentifier { col: 0, row: 0 } && end == CellIdentifier { col: 3, row: 4 }
+        ));
+        assert!("whatif D1 A1 100".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "colsum B".parse::<ServerCommand>(),
+            Ok(ServerCommand::Aggregate(AggTarget::Column(1), kinds, false)) if kinds == [AggKind::Sum]
+        ));
+        assert!(matches!(
+            "rowsum 3 skiperrors".parse::<ServerCommand>(),
+            Ok(ServerCommand::Aggregate(AggTarget::Row(2), _, true))
+        ));
+        assert!(matches!(
+            "colstats AA".parse::<ServerCommand>(),
+            Ok(ServerCommand::Aggregate(AggTarget::Column(26), kinds, false)) if kinds.len() == 4
+        ));
+        assert!("colsum 3".parse::<ServerCommand>().is_err());
+        assert!("rowsum 0".parse::<ServerCommand>().is_err());
+        assert!("colsum B all".parse::<ServerCommand>().is_err());
+        assert!("cas B2 5".parse::<ServerCommand>().is_err());
+        assert!("cas B2 five 6".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "deps B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Dependencies(cell_id)) if cell_id == b2
+        ));
+        assert!(matches!(
+            "rdeps B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Dependents(cell_id, false)) if cell_id == b2
+        ));
+        assert!(matches!(
+            "rdeps B2 transitive".parse::<ServerCommand>(),
+            Ok(ServerCommand::Dependents(cell_id, true)) if cell_id == b2
+        ));
+        assert!("rdeps B2 all".parse::<ServerCommand>().is_err());
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        assert!(matches!(
+            "watch A1_B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Watch(regions)) if regions == [(a1, b2)]
+        ));
+        assert!(matches!(
+            "watch A1  B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Watch(regions)) if regions == [(a1, a1), (b2, b2)]
+        ));
+        assert!(matches!(
+            "unwatch B2".parse::<ServerCommand>(),
+            Ok(ServerCommand::Unwatch(regions)) if regions == [(b2, b2)]
+        ));
+        assert!("watch A1_".parse::<ServerCommand>().is_err());
+        assert!("watch A1 B".parse::<ServerCommand>().is_err());
+        assert!("unwatch".parse::<ServerCommand>().is_err());
+        assert!(matches!(
+            "changes 12".parse::<ServerCommand>(),
+            Ok(ServerCommand::Changes(12))
+        ));
+        assert!("changes".parse::<ServerCommand>().is_err());
+        assert!("changes -1".parse::<ServerCommand>().is_err());
+        assert_eq!(
+            format_cell_names(&[CellIdentifier { col: 0, row: 0 }, b2]),
+            "A1\nB2"
+        );
+    }
+
+    #[test]
+    fn test_command_cells() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let cells = |text: &str| text.parse::<ServerCommand>().unwrap().cells();
+
+        assert_eq!(cells("get C3"), [cell("C3")]);
+        assert_eq!(cells("set A1 1; B2 2"), [cell("A1"), cell("B2")]);
+        assert_eq!(cells("dump B3_A1"), [cell("A1"), cell("B3")]);
+        assert_eq!(
+            cells("copy A1_B3 D1"),
+            [cell("A1"), cell("B3"), cell("D1"), cell("E3")]
+        );
+        assert_eq!(cells("move A1 D4 overwrite"), [cell("A1"), cell("D4")]);
+        assert_eq!(cells("deleterow 7"), [cell("A7")]);
+        assert_eq!(cells("colsum C"), [cell("C1")]);
+        assert!(cells("stats").is_empty());
+
+        // Column names too long to number don't parse, rather than overflow
+        assert!("get ZZZZZZZ1".parse::<ServerCommand>().is_err());
+        assert!("set ZZZZZZZ1 5".parse::<ServerCommand>().is_err());
+        assert!("set A1 1; ZZZZZZZ1 2".parse::<ServerCommand>().is_err());
+        assert!("colsum ZZZZZZZ".parse::<ServerCommand>().is_err());
+    }
+
+    #[test]
+    fn test_split_sheet() {
+        assert_eq!(
+            split_sheet("set Sheet2!A1 5"),
+            (Some("Sheet2"), "set A1 5".to_string())
+        );
+        assert_eq!(
+            split_sheet(" get  data_1!A1_B2"),
+            (Some("data_1"), "get A1_B2".to_string())
+        );
+        assert_eq!(split_sheet("get A1"), (None, "get A1".to_string()));
+        assert_eq!(
+            split_sheet("set A1 \"Hi!\""),
+            (None, "set A1 \"Hi!\"".to_string())
+        );
+        assert_eq!(split_sheet("list"), (None, "list".to_string()));
+        assert!("list A1".parse::<ServerCommand>().is_err());
+    }
+
+    #[test]
+    fn test_format_cell_list() {
+        let cells = vec![
+            (
+                CellIdentifier { col: 0, row: 0 },
+                "5".to_string(),
+                CellValue::Int(5),
+            ),
+            (
+                CellIdentifier { col: 1, row: 2 },
+                "A1 + 1".to_string(),
+                CellValue::Int(6),
+            ),
+        ];
+
+        assert_eq!(format_cell_list(&cells), "A1\t5\t5\nB3\tA1 + 1\t6");
+        assert_eq!(format_cell_list(&[]), "");
+        assert_eq!(
+            format_changes(&[(CellIdentifier { col: 1, row: 2 }, CellValue::Int(6), 4)]),
+            "B3\t6\t4"
+        );
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let stats = SheetStats {
+            cells: 4,
+            errors: 1,
+            edges: 3,
+            max_chain_depth: 2,
+            queue_depth: 0,
+            evaluations: 9,
+            evaluator_threads: 1,
+            abandoned_evaluations: 0,
+            uptime: std::time::Duration::from_millis(250),
+        };
+        assert_eq!(
+            format_stats(&stats),
+            "cells\t4\nerrors\t1\nedges\t3\nmax_chain_depth\t2\nqueue_depth\t0\n\
+             evaluations\t9\nevaluator_threads\t1\nabandoned_evaluations\t0\nuptime_ms\t250"
+        );
+    }
+
+    #[test]
+    fn test_reply_value() {
+        let text = |text: &str| CellValue::String(text.to_string());
+        assert_eq!(reply_value(text("hello")), text("hello"));
+        assert_eq!(
+            reply_value(text(r#"say "hi" \ bye"#)).to_string(),
+            r#""say \"hi\" \\ bye""#
+        );
+        assert_eq!(reply_value(CellValue::Int(3)), CellValue::Int(3));
+    }
+
+    #[test]
+    fn test_format_history() {
+        let at = |ms: u64| UNIX_EPOCH + std::time::Duration::from_millis(ms);
+        let entries = vec![
+            (at(1500), "1".to_string()),
+            (at(2750), "A1 + 1".to_string()),
+        ];
+        assert_eq!(format_history(&entries), "1500\t1\n2750\tA1 + 1");
+        assert_eq!(format_history(&[]), "");
+    }
+
+    #[test]
+    fn test_capabilities_list_every_command() {
+        assert!(matches!(
+            "version".parse::<ServerCommand>(),
+            Ok(ServerCommand::Capabilities)
+        ));
+        let reply = format_capabilities();
+        let (version, commands) = reply.split_once('\n').unwrap();
+        assert_eq!(version, format!("version\t{}", env!("CARGO_PKG_VERSION")));
+
+        // Each listed keyword parses with some argument, and an unknown one doesn't
+        let commands: Vec<&str> = commands
+            .strip_prefix("commands\t")
+            .unwrap()
+            .split(' ')
+            .collect();
+        for keyword in [
+            "get", "set", "list", "reset", "watch", "export", "whatif", "findexpr",
+        ] {
+            assert!(commands.contains(&keyword), "{keyword} is not listed");
+        }
+        let parses = |keyword: &str| {
+            ["", " A1", " 1", " B", " A1 A1", " A1 1 1", " A1 A1=1"]
+                .iter()
+                .any(|argument| {
+                    format!("{keyword}{argument}")
+                        .parse::<ServerCommand>()
+                        .is_ok()
+                })
+        };
+        for keyword in &commands {
+            assert!(parses(keyword), "{keyword} is not parsed");
+        }
+        assert!(!parses("frobnicate"));
+    }
+
+    #[test]
+    fn test_format_matches() {
+        let cells: Vec<CellIdentifier> = ["B1", "A2", "C3"]
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        assert_eq!(format_matches(&cells, 5), "B1\nA2\nC3");
+        assert_eq!(format_matches(&cells, 1), "B1\n+2 more");
+        assert_eq!(format_matches(&[], 1), "");
+    }
+
+    #[test]
+    fn test_format_health() {
+        let mut report = HealthReport {
+            worker_alive: true,
+            cells_poisoned: false,
+            graph_poisoned: false,
+            pending_updates: 2,
+            uptime: std::time::Duration::from_millis(1500),
+        };
+        assert_eq!(
+            format_health(&report),
+            "OK\nworker_alive\ttrue\ncells_poisoned\tfalse\ngraph_poisoned\tfalse\n\
+             pending_updates\t2\nuptime_ms\t1500"
+        );
+
+        report.worker_alive = false;
+        assert!(format_health(&report).starts_with("DEGRADED\n"));
+    }
+}
@@ -0,0 +1,260 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+
+use rsheet_lib::connect::{
+    Connection, ConnectionError, ConnectionReader, Manager, ReadMessageResult, Reader,
+    ReaderWriter, TerminalReader, WriteMessageResult, Writer,
+};
+use rsheet_lib::replies::Reply;
+
+// Longest message a TcpReader accepts, newline included, as rsheet_lib's
+// ConnectionReader does
+const MAX_MESSAGE_LENGTH: usize = 512;
+
+/**
+ * Closes a connection from another thread, ending any read blocked on it
+ */
+pub struct Closer(Box<dyn FnOnce() + Send>);
+
+impl Closer {
+    /**
+     * Public Function
+     * Wraps the function that closes the connection
+     */
+    pub fn new(close: impl FnOnce() + Send + 'static) -> Self {
+        Closer(Box::new(close))
+    }
+
+    /**
+     * Public Function
+     * Closes the connection; a blocked read returns ConnectionClosed or an
+     * error
+     */
+    pub fn close(self) {
+        (self.0)()
+    }
+}
+
+/**
+ * A Reader whose connection the server can close once it is done with it,
+ * so the thread reading from it exits rather than block until the client
+ * next sends something
+ */
+pub trait Closeable {
+    /**
+     * Public Function
+     * Gives a way to close the connection, or None, by default, if it
+     * can't be closed while a read is blocked on it
+     */
+    fn closer(&self) -> Option<Closer> {
+        None
+    }
+}
+
+/**
+ * A Reader the server can't close, for managers whose readers don't
+ * implement Closeable, see start_server
+ */
+pub struct Unclosable<R>(pub R);
+
+impl<R: Reader> Reader for Unclosable<R> {
+    fn read_message(&mut self) -> ReadMessageResult {
+        self.0.read_message()
+    }
+
+    fn id(&self) -> String {
+        self.0.id()
+    }
+}
+
+impl<R> Closeable for Unclosable<R> {}
+
+/**
+ * Terminal clients share stdin, which can't be closed for one of them
+ */
+impl Closeable for TerminalReader {}
+
+/**
+ * rsheet_lib keeps the socket private, so serve TCP with TcpManager instead
+ */
+impl Closeable for ConnectionReader {}
+
+/**
+ * Accepts TCP connections, speaking the same protocol as rsheet_lib's
+ * ConnectionManager: newline-terminated commands in, one JSON reply per
+ * line out. Unlike it, the server can close each connection, see Closeable
+ */
+pub struct TcpManager {
+    listener: TcpListener, // Socket new connections arrive on
+}
+
+impl TcpManager {
+    /**
+     * Public Function
+     * Listens on the given address
+     */
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        Ok(TcpManager {
+            listener: TcpListener::bind(address)?,
+        })
+    }
+
+    /**
+     * Public Function
+     * Gets the address being listened on, e.g. the port picked for port 0
+     */
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/**
+ * The reader and writer of a TcpManager connection
+ */
+pub struct TcpReaderWriter;
+
+impl ReaderWriter for TcpReaderWriter {
+    type Reader = TcpReader;
+    type Writer = TcpWriter;
+}
+
+impl Manager for TcpManager {
+    type ReaderWriter = TcpReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Connection<TcpReader, TcpWriter> {
+        let Ok((socket, address)) = self.listener.accept() else {
+            return Connection::NoMoreConnections;
+        };
+        let Ok(read_socket) = socket.try_clone() else {
+            return Connection::NoMoreConnections;
+        };
+        Connection::NewConnection {
+            reader: TcpReader {
+                socket: read_socket,
+                address,
+                buffer: Vec::new(),
+            },
+            writer: TcpWriter { socket, address },
+        }
+    }
+}
+
+/**
+ * Reads newline-terminated messages from a TCP connection
+ */
+pub struct TcpReader {
+    socket: TcpStream,   // Read half of the connection
+    address: SocketAddr, // Client's address, used as the id
+    buffer: Vec<u8>,     // Bytes read but not yet returned as a message
+}
+
+impl Reader for TcpReader {
+    fn read_message(&mut self) -> ReadMessageResult {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).take(end).collect();
+                return match String::from_utf8(line) {
+                    Ok(message) => ReadMessageResult::Message(message),
+                    Err(_) => ReadMessageResult::Err(ConnectionError::MessageInvalidUtf8),
+                };
+            }
+            if self.buffer.len() >= MAX_MESSAGE_LENGTH {
+                self.buffer.clear();
+                return ReadMessageResult::Err(ConnectionError::MessageTooLong);
+            }
+
+            let mut chunk = [0; MAX_MESSAGE_LENGTH];
+            match self
+                .socket
+                .read(&mut chunk[..MAX_MESSAGE_LENGTH - self.buffer.len()])
+            {
+                Ok(0) => return ReadMessageResult::ConnectionClosed,
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return ReadMessageResult::Err(ConnectionError::ConnectionLost),
+            }
+        }
+    }
+
+    fn id(&self) -> String {
+        self.address.to_string()
+    }
+}
+
+impl Closeable for TcpReader {
+    fn closer(&self) -> Option<Closer> {
+        let socket = self.socket.try_clone().ok()?;
+        Some(Closer::new(move || {
+            let _ = socket.shutdown(Shutdown::Both);
+        }))
+    }
+}
+
+/**
+ * Writes one JSON reply per line to a TCP connection
+ */
+pub struct TcpWriter {
+    socket: TcpStream,   // Write half of the connection
+    address: SocketAddr, // Client's address, used as the id
+}
+
+impl Writer for TcpWriter {
+    fn write_message(&mut self, message: Reply) -> WriteMessageResult {
+        let Ok(message) = serde_json::to_string(&message) else {
+            return WriteMessageResult::Err(ConnectionError::CouldNotConvertToJson);
+        };
+        if self
+            .socket
+            .write_all(format!("{message}\n").as_bytes())
+            .is_err()
+        {
+            return WriteMessageResult::ConnectionClosed;
+        }
+        let _ = self.socket.flush();
+        WriteMessageResult::Ok
+    }
+
+    fn id(&self) -> String {
+        self.address.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_reader_splits_lines_and_closes() {
+        let mut manager = TcpManager::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = TcpStream::connect(manager.local_addr().unwrap()).unwrap();
+        let Connection::NewConnection { mut reader, .. } = manager.accept_new_connection() else {
+            panic!("no connection accepted");
+        };
+
+        // Messages split across writes, or sharing one, come out a line each
+        client.write_all(b"get A1\nset B").unwrap();
+        client.write_all(b"2 3\n").unwrap();
+        let message = |result| match result {
+            ReadMessageResult::Message(message) => message,
+            _ => panic!("expected a message"),
+        };
+        assert_eq!(message(reader.read_message()), "get A1");
+        assert_eq!(message(reader.read_message()), "set B2 3");
+
+        // An overlong line is rejected
+        client.write_all(&[b'x'; MAX_MESSAGE_LENGTH]).unwrap();
+        assert!(matches!(
+            reader.read_message(),
+            ReadMessageResult::Err(ConnectionError::MessageTooLong)
+        ));
+
+        // Closing ends a read that is waiting on the client
+        let closer = reader.closer().unwrap();
+        let read = std::thread::spawn(move || reader.read_message());
+        closer.close();
+        assert!(matches!(
+            read.join().unwrap(),
+            ReadMessageResult::ConnectionClosed
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+/**
+ * HELPER FUNCTION
+ * Splits CSV text into rows of fields
+ *
+ * Procedure:
+ * 1. Separates fields on commas and rows on LF or CRLF line endings
+ * 2. Reads fields starting with a quote up to the closing quote, keeping
+ *    commas and line breaks inside them and turning "" into one quote
+ * 3. Ignores a final line ending, so "1,2\n" is one row
+ * 4. Returns an error naming the row for a quote that is never closed or
+ *    is followed by anything but a separator
+ */
+pub fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+
+    if text.is_empty() {
+        return Ok(rows);
+    }
+
+    loop {
+        // Step 2: A quoted field runs to its closing quote
+        if chars.peek() == Some(&'"') && field.is_empty() {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err(format!("Unclosed quote in row {}", rows.len() + 1)),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+                return Err(format!(
+                    "Unexpected text after quote in row {}",
+                    rows.len() + 1
+                ));
+            }
+        }
+
+        // Step 1: Separators end the field, and line endings the row
+        match chars.next() {
+            Some(',') => row.push(std::mem::take(&mut field)),
+            Some('\r') if chars.peek() == Some(&'\n') => {}
+            Some('\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+                // Step 3: No empty row after the final line ending
+                if chars.peek().is_none() {
+                    return Ok(rows);
+                }
+            }
+            Some(c) => field.push(c),
+            None => {
+                row.push(field);
+                rows.push(row);
+                return Ok(rows);
+            }
+        }
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats a field for CSV output, quoting it when it holds a comma, quote
+ * or line break
+ */
+pub fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Writes a field as a string literal for an expression, escaping the
+ * characters that would otherwise end or alter it
+ */
+pub fn string_literal(field: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in field.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        assert_eq!(
+            parse("1,,x\r\n\"a, \"\"b\"\"\",\"two\nlines\"\n").unwrap(),
+            vec![vec!["1", "", "x"], vec!["a, \"b\"", "two\nlines"],]
+        );
+        assert_eq!(parse("5").unwrap(), vec![vec!["5"]]);
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_csv() {
+        assert_eq!(parse("1\n\"open").unwrap_err(), "Unclosed quote in row 2");
+        assert!(parse("\"a\"b").is_err());
+    }
+
+    #[test]
+    fn test_quote_round_trips() {
+        let field = "say \"hi\", twice";
+        assert_eq!(parse(&quote(field)).unwrap(), vec![vec![field]]);
+        assert_eq!(quote("plain"), "plain");
+    }
+}
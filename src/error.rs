@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fmt;
+
+use rsheet_lib::cell_expr::CellExprEvalError;
+use rsheet_lib::command::CellIdentifier;
+
+use crate::references::a1_name;
+
+/**
+ * Errors returned by Spreadsheet operations
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpreadsheetError {
+    CellNotSet(CellIdentifier), // The operation needs a cell that has never been set
+    LockPoisoned,               // A thread panicked while holding one of the sheet's locks
+    InvalidReference(String),   // A variable that is neither a cell nor a valid range
+    InvalidName(String),        // A name that would clash with a cell, range or keyword
+    UnknownName(String),        // A name that has not been defined
+    WorkerUnavailable,          // The update worker has stopped, so dependents can't be recomputed
+    NothingToUndo(CellIdentifier), // The cell has no earlier expression left to restore
+    NothingToRedo(CellIdentifier), // Nothing was undone in the cell since its last set
+    CellLimitReached(usize),    // The set would add a cell to a sheet already holding its maximum
+    RangeTooLarge(String, usize), // A range covering more cells than the sheet allows
+    ExpressionTooLong(usize),   // An expression longer than the sheet allows, in characters
+    OutOfBounds(CellIdentifier, CellIdentifier), // A cell past the sheet's edge, and its last cell
+    CellOccupied(CellIdentifier), // A move onto a cell that holds an expression, without overwrite
+    EvalError(CellExprEvalError), // The update could not be applied
+}
+
+impl fmt::Display for SpreadsheetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpreadsheetError::CellNotSet(cell_id) => {
+                write!(f, "Cell {} has not been set", a1_name(cell_id))
+            }
+            SpreadsheetError::LockPoisoned => write!(f, "Spreadsheet state is unavailable"),
+            SpreadsheetError::InvalidReference(name) => {
+                write!(f, "{} is not a valid cell or range reference", name)
+            }
+            SpreadsheetError::InvalidName(name) => write!(f, "{} can't be used as a name", name),
+            SpreadsheetError::UnknownName(name) => write!(f, "{} is not a defined name", name),
+            SpreadsheetError::WorkerUnavailable => {
+                write!(
+                    f,
+                    "Updates are unavailable because the update worker has stopped"
+                )
+            }
+            SpreadsheetError::NothingToUndo(cell_id) => {
+                write!(f, "Nothing to undo in {}", a1_name(cell_id))
+            }
+            SpreadsheetError::NothingToRedo(cell_id) => {
+                write!(f, "Nothing to redo in {}", a1_name(cell_id))
+            }
+            SpreadsheetError::CellLimitReached(max_cells) => {
+                write!(f, "Sheet is full: it holds at most {} cells", max_cells)
+            }
+            SpreadsheetError::RangeTooLarge(range, max_cells) => {
+                write!(f, "range {} exceeds the {}-cell limit", range, max_cells)
+            }
+            SpreadsheetError::ExpressionTooLong(max_length) => {
+                write!(f, "expression exceeds the {}-character limit", max_length)
+            }
+            SpreadsheetError::OutOfBounds(cell_id, last) => write!(
+                f,
+                "cell {} is outside the sheet bounds (max {})",
+                a1_name(cell_id),
+                a1_name(last)
+            ),
+            SpreadsheetError::CellOccupied(cell_id) => {
+                write!(f, "Cell {} is not empty", a1_name(cell_id))
+            }
+            SpreadsheetError::EvalError(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl Error for SpreadsheetError {}
+
+/**
+ * Errors returned by Spreadsheet::aggregate
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum AggError {
+    ErrorCell(CellIdentifier), // A cell in scope holds an error and errors aren't being skipped
+    Overflow,                  // The sum doesn't fit in a 64-bit integer
+}
+
+impl fmt::Display for AggError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggError::ErrorCell(cell_id) => write!(f, "Cell {} holds an error", a1_name(cell_id)),
+            AggError::Overflow => write!(f, "The sum is too large to hold in a cell"),
+        }
+    }
+}
+
+impl Error for AggError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_read_distinctly() {
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let last = CellIdentifier { col: 2, row: 9 };
+        let messages: Vec<String> = [
+            SpreadsheetError::CellNotSet(a1),
+            SpreadsheetError::LockPoisoned,
+            SpreadsheetError::InvalidReference("A1_".into()),
+            SpreadsheetError::WorkerUnavailable,
+            SpreadsheetError::RangeTooLarge("A_C".into(), 100),
+            SpreadsheetError::OutOfBounds(CellIdentifier { col: 3, row: 0 }, last),
+            SpreadsheetError::CellOccupied(a1),
+            SpreadsheetError::EvalError(CellExprEvalError::VariableDependsOnError),
+        ]
+        .iter()
+        .map(SpreadsheetError::to_string)
+        .collect();
+        assert_eq!(
+            messages,
+            [
+                "Cell A1 has not been set",
+                "Spreadsheet state is unavailable",
+                "A1_ is not a valid cell or range reference",
+                "Updates are unavailable because the update worker has stopped",
+                "range A_C exceeds the 100-cell limit",
+                "cell D1 is outside the sheet bounds (max C10)",
+                "Cell A1 is not empty",
+                "VariableDependsOnError",
+            ]
+        );
+    }
+}
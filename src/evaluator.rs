@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use rsheet_lib::cell_expr::{CellArgument, CellExpr, CellExprEvalError};
+use rsheet_lib::cell_value::CellValue;
+
+use crate::references;
+
+// Timed-out evaluations that may still be running at once. Past this, an
+// expression calling sleep_then, the one builtin that runs long, times out
+// straight away rather than start another thread; any other still runs
+pub const MAX_ABANDONED_EVALUATIONS: usize = 4;
+
+// Builtin that waits before returning, e.g. sleep_then(100000, 5)
+const SLEEP_FUNCTION: &str = "sleep_then";
+
+type Evaluated = Result<CellValue, CellExprEvalError>;
+
+// An expression, its variables, where to send its result, and whether the
+// caller gave up waiting for it
+type Job = (
+    String,
+    HashMap<String, CellArgument>,
+    mpsc::Sender<Evaluated>,
+    Arc<Mutex<bool>>,
+);
+
+/**
+ * Evaluates expressions on one long-lived thread, giving up on any that
+ * runs past a timeout, see SpreadsheetOptions::eval_timeout
+ *
+ * A Rhai evaluation can't be interrupted, so a timed-out one keeps its
+ * thread until it finishes. That thread is abandoned and the next
+ * evaluation starts a fresh one; every other evaluation reuses the current
+ * thread, so rsheet_lib sees a single thread evaluating expressions
+ */
+#[derive(Debug)]
+pub struct TimedEvaluator {
+    timeout: Duration,                      // Longest one evaluation may take
+    jobs: Mutex<Option<mpsc::Sender<Job>>>, // Queue of the current thread, if it is usable
+    started: AtomicUsize,                   // Evaluator threads started so far
+    abandoned: Arc<AtomicUsize>,            // Timed-out evaluations still running
+}
+
+impl TimedEvaluator {
+    /**
+     * Public Function
+     * Prepares an evaluator; its first thread starts with the first evaluation
+     */
+    pub fn new(timeout: Duration) -> Self {
+        TimedEvaluator {
+            timeout,
+            jobs: Mutex::new(None),
+            started: AtomicUsize::new(0),
+            abandoned: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /**
+     * Public Function
+     * Evaluates an expression, returning None if it hasn't finished once the
+     * timeout has passed, or if it panicked
+     *
+     * Procedure:
+     * 1. Takes the queue lock for the whole evaluation, so the timeout only
+     *    counts time spent on this expression
+     * 2. Starts an evaluator thread if there is none, unless
+     *    MAX_ABANDONED_EVALUATIONS are still running and the expression
+     *    calls sleep_then, in which case gives up straight away, so
+     *    runaway cells can't hold every other cell's evaluations hostage
+     * 3. Sends the expression to the thread and waits for its result
+     * 4. On a timeout, marks the evaluation as given up and counts it as
+     *    abandoned until the thread finishes it, unless the result arrived
+     *    meanwhile
+     * 5. On a timeout or panic, drops the thread's queue so it exits once
+     *    its evaluation finishes, and the next evaluation starts a new one
+     */
+    pub fn evaluate(
+        &self,
+        expression: &str,
+        variables: &HashMap<String, CellArgument>,
+    ) -> Option<Evaluated> {
+        // Step 1: One evaluation at a time
+        let mut jobs = self.jobs.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Step 2: Make sure there's a thread to evaluate on
+        if jobs.is_none() {
+            if self.abandoned.load(Ordering::SeqCst) >= MAX_ABANDONED_EVALUATIONS
+                && references::identifiers(expression)
+                    .iter()
+                    .any(|token| token == SLEEP_FUNCTION)
+            {
+                return None;
+            }
+            *jobs = Some(self.spawn());
+        }
+
+        // Step 3: Evaluate
+        let (sender, receiver) = mpsc::channel();
+        let given_up = Arc::new(Mutex::new(false));
+        let job = (
+            expression.to_string(),
+            variables.clone(),
+            sender,
+            Arc::clone(&given_up),
+        );
+        let sent = jobs.as_ref().is_some_and(|queue| queue.send(job).is_ok());
+        let result = match sent.then(|| receiver.recv_timeout(self.timeout)) {
+            Some(Ok(result)) => Some(result),
+            // Step 4: Give up, unless the thread is just sending the result
+            Some(Err(mpsc::RecvTimeoutError::Timeout)) => {
+                let mut given_up = given_up.lock().unwrap_or_else(PoisonError::into_inner);
+                let result = receiver.try_recv().ok();
+                if result.is_none() {
+                    *given_up = true;
+                    self.abandoned.fetch_add(1, Ordering::SeqCst);
+                }
+                result
+            }
+            Some(Err(mpsc::RecvTimeoutError::Disconnected)) | None => None,
+        };
+
+        // Step 5: Abandon a thread that is stuck or gone
+        if result.is_none() {
+            *jobs = None;
+        }
+        result
+    }
+
+    /**
+     * Public Function
+     * Counts the evaluator threads started so far, including abandoned ones
+     */
+    pub fn threads_started(&self) -> usize {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Public Function
+     * Counts the timed-out evaluations still running on abandoned threads
+     */
+    pub fn abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Starts an evaluator thread, returning its queue
+     * The thread evaluates each job in turn and exits once its queue is
+     * dropped. Finishing a job its caller gave up on ends its abandonment
+     */
+    fn spawn(&self) -> mpsc::Sender<Job> {
+        let (queue, jobs) = mpsc::channel::<Job>();
+        let abandoned = Arc::clone(&self.abandoned);
+        self.started.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            for (expression, variables, reply, given_up) in jobs {
+                let result = CellExpr::new(&expression).evaluate(&variables);
+                let given_up = given_up.lock().unwrap_or_else(PoisonError::into_inner);
+                if *given_up {
+                    abandoned.fetch_sub(1, Ordering::SeqCst);
+                } else {
+                    let _ = reply.send(result);
+                }
+            }
+        });
+        queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeouts_replace_the_thread_up_to_the_cap() {
+        let evaluator = TimedEvaluator::new(Duration::from_millis(20));
+        let variables = HashMap::new();
+        assert_eq!(
+            evaluator.evaluate("1 + 1", &variables),
+            Some(Ok(CellValue::Int(2)))
+        );
+        assert_eq!(evaluator.threads_started(), 1);
+
+        // Each timeout abandons its thread, until too many are still running
+        for _ in 0..MAX_ABANDONED_EVALUATIONS + 1 {
+            assert_eq!(evaluator.evaluate("sleep_then(300, 1)", &variables), None);
+        }
+        assert_eq!(evaluator.threads_started(), MAX_ABANDONED_EVALUATIONS);
+        assert_eq!(evaluator.abandoned(), MAX_ABANDONED_EVALUATIONS);
+
+        // Other expressions still get a fresh thread
+        assert_eq!(
+            evaluator.evaluate("1 + 1", &variables),
+            Some(Ok(CellValue::Int(2)))
+        );
+        assert_eq!(evaluator.threads_started(), MAX_ABANDONED_EVALUATIONS + 1);
+
+        // Once the runaways finish, sleep_then runs again too
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(evaluator.abandoned(), 0);
+        assert_eq!(
+            evaluator.evaluate("sleep_then(1, 3)", &variables),
+            Some(Ok(CellValue::Int(3)))
+        );
+    }
+}
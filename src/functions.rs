@@ -0,0 +1,541 @@
+use std::collections::{HashMap, HashSet};
+
+use rsheet_lib::cell_expr::{CellArgument, CellExpr, CellExprEvalError};
+use rsheet_lib::cell_value::CellValue;
+
+use crate::references;
+
+/**
+ * A function call found in an expression
+ */
+struct Call<'a> {
+    before: &'a str,    // Text preceding the function name
+    args: Vec<&'a str>, // Trimmed arguments
+    after: &'a str,     // Text following the closing parenthesis
+}
+
+/**
+ * HELPER FUNCTION
+ * Expands calls to the functions implemented here, which rsheet_lib's engine
+ * doesn't know about, into plain values
+ *
+ * Supported functions:
+ * - if(CONDITION, THEN, ELSE): THEN when CONDITION is true or a non-zero
+ *   integer, otherwise ELSE. Rhai's own "if (c) { a } else { b }" is left
+ *   alone
+ * - sumif(RANGE, THRESHOLD): sums the integers in RANGE greater than
+ *   THRESHOLD, skipping empty, string and error cells
+ * - ref_error("NAME"): always fails, marking where a reference was moved
+ *   off the sheet, see references::map_references
+ *
+ * Procedure:
+ * 1. Scans the expression for calls, skipping string and character literals
+ * 2. Splits each call's arguments at top-level commas
+ * 3. Replaces each if call with its chosen branch, outermost first, so only
+ *    the variables of taken branches are left to evaluate
+ * 4. Evaluates each sumif call and splices its result in as a literal
+ * 5. Returns an error message if a call is malformed or is a ref_error
+ */
+pub fn expand_calls(
+    expr: &str,
+    variables: &HashMap<String, CellArgument>,
+) -> Result<String, String> {
+    if let Some(call) = next_call(expr, "ref_error")? {
+        let name = call.args.first().map_or("", |arg| arg.trim_matches('"'));
+        return Err(format!(
+            "Reference error: {name} no longer points to a cell"
+        ));
+    }
+
+    let mut expr = expr.to_string();
+    let mut offset = 0;
+    while let Some(call) = next_call(&expr[offset..], "if")? {
+        let start = offset + call.before.len();
+        if call.after.trim_start().starts_with('{') {
+            offset = expr.len() - call.after.len();
+            continue;
+        }
+
+        // The branch may hold further if calls, so scanning resumes at it
+        let branch = choose_branch(&call.args, variables)?;
+        expr = format!("{}({}){}", &expr[..start], branch, call.after);
+        offset = start;
+    }
+
+    let mut output = String::with_capacity(expr.len());
+    let mut rest = expr.as_str();
+
+    while let Some(call) = next_call(rest, "sumif")? {
+        output.push_str(call.before);
+        output.push_str(&format!("({})", sumif(&call.args, variables)?));
+        rest = call.after;
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/**
+ * HELPER FUNCTION
+ * Keeps only the variables an expression still refers to
+ */
+pub fn used_variables(
+    expr: &str,
+    variables: &HashMap<String, CellArgument>,
+) -> HashMap<String, CellArgument> {
+    let used = references::identifiers(expr);
+    variables
+        .iter()
+        .filter(|(name, _)| used.contains(name))
+        .map(|(name, arg)| (name.clone(), arg.clone()))
+        .collect()
+}
+
+/**
+ * A token of an expression, as far as mixed_operands needs to tell them apart
+ */
+enum Token<'a> {
+    Operand(Kind, &'a str), // A literal or variable, with any leading sign
+    Operator(char),         // One of + - * / %
+    Other,                  // Any other character, e.g. a parenthesis
+}
+
+/**
+ * The kind of value an operand stands for
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Text,    // A string literal, or a variable holding a string
+    Number,  // A number literal, or a variable holding an integer
+    Boolean, // true or false, or a variable holding a boolean
+    Other,   // Anything else, e.g. a range, a call or a name
+}
+
+/**
+ * HELPER FUNCTION
+ * Finds an arithmetic operator written between a string and a number, which
+ * rsheet_lib's engine either rejects with an obscure message or, for "+",
+ * quietly turns into concatenation, or beside a boolean, and describes it
+ * as a TypeError
+ *
+ * Procedure:
+ * 1. Splits the expression into operands (literals and variables, with any
+ *    leading sign), the operators + - * / %, and other characters, skipping
+ *    whitespace
+ * 2. Classifies literals by their form and variables by the value they
+ *    hold, the variables named in booleans holding a boolean
+ * 3. Returns an error for the first operator between a string and a
+ *    number, or with a boolean on either side
+ *
+ * Only operands written right beside the operator are checked, so e.g.
+ * "(A1) + 1" is left to the engine. Adding two strings is concatenation
+ * and is allowed
+ */
+pub fn mixed_operands(
+    expr: &str,
+    variables: &HashMap<String, CellArgument>,
+    booleans: &HashSet<String>,
+) -> Option<String> {
+    let bytes = expr.as_bytes();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut sign_start: Option<usize> = None;
+    let mut index = 0;
+
+    // Step 1: Split the expression into tokens
+    while index < bytes.len() {
+        let first = bytes[index];
+        let end = match first {
+            b'"' | b'`' | b'\'' => skip_literal(bytes, index),
+            c if c.is_ascii_alphanumeric() || c == b'_' || c == b'$' => {
+                let mut end = index;
+                while end < bytes.len()
+                    && (bytes[end].is_ascii_alphanumeric()
+                        || matches!(bytes[end], b'_' | b'$')
+                        || (bytes[end] == b'.' && first.is_ascii_digit()))
+                {
+                    end += 1;
+                }
+                end
+            }
+            c => {
+                let follows_operand = matches!(tokens.last(), Some(Token::Operand(..)));
+                match c {
+                    _ if c.is_ascii_whitespace() => {}
+                    b'+' | b'-' if !follows_operand && sign_start.is_none() => {
+                        sign_start = Some(index)
+                    }
+                    b'+' | b'-' | b'*' | b'/' | b'%' => {
+                        sign_start = None;
+                        tokens.push(Token::Operator(c as char));
+                    }
+                    _ => {
+                        sign_start = None;
+                        tokens.push(Token::Other);
+                    }
+                }
+                index += 1;
+                continue;
+            }
+        };
+
+        // Step 2: Classify the operand
+        let kind = match first {
+            b'"' | b'`' => Kind::Text,
+            b'\'' => Kind::Other,
+            c if c.is_ascii_digit() => Kind::Number,
+            _ if matches!(&expr[index..end], "true" | "false")
+                || booleans.contains(&expr[index..end]) =>
+            {
+                Kind::Boolean
+            }
+            _ => match variables.get(&expr[index..end]) {
+                Some(CellArgument::Value(CellValue::String(_))) => Kind::Text,
+                Some(CellArgument::Value(CellValue::Int(_))) => Kind::Number,
+                _ => Kind::Other,
+            },
+        };
+        let text = &expr[sign_start.take().unwrap_or(index)..end];
+        tokens.push(Token::Operand(kind, text));
+        index = end;
+    }
+
+    // Step 3: Look for a string and a number, or a boolean, around one operator
+    tokens.windows(3).find_map(|window| match window {
+        [Token::Operand(left, left_text), Token::Operator(operator), Token::Operand(right, right_text)]
+            if *left == Kind::Boolean || *right == Kind::Boolean =>
+        {
+            Some(format!(
+                "TypeError: {left_text} {operator} {right_text} uses a boolean in arithmetic"
+            ))
+        }
+        [Token::Operand(left, left_text), Token::Operator(operator), Token::Operand(right, right_text)]
+            if matches!(
+                (left, right),
+                (Kind::Text, Kind::Number) | (Kind::Number, Kind::Text)
+            ) =>
+        {
+            Some(format!(
+                "TypeError: {left_text} {operator} {right_text} mixes a string and a number"
+            ))
+        }
+        _ => None,
+    })
+}
+
+/**
+ * HELPER FUNCTION
+ * Turns each variable named in booleans, which holds 1 or 0, back into the
+ * engine's own true or false, e.g. "A1 && B1" into
+ * "(A1 != 0) && (B1 != 0)", so it can be combined with other booleans but
+ * not used as a number
+ */
+pub fn as_booleans(expr: &str, booleans: &HashSet<String>) -> String {
+    references::rewrite_identifiers(expr, |token| {
+        booleans.contains(token).then(|| format!("({token} != 0)"))
+    })
+}
+
+/**
+ * HELPER FUNCTION
+ * Finds the first call to a function in an expression
+ */
+fn next_call<'a>(expr: &'a str, function: &str) -> Result<Option<Call<'a>>, String> {
+    let bytes = expr.as_bytes();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let c = bytes[index];
+        if c == b'"' || c == b'\'' || c == b'`' {
+            index = skip_literal(bytes, index);
+        } else if c.is_ascii_alphanumeric() || c == b'_' {
+            let start = index;
+            while index < bytes.len()
+                && (bytes[index].is_ascii_alphanumeric() || bytes[index] == b'_')
+            {
+                index += 1;
+            }
+            if &expr[start..index] != function {
+                continue;
+            }
+
+            let open = index + expr[index..].len() - expr[index..].trim_start().len();
+            if bytes.get(open) != Some(&b'(') {
+                continue;
+            }
+            let (args, close) = split_arguments(expr, open)
+                .ok_or_else(|| format!("Unclosed call to {function}"))?;
+            return Ok(Some(Call {
+                before: &expr[..start],
+                args,
+                after: &expr[close + 1..],
+            }));
+        } else {
+            index += 1;
+        }
+    }
+
+    Ok(None)
+}
+
+/**
+ * HELPER FUNCTION
+ * Returns the index just past the literal starting at `start`
+ */
+fn skip_literal(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut index = start + 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            c if c == quote => return index + 1,
+            _ => index += 1,
+        }
+    }
+    index
+}
+
+/**
+ * HELPER FUNCTION
+ * Splits the arguments of the call whose opening parenthesis is at `open`,
+ * returning them trimmed along with the index of the closing parenthesis
+ */
+fn split_arguments(expr: &str, open: usize) -> Option<(Vec<&str>, usize)> {
+    let bytes = expr.as_bytes();
+    let mut depth = 0;
+    let mut arg_start = open + 1;
+    let mut args = Vec::new();
+    let mut index = open;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'"' | b'\'' | b'`' => {
+                index = skip_literal(bytes, index);
+                continue;
+            }
+            b'(' | b'[' => depth += 1,
+            b')' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push(expr[arg_start..index].trim());
+                    return Some((args, index));
+                }
+            }
+            b',' if depth == 1 => {
+                args.push(expr[arg_start..index].trim());
+                arg_start = index + 1;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+
+    None
+}
+
+/**
+ * HELPER FUNCTION
+ * Evaluates sumif(RANGE, THRESHOLD)
+ *
+ * Procedure:
+ * 1. Requires the first argument to be a range or cell variable
+ * 2. Evaluates the threshold as its own expression, which must give an integer
+ * 3. Sums the range's integers above the threshold, skipping everything else
+ */
+fn sumif(args: &[&str], variables: &HashMap<String, CellArgument>) -> Result<i64, String> {
+    let [range, threshold] = args else {
+        return Err(format!(
+            "sumif takes 2 arguments but {} were given",
+            args.len()
+        ));
+    };
+
+    let values: Vec<&CellValue> = match variables.get(*range) {
+        Some(CellArgument::Value(value)) => vec![value],
+        Some(CellArgument::Vector(values)) => values.iter().collect(),
+        Some(CellArgument::Matrix(rows)) => rows.iter().flatten().collect(),
+        None => return Err(format!("sumif expects a range, not {range}")),
+    };
+
+    // Only pass the threshold its own variables, so errors skipped in the
+    // range can't fail it
+    let threshold = expand_calls(threshold, variables)?;
+    let threshold_variables = used_variables(&threshold, variables);
+    let threshold = match CellExpr::new(&threshold).evaluate(&threshold_variables) {
+        Ok(CellValue::Int(threshold)) => threshold,
+        _ => return Err(format!("sumif threshold {threshold} is not a number")),
+    };
+
+    Ok(values
+        .into_iter()
+        .filter_map(|value| match value {
+            CellValue::Int(i) if *i > threshold => Some(*i),
+            _ => None,
+        })
+        .sum())
+}
+
+/**
+ * HELPER FUNCTION
+ * Picks the branch of if(CONDITION, THEN, ELSE) to evaluate
+ *
+ * Procedure:
+ * 1. Requires exactly three arguments
+ * 2. Evaluates the condition as its own expression, with only its own
+ *    variables; a comparison gives a boolean, which is mapped to 1 or 0
+ *    since a boolean isn't a cell value
+ * 3. Returns THEN for a non-zero integer and ELSE for zero, failing for any
+ *    other value and passing a dependency error on unchanged
+ */
+fn choose_branch<'a>(
+    args: &[&'a str],
+    variables: &HashMap<String, CellArgument>,
+) -> Result<&'a str, String> {
+    let [condition, then, otherwise] = args else {
+        return Err(format!(
+            "if takes 3 arguments but {} were given",
+            args.len()
+        ));
+    };
+
+    let condition = expand_calls(condition, variables)?;
+    let condition_variables = used_variables(&condition, variables);
+    let test = format!(
+        "if ({condition}) == true {{ 1 }} else if ({condition}) == false {{ 0 }} else {{ {condition} }}"
+    );
+    match CellExpr::new(&test).evaluate(&condition_variables) {
+        Ok(CellValue::Int(0)) => Ok(otherwise),
+        Ok(CellValue::Int(_)) => Ok(then),
+        Ok(CellValue::Error(message)) => Err(message),
+        Ok(_) => Err(format!(
+            "if condition {condition} is not a boolean or number"
+        )),
+        Err(CellExprEvalError::VariableDependsOnError) => Err("VariableDependsOnError".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, CellArgument> {
+        HashMap::from([
+            (
+                "A1_A4".to_string(),
+                CellArgument::Vector(vec![
+                    CellValue::Int(1),
+                    CellValue::Int(5),
+                    CellValue::Error("oops".to_string()),
+                    CellValue::Int(8),
+                ]),
+            ),
+            ("B1".to_string(), CellArgument::Value(CellValue::Int(4))),
+        ])
+    }
+
+    #[test]
+    fn test_expand_sumif() {
+        let variables = variables();
+
+        assert_eq!(
+            expand_calls("sumif(A1_A4, 2) + 1", &variables),
+            Ok("(13) + 1".to_string())
+        );
+        assert_eq!(
+            expand_calls("sumif (A1_A4, B1 + 1) * sumif(A1_A4, 0)", &variables),
+            Ok("(8) * (14)".to_string())
+        );
+        assert_eq!(
+            expand_calls(r#""sumif(A1_A4, 2)" + B1"#, &variables),
+            Ok(r#""sumif(A1_A4, 2)" + B1"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_malformed_sumif() {
+        let variables = variables();
+
+        assert!(expand_calls("sumif(A1_A4)", &variables).is_err());
+        assert!(expand_calls("sumif(5, 2)", &variables).is_err());
+        assert!(expand_calls(r#"sumif(A1_A4, "x")"#, &variables).is_err());
+        assert!(expand_calls("sumif(A1_A4, 2", &variables).is_err());
+        assert_eq!(
+            expand_calls(r#"B1 + ref_error("A1")"#, &variables),
+            Err("Reference error: A1 no longer points to a cell".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mixed_operands() {
+        let mut variables = variables();
+        variables.insert(
+            "C1".to_string(),
+            CellArgument::Value(CellValue::String("hi".to_string())),
+        );
+        let booleans = HashSet::from(["D1".to_string()]);
+        let mixed = |expr: &str| mixed_operands(expr, &variables, &booleans);
+
+        assert_eq!(
+            mixed("C1 + 1"),
+            Some("TypeError: C1 + 1 mixes a string and a number".to_string())
+        );
+        assert_eq!(
+            mixed("2 * B1 - -C1"),
+            Some("TypeError: B1 - -C1 mixes a string and a number".to_string())
+        );
+        assert_eq!(
+            mixed(r#""total: " + B1"#),
+            Some(r#"TypeError: "total: " + B1 mixes a string and a number"#.to_string())
+        );
+
+        // Strings may be joined, and numbers combined, freely
+        assert_eq!(mixed(r#"C1 + "!" + C1"#), None);
+        assert_eq!(mixed("-B1 * 2.5 % (B1 - 1)"), None);
+        assert_eq!(mixed(r#"C1 == "1 + 1""#), None);
+        assert_eq!(mixed("sum(A1_A4) + B1"), None);
+
+        // Booleans can be compared and combined, but not used as numbers
+        assert_eq!(
+            mixed("D1 + 1"),
+            Some("TypeError: D1 + 1 uses a boolean in arithmetic".to_string())
+        );
+        assert_eq!(
+            mixed("2 * true"),
+            Some("TypeError: 2 * true uses a boolean in arithmetic".to_string())
+        );
+        assert_eq!(mixed("D1 && B1 > 2 || !D1"), None);
+        assert_eq!(
+            as_booleans("D1 && D1_D2 == \"D1\"", &booleans),
+            "(D1 != 0) && D1_D2 == \"D1\""
+        );
+    }
+
+    #[test]
+    fn test_expand_if() {
+        let variables = variables();
+
+        assert_eq!(
+            expand_calls("if(B1 > 2, B1, 0) + 1", &variables),
+            Ok("(B1) + 1".to_string())
+        );
+        assert_eq!(
+            expand_calls("if(B1 - 4, \"yes\", if(1, sumif(A1_A4, 4), 2))", &variables),
+            Ok("(((13)))".to_string())
+        );
+        assert_eq!(
+            expand_calls("if (B1 > 2) { 1 } else { 2 }", &variables),
+            Ok("if (B1 > 2) { 1 } else { 2 }".to_string())
+        );
+
+        // The untaken branch's error isn't looked at, the condition's is
+        assert_eq!(
+            expand_calls("if(0, A1_A4, B1)", &variables),
+            Ok("(B1)".to_string())
+        );
+        assert_eq!(
+            expand_calls("if(A1_A4, 1, 2)", &variables),
+            Err("VariableDependsOnError".to_string())
+        );
+        assert!(expand_calls("if(B1, 1)", &variables).is_err());
+        assert!(expand_calls("if(\"x\", 1, 2)", &variables).is_err());
+    }
+}
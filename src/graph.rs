@@ -0,0 +1,767 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+
+use rsheet_lib::command::CellIdentifier;
+
+use serde::Serialize;
+
+use crate::references::{a1_name, Reference};
+
+/**
+ * A run of rows in one column that a range reference reads from
+ */
+#[derive(Debug)]
+struct RowSpan {
+    first_row: u32,         // First row covered
+    last_row: u32,          // Last row covered, u32::MAX for open-ended ranges
+    reader: CellIdentifier, // Cell whose expression holds the range
+}
+
+/**
+ * Tracks the dependency edges between cells
+ * Kept separate from cell values so traversals never need the value lock
+ *
+ * Ranges are stored whole rather than expanded into one edge per cell, so a
+ * formula over a huge range costs one span per column it touches, and one
+ * over whole rows one entry per row
+ */
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    references: HashMap<CellIdentifier, Vec<Reference>>, // What each cell reads from
+    cell_readers: HashMap<CellIdentifier, HashSet<CellIdentifier>>, // Cells naming each cell directly
+    range_readers: HashMap<u32, Vec<RowSpan>>, // Per column, the row spans read by ranges
+    row_readers: HashMap<u32, HashSet<CellIdentifier>>, // Per row, the cells reading it whole
+}
+
+impl DependencyGraph {
+    /**
+     * HELPER FUNCTION
+     * Creates an empty dependency graph
+     */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Public Function
+     * Records that a cell depends on each of the given references
+     *
+     * Procedure:
+     * 1. Appends the references to the cell's reference list
+     * 2. Adds the cell to the readers of each single cell it names, whether
+     *    or not that cell has been set yet
+     * 3. Adds one row span per column covered by each range, with open-ended
+     *    ranges and whole columns spanning to the last possible row
+     * 4. Adds the cell to the readers of each row covered by whole rows
+     */
+    pub fn add_edges(&mut self, cell_id: CellIdentifier, references: &[Reference]) {
+        for &reference in references {
+            match reference {
+                Reference::Cell(dep) => {
+                    self.cell_readers.entry(dep).or_default().insert(cell_id);
+                }
+                Reference::Range(start, end) => {
+                    self.add_spans(cell_id, start.col..=end.col, start.row, end.row);
+                }
+                Reference::ColumnsFrom(start, end_col) => {
+                    self.add_spans(cell_id, start.col..=end_col, start.row, u32::MAX);
+                }
+                Reference::Columns(start_col, end_col) => {
+                    self.add_spans(cell_id, start_col..=end_col, 0, u32::MAX);
+                }
+                Reference::Rows(start_row, end_row) => {
+                    for row in start_row..=end_row {
+                        self.row_readers.entry(row).or_default().insert(cell_id);
+                    }
+                }
+            }
+        }
+        self.references
+            .entry(cell_id)
+            .or_default()
+            .extend_from_slice(references);
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Adds a row span to each column in a range of columns
+     */
+    fn add_spans(
+        &mut self,
+        reader: CellIdentifier,
+        columns: RangeInclusive<u32>,
+        first_row: u32,
+        last_row: u32,
+    ) {
+        for col in columns {
+            self.range_readers.entry(col).or_default().push(RowSpan {
+                first_row,
+                last_row,
+                reader,
+            });
+        }
+    }
+
+    /**
+     * Public Function
+     * Removes every edge from a cell to the cells it depends on
+     *
+     * Procedure:
+     * 1. Takes the cell's reference list out of the graph
+     * 2. Removes the cell from the readers of each single cell it named
+     * 3. Removes the cell's spans from each column its ranges covered, and
+     *    the cell from the readers of each whole row it read
+     * 4. Drops reader sets and span lists that become empty
+     */
+    pub fn remove_edges(&mut self, cell_id: CellIdentifier) {
+        let Some(old_references) = self.references.remove(&cell_id) else {
+            return;
+        };
+
+        for reference in old_references {
+            let columns = match reference {
+                Reference::Cell(dep) => {
+                    if let Some(readers) = self.cell_readers.get_mut(&dep) {
+                        readers.remove(&cell_id);
+                        if readers.is_empty() {
+                            self.cell_readers.remove(&dep);
+                        }
+                    }
+                    continue;
+                }
+                Reference::Range(start, end) => start.col..=end.col,
+                Reference::ColumnsFrom(start, end_col) => start.col..=end_col,
+                Reference::Columns(start_col, end_col) => start_col..=end_col,
+                Reference::Rows(start_row, end_row) => {
+                    for row in start_row..=end_row {
+                        if let Some(readers) = self.row_readers.get_mut(&row) {
+                            readers.remove(&cell_id);
+                            if readers.is_empty() {
+                                self.row_readers.remove(&row);
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            for col in columns {
+                if let Some(spans) = self.range_readers.get_mut(&col) {
+                    spans.retain(|span| span.reader != cell_id);
+                    if spans.is_empty() {
+                        self.range_readers.remove(&col);
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * Public Function
+     * Returns the references, single cells and ranges, that a cell reads from
+     */
+    pub fn references_of(&self, cell_id: CellIdentifier) -> &[Reference] {
+        self.references
+            .get(&cell_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /**
+     * Public Function
+     * Returns the cells that directly read from a cell, sorted by (col, row)
+     *
+     * Procedure:
+     * 1. Collects the cells naming the cell directly
+     * 2. Adds the readers of every span in the cell's column covering its row
+     * 3. Adds the cells reading the cell's whole row
+     * 4. Sorts and deduplicates the result
+     */
+    pub fn dependents_of(&self, cell_id: CellIdentifier) -> Vec<CellIdentifier> {
+        let mut dependents: Vec<CellIdentifier> = self
+            .cell_readers
+            .get(&cell_id)
+            .map(|readers| readers.iter().copied().collect())
+            .unwrap_or_default();
+
+        if let Some(spans) = self.range_readers.get(&cell_id.col) {
+            dependents.extend(
+                spans
+                    .iter()
+                    .filter(|span| (span.first_row..=span.last_row).contains(&cell_id.row))
+                    .map(|span| span.reader),
+            );
+        }
+        if let Some(readers) = self.row_readers.get(&cell_id.row) {
+            dependents.extend(readers.iter().copied());
+        }
+
+        dependents.sort();
+        dependents.dedup();
+        dependents
+    }
+
+    /**
+     * Public Function
+     * Returns the number of stored reverse edges: one per directly named
+     * cell, plus one per column covered by each range and one per row
+     * covered by whole rows
+     */
+    pub fn edge_count(&self) -> usize {
+        let cell_edges: usize = self.cell_readers.values().map(HashSet::len).sum();
+        let span_edges: usize = self.range_readers.values().map(Vec::len).sum();
+        let row_edges: usize = self.row_readers.values().map(HashSet::len).sum();
+        cell_edges + span_edges + row_edges
+    }
+
+    /**
+     * Public Function
+     * Serializes the dependencies as JSON, one entry per cell that reads
+     * from others, sorted by (col, row):
+     *
+     *   {"cells": [{"cell": "B1", "reads": ["A1_Z1000", "C1"]}]}
+     *
+     * Each reference is written as in the cell's expression, so a range is
+     * one compact entry rather than one edge per cell it covers
+     */
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct ExportedCell {
+            cell: String,       // Cell holding the expression
+            reads: Vec<String>, // Its references, ranges unexpanded
+        }
+        #[derive(Serialize)]
+        struct Exported {
+            cells: Vec<ExportedCell>,
+        }
+
+        let exported = Exported {
+            cells: self
+                .sorted_references()
+                .into_iter()
+                .map(|(cell_id, references)| ExportedCell {
+                    cell: a1_name(&cell_id),
+                    reads: references.iter().map(Reference::name).collect(),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&exported).unwrap_or_default()
+    }
+
+    /**
+     * Public Function
+     * Serializes the dependencies as a Graphviz DOT digraph, with an edge
+     * from each reference to the cell that reads it:
+     *
+     *   digraph dependencies {
+     *     "A1_Z1000" [shape=box];
+     *     "A1_Z1000" -> "B1";
+     *   }
+     *
+     * Ranges are single box-shaped nodes named as in the expression, so a
+     * huge range adds one node and one edge
+     */
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec!["digraph dependencies {".to_string()];
+        let mut range_nodes: HashSet<Reference> = HashSet::new();
+
+        for (cell_id, references) in self.sorted_references() {
+            for reference in references {
+                if !matches!(reference, Reference::Cell(_)) && range_nodes.insert(*reference) {
+                    lines.push(format!("  \"{}\" [shape=box];", reference.name()));
+                }
+                lines.push(format!(
+                    "  \"{}\" -> \"{}\";",
+                    reference.name(),
+                    a1_name(&cell_id)
+                ));
+            }
+        }
+
+        lines.push("}".to_string());
+        lines.join("\n")
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Returns every cell with references, sorted by (col, row) so exports
+     * are stable
+     */
+    fn sorted_references(&self) -> Vec<(CellIdentifier, &[Reference])> {
+        let mut sorted: Vec<(CellIdentifier, &[Reference])> = self
+            .references
+            .iter()
+            .filter(|(_, references)| !references.is_empty())
+            .map(|(cell_id, references)| (*cell_id, references.as_slice()))
+            .collect();
+        sorted.sort_by_key(|(cell_id, _)| *cell_id);
+        sorted
+    }
+
+    /**
+     * Public Function
+     * Returns the number of edges on the longest dependency chain starting
+     * from any of the given cells, e.g. 2 for C1 = B1, B1 = A1
+     *
+     * Procedure:
+     * 1. Walks dependents depth-first from each cell, using an explicit stack
+     *    so long chains can't overflow the thread's stack
+     * 2. Remembers each cell's chain length so shared tails are walked once
+     * 3. Ignores edges that close a cycle, so cycles can't loop forever
+     */
+    pub fn longest_chain(&self, cells: &[CellIdentifier]) -> usize {
+        let mut known: HashMap<CellIdentifier, usize> = HashMap::new();
+        let mut on_path = HashSet::new();
+
+        for &start in cells {
+            if known.contains_key(&start) {
+                continue;
+            }
+
+            // Each frame holds a cell and the dependents it has yet to walk
+            on_path.insert(start);
+            let mut stack = vec![(start, self.dependents_of(start), 0)];
+            while let Some((node, pending, longest)) = stack.last_mut() {
+                let node = *node;
+                match pending.pop() {
+                    Some(dep) if on_path.contains(&dep) => {}
+                    Some(dep) => match known.get(&dep) {
+                        Some(&depth) => *longest = (*longest).max(1 + depth),
+                        None => {
+                            on_path.insert(dep);
+                            stack.push((dep, self.dependents_of(dep), 0));
+                        }
+                    },
+                    None => {
+                        let longest = *longest;
+                        stack.pop();
+                        on_path.remove(&node);
+                        known.insert(node, longest);
+                        if let Some((_, _, parent_longest)) = stack.last_mut() {
+                            *parent_longest = (*parent_longest).max(1 + longest);
+                        }
+                    }
+                }
+            }
+        }
+
+        cells
+            .iter()
+            .map(|cell_id| known[cell_id])
+            .max()
+            .unwrap_or(0)
+    }
+
+    /**
+     * Public Function
+     * Returns, for each cell of a cascade, the number of edges on the longest
+     * path reaching it from one of the roots, e.g. 2 for C1 after A1 changes
+     * when C1 = B1 and B1 = A1
+     *
+     * Procedure:
+     * 1. Starts every root at depth 0
+     * 2. Walks the roots, then the cascade in topological order, raising each
+     *    dependent in the cascade to one more than the cell it reads from
+     */
+    pub fn depths_from(
+        &self,
+        roots: &[CellIdentifier],
+        order: &[CellIdentifier],
+    ) -> HashMap<CellIdentifier, usize> {
+        let in_order: HashSet<&CellIdentifier> = order.iter().collect();
+        let mut depths: HashMap<CellIdentifier, usize> =
+            roots.iter().map(|&root| (root, 0)).collect();
+
+        for cell_id in roots.iter().chain(order) {
+            let Some(&depth) = depths.get(cell_id) else {
+                continue;
+            };
+            for dep in self.dependents_of(*cell_id) {
+                if in_order.contains(&dep) {
+                    let dep_depth = depths.entry(dep).or_insert(0);
+                    *dep_depth = (*dep_depth).max(depth + 1);
+                }
+            }
+        }
+        depths
+    }
+
+    /**
+     * Public Function
+     * Returns every transitive dependent of several cells in one order where
+     * each cell appears after all of the cells it depends on
+     *
+     * Procedure:
+     * 1. Discovers the transitive dependents of each root with a BFS
+     * 2. Records, for each discovered cell, which discovered cells it reads from
+     * 3. Performs an iterative DFS-based topological sort over those cells
+     * 4. Returns the sorted cells; a root only appears if it depends on
+     *    another root, since it must then be evaluated again
+     */
+    pub fn topo_order_from(&self, roots: &[CellIdentifier]) -> Vec<CellIdentifier> {
+        // Step 1: Build the sub-graph reachable from the roots
+        let mut predecessors: HashMap<CellIdentifier, HashSet<CellIdentifier>> = HashMap::new();
+
+        for &root in roots {
+            let mut to_process = VecDeque::new();
+            let mut discovered = HashSet::new();
+
+            to_process.push_back(root);
+            discovered.insert(root);
+
+            while let Some(current_id) = to_process.pop_front() {
+                for dep_id in self.dependents_of(current_id) {
+                    if dep_id == root {
+                        continue;
+                    }
+
+                    predecessors.entry(dep_id).or_default().insert(current_id);
+
+                    if discovered.insert(dep_id) {
+                        to_process.push_back(dep_id);
+                    }
+                }
+            }
+        }
+
+        // Step 2: Perform topological sort
+        let mut update_order = Vec::new();
+        let mut permanent_marks = HashSet::new();
+        let mut temporary_marks = HashSet::new();
+
+        // Roots that no other root reaches have already been evaluated
+        for root in roots {
+            if !predecessors.contains_key(root) {
+                permanent_marks.insert(*root);
+            }
+        }
+
+        // Visit nodes in a fixed order so the result is deterministic
+        let mut nodes: Vec<CellIdentifier> = predecessors.keys().copied().collect();
+        nodes.sort();
+
+        // Predecessors of a node, reversed so popping yields them in order
+        let pending_of = |node: &CellIdentifier| {
+            let mut deps: Vec<CellIdentifier> = predecessors
+                .get(node)
+                .map_or_else(Vec::new, |deps| deps.iter().copied().collect());
+            deps.sort_by(|a, b| b.cmp(a));
+            deps
+        };
+
+        // DFS-based topological sort with an explicit stack, so long chains
+        // can't overflow the thread's stack
+        for node in nodes {
+            if permanent_marks.contains(&node) {
+                continue;
+            }
+
+            temporary_marks.insert(node);
+            let mut stack = vec![(node, pending_of(&node))];
+            while let Some((current, pending)) = stack.last_mut() {
+                let current = *current;
+                match pending.pop() {
+                    // Skip processed nodes and nodes on the current path,
+                    // cycles are reported by detect_cycle
+                    Some(dep)
+                        if permanent_marks.contains(&dep) || temporary_marks.contains(&dep) => {}
+                    Some(dep) => {
+                        temporary_marks.insert(dep);
+                        stack.push((dep, pending_of(&dep)));
+                    }
+                    // Every dependency is sorted, so the node can follow them
+                    None => {
+                        stack.pop();
+                        temporary_marks.remove(&current);
+                        permanent_marks.insert(current);
+                        update_order.push(current);
+                    }
+                }
+            }
+        }
+
+        update_order
+    }
+
+    /**
+     * Public Function
+     * Finds a dependency cycle passing through the given cell
+     *
+     * Procedure:
+     * 1. Walks dependents depth-first from the cell, remembering how each
+     *    cell was reached
+     * 2. If the walk arrives back at the starting cell, rebuilds the path
+     * 3. Returns the cells on the cycle starting from the given cell, or
+     *    None if the cell is not part of a cycle
+     */
+    pub fn detect_cycle(&self, root: CellIdentifier) -> Option<Vec<CellIdentifier>> {
+        let mut reached_from: HashMap<CellIdentifier, CellIdentifier> = HashMap::new();
+        let mut stack = vec![root];
+
+        while let Some(current_id) = stack.pop() {
+            for dep_id in self.dependents_of(current_id) {
+                if dep_id == root {
+                    // Walk back from the closing edge to rebuild the cycle
+                    let mut cycle = vec![current_id];
+                    let mut node = current_id;
+                    while node != root {
+                        node = reached_from[&node];
+                        cycle.push(node);
+                    }
+                    cycle.reverse();
+                    return Some(cycle);
+                }
+
+                if let Entry::Vacant(entry) = reached_from.entry(dep_id) {
+                    entry.insert(current_id);
+                    stack.push(dep_id);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(name: &str) -> CellIdentifier {
+        name.parse().unwrap()
+    }
+
+    fn refs(names: &[&str]) -> Vec<Reference> {
+        names
+            .iter()
+            .map(|name| crate::references::parse_reference(name).unwrap())
+            .collect()
+    }
+
+    /// Builds D1 = B1 + C1, B1 = A1, C1 = A1
+    fn diamond() -> DependencyGraph {
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("B1"), &refs(&["A1"]));
+        graph.add_edges(cell("C1"), &refs(&["A1"]));
+        graph.add_edges(cell("D1"), &refs(&["B1", "C1"]));
+        graph
+    }
+
+    fn position(order: &[CellIdentifier], name: &str) -> usize {
+        order.iter().position(|&id| id == cell(name)).unwrap()
+    }
+
+    #[test]
+    fn test_dependents_of() {
+        let graph = diamond();
+
+        assert_eq!(
+            graph.dependents_of(cell("A1")),
+            vec![cell("B1"), cell("C1")]
+        );
+        assert_eq!(graph.dependents_of(cell("B1")), vec![cell("D1")]);
+        assert_eq!(graph.dependents_of(cell("C1")), vec![cell("D1")]);
+        assert!(graph.dependents_of(cell("D1")).is_empty());
+    }
+
+    #[test]
+    fn test_remove_edges() {
+        let mut graph = diamond();
+        graph.remove_edges(cell("D1"));
+
+        assert!(graph.dependents_of(cell("B1")).is_empty());
+        assert!(graph.references_of(cell("D1")).is_empty());
+        assert_eq!(
+            graph.dependents_of(cell("A1")),
+            vec![cell("B1"), cell("C1")]
+        );
+    }
+
+    #[test]
+    fn test_topo_order_chain() {
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("A2"), &refs(&["A1"]));
+        graph.add_edges(cell("A3"), &refs(&["A2"]));
+        graph.add_edges(cell("A4"), &refs(&["A3"]));
+
+        assert_eq!(
+            graph.topo_order_from(&[cell("A1")]),
+            vec![cell("A2"), cell("A3"), cell("A4")]
+        );
+        assert_eq!(graph.topo_order_from(&[cell("A3")]), vec![cell("A4")]);
+    }
+
+    #[test]
+    fn test_very_long_chain() {
+        // A1 -> A2 -> ... -> A10000, deeper than a thread's stack could recurse
+        let mut graph = DependencyGraph::new();
+        let length = 10_000;
+        let chain: Vec<CellIdentifier> = (0..length)
+            .map(|row| CellIdentifier { col: 0, row })
+            .collect();
+        for pair in chain.windows(2) {
+            graph.add_edges(pair[1], &[Reference::Cell(pair[0])]);
+        }
+
+        assert_eq!(graph.topo_order_from(&[chain[0]]), chain[1..]);
+        assert_eq!(graph.longest_chain(&[chain[0]]), length as usize - 1);
+    }
+
+    #[test]
+    fn test_depths_from() {
+        // D1 reads A1 directly and through a longer B1 -> C1 path
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("B1"), &refs(&["A1"]));
+        graph.add_edges(cell("C1"), &refs(&["B1"]));
+        graph.add_edges(cell("D1"), &refs(&["A1", "C1"]));
+        let order = graph.topo_order_from(&[cell("A1")]);
+
+        let depths = graph.depths_from(&[cell("A1")], &order);
+        let depth = |name: &str| depths[&cell(name)];
+        assert_eq!(
+            (depth("A1"), depth("B1"), depth("C1"), depth("D1")),
+            (0, 1, 2, 3)
+        );
+    }
+
+    #[test]
+    fn test_topo_order_diamond() {
+        let graph = diamond();
+        let order = graph.topo_order_from(&[cell("A1")]);
+
+        // Each cell appears once, the root is excluded, and D1 comes last
+        assert_eq!(order.len(), 3);
+        assert!(!order.contains(&cell("A1")));
+        assert!(position(&order, "B1") < position(&order, "D1"));
+        assert!(position(&order, "C1") < position(&order, "D1"));
+    }
+
+    #[test]
+    fn test_topo_order_uneven_diamond() {
+        // D1 reads A1 directly and through a longer B1 -> C1 path
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("B1"), &refs(&["A1"]));
+        graph.add_edges(cell("C1"), &refs(&["B1"]));
+        graph.add_edges(cell("D1"), &refs(&["A1", "C1"]));
+
+        assert_eq!(
+            graph.topo_order_from(&[cell("A1")]),
+            vec![cell("B1"), cell("C1"), cell("D1")]
+        );
+    }
+
+    #[test]
+    fn test_topo_order_from_roots() {
+        // B1 reads A1, C1 reads B1 and D1 reads C1
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("B1"), &refs(&["A1"]));
+        graph.add_edges(cell("C1"), &refs(&["B1"]));
+        graph.add_edges(cell("D1"), &refs(&["C1"]));
+
+        // C1 is re-evaluated because it reads from the other root
+        assert_eq!(
+            graph.topo_order_from(&[cell("C1"), cell("A1")]),
+            vec![cell("B1"), cell("C1"), cell("D1")]
+        );
+        assert_eq!(
+            graph.topo_order_from(&[cell("C1"), cell("C1")]),
+            vec![cell("D1")]
+        );
+    }
+
+    #[test]
+    fn test_large_range_serializes_compactly() {
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("AA1"), &refs(&["A1_Z1000", "AB1"]));
+        graph.add_edges(cell("AC1"), &refs(&["A2_B"]));
+
+        assert_eq!(
+            graph.to_json(),
+            r#"{"cells":[{"cell":"AA1","reads":["A1_Z1000","AB1"]},{"cell":"AC1","reads":["A2_B"]}]}"#
+        );
+        assert_eq!(
+            graph.to_dot(),
+            "digraph dependencies {\n  \"A1_Z1000\" [shape=box];\n  \"A1_Z1000\" -> \"AA1\";\n  \
+             \"AB1\" -> \"AA1\";\n  \"A2_B\" [shape=box];\n  \"A2_B\" -> \"AC1\";\n}"
+        );
+    }
+
+    #[test]
+    fn test_open_range_dependents() {
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("C1"), &refs(&["A2_B", "A1"]));
+
+        // Cells below the start row, in any row, are covered
+        assert_eq!(graph.dependents_of(cell("A1")), vec![cell("C1")]);
+        assert_eq!(graph.dependents_of(cell("A2")), vec![cell("C1")]);
+        assert_eq!(graph.dependents_of(cell("B500")), vec![cell("C1")]);
+        assert!(graph.dependents_of(cell("B1")).is_empty());
+        assert!(graph.dependents_of(cell("C2")).is_empty());
+
+        graph.remove_edges(cell("C1"));
+        assert!(graph.dependents_of(cell("A2")).is_empty());
+        assert!(graph.references_of(cell("C1")).is_empty());
+        assert_eq!(graph.edge_count(), 0);
+    }
+
+    #[test]
+    fn test_whole_column_and_row_dependents() {
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("D1"), &refs(&["A_B", "2_3"]));
+        assert_eq!(graph.edge_count(), 4);
+
+        // Any row of the columns, and any column of the rows, is covered
+        assert_eq!(graph.dependents_of(cell("A1")), vec![cell("D1")]);
+        assert_eq!(graph.dependents_of(cell("B9000")), vec![cell("D1")]);
+        assert_eq!(graph.dependents_of(cell("ZZ3")), vec![cell("D1")]);
+        assert!(graph.dependents_of(cell("C1")).is_empty());
+        assert!(graph.dependents_of(cell("C4")).is_empty());
+
+        graph.remove_edges(cell("D1"));
+        assert!(graph.dependents_of(cell("C2")).is_empty());
+        assert_eq!(graph.edge_count(), 0);
+    }
+
+    #[test]
+    fn test_large_range_edges_stay_bounded() {
+        // A 100x100 block costs one span per column, not one edge per cell
+        let mut graph = DependencyGraph::new();
+        graph.add_edges(cell("CX1"), &refs(&["A1_CV100"]));
+        assert_eq!(graph.edge_count(), 100);
+
+        assert_eq!(graph.dependents_of(cell("A1")), vec![cell("CX1")]);
+        assert_eq!(graph.dependents_of(cell("BA50")), vec![cell("CX1")]);
+        assert!(graph.dependents_of(cell("A101")).is_empty());
+        assert!(graph.dependents_of(cell("CW1")).is_empty());
+
+        // Re-setting the cell replaces its spans rather than adding to them
+        graph.remove_edges(cell("CX1"));
+        graph.add_edges(cell("CX1"), &refs(&["A1_CV100"]));
+        assert_eq!(graph.edge_count(), 100);
+    }
+
+    #[test]
+    fn test_longest_chain() {
+        let mut graph = diamond();
+        assert_eq!(graph.longest_chain(&[cell("A1")]), 2);
+        assert_eq!(graph.longest_chain(&[cell("C1"), cell("D1")]), 1);
+        assert_eq!(graph.longest_chain(&[]), 0);
+
+        // A cycle doesn't make the chain endless
+        graph.add_edges(cell("A1"), &refs(&["D1"]));
+        assert_eq!(graph.longest_chain(&[cell("A1")]), 2);
+    }
+
+    #[test]
+    fn test_detect_cycle() {
+        let mut graph = diamond();
+        assert_eq!(graph.detect_cycle(cell("A1")), None);
+
+        graph.add_edges(cell("A1"), &refs(&["D1"]));
+        let cycle = graph.detect_cycle(cell("A1")).unwrap();
+        assert_eq!(cycle.first(), Some(&cell("A1")));
+        assert_eq!(cycle.last(), Some(&cell("D1")));
+        assert_eq!(cycle.len(), 3);
+    }
+}
@@ -1,53 +1,689 @@
+mod commands;
+mod connection;
+mod csv;
+mod error;
+mod evaluator;
+mod functions;
+mod graph;
+mod references;
+mod snapshot;
 mod spreadsheet;
+mod table;
+mod wal;
+mod workbook;
 
 use rsheet_lib::cell_value::CellValue;
-use rsheet_lib::cells::column_number_to_name;
-use rsheet_lib::command::Command;
+use rsheet_lib::command::{CellIdentifier, Command};
 use rsheet_lib::connect::{
-    Connection, Manager, ReadMessageResult, Reader, WriteMessageResult, Writer,
+    Connection, Manager, ReadMessageResult, Reader, ReaderWriter, WriteMessageResult, Writer,
 };
 use rsheet_lib::replies::Reply;
 
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError, Weak};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use log::info;
+use log::{info, warn};
 
-use spreadsheet::Spreadsheet;
+use commands::ServerCommand;
 
-// Handle a single client connection in its own thread
-fn handle_connection<R: Reader, W: Writer>(
-    mut recv: R,
-    mut send: W,
-    spreadsheet: Arc<Spreadsheet>,
+use connection::Unclosable;
+pub use connection::{Closeable, Closer, TcpManager, TcpReader, TcpReaderWriter, TcpWriter};
+pub use error::{AggError, SpreadsheetError};
+pub use spreadsheet::{
+    AddressMode, AggKind, AggTarget, Autosave, EmptyCells, HealthReport, SheetStats, Spreadsheet,
+    SpreadsheetOptions, DEFAULT_MAX_EXPRESSION_LENGTH, DEFAULT_MAX_RANGE_CELLS,
+};
+pub use workbook::{Workbook, DEFAULT_SHEET};
+
+// Reply to reading a dependency error whose source can't be named
+const UNKNOWN_DEPENDENCY_ERROR: &str = "Cell depends on another error cell";
+
+// Matches find and findexpr name before summarising the rest, by default
+pub const DEFAULT_FIND_LIMIT: usize = 20;
+
+// Characters dump shows of a value before cutting it short, by default
+pub const DEFAULT_DUMP_WIDTH: usize = 16;
+
+// Connections served at once, and accepted connections waiting for a
+// thread, by default
+pub const DEFAULT_CONNECTION_THREADS: usize = 64;
+pub const DEFAULT_CONNECTION_QUEUE: usize = 64;
+
+// How often waits for a connection or a message check for a shutdown
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+// Window ServerOptions::set_rate_limit counts sets over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Number given to each accepted connection, from 1 in the order accepted
+pub type ConnectionId = u64;
+
+/**
+ * Called with a connection's id and the error that ended it, see
+ * ServerOptions::on_connection_error
+ */
+#[derive(Clone)]
+pub struct ConnectionErrorHook(pub Arc<dyn Fn(ConnectionId, Box<dyn Error>) + Send + Sync>);
+
+impl fmt::Debug for ConnectionErrorHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ConnectionErrorHook")
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Describes why a cell holds a dependency error, naming the cell whose own
+ * error it inherits when that can be found
+ */
+fn dependency_error(spreadsheet: &Spreadsheet, cell_id: &CellIdentifier) -> String {
+    match spreadsheet.error_source(cell_id) {
+        Some((source, message)) => format!(
+            "{} depends on {}, which has an error: {}",
+            references::a1_name(cell_id),
+            references::a1_name(&source),
+            message
+        ),
+        None => UNKNOWN_DEPENDENCY_ERROR.to_string(),
+    }
+}
+
+/**
+ * Where a connection's messages come from
+ * A Reader can't be interrupted once it blocks, so the reads happen on a
+ * separate thread and are waited for in slices, letting an idle timeout or
+ * a shutdown end the wait. Dropping it closes the connection, if the
+ * Reader is Closeable, so the read thread exits too
+ */
+struct Incoming {
+    receiver: mpsc::Receiver<ReadMessageResult>, // Read results forwarded by the read thread
+    idle_timeout: Option<Duration>,              // Gives up after this long without a message
+    shutdown: Arc<AtomicBool>,                   // Gives up once set and the client goes quiet
+    closer: Option<Closer>,                      // Closes the connection, ending a blocked read
+}
+
+impl Incoming {
+    /**
+     * HELPER FUNCTION
+     * Prepares to read from a connection
+     * Spawns a thread that forwards each read result, and stops once the
+     * connection ends or nobody is listening any more
+     */
+    fn new<R: Reader + Closeable + Send + 'static>(
+        mut recv: R,
+        idle_timeout: Option<Duration>,
+        shutdown: Arc<AtomicBool>,
+    ) -> Self {
+        let closer = recv.closer();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        thread::spawn(move || loop {
+            let result = recv.read_message();
+            let last = !matches!(result, ReadMessageResult::Message(_));
+            if sender.send(result).is_err() || last {
+                break;
+            }
+        });
+        Incoming {
+            receiver,
+            idle_timeout,
+            shutdown,
+            closer,
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Waits for the next read result, or returns None once the connection
+     * has been idle for the whole timeout, or as soon as a shutdown is
+     * requested, so only the message already in hand is answered and a
+     * client that never goes quiet can't hold the server up
+     */
+    fn next(&mut self) -> Option<ReadMessageResult> {
+        let started = Instant::now();
+        loop {
+            if self.shutdown.load(Ordering::Relaxed) {
+                return None;
+            }
+            let wait = match self.idle_timeout {
+                Some(timeout) => timeout.saturating_sub(started.elapsed()),
+                None => SHUTDOWN_POLL,
+            };
+            if wait.is_zero() {
+                return None;
+            }
+
+            match self.receiver.recv_timeout(wait.min(SHUTDOWN_POLL)) {
+                Ok(result) => return Some(result),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Some(ReadMessageResult::ConnectionClosed)
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Incoming {
+    /**
+     * HELPER FUNCTION
+     * Closes the connection, so the read thread's blocked read returns and
+     * the thread exits. Without a closer, it stays blocked until the client
+     * next sends something or disconnects
+     */
+    fn drop(&mut self) {
+        if let Some(closer) = self.closer.take() {
+            closer.close();
+        }
+    }
+}
+
+/**
+ * The regions a connection watches, keyed by sheet name and corners
+ * Every watch is removed when the connection ends, however it ends
+ */
+#[derive(Default)]
+struct Watches(HashMap<(String, CellIdentifier, CellIdentifier), (Weak<Spreadsheet>, u64)>);
+
+impl Watches {
+    /**
+     * HELPER FUNCTION
+     * Records a watch, removing any earlier watch of the same region
+     */
+    fn insert(
+        &mut self,
+        key: (String, CellIdentifier, CellIdentifier),
+        sheet: &Arc<Spreadsheet>,
+        id: u64,
+    ) {
+        if let Some((earlier, earlier_id)) = self.0.insert(key, (Arc::downgrade(sheet), id)) {
+            if let Some(earlier) = earlier.upgrade() {
+                earlier.unwatch(earlier_id);
+            }
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Removes the watch of a region, returning whether there was one
+     */
+    fn remove(&mut self, key: &(String, CellIdentifier, CellIdentifier)) -> bool {
+        match self.0.remove(key) {
+            Some((sheet, id)) => {
+                if let Some(sheet) = sheet.upgrade() {
+                    sheet.unwatch(id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Watches {
+    fn drop(&mut self) {
+        for (sheet, id) in self.0.values() {
+            if let Some(sheet) = sheet.upgrade() {
+                sheet.unwatch(*id);
+            }
+        }
+    }
+}
+
+/**
+ * Counts a connection's sets in fixed windows of RATE_WINDOW, see
+ * ServerOptions::set_rate_limit
+ */
+struct SetRateLimit {
+    limit: Option<u32>,    // Sets allowed per window, or None for no limit
+    window_start: Instant, // When the current window began
+    used: u32,             // Sets allowed so far in the current window
+}
+
+impl SetRateLimit {
+    /**
+     * HELPER FUNCTION
+     * Starts counting from a fresh window
+     */
+    fn new(limit: Option<u32>) -> Self {
+        SetRateLimit {
+            limit,
+            window_start: Instant::now(),
+            used: 0,
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Records a set, returning whether it fits in the current window
+     * A new window starts once the current one has lasted RATE_WINDOW, and
+     * rejected sets don't count toward it
+     */
+    fn allow(&mut self) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        if self.window_start.elapsed() >= RATE_WINDOW {
+            self.window_start = Instant::now();
+            self.used = 0;
+        }
+        if self.used >= limit {
+            return false;
+        }
+        self.used += 1;
+        true
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Handles a single client connection in its own thread
+ *
+ * The writer is shared with the sheets' workers, which push each new value
+ * of a watched region to it as an unsolicited Reply::Value, so pushes and
+ * replies never interleave mid-message. Pushes for a cell arrive in the
+ * order its values were committed, and a cell in two watched regions is
+ * pushed once for each. Each region named by watch is its own watch, which
+ * unwatch removes by naming the same region; values already being pushed
+ * when the unwatch is read may still arrive after it
+ */
+fn handle_connection<R: Reader + Closeable + Send + 'static, W: Writer + Send + 'static>(
+    connection: ConnectionId,
+    recv: R,
+    send: W,
+    workbook: Arc<Workbook>,
+    options: ServerOptions,
 ) -> Result<(), Box<dyn Error>> {
+    let send = Arc::new(Mutex::new(send));
+    let write = |reply: Reply| {
+        send.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_message(reply)
+    };
+    let mut watches = Watches::default();
+    let mut set_rate_limit = SetRateLimit::new(options.set_rate_limit);
+    let mut incoming = Incoming::new(recv, options.idle_timeout, Arc::clone(&options.shutdown));
     loop {
+        let Some(result) = incoming.next() else {
+            // Idle for too long, or drained after a shutdown; tell the client
+            // and give up the thread
+            let id = send.lock().unwrap_or_else(PoisonError::into_inner).id();
+            if options.shutdown.load(Ordering::Relaxed) {
+                info!(
+                    "event=connection_drained connection={} id={}",
+                    connection, id
+                );
+                let _ = write(Reply::Error(
+                    "Connection closed because the server is shutting down".into(),
+                ));
+            } else {
+                info!("event=connection_idle connection={} id={}", connection, id);
+                let _ = write(Reply::Error("connection idle, closing".into()));
+            }
+            break;
+        };
+
         info!("Just got message");
-        match recv.read_message() {
+        match result {
             ReadMessageResult::Message(msg) => {
-                let reply = match msg.parse::<Command>() {
+                // Commands go to the sheet named by their prefix, if any
+                let (sheet, command_text) = commands::split_sheet(&msg);
+                let spreadsheet = workbook.sheet(sheet.unwrap_or(DEFAULT_SHEET));
+
+                // Commands naming a cell past the sheet's edge are rejected unrun
+                let parsed = command_text.parse::<ServerCommand>().and_then(|command| {
+                    for cell_id in command.cells() {
+                        spreadsheet
+                            .check_bounds(&cell_id)
+                            .map_err(|e| format!("Error: {}", e))?;
+                    }
+                    Ok(command)
+                });
+                let reply = match parsed {
+                    // Sets over the connection's limit are dropped unapplied
+                    Ok(command) if command.is_set() && !set_rate_limit.allow() => {
+                        Reply::Error("rate limited".to_string())
+                    }
                     Ok(command) => match command {
-                        Command::Get { cell_identifier } => {
-                            let name = format!(
-                                "{}{}",
-                                column_number_to_name(cell_identifier.col),
-                                cell_identifier.row + 1
-                            );
+                        ServerCommand::ListCells(region) => Reply::Value(
+                            "cells".to_string(),
+                            CellValue::String(commands::format_cell_list(&match region {
+                                Some((start, end)) => spreadsheet.list_cells_in_range(start, end),
+                                None => spreadsheet.list_cells(),
+                            })),
+                        ),
+                        ServerCommand::Workers => Reply::Value(
+                            "workers".to_string(),
+                            CellValue::String(commands::format_worker_backlogs(
+                                &spreadsheet.worker_backlogs(),
+                            )),
+                        ),
+                        ServerCommand::Stats => Reply::Value(
+                            "stats".to_string(),
+                            CellValue::String(commands::format_stats(&spreadsheet.stats())),
+                        ),
+                        ServerCommand::Capabilities => Reply::Value(
+                            "capabilities".to_string(),
+                            CellValue::String(commands::format_capabilities()),
+                        ),
+                        ServerCommand::Health => Reply::Value(
+                            "health".to_string(),
+                            CellValue::String(commands::format_health(&spreadsheet.health())),
+                        ),
+                        ServerCommand::ErrorsIn(start, end) => Reply::Value(
+                            "errors".to_string(),
+                            CellValue::String(commands::format_error_cells(
+                                &spreadsheet.error_cells_in_range(start, end),
+                            )),
+                        ),
+                        ServerCommand::Export(path) => {
+                            match export_path(options.export_dir.as_deref(), &path) {
+                                Err(e) => Reply::Error(format!(
+                                    "Error exporting {}: {}",
+                                    path.display(),
+                                    e
+                                )),
+                                Ok(file) => match File::create(&file)
+                                    .and_then(|file| spreadsheet.write_csv(BufWriter::new(file)))
+                                {
+                                    Ok(rows) => Reply::Value(
+                                        "rows".to_string(),
+                                        CellValue::Int(rows as i64),
+                                    ),
+                                    Err(e) => Reply::Error(format!(
+                                        "Error exporting {}: {}",
+                                        path.display(),
+                                        e
+                                    )),
+                                },
+                            }
+                        }
+                        ServerCommand::Reset => {
+                            // Like set, a reset has no reply
+                            spreadsheet.clear_all();
+                            continue;
+                        }
+                        ServerCommand::Expression(cell_identifier) => Reply::Value(
+                            references::a1_name(&cell_identifier),
+                            spreadsheet
+                                .get_expression(&cell_identifier)
+                                .map_or(CellValue::None, CellValue::String),
+                        ),
+                        ServerCommand::Presence(cell_identifier) => {
+                            let (present, value) = spreadsheet.get_with_presence(&cell_identifier);
+                            Reply::Value(
+                                references::a1_name(&cell_identifier),
+                                CellValue::String(commands::format_presence(present, &value)),
+                            )
+                        }
+                        ServerCommand::Sheet(Command::Get { cell_identifier }) => {
+                            let name = references::a1_name(&cell_identifier);
                             let value = spreadsheet.get(&cell_identifier);
                             match value {
                                 CellValue::Error(ref msg) if msg == "VariableDependsOnError" => {
-                                    Reply::Error("Cell depends on another error cell".to_string())
+                                    Reply::Error(dependency_error(&spreadsheet, &cell_identifier))
                                 }
-                                _ => Reply::Value(name, value),
+                                _ => Reply::Value(name, commands::reply_value(value)),
                             }
                         }
-                        Command::Set {
+                        ServerCommand::GetRange(start, end) => {
+                            let values: Vec<(CellIdentifier, String)> = spreadsheet
+                                .get_range(start, end)
+                                .into_iter()
+                                .map(|(cell_id, value)| match value {
+                                    CellValue::Error(ref msg)
+                                        if msg == "VariableDependsOnError" =>
+                                    {
+                                        let text = dependency_error(&spreadsheet, &cell_id);
+                                        (cell_id, format!("Error: {}", text))
+                                    }
+                                    _ => (cell_id, commands::reply_value(value).to_string()),
+                                })
+                                .collect();
+                            Reply::Value(
+                                format!(
+                                    "{}_{}",
+                                    references::a1_name(&start),
+                                    references::a1_name(&end)
+                                ),
+                                CellValue::String(commands::format_range_values(&values)),
+                            )
+                        }
+                        ServerCommand::Sheet(Command::Set {
                             cell_identifier,
                             cell_expr,
-                        } => {
+                        }) => {
                             if let Err(e) = spreadsheet.set(cell_identifier, cell_expr) {
-                                Reply::Error(format!("Error: {:?}", e))
+                                Reply::Error(format!("Error: {}", e))
+                            } else {
+                                continue;
+                            }
+                        }
+                        ServerCommand::Copy(start, end, dest) => {
+                            if let Err(e) = spreadsheet.copy_range(start, end, dest) {
+                                Reply::Error(format!("Error: {}", e))
+                            } else {
+                                continue;
+                            }
+                        }
+                        ServerCommand::Move(src, dst, overwrite) => {
+                            match spreadsheet.move_cell(src, dst, overwrite) {
+                                Ok(()) => continue,
+                                Err(e) => Reply::Error(format!("Error: {}", e)),
+                            }
+                        }
+                        ServerCommand::InsertRow(row) => match spreadsheet.insert_row(row) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::DeleteRow(row) => match spreadsheet.delete_row(row) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::InsertCol(col) => match spreadsheet.insert_col(col) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::DeleteCol(col) => match spreadsheet.delete_col(col) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::DefineName(name, target) => {
+                            match spreadsheet.define_name(&name, &target) {
+                                Ok(()) => continue,
+                                Err(e) => Reply::Error(format!("Error: {}", e)),
+                            }
+                        }
+                        ServerCommand::RemoveName(name) => match spreadsheet.remove_name(&name) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::Dependencies(cell_id) => Reply::Value(
+                            references::a1_name(&cell_id),
+                            CellValue::String(commands::format_cell_names(
+                                &spreadsheet.dependencies_of(&cell_id),
+                            )),
+                        ),
+                        ServerCommand::Dependents(cell_id, transitive) => Reply::Value(
+                            references::a1_name(&cell_id),
+                            CellValue::String(commands::format_cell_names(
+                                &spreadsheet.dependents_of(&cell_id, transitive),
+                            )),
+                        ),
+                        ServerCommand::Watch(regions) => {
+                            let prefix =
+                                sheet.map(|sheet| format!("{}!", sheet)).unwrap_or_default();
+                            for (start, end) in regions {
+                                let writer = Arc::clone(&send);
+                                let prefix = prefix.clone();
+                                let id = spreadsheet.watch(start, end, move |cell_id, value| {
+                                    let name =
+                                        format!("{}{}", prefix, references::a1_name(&cell_id));
+                                    let result = writer
+                                        .lock()
+                                        .unwrap_or_else(PoisonError::into_inner)
+                                        .write_message(Reply::Value(
+                                            name,
+                                            commands::reply_value(value.clone()),
+                                        ));
+                                    !matches!(result, WriteMessageResult::ConnectionClosed)
+                                });
+                                let key = (sheet.unwrap_or(DEFAULT_SHEET).to_string(), start, end);
+                                watches.insert(key, &spreadsheet, id);
+                            }
+                            continue;
+                        }
+                        ServerCommand::Unwatch(regions) => {
+                            let unwatched: Vec<String> = regions
+                                .into_iter()
+                                .filter(|(start, end)| {
+                                    let key =
+                                        (sheet.unwrap_or(DEFAULT_SHEET).to_string(), *start, *end);
+                                    !watches.remove(&key)
+                                })
+                                .map(|(start, end)| {
+                                    if start == end {
+                                        references::Reference::Cell(start).name()
+                                    } else {
+                                        references::Reference::Range(start, end).name()
+                                    }
+                                })
+                                .collect();
+                            match unwatched.as_slice() {
+                                [] => continue,
+                                [region] => {
+                                    Reply::Error(format!("Error: {} is not watched", region))
+                                }
+                                regions => Reply::Error(format!(
+                                    "Error: {} are not watched",
+                                    regions.join(", ")
+                                )),
+                            }
+                        }
+                        ServerCommand::Extent => Reply::Value(
+                            "extent".to_string(),
+                            spreadsheet
+                                .extent()
+                                .map_or(CellValue::None, |(start, end)| {
+                                    CellValue::String(
+                                        references::Reference::Range(start, end).name(),
+                                    )
+                                }),
+                        ),
+                        ServerCommand::Sheets => Reply::Value(
+                            "sheets".to_string(),
+                            CellValue::String(workbook.sheet_names().join("\n")),
+                        ),
+                        ServerCommand::DropSheet(name) => {
+                            if workbook.drop_sheet(&name) {
+                                continue;
+                            }
+                            Reply::Error(format!("Error: no sheet named {}", name))
+                        }
+                        ServerCommand::Undo(cell_id) => match spreadsheet.undo(cell_id) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::Redo(cell_id) => match spreadsheet.redo(cell_id) {
+                            Ok(()) => continue,
+                            Err(e) => Reply::Error(format!("Error: {}", e)),
+                        },
+                        ServerCommand::History(cell_id, count) => Reply::Value(
+                            references::a1_name(&cell_id),
+                            CellValue::String(commands::format_history(
+                                &spreadsheet.history(&cell_id, count.unwrap_or(usize::MAX)),
+                            )),
+                        ),
+                        ServerCommand::CompareAndSet(cell_id, expected, expression) => {
+                            let name = references::a1_name(&cell_id);
+                            match spreadsheet.compare_and_set(cell_id, &expected, expression) {
+                                Ok(true) => Reply::Value(name, CellValue::String("swapped".into())),
+                                Ok(false) => Reply::Error(format!(
+                                    "Error: {} doesn't hold {}",
+                                    name, expected
+                                )),
+                                Err(e) => Reply::Error(format!("Error: {}", e)),
+                            }
+                        }
+                        ServerCommand::Eval(expression) => {
+                            match spreadsheet.evaluate_adhoc(&expression) {
+                                CellValue::Error(ref msg) if msg == "VariableDependsOnError" => {
+                                    Reply::Error(UNKNOWN_DEPENDENCY_ERROR.to_string())
+                                }
+                                value => Reply::Value(expression, commands::reply_value(value)),
+                            }
+                        }
+                        ServerCommand::Aggregate(target, kinds, skip_errors) => {
+                            match spreadsheet.aggregate_many(target, &kinds, skip_errors) {
+                                Ok(values) if kinds.len() == 1 => {
+                                    Reply::Value(target.name(), values[0].clone())
+                                }
+                                Ok(values) => Reply::Value(
+                                    target.name(),
+                                    CellValue::String(commands::format_aggregates(&kinds, &values)),
+                                ),
+                                Err(e) => Reply::Error(format!("Error: {}", e)),
+                            }
+                        }
+                        ServerCommand::Dump(start, end) => Reply::Value(
+                            format!(
+                                "{}_{}",
+                                references::a1_name(&start),
+                                references::a1_name(&end)
+                            ),
+                            CellValue::String(table::render(
+                                CellIdentifier {
+                                    col: start.col.min(end.col),
+                                    row: start.row.min(end.row),
+                                },
+                                &spreadsheet.get_grid(start, end),
+                                options.dump_width,
+                            )),
+                        ),
+                        ServerCommand::Changes(version) => Reply::Value(
+                            "changes".to_string(),
+                            CellValue::String(commands::format_changes(
+                                &spreadsheet.changed_since(version),
+                            )),
+                        ),
+                        ServerCommand::Find(value) => Reply::Value(
+                            "matches".to_string(),
+                            CellValue::String(commands::format_matches(
+                                &spreadsheet.find_by_value(&value),
+                                options.find_limit,
+                            )),
+                        ),
+                        ServerCommand::FindExpression(needle) => Reply::Value(
+                            "matches".to_string(),
+                            CellValue::String(commands::format_matches(
+                                &spreadsheet.find_by_expression(&needle),
+                                options.find_limit,
+                            )),
+                        ),
+                        ServerCommand::WhatIf(cell_id, overrides) => {
+                            let overrides: HashMap<CellIdentifier, CellValue> =
+                                overrides.into_iter().collect();
+                            match spreadsheet.evaluate_with_overrides(cell_id, &overrides) {
+                                CellValue::Error(ref msg) if msg == "VariableDependsOnError" => {
+                                    Reply::Error(UNKNOWN_DEPENDENCY_ERROR.to_string())
+                                }
+                                value => Reply::Value(
+                                    references::a1_name(&cell_id),
+                                    commands::reply_value(value),
+                                ),
+                            }
+                        }
+                        ServerCommand::SetBatch(assignments) => {
+                            if let Err(e) = spreadsheet.set_batch(assignments) {
+                                Reply::Error(format!("Error: {}", e))
                             } else {
                                 continue;
                             }
@@ -56,7 +692,15 @@ fn handle_connection<R: Reader, W: Writer>(
                     Err(e) => Reply::Error(e),
                 };
 
-                match send.write_message(reply) {
+                // Name values after the sheet they came from
+                let reply = match (sheet, reply) {
+                    (Some(sheet), Reply::Value(name, value)) => {
+                        Reply::Value(format!("{}!{}", sheet, name), value)
+                    }
+                    (_, reply) => reply,
+                };
+
+                match write(reply) {
                     WriteMessageResult::Ok => {}
                     WriteMessageResult::ConnectionClosed => break,
                     WriteMessageResult::Err(e) => return Err(Box::new(e)),
@@ -69,33 +713,1484 @@ fn handle_connection<R: Reader, W: Writer>(
     Ok(())
 }
 
+/**
+ * HELPER FUNCTION
+ * Resolves the file an export command writes to inside the export
+ * directory, failing if exports are disabled or if the path could leave the
+ * directory, being absolute or having a ".." component
+ */
+fn export_path(export_dir: Option<&Path>, path: &Path) -> Result<PathBuf, &'static str> {
+    let export_dir = export_dir.ok_or("exports are disabled")?;
+    let confined = path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !confined {
+        return Err("path must stay within the export directory");
+    }
+    Ok(export_dir.join(path))
+}
+
+/**
+ * HELPER FUNCTION
+ * Serves one connection on a connection thread, logging a read or write
+ * error that ends it with the connection's id and passing it to
+ * on_connection_error. A panic is logged and ends only this connection, so
+ * the thread goes on to the next one
+ */
+fn serve_connection<R: Reader + Closeable + Send + 'static, W: Writer + Send + 'static>(
+    connection: ConnectionId,
+    reader: R,
+    writer: W,
+    workbook: &Arc<Workbook>,
+    options: &ServerOptions,
+) {
+    let served = panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_connection(
+            connection,
+            reader,
+            writer,
+            Arc::clone(workbook),
+            options.clone(),
+        )
+    }));
+    match served {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            warn!(
+                "event=connection_error connection={} error={:?}",
+                connection, e
+            );
+            if let Some(ConnectionErrorHook(hook)) = &options.on_connection_error {
+                hook(connection, e);
+            }
+        }
+        Err(_) => warn!("event=connection_panicked connection={}", connection),
+    }
+}
+
+/**
+ * Settings for start_server_with_options
+ */
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    pub snapshot_path: Option<PathBuf>, // Loaded on start if present, saved on shutdown
+    pub idle_timeout: Option<Duration>, // Closes connections that send nothing for this long
+    pub find_limit: usize,              // Matches find and findexpr name before "+N more"
+    pub dump_width: usize,              // Characters dump shows of a value before "…"
+    pub set_rate_limit: Option<u32>,    // Sets per second allowed to each connection, if limited
+    pub shutdown: Arc<AtomicBool>,      // Set to stop taking connections, see request_shutdown
+    pub on_connection_error: Option<ConnectionErrorHook>, // Told of each connection ended by an error
+    pub connection_threads: usize, // Connections served at once, each on a pool thread plus a read thread
+    pub connection_queue: usize,   // Accepted connections waiting for a free thread
+    pub max_range_cells: Option<usize>, // Most cells one range in a set may cover, if limited
+    pub max_expression_length: Option<usize>, // Longest expression a set accepts, if limited
+    pub export_dir: Option<PathBuf>, // Directory export writes into; None disables export
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            snapshot_path: None,
+            idle_timeout: None,
+            find_limit: DEFAULT_FIND_LIMIT,
+            dump_width: DEFAULT_DUMP_WIDTH,
+            set_rate_limit: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            on_connection_error: None,
+            connection_threads: DEFAULT_CONNECTION_THREADS,
+            connection_queue: DEFAULT_CONNECTION_QUEUE,
+            max_range_cells: Some(DEFAULT_MAX_RANGE_CELLS),
+            max_expression_length: Some(DEFAULT_MAX_EXPRESSION_LENGTH),
+            export_dir: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /**
+     * Public Function
+     * Asks a server started with these options, or a clone of them, to shut
+     * down gracefully, see start_server_with_options
+     * Safe to call from any thread; a signal handler can set shutdown directly
+     */
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Starts servers in the background, see Server::start
+ */
+pub struct Server;
+
+impl Server {
+    /**
+     * Public Function
+     * Serves connections from the manager on a background thread, as
+     * start_server_with_options does, returning a handle to stop it with
+     */
+    pub fn start<M>(manager: M, options: ServerOptions) -> ServerHandle
+    where
+        M: Manager + Send + 'static,
+        <M::ReaderWriter as ReaderWriter>::Reader: Closeable,
+    {
+        let shutdown = options.clone();
+        let (finished, done) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // Box<dyn Error> can't leave the thread, so only its message does
+            let result = start_server_with_options(manager, options).map_err(|e| e.to_string());
+            let _ = finished.send(result);
+        });
+        ServerHandle {
+            options: shutdown,
+            done,
+            thread,
+        }
+    }
+}
+
+/**
+ * A server running in the background, returned by Server::start
+ */
+pub struct ServerHandle {
+    options: ServerOptions,                   // Shares the server's shutdown flag
+    done: mpsc::Receiver<Result<(), String>>, // Receives the server's result once it returns
+    thread: thread::JoinHandle<()>,           // Runs start_server_with_options
+}
+
+impl ServerHandle {
+    /**
+     * Public Function
+     * Stops the server gracefully and waits up to timeout for it to finish
+     *
+     * Procedure:
+     * 1. Requests a shutdown, so no more connections are taken and each open
+     *    one closes once it has finished the message in hand
+     * 2. Waits for the server to flush every sheet's worker, save its
+     *    snapshot and join its threads, then joins the server thread. The
+     *    accept thread is left behind, see start_server_with_options
+     * 3. Returns an error if that takes longer than timeout, e.g. while a
+     *    slow formula is still being set, leaving the server thread to
+     *    finish on its own
+     */
+    pub fn shutdown(self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        // Step 1: Ask the server to stop
+        self.options.request_shutdown();
+
+        // Step 2 & 3: Wait for it, within the timeout
+        match self.done.recv_timeout(timeout) {
+            Ok(result) => {
+                let _ = self.thread.join();
+                result.map_err(Into::into)
+            }
+            Err(_) => Err(format!("Server did not stop within {:?}", timeout).into()),
+        }
+    }
+
+    /**
+     * Public Function
+     * Waits for the server to return by itself, as start_server_with_options
+     * does when the manager has no more connections
+     */
+    pub fn wait(self) -> Result<(), Box<dyn Error>> {
+        let result = self
+            .done
+            .recv()
+            .unwrap_or_else(|_| Err("Server thread panicked".to_string()));
+        let _ = self.thread.join();
+        result.map_err(Into::into)
+    }
+}
+
+/**
+ * Public Function
+ * Serves connections from the manager with default options until it has no
+ * more, as start_server_with_options does
+ * Nothing can ask it to shut down, so connections are accepted on the
+ * calling thread and any Manager works, as it did before Closeable. A
+ * connection's read thread exits once its client disconnects, as the
+ * server has no way to close it, see Unclosable
+ */
 pub fn start_server<M>(mut manager: M) -> Result<(), Box<dyn Error>>
 where
     M: Manager,
 {
-    // Create a new spreadsheet instance
-    let spreadsheet = Arc::new(Spreadsheet::new());
-
-    // Store handles to all spawned threads
-    let mut handles = Vec::new();
-
-    // Accept and handle connections until NoMoreConnections is received
-    while let Connection::NewConnection { reader, writer } = manager.accept_new_connection() {
-        let spreadsheet_clone = Arc::clone(&spreadsheet);
+    run_server(ServerOptions::default(), |queue| {
+        let mut next_connection: ConnectionId = 0;
+        while let Connection::NewConnection { reader, writer } = manager.accept_new_connection() {
+            next_connection += 1;
+            if queue
+                .send((next_connection, Unclosable(reader), writer))
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+}
 
-        let handle = thread::spawn(move || {
-            if let Err(e) = handle_connection(reader, writer, spreadsheet_clone) {
-                eprintln!("Connection error: {:?}", e);
+/**
+ * Public Function
+ * Serves connections from the manager until it has no more, or until a
+ * shutdown is requested through options.shutdown
+ *
+ * Procedure:
+ * 1. Restores the default sheet from the snapshot, if there is one. Every
+ *    sheet, the restored one included, gets the options' range size and
+ *    expression length limits, so one client can't tie up the server with
+ *    e.g. sum(A1_ZZ99999)
+ * 2. Starts connection_threads threads, which serve accepted connections
+ *    one at a time from a queue holding up to connection_queue more, and
+ *    accepts connections on a separate thread, as accept_new_connection
+ *    can't be interrupted once it blocks. Once the queue is full, the next
+ *    connection isn't accepted until a thread is free. A connection being
+ *    served also has a read thread, which exits once the connection is
+ *    closed, see Closeable, so at most twice connection_threads threads
+ *    serve clients
+ * 3. Numbers each connection with a ConnectionId and queues it, until a
+ *    shutdown is requested, after queueing those already accepted. The
+ *    accept thread can't be stopped, as Manager has no way to interrupt
+ *    a blocked accept_new_connection, so it is left behind; it exits the
+ *    next time that returns, dropping and so closing any connection it
+ *    accepted
+ * 4. Waits for every queued connection to be served, which after a
+ *    shutdown happens once each has finished the message in hand; the
+ *    rest of what its client sent goes unanswered
+ * 5. Waits for every sheet's worker to apply the updates still queued,
+ *    saves the default sheet to the snapshot, then stops and joins the
+ *    workers
+ */
+pub fn start_server_with_options<M>(
+    mut manager: M,
+    options: ServerOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    M: Manager + Send + 'static,
+    <M::ReaderWriter as ReaderWriter>::Reader: Closeable,
+{
+    let shutdown = Arc::clone(&options.shutdown);
+    run_server(options, move |queue| {
+        // Accept connections until NoMoreConnections is received, one at a
+        // time so accept_new_connection waits while the queue is full
+        let (connections, accepted) = mpsc::sync_channel(0);
+        thread::spawn(move || {
+            while let Connection::NewConnection { reader, writer } = manager.accept_new_connection()
+            {
+                if connections.send((reader, writer)).is_err() {
+                    break;
+                }
             }
         });
 
-        handles.push(handle);
-    }
+        // Step 3: Queue each one until a shutdown is requested, including
+        // those accepted before it, leaving the accept thread behind
+        let mut next_connection: ConnectionId = 0;
+        loop {
+            let connection = if shutdown.load(Ordering::Relaxed) {
+                accepted.try_recv().ok()
+            } else {
+                match accepted.recv_timeout(SHUTDOWN_POLL) {
+                    Ok(connection) => Some(connection),
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => None,
+                }
+            };
+            let Some((reader, writer)) = connection else {
+                break;
+            };
+            next_connection += 1;
+            if queue.send((next_connection, reader, writer)).is_err() {
+                break;
+            }
+        }
+        if shutdown.load(Ordering::Relaxed) {
+            info!("event=shutdown_requested accepted={}", next_connection);
+        }
+    })
+}
+
+/**
+ * HELPER FUNCTION
+ * Runs a server, shared by start_server and start_server_with_options
+ * Does steps 1, 2, 4 and 5 of start_server_with_options, handing the
+ * connection threads' queue to queue_connections for step 3; the queue
+ * closes once queue_connections returns
+ */
+fn run_server<R, W>(
+    options: ServerOptions,
+    queue_connections: impl FnOnce(mpsc::SyncSender<(ConnectionId, R, W)>),
+) -> Result<(), Box<dyn Error>>
+where
+    R: Reader + Closeable + Send + 'static,
+    W: Writer + Send + 'static,
+{
+    // Step 1: Restore the default sheet from the last snapshot, if there is
+    // one, or start empty
+    let sheet_options = SpreadsheetOptions {
+        max_range_cells: options.max_range_cells,
+        max_expression_length: options.max_expression_length,
+        ..Default::default()
+    };
+    let workbook = match &options.snapshot_path {
+        Some(path) if path.exists() => {
+            info!("event=snapshot_load path={}", path.display());
+            let sheet = Spreadsheet::load_from_path_with_options(path, sheet_options.clone())?;
+            Arc::new(Workbook::with_default_sheet(sheet, sheet_options))
+        }
+        _ => Arc::new(Workbook::with_options(sheet_options)),
+    };
 
-    // Wait for all connection threads to complete
+    // Step 2: Start the connection threads, which take connections from a
+    // queue in the order they were accepted
+    let (queue, queued) = mpsc::sync_channel::<(ConnectionId, R, W)>(options.connection_queue);
+    let queued = Arc::new(Mutex::new(queued));
+    let handles: Vec<thread::JoinHandle<()>> = (0..options.connection_threads.max(1))
+        .map(|_| {
+            let queued = Arc::clone(&queued);
+            let workbook = Arc::clone(&workbook);
+            let options = options.clone();
+            thread::spawn(move || loop {
+                let next = queued.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                let Ok((connection, reader, writer)) = next else {
+                    break;
+                };
+                serve_connection(connection, reader, writer, &workbook, &options);
+            })
+        })
+        .collect();
+
+    // Step 3: Accept and queue connections
+    queue_connections(queue);
+
+    // Step 4: Let the connection threads finish the queue, then wait for them
     for handle in handles {
         handle.join().unwrap();
     }
 
+    // Step 5: Let the workers catch up, save the default sheet for the next
+    // start, then stop the workers
+    for name in workbook.sheet_names() {
+        workbook.sheet(&name).flush();
+    }
+    if let Some(path) = &options.snapshot_path {
+        info!("event=snapshot_save path={}", path.display());
+        workbook.sheet(DEFAULT_SHEET).save_to_path(path)?;
+    }
+    workbook.close();
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsheet_lib::connect::ConnectionError;
+    use std::sync::{Condvar, Mutex};
+    use std::time::Instant;
+
+    /// Blocks a mock client's reads, like a silent socket, until the server
+    /// closes the connection
+    #[derive(Clone, Default)]
+    struct Hangup(Arc<(Mutex<bool>, Condvar)>);
+
+    impl Hangup {
+        fn wait(&self) -> ReadMessageResult {
+            let (closed, changed) = &*self.0;
+            let mut closed = closed.lock().unwrap();
+            while !*closed {
+                closed = changed.wait(closed).unwrap();
+            }
+            ReadMessageResult::ConnectionClosed
+        }
+
+        /// Waits out a pause, returning whether the connection was closed
+        fn closed_within(&self, pause: Duration) -> bool {
+            let (closed, changed) = &*self.0;
+            let closed = closed.lock().unwrap();
+            *changed
+                .wait_timeout_while(closed, pause, |closed| !*closed)
+                .unwrap()
+                .0
+        }
+
+        fn closer(&self) -> Closer {
+            let hangup = self.clone();
+            Closer::new(move || {
+                let (closed, changed) = &*hangup.0;
+                *closed.lock().unwrap() = true;
+                changed.notify_all();
+            })
+        }
+    }
+
+    /// Waits up to 5s for a mock client's read thread to exit, which drops
+    /// the reader and its tracker
+    fn read_side_ended(tracker: &Weak<()>) -> bool {
+        let started = Instant::now();
+        while tracker.upgrade().is_some() {
+            if started.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// A client that sends some messages and then goes quiet without leaving
+    struct QuietReader {
+        messages: Vec<String>,
+        hangup: Hangup,
+        tracker: Arc<()>, // Dropped with the reader, see read_side_ended
+    }
+
+    impl QuietReader {
+        fn new(messages: Vec<String>) -> Self {
+            QuietReader {
+                messages,
+                hangup: Hangup::default(),
+                tracker: Arc::new(()),
+            }
+        }
+
+        fn tracker(&self) -> Weak<()> {
+            Arc::downgrade(&self.tracker)
+        }
+    }
+
+    impl Reader for QuietReader {
+        fn read_message(&mut self) -> ReadMessageResult {
+            if self.messages.is_empty() {
+                return self.hangup.wait();
+            }
+            ReadMessageResult::Message(self.messages.remove(0))
+        }
+
+        fn id(&self) -> String {
+            "quiet".to_string()
+        }
+    }
+
+    impl Closeable for QuietReader {
+        fn closer(&self) -> Option<Closer> {
+            Some(self.hangup.closer())
+        }
+    }
+
+    struct RecordingWriter(Arc<Mutex<Vec<Reply>>>);
+
+    impl Writer for RecordingWriter {
+        fn write_message(&mut self, message: Reply) -> WriteMessageResult {
+            self.0.lock().unwrap().push(message);
+            WriteMessageResult::Ok
+        }
+
+        fn id(&self) -> String {
+            "recording".to_string()
+        }
+    }
+
+    struct QuietClient;
+
+    impl ReaderWriter for QuietClient {
+        type Reader = QuietReader;
+        type Writer = RecordingWriter;
+    }
+
+    /// Hands out a single connection
+    struct OneConnection(Option<(QuietReader, RecordingWriter)>);
+
+    impl Manager for OneConnection {
+        type ReaderWriter = QuietClient;
+
+        fn accept_new_connection(&mut self) -> Connection<QuietReader, RecordingWriter> {
+            match self.0.take() {
+                Some((reader, writer)) => Connection::NewConnection { reader, writer },
+                None => Connection::NoMoreConnections,
+            }
+        }
+    }
+
+    /// Runs one client sending the given messages, then going quiet, until
+    /// the server closes it as idle, returning every reply it was sent
+    fn run_session(messages: &[&str]) -> Vec<Reply> {
+        run_session_with_options(messages, ServerOptions::default())
+    }
+
+    /// As run_session, with the given options besides the idle timeout
+    fn run_session_with_options(messages: &[&str], options: ServerOptions) -> Vec<Reply> {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let reader = QuietReader::new(messages.iter().map(|m| m.to_string()).collect());
+        let manager = OneConnection(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+        start_server_with_options(
+            manager,
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(200)),
+                ..options
+            },
+        )
+        .unwrap();
+        let replies = replies.lock().unwrap();
+        replies.clone()
+    }
+
+    /// Hands out one connection, then blocks forever like a listener nobody
+    /// else connects to
+    struct ListeningAfterOne(Option<(QuietReader, RecordingWriter)>);
+
+    impl Manager for ListeningAfterOne {
+        type ReaderWriter = QuietClient;
+
+        fn accept_new_connection(&mut self) -> Connection<QuietReader, RecordingWriter> {
+            if let Some((reader, writer)) = self.0.take() {
+                return Connection::NewConnection { reader, writer };
+            }
+            loop {
+                thread::park();
+            }
+        }
+    }
+
+    /// Hands out the given connections in order
+    struct ManyConnections(Vec<(QuietReader, RecordingWriter)>);
+
+    impl Manager for ManyConnections {
+        type ReaderWriter = QuietClient;
+
+        fn accept_new_connection(&mut self) -> Connection<QuietReader, RecordingWriter> {
+            if self.0.is_empty() {
+                return Connection::NoMoreConnections;
+            }
+            let (reader, writer) = self.0.remove(0);
+            Connection::NewConnection { reader, writer }
+        }
+    }
+
+    /// A client that sends each message after a pause, then goes quiet
+    struct SlowReader {
+        messages: Vec<(Duration, String)>,
+        hangup: Hangup,
+        tracker: Arc<()>, // Dropped with the reader, see read_side_ended
+    }
+
+    impl SlowReader {
+        fn new(pause: Duration, messages: &[&str]) -> Self {
+            SlowReader {
+                messages: messages.iter().map(|m| (pause, m.to_string())).collect(),
+                hangup: Hangup::default(),
+                tracker: Arc::new(()),
+            }
+        }
+
+        fn tracker(&self) -> Weak<()> {
+            Arc::downgrade(&self.tracker)
+        }
+    }
+
+    impl Closeable for SlowReader {
+        fn closer(&self) -> Option<Closer> {
+            Some(self.hangup.closer())
+        }
+    }
+
+    impl Reader for SlowReader {
+        fn read_message(&mut self) -> ReadMessageResult {
+            if self.messages.is_empty() {
+                return self.hangup.wait();
+            }
+            let (pause, message) = self.messages.remove(0);
+            if self.hangup.closed_within(pause) {
+                return ReadMessageResult::ConnectionClosed;
+            }
+            ReadMessageResult::Message(message)
+        }
+
+        fn id(&self) -> String {
+            "slow".to_string()
+        }
+    }
+
+    struct SlowClient;
+
+    impl ReaderWriter for SlowClient {
+        type Reader = SlowReader;
+        type Writer = RecordingWriter;
+    }
+
+    /// Hands out a single slow connection
+    struct SlowConnection(Option<(SlowReader, RecordingWriter)>);
+
+    impl Manager for SlowConnection {
+        type ReaderWriter = SlowClient;
+
+        fn accept_new_connection(&mut self) -> Connection<SlowReader, RecordingWriter> {
+            match self.0.take() {
+                Some((reader, writer)) => Connection::NewConnection { reader, writer },
+                None => Connection::NoMoreConnections,
+            }
+        }
+    }
+
+    /// A client whose first read fails
+    struct FailingReader;
+
+    impl Closeable for FailingReader {}
+
+    impl Reader for FailingReader {
+        fn read_message(&mut self) -> ReadMessageResult {
+            ReadMessageResult::Err(ConnectionError::ConnectionLost)
+        }
+
+        fn id(&self) -> String {
+            "failing".to_string()
+        }
+    }
+
+    struct FailingClient;
+
+    impl ReaderWriter for FailingClient {
+        type Reader = FailingReader;
+        type Writer = RecordingWriter;
+    }
+
+    /// Hands out the given number of failing connections
+    struct FailingConnections(usize, Arc<Mutex<Vec<Reply>>>);
+
+    impl Manager for FailingConnections {
+        type ReaderWriter = FailingClient;
+
+        fn accept_new_connection(&mut self) -> Connection<FailingReader, RecordingWriter> {
+            if self.0 == 0 {
+                return Connection::NoMoreConnections;
+            }
+            self.0 -= 1;
+            Connection::NewConnection {
+                reader: FailingReader,
+                writer: RecordingWriter(Arc::clone(&self.1)),
+            }
+        }
+    }
+
+    /// A client written before Closeable, which sends its messages and
+    /// then disconnects
+    struct PlainReader(Vec<String>);
+
+    impl Reader for PlainReader {
+        fn read_message(&mut self) -> ReadMessageResult {
+            if self.0.is_empty() {
+                return ReadMessageResult::ConnectionClosed;
+            }
+            ReadMessageResult::Message(self.0.remove(0))
+        }
+
+        fn id(&self) -> String {
+            "plain".to_string()
+        }
+    }
+
+    struct PlainClient;
+
+    impl ReaderWriter for PlainClient {
+        type Reader = PlainReader;
+        type Writer = RecordingWriter;
+    }
+
+    /// Hands out one plain connection, and can't be sent to another thread
+    struct LocalManager(
+        Option<(PlainReader, RecordingWriter)>,
+        std::marker::PhantomData<std::rc::Rc<()>>,
+    );
+
+    impl Manager for LocalManager {
+        type ReaderWriter = PlainClient;
+
+        fn accept_new_connection(&mut self) -> Connection<PlainReader, RecordingWriter> {
+            match self.0.take() {
+                Some((reader, writer)) => Connection::NewConnection { reader, writer },
+                None => Connection::NoMoreConnections,
+            }
+        }
+    }
+
+    #[test]
+    fn test_start_server_takes_any_manager() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let reader = PlainReader(vec!["set A1 5".to_string(), "get A1".to_string()]);
+        let manager = LocalManager(
+            Some((reader, RecordingWriter(Arc::clone(&replies)))),
+            std::marker::PhantomData,
+        );
+        start_server(manager).unwrap();
+        assert_eq!(
+            replies.lock().unwrap()[..],
+            [Reply::Value("A1".to_string(), CellValue::Int(5))]
+        );
+    }
+
+    #[test]
+    fn test_connection_errors_reach_the_hook() {
+        let failures: Arc<Mutex<Vec<(ConnectionId, String)>>> = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&failures);
+        let manager = FailingConnections(2, Arc::new(Mutex::new(Vec::new())));
+        start_server_with_options(
+            manager,
+            ServerOptions {
+                on_connection_error: Some(ConnectionErrorHook(Arc::new(move |connection, e| {
+                    seen.lock().unwrap().push((connection, format!("{e:?}")));
+                }))),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Each connection is told apart by its id
+        let mut failures = failures.lock().unwrap().clone();
+        failures.sort();
+        assert_eq!(
+            failures,
+            [
+                (1, "ConnectionLost".to_string()),
+                (2, "ConnectionLost".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pool_serves_queued_connections_in_turn() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let mut trackers = Vec::new();
+        let connections = (1..=3)
+            .map(|i| {
+                let reader = QuietReader::new(vec![format!("set A{i} {i}"), format!("get A{i}")]);
+                trackers.push(reader.tracker());
+                (reader, RecordingWriter(Arc::clone(&replies)))
+            })
+            .collect();
+        start_server_with_options(
+            ManyConnections(connections),
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(100)),
+                connection_threads: 1,
+                connection_queue: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // With one thread, each connection is served to the end before the next
+        let replies = replies.lock().unwrap();
+        assert_eq!(replies.len(), 6);
+        for (i, served) in (1..=3).zip(replies.chunks(2)) {
+            assert!(
+                matches!(&served[0], Reply::Value(name, CellValue::Int(n)) if *name == format!("A{i}") && *n == i)
+            );
+            assert!(matches!(&served[1], Reply::Error(_)));
+        }
+
+        // No connection leaves its read thread behind
+        assert!(trackers.iter().all(read_side_ended));
+    }
+
+    #[test]
+    fn test_idle_connection_is_reclaimed() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let reader = QuietReader::new(vec!["set A1 5".to_string(), "get A1".to_string()]);
+        let tracker = reader.tracker();
+        let manager = OneConnection(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+
+        // The server only returns once the quiet connection's thread has exited
+        let started = Instant::now();
+        start_server_with_options(
+            manager,
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let replies = replies.lock().unwrap();
+        assert!(matches!(&replies[0], Reply::Value(name, CellValue::Int(5)) if name == "A1"));
+        assert!(matches!(&replies[1], Reply::Error(_)));
+
+        // The connection was closed, so its read thread exited too
+        assert!(read_side_ended(&tracker));
+    }
+
+    #[test]
+    fn test_idle_tcp_client_is_disconnected() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpStream;
+
+        let manager = TcpManager::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = manager.local_addr().unwrap();
+        let server = Server::start(
+            manager,
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        // The server hangs up on the idle client, which then reads to the end
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(b"set A1 5\nget A1\n").unwrap();
+        let lines: Vec<String> = BufReader::new(client)
+            .lines()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("A1") && lines[0].contains('5'));
+        assert!(lines[1].contains("idle"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_messages_reset_the_idle_timer() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let pause = Duration::from_millis(120);
+        let reader = SlowReader::new(pause, &["set A1 1", "set A1 2", "set A1 3", "get A1"]);
+        let tracker = reader.tracker();
+        let manager = SlowConnection(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+
+        // Each pause is within the timeout, though all of them together aren't
+        let started = Instant::now();
+        start_server_with_options(
+            manager,
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(
+            replies.lock().unwrap()[..],
+            [
+                Reply::Value("A1".to_string(), CellValue::Int(3)),
+                Reply::Error("connection idle, closing".to_string()),
+            ]
+        );
+        assert!(read_side_ended(&tracker));
+    }
+
+    #[test]
+    fn test_slow_client_is_closed_when_idle() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let reader = SlowReader::new(Duration::from_secs(30), &["get A1"]);
+        let tracker = reader.tracker();
+        let manager = SlowConnection(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+
+        // The client is hung up on mid-pause, and its read thread exits
+        // rather than wait out the rest of the pause
+        let started = Instant::now();
+        start_server_with_options(
+            manager,
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(read_side_ended(&tracker));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            replies.lock().unwrap()[..],
+            [Reply::Error("connection idle, closing".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_ranged_get_matches_single_gets() {
+        let messages = ["set A1 1 +", "set B1 A1", "set A2 7", "get B1", "get A1_B2"];
+        let replies = run_session(&messages);
+
+        let Reply::Error(single) = &replies[0] else {
+            panic!("expected an error for B1");
+        };
+        let Reply::Value(name, CellValue::String(range)) = &replies[1] else {
+            panic!("expected a value for the range");
+        };
+        assert_eq!(name, "A1_B2");
+        let lines: Vec<&str> = range.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("A1=Error: "));
+        assert_eq!(lines[1], format!("B1=Error: {single}"));
+        assert_eq!(lines[2..], ["A2=7", "B2=None"]);
+    }
+
+    #[test]
+    fn test_string_cells_round_trip() {
+        let messages = [
+            r#"set A1 "hello""#,
+            r#"set A2 "say \"hi\"""#,
+            "set B1 A1 + 1",
+            r#"set B2 A1 + " world""#,
+            "get A1",
+            "get A2",
+            "get B1",
+            "get B2",
+            "get C9",
+        ];
+        let replies = run_session(&messages);
+
+        // A string prints as the literal that set it, and a cell never set
+        // as None
+        let printed: Vec<String> = replies[..5]
+            .iter()
+            .map(|reply| match reply {
+                Reply::Value(name, value) => format!("{name} = {value}"),
+                Reply::Error(e) => format!("Error: {e}"),
+            })
+            .collect();
+        assert_eq!(
+            printed,
+            [
+                r#"A1 = "hello""#,
+                r#"A2 = "say \"hi\"""#,
+                r#"B1 = Error: "TypeError: A1 + 1 mixes a string and a number""#,
+                r#"B2 = "hello world""#,
+                "C9 = None",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_formula_returns_expression_as_typed() {
+        let messages = ["set C1 A1 + B1", "formula C1", "formula D1"];
+        let replies = run_session(&messages);
+
+        assert!(matches!(
+            &replies[0],
+            Reply::Value(name, CellValue::String(formula)) if name == "C1" && formula == "A1 + B1"
+        ));
+        assert!(matches!(&replies[1], Reply::Value(name, CellValue::None) if name == "D1"));
+    }
+
+    #[test]
+    fn test_sheet_prefix_routes_commands() {
+        let messages = [
+            "set Sheet2!A1 5",
+            "set A1 1",
+            "get Sheet2!A1",
+            "get A1",
+            "sheets",
+            "dropsheet Sheet2",
+            "get Sheet2!A1",
+        ];
+        let replies = run_session(&messages);
+
+        assert!(
+            matches!(&replies[0], Reply::Value(name, CellValue::Int(5)) if name == "Sheet2!A1")
+        );
+        assert!(matches!(&replies[1], Reply::Value(name, CellValue::Int(1)) if name == "A1"));
+        assert!(matches!(
+            &replies[2],
+            Reply::Value(name, CellValue::String(sheets)) if name == "sheets" && sheets == "Sheet2\nmain"
+        ));
+
+        // A dropped sheet comes back empty
+        assert!(matches!(&replies[3], Reply::Value(name, CellValue::None) if name == "Sheet2!A1"));
+    }
+
+    #[test]
+    fn test_watch_pushes_new_values() {
+        let messages = [
+            "set A1 1",
+            "set B1 A1 * 2",
+            "watch A1_B1",
+            "watch Sheet2!C3",
+            "set A1 5",
+            "set Sheet2!C3 7",
+            "unwatch D4",
+        ];
+        let replies = run_session(&messages);
+
+        // Pushes arrive alongside the replies, each sheet's in commit order;
+        // A1 and B1 may also report their first values, depending on when
+        // the worker picked those sets up
+        let pushed = |prefixed: bool| -> Vec<(String, CellValue)> {
+            replies
+                .iter()
+                .filter_map(|reply| match reply {
+                    Reply::Value(name, value) if name.contains('!') == prefixed => {
+                        Some((name.clone(), value.clone()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        assert!(pushed(false).ends_with(&[
+            ("A1".to_string(), CellValue::Int(5)),
+            ("B1".to_string(), CellValue::Int(10)),
+        ]));
+        assert_eq!(
+            pushed(true),
+            vec![("Sheet2!C3".to_string(), CellValue::Int(7))]
+        );
+        assert!(replies
+            .iter()
+            .any(|reply| matches!(reply, Reply::Error(e) if e == "Error: D4 is not watched")));
+    }
+
+    #[test]
+    fn test_watch_several_cells() {
+        let messages = [
+            "set A1 1",
+            "set B1 A1 * 2",
+            "set C1 3",
+            "eval sleep_then(200, 0)",
+            "watch B1 C1",
+            "set A1 5",
+            "eval sleep_then(200, 0)",
+            "unwatch B1 C1 D1 E1",
+            "set A1 6",
+            "set C1 4",
+        ];
+        let replies = run_session(&messages);
+
+        // B1 is pushed when its dependency changes, until it is unwatched;
+        // the watch of C1 itself is never triggered. The pauses let the
+        // worker finish each cascade before the watches change
+        let pushed: Vec<(String, CellValue)> = replies
+            .iter()
+            .filter_map(|reply| match reply {
+                Reply::Value(name, value) if !name.starts_with("sleep_then") => {
+                    Some((name.clone(), value.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert!(pushed.ends_with(&[("B1".to_string(), CellValue::Int(10))]));
+        assert!(!pushed.iter().any(|(name, _)| name == "C1"));
+        assert!(replies
+            .iter()
+            .any(|reply| matches!(reply, Reply::Error(e) if e == "Error: D1, E1 are not watched")));
+    }
+
+    #[test]
+    fn test_eval_replies_without_storing() {
+        let messages = [
+            "set A1 4",
+            "set A2 1 / 0",
+            "eval sum(A1_A1) * 2",
+            "eval A2 + 1",
+            "get A3",
+        ];
+        let replies = run_session(&messages);
+
+        assert!(matches!(
+            &replies[0],
+            Reply::Value(name, CellValue::Int(8)) if name == "sum(A1_A1) * 2"
+        ));
+        assert!(matches!(
+            &replies[1],
+            Reply::Error(e) if e == "Cell depends on another error cell"
+        ));
+        assert!(matches!(&replies[2], Reply::Value(name, CellValue::None) if name == "A3"));
+    }
+
+    #[test]
+    fn test_sheet_errors_reply_distinctly() {
+        let messages = [
+            "set A1 sum(A1_)",
+            "set XFE1 1",
+            "set A1 1",
+            "set B1 2",
+            "move A1 B1",
+            "undo C1",
+        ];
+        let replies = run_session(&messages);
+
+        let errors: Vec<&str> = replies
+            .iter()
+            .filter_map(|reply| match reply {
+                Reply::Error(e) => Some(e.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Error: A1_ is not a valid cell or range reference",
+                "Error: cell XFE1 is outside the sheet bounds (max XFD1048576)",
+                "Error: Cell B1 is not empty",
+                "Error: Nothing to undo in C1",
+                "connection idle, closing",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_whatif_replies_without_storing() {
+        let messages = [
+            "set A1 1",
+            "set B1 A1 * 2",
+            "set D1 sum(A1_B1)",
+            "whatif D1 A1=100",
+            "get A1",
+            "get D1",
+        ];
+        let replies = run_session(&messages);
+
+        assert!(matches!(&replies[0], Reply::Value(name, CellValue::Int(300)) if name == "D1"));
+        assert!(matches!(&replies[1], Reply::Value(name, CellValue::Int(1)) if name == "A1"));
+        assert!(matches!(&replies[2], Reply::Value(name, CellValue::Int(3)) if name == "D1"));
+    }
+
+    #[test]
+    fn test_find_caps_its_matches() {
+        let messages = [
+            "set A1 3",
+            "set A2 A1",
+            "set A3 A1",
+            "set B1 A1",
+            "find 3",
+            "findexpr A1",
+            "find 4",
+        ];
+        let replies = run_session_with_options(
+            &messages,
+            ServerOptions {
+                find_limit: 2,
+                ..Default::default()
+            },
+        );
+
+        let matches: Vec<&str> = replies
+            .iter()
+            .filter_map(|reply| match reply {
+                Reply::Value(name, CellValue::String(text)) if name == "matches" => {
+                    Some(text.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(matches, ["A1\nB1\n+2 more", "B1\nA2\n+1 more", ""]);
+    }
+
+    #[test]
+    fn test_get_names_wide_columns() {
+        let names = ["A1", "Z1", "AA2", "AZ3", "BA4", "ZZ5", "AAA6"];
+        let messages: Vec<String> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("set {name} {i}"))
+            .chain(names.iter().map(|name| format!("get {name}")))
+            .collect();
+        let replies = run_session(&messages.iter().map(String::as_str).collect::<Vec<_>>());
+
+        // Each reply names the cell it was asked for, and the name parses back
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(
+                replies[i],
+                Reply::Value(name.to_string(), CellValue::Int(i as i64))
+            );
+            let Reply::Value(reply_name, _) = &replies[i] else {
+                unreachable!()
+            };
+            assert_eq!(
+                reply_name.parse::<CellIdentifier>(),
+                name.parse::<CellIdentifier>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_out_of_bounds_cells_are_rejected() {
+        let messages = [
+            "set XFD1048576 4",
+            "get XFD1048576",
+            "get XFE1",
+            "set A1048577 1",
+            "get C3_A1048577",
+            "set A1 A1048577 + 1",
+            "list",
+        ];
+        let replies = run_session(&messages);
+
+        // The last cell is accepted; anything past it is refused, never stored
+        let outside = |name: &str| {
+            Reply::Error(format!(
+                "Error: cell {name} is outside the sheet bounds (max XFD1048576)"
+            ))
+        };
+        assert_eq!(
+            replies[0],
+            Reply::Value("XFD1048576".to_string(), CellValue::Int(4))
+        );
+        assert_eq!(replies[1], outside("XFE1"));
+        assert_eq!(replies[2], outside("A1048577"));
+        assert_eq!(replies[3], outside("C1048577"));
+        assert_eq!(replies[4], outside("A1048577"));
+        assert!(matches!(
+            &replies[5],
+            Reply::Value(_, CellValue::String(cells)) if !cells.contains("A1048577")
+        ));
+    }
+
+    #[test]
+    fn test_server_limits_ranges_and_expressions() {
+        let path = std::env::temp_dir().join(format!("rsheet-limits-{}.json", std::process::id()));
+        let saved = Spreadsheet::new();
+        saved.set("A1".parse().unwrap(), "2".to_string()).unwrap();
+        saved.save_to_path(&path).unwrap();
+        let long_expression = format!("set A2 {}", "1 + ".repeat(300) + "1");
+        let messages = [
+            "set XFD1 sum(A1_ZZ99999)",
+            "set Sheet2!A1 sum(A1_ZZ99999)",
+            long_expression.as_str(),
+            "set B1 A1 * 3",
+            "get B1",
+            "get XFD1",
+        ];
+        let replies = run_session_with_options(
+            &messages,
+            ServerOptions {
+                snapshot_path: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let _ = std::fs::remove_file(&path);
+
+        // The loaded sheet and a new one both refuse the huge range, and
+        // the rest of the sheet keeps working
+        let limit = Reply::Error(format!(
+            "Error: range A1_ZZ99999 exceeds the {DEFAULT_MAX_RANGE_CELLS}-cell limit"
+        ));
+        assert_eq!(
+            replies[..5],
+            [
+                limit.clone(),
+                limit,
+                Reply::Error(format!(
+                    "Error: expression exceeds the {DEFAULT_MAX_EXPRESSION_LENGTH}-character limit"
+                )),
+                Reply::Value("B1".to_string(), CellValue::Int(6)),
+                Reply::Value("XFD1".to_string(), CellValue::None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_stays_in_its_directory() {
+        let dir = std::env::temp_dir().join(format!("rsheet-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        let escaped = dir.with_extension("csv");
+        let absolute = format!("export {}", escaped.display());
+        let messages = [
+            "set A1 5",
+            "export out/grid.csv",
+            "export ../rsheet-escaped.csv",
+            absolute.as_str(),
+        ];
+        let replies = run_session_with_options(
+            &messages,
+            ServerOptions {
+                export_dir: Some(dir.clone()),
+                ..Default::default()
+            },
+        );
+        let written = std::fs::read_to_string(dir.join("out/grid.csv"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Only the path inside the directory is written
+        let outside = |path: &str| {
+            Reply::Error(format!(
+                "Error exporting {path}: path must stay within the export directory"
+            ))
+        };
+        assert_eq!(
+            replies[..3],
+            [
+                Reply::Value("rows".to_string(), CellValue::Int(1)),
+                outside("../rsheet-escaped.csv"),
+                outside(&escaped.display().to_string()),
+            ]
+        );
+        assert_eq!(written.unwrap(), "5\n");
+        assert!(!escaped.exists());
+
+        // Without a directory, export is off
+        assert_eq!(
+            run_session(&["export grid.csv"])[0],
+            Reply::Error("Error exporting grid.csv: exports are disabled".to_string())
+        );
+    }
+
+    #[test]
+    fn test_shutdown_drains_connections() {
+        let path = std::env::temp_dir().join(format!("rsheet-drain-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let mut messages: Vec<String> = (1..=20).map(|row| format!("set A{row} {row}")).collect();
+        messages.push("set B1 sum(A1_A20)".to_string());
+        messages.push("get B1".to_string());
+        let reader = QuietReader::new(messages);
+        let tracker = reader.tracker();
+        let manager = ListeningAfterOne(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+        let server = Server::start(
+            manager,
+            ServerOptions {
+                snapshot_path: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let started = Instant::now();
+        while replies.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Neither the blocked accept nor the quiet client, which has no idle
+        // timeout, keeps the server from returning
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(read_side_ended(&tracker));
+
+        // What the client sent first was answered and saved
+        let replies = replies.lock().unwrap();
+        assert_eq!(
+            replies[..],
+            [
+                Reply::Value("B1".to_string(), CellValue::Int(210)),
+                Reply::Error("Connection closed because the server is shutting down".to_string()),
+            ]
+        );
+        let saved = Spreadsheet::load_from_path(&path).unwrap();
+        assert_eq!(saved.count_nonempty(), 21);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_shutdown_stops_a_busy_client() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let reader = SlowReader::new(Duration::from_millis(10), &vec!["get A1"; 10_000]);
+        let tracker = reader.tracker();
+        let manager = SlowConnection(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+        let server = Server::start(manager, ServerOptions::default());
+        let started = Instant::now();
+        while replies.lock().unwrap().len() < 3 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // A client that never goes quiet is closed after its message in hand
+        let answered = replies.lock().unwrap().len();
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(read_side_ended(&tracker));
+        let replies = replies.lock().unwrap();
+        assert!(replies.len() <= answered + 2);
+        assert_eq!(
+            replies.last(),
+            Some(&Reply::Error(
+                "Connection closed because the server is shutting down".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_server_handle_shuts_down() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let reader = QuietReader::new(vec!["set A1 5".to_string(), "get A1".to_string()]);
+        let manager = ListeningAfterOne(Some((reader, RecordingWriter(Arc::clone(&replies)))));
+        let server = Server::start(manager, ServerOptions::default());
+
+        // Wait for the commands to be answered while the server runs
+        let started = Instant::now();
+        while replies.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The blocked accept and the quiet client both give way to shutdown
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            replies.lock().unwrap()[..],
+            [
+                Reply::Value("A1".to_string(), CellValue::Int(5)),
+                Reply::Error("Connection closed because the server is shutting down".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_rate_limit_rejects_bursts() {
+        let mut messages: Vec<String> = (1..=5).map(|row| format!("set A{row} {row}")).collect();
+        messages.extend([
+            "get A3".to_string(),
+            "get A4".to_string(),
+            "eval sleep_then(1100, 0)".to_string(),
+            "set A6 6".to_string(),
+            "get A6".to_string(),
+        ]);
+        let replies = run_session_with_options(
+            &messages.iter().map(String::as_str).collect::<Vec<_>>(),
+            ServerOptions {
+                set_rate_limit: Some(3),
+                ..Default::default()
+            },
+        );
+
+        // The burst's last two sets are refused, and reads aren't limited;
+        // once the window has passed, sets go through again
+        let rate_limited = Reply::Error("rate limited".to_string());
+        assert_eq!(
+            replies[..6],
+            [
+                rate_limited.clone(),
+                rate_limited,
+                Reply::Value("A3".to_string(), CellValue::Int(3)),
+                Reply::Value("A4".to_string(), CellValue::None),
+                Reply::Value("sleep_then(1100, 0)".to_string(), CellValue::Int(0)),
+                Reply::Value("A6".to_string(), CellValue::Int(6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregates_reply_like_get() {
+        let messages = [
+            "set B1 4",
+            "set B3 8",
+            "set C3 1 / 0",
+            "colsum B",
+            "rowsum 3",
+            "rowsum 3 skiperrors",
+            "colstats B",
+        ];
+        let replies = run_session(&messages);
+
+        assert_eq!(
+            replies[..4],
+            [
+                Reply::Value("B".to_string(), CellValue::Int(12)),
+                Reply::Error("Error: Cell C3 holds an error".to_string()),
+                Reply::Value("3".to_string(), CellValue::Int(8)),
+                Reply::Value(
+                    "B".to_string(),
+                    CellValue::String("min\t4\nmax\t8\navg\t6\ncount\t2".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dump_renders_a_table() {
+        let messages = ["set A1 5", "set B2 1 / 0", "set A2 \"hi\"", "dump B2_A1"];
+        let replies = run_session(&messages);
+
+        // Either pair of corners gives the same table, errors shown as #ERR
+        assert!(matches!(
+            &replies[0],
+            Reply::Value(name, CellValue::String(table))
+                if name == "A1_B2" && table == "  | A  | B\n1 | 5\n2 | hi | #ERR"
+        ));
+    }
+}
@@ -1,8 +1,17 @@
 use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
-use rsheet::start_server;
-use rsheet_lib::connect::{resolve_address, ConnectionManager, TerminalManager};
+use rsheet::{
+    start_server_with_options, ConnectionErrorHook, ServerOptions, TcpManager,
+    DEFAULT_CONNECTION_QUEUE, DEFAULT_CONNECTION_THREADS, DEFAULT_DUMP_WIDTH, DEFAULT_FIND_LIMIT,
+    DEFAULT_MAX_EXPRESSION_LENGTH, DEFAULT_MAX_RANGE_CELLS,
+};
+use rsheet_lib::connect::{resolve_address, TerminalManager};
+use signal_hook::consts::SIGTERM;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -12,6 +21,46 @@ struct Args {
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// Snapshot file to load on start and save on shutdown
+    #[arg(short, long)]
+    snapshot: Option<PathBuf>,
+
+    /// Closes connections that send nothing for this many seconds
+    #[arg(short, long)]
+    idle_timeout: Option<u64>,
+
+    /// Most matches find and findexpr list before summarising the rest
+    #[arg(long, default_value_t = DEFAULT_FIND_LIMIT)]
+    find_limit: usize,
+
+    /// Characters dump shows of a value before cutting it short
+    #[arg(long, default_value_t = DEFAULT_DUMP_WIDTH)]
+    dump_width: usize,
+
+    /// Sets each connection may make per second; further sets are rejected
+    #[arg(long)]
+    set_rate_limit: Option<u32>,
+
+    /// Connections served at once
+    #[arg(long, default_value_t = DEFAULT_CONNECTION_THREADS)]
+    connection_threads: usize,
+
+    /// Accepted connections that may wait for a free thread
+    #[arg(long, default_value_t = DEFAULT_CONNECTION_QUEUE)]
+    connection_queue: usize,
+
+    /// Most cells one range in a set may cover; 0 means no limit
+    #[arg(long, default_value_t = DEFAULT_MAX_RANGE_CELLS)]
+    max_range_cells: usize,
+
+    /// Longest expression a set accepts, in characters; 0 means no limit
+    #[arg(long, default_value_t = DEFAULT_MAX_EXPRESSION_LENGTH)]
+    max_expression_length: usize,
+
+    /// Directory the export command writes into; export is disabled without it
+    #[arg(long)]
+    export_dir: Option<PathBuf>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -19,12 +68,33 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let args = Args::parse();
 
+    // SIGTERM stops new connections and lets the open ones drain
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, Arc::clone(&shutdown))?;
+
+    let options = ServerOptions {
+        snapshot_path: args.snapshot,
+        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+        find_limit: args.find_limit,
+        dump_width: args.dump_width,
+        set_rate_limit: args.set_rate_limit,
+        shutdown,
+        on_connection_error: Some(ConnectionErrorHook(Arc::new(|connection, e| {
+            eprintln!("Connection {} error: {:?}", connection, e);
+        }))),
+        connection_threads: args.connection_threads,
+        connection_queue: args.connection_queue,
+        max_range_cells: Some(args.max_range_cells).filter(|&cells| cells > 0),
+        max_expression_length: Some(args.max_expression_length).filter(|&length| length > 0),
+        export_dir: args.export_dir,
+    };
+
     if let Some(addr) = args.addr {
         let addr = resolve_address(&addr)?;
-        let manager = ConnectionManager::launch(addr.ip(), addr.port());
-        start_server(manager)
+        let manager = TcpManager::bind(addr)?;
+        start_server_with_options(manager, options)
     } else {
         let manager = TerminalManager::launch(args.mark_mode);
-        start_server(manager)
+        start_server_with_options(manager, options)
     }
 }
@@ -0,0 +1,1118 @@
+use rsheet_lib::cells::column_name_to_number;
+use rsheet_lib::command::CellIdentifier;
+
+// Words the expression language reserves, which are never variables
+const KEYWORDS: &[&str] = &["true", "false", "if", "else", "switch", "in", "this"];
+
+// Longest column name whose index fits a u32, "ZZZZZZ" being index 321272405
+const MAX_COLUMN_LETTERS: usize = 6;
+
+// Prefix turning whole rows such as "1_3" into variables, see strip_anchors
+const ROWS_PREFIX: &str = "rows_";
+
+/**
+ * A reference to one or more cells, as written in an expression
+ *
+ * Accepted grammar, where CELL is an A1 name such as "B2" and COL is a
+ * column name such as "C":
+ * - CELL: a single cell
+ * - CELL_CELL: the rectangle between two corners, e.g. "A1_B3"
+ * - CELL_COL: the columns from CELL to COL, from CELL's row down to the
+ *   last populated row of those columns, e.g. "A2_A"
+ * - COL_COL: whole columns, down to their last populated row, e.g. "A_A"
+ * - ROW_ROW: whole rows, where ROW is a 1-based row number, across to their
+ *   last populated column, e.g. "1_1"; as the expression language would
+ *   read "1_1" as the number 11, it is evaluated as "rows_1_1", see
+ *   strip_anchors
+ *
+ * Any column or row may be anchored with a "$" prefix, e.g. "$A$1",
+ * "A$1_$B" or "$1_$3", which fixes it when the expression is copied, see
+ * Anchors
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reference {
+    Cell(CellIdentifier),
+    Range(CellIdentifier, CellIdentifier),
+    ColumnsFrom(CellIdentifier, u32),
+    Columns(u32, u32),
+    Rows(u32, u32),
+}
+
+/**
+ * Which parts of a reference are anchored with a "$" prefix, so a copy
+ * leaves them where they are; for a single cell only the start is used
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Anchors {
+    pub start_col: bool, // "$A1"
+    pub start_row: bool, // "A$1"
+    pub end_col: bool,   // "A1_$B2" or "A1_$B"
+    pub end_row: bool,   // "A1_B$2"
+}
+
+/**
+ * A whole row or column of the sheet, by its 0-based index, see
+ * Reference::after_insert and Reference::after_delete
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Line {
+    Row(u32),
+    Col(u32),
+}
+
+impl Line {
+    fn index(self) -> u32 {
+        match self {
+            Line::Row(index) | Line::Col(index) => index,
+        }
+    }
+
+    // A cell's row for a row, or its column for a column
+    fn position(self, cell_id: CellIdentifier) -> u32 {
+        match self {
+            Line::Row(_) => cell_id.row,
+            Line::Col(_) => cell_id.col,
+        }
+    }
+
+    fn with_position(self, cell_id: CellIdentifier, position: u32) -> CellIdentifier {
+        match self {
+            Line::Row(_) => CellIdentifier {
+                row: position,
+                ..cell_id
+            },
+            Line::Col(_) => CellIdentifier {
+                col: position,
+                ..cell_id
+            },
+        }
+    }
+}
+
+impl Reference {
+    /**
+     * HELPER FUNCTION
+     * Checks whether a cell falls inside the reference
+     */
+    pub fn contains(&self, cell_id: &CellIdentifier) -> bool {
+        match *self {
+            Reference::Cell(id) => id == *cell_id,
+            Reference::Range(start, end) => {
+                (start.col..=end.col).contains(&cell_id.col)
+                    && (start.row..=end.row).contains(&cell_id.row)
+            }
+            Reference::ColumnsFrom(start, end_col) => {
+                (start.col..=end_col).contains(&cell_id.col) && cell_id.row >= start.row
+            }
+            Reference::Columns(start_col, end_col) => (start_col..=end_col).contains(&cell_id.col),
+            Reference::Rows(start_row, end_row) => (start_row..=end_row).contains(&cell_id.row),
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Formats the reference as it is written in an expression, so a range
+     * stays a single name such as "A1_Z1000" however many cells it covers
+     */
+    pub fn name(&self) -> String {
+        self.anchored_name(Anchors::default())
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Formats the reference like name, marking the anchored parts with "$"
+     */
+    pub fn anchored_name(&self, anchors: Anchors) -> String {
+        let dollar = |anchored: bool| if anchored { "$" } else { "" };
+        let cell = |id: &CellIdentifier, col: bool, row: bool| {
+            format!(
+                "{}{}{}{}",
+                dollar(col),
+                column_name(id.col),
+                dollar(row),
+                row_name(id.row)
+            )
+        };
+        let start = |id: &CellIdentifier| cell(id, anchors.start_col, anchors.start_row);
+
+        match self {
+            Reference::Cell(id) => start(id),
+            Reference::Range(from, to) => format!(
+                "{}_{}",
+                start(from),
+                cell(to, anchors.end_col, anchors.end_row)
+            ),
+            Reference::ColumnsFrom(from, end_col) => format!(
+                "{}_{}{}",
+                start(from),
+                dollar(anchors.end_col),
+                column_name(*end_col)
+            ),
+            Reference::Columns(start_col, end_col) => format!(
+                "{}{}_{}{}",
+                dollar(anchors.start_col),
+                column_name(*start_col),
+                dollar(anchors.end_col),
+                column_name(*end_col)
+            ),
+            Reference::Rows(start_row, end_row) => format!(
+                "{}{}_{}{}",
+                dollar(anchors.start_row),
+                row_name(*start_row),
+                dollar(anchors.end_row),
+                row_name(*end_row)
+            ),
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Lists every cell covered by a bounded reference, row by row
+     * Open ranges only list their first row, and whole rows their first
+     * column, so bound them first
+     */
+    pub fn cells(&self) -> Vec<CellIdentifier> {
+        let (start, end) = self.corners();
+        (start.row..=end.row)
+            .flat_map(|row| (start.col..=end.col).map(move |col| CellIdentifier { col, row }))
+            .collect()
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Counts the cells a bounded reference covers without listing them, so
+     * "A1_ZZ99999" can be measured before anything is built from it
+     */
+    pub fn cell_count(&self) -> u64 {
+        let (start, end) = self.corners();
+        u64::from(end.row - start.row + 1) * u64::from(end.col - start.col + 1)
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Returns the top-left and bottom-right cells listed by cells
+     */
+    pub fn corners(&self) -> (CellIdentifier, CellIdentifier) {
+        match *self {
+            Reference::Cell(id) => (id, id),
+            Reference::Range(start, end) => (start, end),
+            Reference::ColumnsFrom(start, end_col) => (
+                start,
+                CellIdentifier {
+                    col: end_col,
+                    row: start.row,
+                },
+            ),
+            Reference::Columns(start_col, end_col) => (
+                CellIdentifier {
+                    col: start_col,
+                    row: 0,
+                },
+                CellIdentifier {
+                    col: end_col,
+                    row: 0,
+                },
+            ),
+            Reference::Rows(start_row, end_row) => (
+                CellIdentifier {
+                    col: 0,
+                    row: start_row,
+                },
+                CellIdentifier {
+                    col: 0,
+                    row: end_row,
+                },
+            ),
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Adjusts the reference for a line inserted before the given one
+     * Every cell at or past the line moves one further along, so a range
+     * grows when the line lands inside it and moves when it lands before it
+     */
+    pub fn after_insert(&self, line: Line) -> Reference {
+        let shift = |position: u32| {
+            if position >= line.index() {
+                position.saturating_add(1)
+            } else {
+                position
+            }
+        };
+        let cell = |id: CellIdentifier| line.with_position(id, shift(line.position(id)));
+
+        match *self {
+            Reference::Cell(id) => Reference::Cell(cell(id)),
+            Reference::Range(start, end) => Reference::Range(cell(start), cell(end)),
+            Reference::ColumnsFrom(start, end_col) => match line {
+                Line::Row(_) => Reference::ColumnsFrom(cell(start), end_col),
+                Line::Col(_) => Reference::ColumnsFrom(cell(start), shift(end_col)),
+            },
+            // Whole columns and rows stay whole, only moving along their own axis
+            Reference::Columns(start_col, end_col) => match line {
+                Line::Row(_) => *self,
+                Line::Col(_) => Reference::Columns(shift(start_col), shift(end_col)),
+            },
+            Reference::Rows(start_row, end_row) => match line {
+                Line::Row(_) => Reference::Rows(shift(start_row), shift(end_row)),
+                Line::Col(_) => *self,
+            },
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Adjusts the reference for the given line being deleted
+     *
+     * Procedure:
+     * 1. Moves every cell past the line back by one
+     * 2. Shrinks a range that spans the line by one
+     * 3. Returns None when everything the reference covers was on the line
+     */
+    pub fn after_delete(&self, line: Line) -> Option<Reference> {
+        let at = line.index();
+        let span = |low: u32, high: u32| -> Option<(u32, u32)> {
+            if low == at && high == at {
+                return None;
+            }
+            let low = if low > at { low - 1 } else { low };
+            let high = if high >= at { high - 1 } else { high };
+            Some((low, high))
+        };
+
+        Some(match *self {
+            Reference::Cell(id) => {
+                let (position, _) = span(line.position(id), line.position(id))?;
+                Reference::Cell(line.with_position(id, position))
+            }
+            Reference::Range(start, end) => {
+                let (a, b) = (line.position(start), line.position(end));
+                let (low, high) = span(a.min(b), a.max(b))?;
+                let (a, b) = if a <= b { (low, high) } else { (high, low) };
+                Reference::Range(line.with_position(start, a), line.with_position(end, b))
+            }
+            Reference::ColumnsFrom(start, end_col) => match line {
+                Line::Row(_) => {
+                    let (row, _) = span(start.row, u32::MAX)?;
+                    Reference::ColumnsFrom(CellIdentifier { row, ..start }, end_col)
+                }
+                Line::Col(_) => {
+                    let (col, end_col) = span(start.col, end_col)?;
+                    Reference::ColumnsFrom(CellIdentifier { col, ..start }, end_col)
+                }
+            },
+            Reference::Columns(start_col, end_col) => match line {
+                Line::Row(_) => *self,
+                Line::Col(_) => {
+                    let (start_col, end_col) = span(start_col, end_col)?;
+                    Reference::Columns(start_col, end_col)
+                }
+            },
+            Reference::Rows(start_row, end_row) => match line {
+                Line::Row(_) => {
+                    let (start_row, end_row) = span(start_row, end_row)?;
+                    Reference::Rows(start_row, end_row)
+                }
+                Line::Col(_) => *self,
+            },
+        })
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Moves the reference by a number of columns and rows, leaving anchored
+     * parts where they are, and returning None if any part of it would move
+     * off the sheet
+     */
+    pub fn offset(&self, cols: i64, rows: i64, anchors: Anchors) -> Option<Reference> {
+        let shift = |value: u32, by: i64, anchored: bool| {
+            if anchored {
+                Some(value)
+            } else {
+                u32::try_from(i64::from(value) + by).ok()
+            }
+        };
+        let cell = |id: CellIdentifier, col: bool, row: bool| {
+            Some(CellIdentifier {
+                col: shift(id.col, cols, col)?,
+                row: shift(id.row, rows, row)?,
+            })
+        };
+        let start = |id: CellIdentifier| cell(id, anchors.start_col, anchors.start_row);
+
+        Some(match *self {
+            Reference::Cell(id) => Reference::Cell(start(id)?),
+            Reference::Range(from, to) => {
+                Reference::Range(start(from)?, cell(to, anchors.end_col, anchors.end_row)?)
+            }
+            Reference::ColumnsFrom(from, end_col) => {
+                Reference::ColumnsFrom(start(from)?, shift(end_col, cols, anchors.end_col)?)
+            }
+            Reference::Columns(start_col, end_col) => Reference::Columns(
+                shift(start_col, cols, anchors.start_col)?,
+                shift(end_col, cols, anchors.end_col)?,
+            ),
+            Reference::Rows(start_row, end_row) => Reference::Rows(
+                shift(start_row, rows, anchors.start_row)?,
+                shift(end_row, rows, anchors.end_row)?,
+            ),
+        })
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses a variable name into a Reference
+ *
+ * Procedure:
+ * 1. Names without an underscore must be a single A1 cell
+ * 2. Otherwise splits on the only underscore and parses the start as a cell
+ * 3. The end is either a cell (closed range) or a bare column name (open
+ *    range), which must not be left of the start column
+ * 4. A bare column name or row number on both sides gives whole columns or
+ *    rows, the second not before the first
+ * 5. Returns None for anything else, including column names too long to
+ *    number, see parse_cell
+ *
+ * A closed range may give its corners in any order, e.g. "B3_A1", and is
+ * returned with the top-left corner first, so "B3_A1" and "A1_B3" cover
+ * the same cells
+ */
+pub fn parse_reference(name: &str) -> Option<Reference> {
+    parse_anchored_reference(name).map(|(reference, _)| reference)
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses a variable name into a Reference like parse_reference, also
+ * returning which of its parts are anchored with "$"
+ */
+pub fn parse_anchored_reference(name: &str) -> Option<(Reference, Anchors)> {
+    // Whole rows are evaluated as "rows_1_3", see strip_anchors
+    let name = match name.strip_prefix(ROWS_PREFIX) {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => rest,
+        _ => name,
+    };
+    let mut anchors = Anchors::default();
+    let (start, end) = match name.split_once('_') {
+        Some((start, end)) => (start, Some(end)),
+        None => (name, None),
+    };
+    let start = strip_anchors_from(start, &mut anchors.start_col, &mut anchors.start_row)?;
+    let plain = match end {
+        Some(end) => format!(
+            "{}_{}",
+            start,
+            strip_anchors_from(end, &mut anchors.end_col, &mut anchors.end_row)?
+        ),
+        None => start,
+    };
+    let reference = parse_plain_reference(&plain)?;
+
+    // A bare row's "$" comes before its digits, where a column's anchor goes
+    if let Reference::Rows(..) = reference {
+        anchors = Anchors {
+            start_row: anchors.start_col,
+            end_row: anchors.end_col,
+            ..Anchors::default()
+        };
+    }
+
+    // Corners given in any order become top-left and bottom-right, taking
+    // their anchors with them
+    if let Reference::Range(mut start, mut end) = reference {
+        if start.col > end.col {
+            std::mem::swap(&mut start.col, &mut end.col);
+            std::mem::swap(&mut anchors.start_col, &mut anchors.end_col);
+        }
+        if start.row > end.row {
+            std::mem::swap(&mut start.row, &mut end.row);
+            std::mem::swap(&mut anchors.start_row, &mut anchors.end_row);
+        }
+        return Some((Reference::Range(start, end), anchors));
+    }
+    Some((reference, anchors))
+}
+
+/**
+ * HELPER FUNCTION
+ * Removes the "$" anchors from one side of a reference, recording which
+ * were present, or returns None if a "$" is anywhere but directly before
+ * the column letters or the row digits
+ */
+fn strip_anchors_from(part: &str, col: &mut bool, row: &mut bool) -> Option<String> {
+    let rest = match part.strip_prefix('$') {
+        Some(rest) => {
+            *col = true;
+            rest
+        }
+        None => part,
+    };
+    let letters = rest.len()
+        - rest
+            .trim_start_matches(|c: char| c.is_ascii_uppercase())
+            .len();
+    let (column, rest) = rest.split_at(letters);
+    let digits = match rest.strip_prefix('$') {
+        Some(digits) if !digits.is_empty() => {
+            *row = true;
+            digits
+        }
+        Some(_) => return None,
+        None => rest,
+    };
+
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{column}{digits}"))
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses a variable name without anchors into a Reference, see parse_reference
+ */
+fn parse_plain_reference(name: &str) -> Option<Reference> {
+    let Some((start, end)) = name.split_once('_') else {
+        return parse_cell(name).map(Reference::Cell);
+    };
+
+    if let (Some(start_col), Some(end_col)) = (parse_column(start), parse_column(end)) {
+        return (end_col >= start_col).then_some(Reference::Columns(start_col, end_col));
+    }
+    let row = |part: &str| part.parse::<u32>().ok()?.checked_sub(1);
+    if let (Some(start_row), Some(end_row)) = (row(start), row(end)) {
+        return (end_row >= start_row).then_some(Reference::Rows(start_row, end_row));
+    }
+
+    let start = parse_cell(start)?;
+    if let Some(end) = parse_cell(end) {
+        return Some(Reference::Range(start, end));
+    }
+
+    let end_col = parse_column(end)?;
+    (end_col >= start.col).then_some(Reference::ColumnsFrom(start, end_col))
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses an A1 cell name such as "B2", like CellIdentifier's own parser,
+ * but returns None instead of overflowing for a column name longer than
+ * MAX_COLUMN_LETTERS letters
+ */
+pub fn parse_cell(name: &str) -> Option<CellIdentifier> {
+    let letters = name.bytes().take_while(u8::is_ascii_uppercase).count();
+    if letters > MAX_COLUMN_LETTERS {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses a bare column name such as "C" into its 0-based index, or returns
+ * None if it isn't one or is longer than MAX_COLUMN_LETTERS letters
+ */
+pub fn parse_column(name: &str) -> Option<u32> {
+    ((1..=MAX_COLUMN_LETTERS).contains(&name.len()) && name.bytes().all(|b| b.is_ascii_uppercase()))
+        .then(|| column_name_to_number(name))
+}
+
+/**
+ * HELPER FUNCTION
+ * Finds every distinct variable name in an expression that is a cell reference
+ */
+pub fn variable_names(expr: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    rewrite_identifiers(expr, |token| {
+        if parse_reference(token).is_some() && !names.iter().any(|name| name == token) {
+            names.push(token.to_string());
+        }
+        None
+    });
+    names
+}
+
+/**
+ * HELPER FUNCTION
+ * Finds every distinct identifier-like token in an expression, outside
+ * string literals, whether or not it is a cell reference
+ */
+pub fn identifiers(expr: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    rewrite_identifiers(expr, |token| {
+        if !tokens.iter().any(|seen| seen == token) {
+            tokens.push(token.to_string());
+        }
+        None
+    });
+    tokens
+}
+
+/**
+ * HELPER FUNCTION
+ * Checks whether a word can name a cell or range, see Spreadsheet::define_name
+ * It must start with a letter, hold only letters, digits and underscores,
+ * and be neither a reference nor a keyword
+ */
+pub fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+        && parse_reference(name).is_none()
+}
+
+/**
+ * HELPER FUNCTION
+ * Finds every variable in an expression that is not a cell reference, such
+ * as "Q" or the malformed range "A1_" in "A1_ + Q"
+ *
+ * Procedure:
+ * 1. Visits every identifier token outside string literals
+ * 2. Skips numbers, keywords, function names (followed by "(") and
+ *    properties or methods (preceded by ".")
+ * 3. Returns each remaining distinct token that doesn't parse as a reference
+ */
+pub fn invalid_variables(expr: &str) -> Vec<String> {
+    let mut invalid: Vec<String> = Vec::new();
+    scan_identifiers(expr, |start, token| {
+        let before = expr[..start].trim_end().chars().next_back();
+        let after = expr[start + token.len()..].trim_start().chars().next();
+        let skipped = token.starts_with(|c: char| c.is_ascii_digit())
+            || KEYWORDS.contains(&token)
+            || after == Some('(')
+            || before == Some('.');
+
+        if !skipped && parse_reference(token).is_none() && !invalid.iter().any(|t| t == token) {
+            invalid.push(token.to_string());
+        }
+        None
+    });
+    invalid
+}
+
+/**
+ * HELPER FUNCTION
+ * Finds the variable names passed, at any depth, to a call of one of the
+ * given functions, e.g. "A1_A3" in "sum(A1_A3) + B1" for ["sum"]
+ *
+ * Procedure:
+ * 1. Scans the expression, skipping string and character literals
+ * 2. Tracks open parentheses, marking those that open a call to one of the
+ *    functions
+ * 3. Collects each distinct identifier seen while any marked call is open
+ */
+pub fn call_arguments(expr: &str, functions: &[&str]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut open_calls: Vec<bool> = Vec::new();
+    let mut last_identifier = String::new();
+    let mut chars = expr.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '"' || c == '\'' || c == '`' {
+            let mut escaped = false;
+            for next in chars.by_ref() {
+                if escaped {
+                    escaped = false;
+                } else if next == '\\' {
+                    escaped = true;
+                } else if next == c {
+                    break;
+                }
+            }
+            last_identifier.clear();
+        } else if is_identifier_char(c) {
+            let mut token = c.to_string();
+            while let Some(&next) = chars.peek() {
+                if !is_identifier_char(next) {
+                    break;
+                }
+                token.push(next);
+                chars.next();
+            }
+
+            if open_calls.contains(&true) && !names.contains(&token) {
+                names.push(token.clone());
+            }
+            last_identifier = token;
+        } else if c.is_whitespace() {
+            // Whitespace may separate a function name from its parenthesis
+        } else {
+            match c {
+                '(' => open_calls.push(functions.contains(&last_identifier.as_str())),
+                ')' => {
+                    open_calls.pop();
+                }
+                _ => {}
+            }
+            last_identifier.clear();
+        }
+    }
+
+    names
+}
+
+/**
+ * HELPER FUNCTION
+ * Rewrites every cell reference in an expression
+ *
+ * Procedure:
+ * 1. Visits every identifier token that parses as a reference
+ * 2. Replaces it with the name of the reference the callback returns,
+ *    keeping the original's anchors
+ * 3. Replaces it with ref_error("NAME") when the callback returns None, so
+ *    the expression evaluates to a reference error naming the old reference
+ */
+pub fn map_references(
+    expr: &str,
+    mut map: impl FnMut(Reference, Anchors) -> Option<Reference>,
+) -> String {
+    rewrite_identifiers(expr, |token| {
+        let (reference, anchors) = parse_anchored_reference(token)?;
+        Some(match map(reference, anchors) {
+            Some(mapped) => mapped.anchored_name(anchors),
+            None => format!("ref_error(\"{token}\")"),
+        })
+    })
+}
+
+/**
+ * HELPER FUNCTION
+ * Removes the "$" anchors from every reference in an expression, giving
+ * the plain names the expression is evaluated with
+ * Whole rows such as "1_3" are also given a "rows_" prefix, as the
+ * expression language would otherwise read them as the number 13
+ */
+pub fn strip_anchors(expr: &str) -> String {
+    if !expr.contains('$') && !expr.contains('_') {
+        return expr.to_string();
+    }
+    rewrite_identifiers(expr, |token| {
+        let (reference, _) = parse_anchored_reference(token)?;
+        match reference {
+            Reference::Rows(..) => Some(format!("{}{}", ROWS_PREFIX, reference.name())),
+            _ => token.contains('$').then(|| reference.name()),
+        }
+    })
+}
+
+/**
+ * HELPER FUNCTION
+ * Rewrites every identifier-like token of an expression, see scan_identifiers
+ */
+pub fn rewrite_identifiers(expr: &str, mut rewrite: impl FnMut(&str) -> Option<String>) -> String {
+    scan_identifiers(expr, |_, token| rewrite(token))
+}
+
+/**
+ * HELPER FUNCTION
+ * Rewrites every identifier-like token of an expression, telling the
+ * callback where each token starts
+ *
+ * Procedure:
+ * 1. Scans the expression, copying string and character literals untouched
+ * 2. Splits the remaining text into runs of ASCII letters, digits and underscores
+ * 3. Replaces each run with whatever the callback returns, or keeps it when
+ *    the callback returns None
+ * 4. Returns the rewritten expression
+ */
+fn scan_identifiers(expr: &str, mut rewrite: impl FnMut(usize, &str) -> Option<String>) -> String {
+    let mut output = String::with_capacity(expr.len());
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c == '"' || c == '\'' || c == '`' {
+            // Copy literals verbatim, honouring backslash escapes
+            output.push(c);
+            let mut escaped = false;
+            for (_, next) in chars.by_ref() {
+                output.push(next);
+                if escaped {
+                    escaped = false;
+                } else if next == '\\' {
+                    escaped = true;
+                } else if next == c {
+                    break;
+                }
+            }
+        } else if is_identifier_char(c) {
+            let mut end = start + c.len_utf8();
+            while let Some(&(index, next)) = chars.peek() {
+                if !is_identifier_char(next) {
+                    break;
+                }
+                end = index + next.len_utf8();
+                chars.next();
+            }
+
+            let token = &expr[start..end];
+            match rewrite(start, token) {
+                Some(replacement) => output.push_str(&replacement),
+                None => output.push_str(token),
+            }
+        } else {
+            output.push(c);
+        }
+    }
+
+    output
+}
+
+// "$" is included so an anchored reference such as "$A$1" is one token
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$'
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses an R1C1-style name such as "R2C3" (row 2, column 3, both 1-based)
+ */
+pub fn parse_r1c1(name: &str) -> Option<CellIdentifier> {
+    let rest = name.strip_prefix('R')?;
+    let (row, col) = rest.split_once('C')?;
+
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(row) || !all_digits(col) {
+        return None;
+    }
+
+    Some(CellIdentifier {
+        col: col.parse::<u32>().ok()?.checked_sub(1)?,
+        row: row.parse::<u32>().ok()?.checked_sub(1)?,
+    })
+}
+
+/**
+ * HELPER FUNCTION
+ * Formats a cell in A1 notation, e.g. (col 2, row 1) becomes "C2"
+ * Every cell name a reply, log line or rewritten expression shows is
+ * built here, so no index is ever off by one or overflows
+ */
+pub fn a1_name(cell_id: &CellIdentifier) -> String {
+    format!("{}{}", column_name(cell_id.col), row_name(cell_id.row))
+}
+
+/**
+ * HELPER FUNCTION
+ * Names a 0-based column, e.g. 27 becomes "AB", as rsheet_lib's
+ * column_number_to_name does, but counting in u64 so the last column a u32
+ * holds can't overflow
+ */
+pub fn column_name(col: u32) -> String {
+    let mut letters: Vec<u8> = Vec::new();
+    let mut remaining = u64::from(col) + 1;
+    while remaining > 0 {
+        remaining -= 1;
+        letters.push(b'A' + (remaining % 26) as u8);
+        remaining /= 26;
+    }
+    letters
+        .iter()
+        .rev()
+        .map(|letter| char::from(*letter))
+        .collect()
+}
+
+/**
+ * HELPER FUNCTION
+ * Numbers a 0-based row from 1, e.g. 0 becomes "1", counting in u64 so the
+ * last row a u32 holds can't overflow
+ */
+pub fn row_name(row: u32) -> String {
+    (u64::from(row) + 1).to_string()
+}
+
+/**
+ * HELPER FUNCTION
+ * Translates R1C1 references in an expression into A1 references
+ *
+ * Procedure:
+ * 1. Visits every identifier token in the expression
+ * 2. Converts single references ("R1C2") and ranges ("R1C1_R3C1")
+ * 3. Leaves every other token unchanged
+ */
+pub fn r1c1_to_a1(expr: &str) -> String {
+    rewrite_identifiers(expr, |token| {
+        if let Some(cell_id) = parse_r1c1(token) {
+            return Some(a1_name(&cell_id));
+        }
+
+        let (start, end) = token.split_once('_')?;
+        let (start, end) = (parse_r1c1(start)?, parse_r1c1(end)?);
+        Some(format!("{}_{}", a1_name(&start), a1_name(&end)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_r1c1() {
+        assert_eq!(parse_r1c1("R1C1"), Some(CellIdentifier { col: 0, row: 0 }));
+        assert_eq!(
+            parse_r1c1("R10C27"),
+            Some(CellIdentifier { col: 26, row: 9 })
+        );
+        assert_eq!(parse_r1c1("R0C1"), None);
+        assert_eq!(parse_r1c1("RC1"), None);
+        assert_eq!(parse_r1c1("R1C"), None);
+        assert_eq!(parse_r1c1("A1"), None);
+    }
+
+    #[test]
+    fn test_r1c1_to_a1() {
+        assert_eq!(r1c1_to_a1("R1C1 + R2C3 * 2"), "A1 + C2 * 2");
+        assert_eq!(r1c1_to_a1("sum(R1C1_R3C2)"), "sum(A1_B3)");
+        assert_eq!(r1c1_to_a1("A1 + R1C28"), "A1 + AB1");
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        assert_eq!(parse_reference("B2"), Some(Reference::Cell(cell("B2"))));
+        assert_eq!(
+            parse_reference("A1_B3"),
+            Some(Reference::Range(cell("A1"), cell("B3")))
+        );
+        assert_eq!(
+            parse_reference("A2_C"),
+            Some(Reference::ColumnsFrom(cell("A2"), 2))
+        );
+
+        // Closed ranges take their corners in any order; open ranges can't
+        // end left of where they start
+        assert_eq!(
+            parse_reference("B3_A1"),
+            Some(Reference::Range(cell("A1"), cell("B3")))
+        );
+        assert_eq!(
+            parse_reference("A3_B1"),
+            Some(Reference::Range(cell("A1"), cell("B3")))
+        );
+        assert_eq!(
+            parse_anchored_reference("$B3_A$1"),
+            Some((
+                Reference::Range(cell("A1"), cell("B3")),
+                Anchors {
+                    start_row: true,
+                    end_col: true,
+                    ..Anchors::default()
+                }
+            ))
+        );
+        assert_eq!(parse_reference("C1_A"), None);
+        assert_eq!(parse_reference("A1_"), None);
+        assert_eq!(parse_reference("A1_B2_C3"), None);
+        assert_eq!(parse_reference("A1_b"), None);
+
+        // Column names too long to number are rejected, not overflowed
+        assert_eq!(parse_cell("ZZZZZZ1"), Some(cell("ZZZZZZ1")));
+        assert_eq!(parse_cell("ZZZZZZZ1"), None);
+        assert_eq!(parse_reference("A1_ZZZZZZZZ1"), None);
+        assert_eq!(parse_reference("A_ZZZZZZZZ"), None);
+    }
+
+    #[test]
+    fn test_whole_columns_and_rows() {
+        assert_eq!(parse_reference("A_A"), Some(Reference::Columns(0, 0)));
+        assert_eq!(parse_reference("B_AA"), Some(Reference::Columns(1, 26)));
+        assert_eq!(parse_reference("1_1"), Some(Reference::Rows(0, 0)));
+        assert_eq!(parse_reference("rows_2_10"), Some(Reference::Rows(1, 9)));
+        assert_eq!(parse_reference("C_A"), None);
+        assert_eq!(parse_reference("3_1"), None);
+        assert_eq!(parse_reference("0_1"), None);
+        assert_eq!(parse_reference("A_1"), None);
+        assert_eq!(
+            parse_anchored_reference("$1_3"),
+            Some((
+                Reference::Rows(0, 2),
+                Anchors {
+                    start_row: true,
+                    ..Anchors::default()
+                }
+            ))
+        );
+
+        // Names round-trip, and whole rows are evaluated with an underscore
+        assert_eq!(Reference::Columns(0, 2).name(), "A_C");
+        assert_eq!(Reference::Rows(0, 2).name(), "1_3");
+        assert_eq!(
+            strip_anchors("sum(1_3) + sum(A_C)"),
+            "sum(rows_1_3) + sum(A_C)"
+        );
+
+        // They stay whole as lines come and go across them
+        assert_eq!(
+            Reference::Columns(1, 2).after_insert(Line::Row(0)),
+            Reference::Columns(1, 2)
+        );
+        assert_eq!(
+            Reference::Columns(1, 2).after_insert(Line::Col(0)),
+            Reference::Columns(2, 3)
+        );
+        assert_eq!(
+            Reference::Rows(1, 2).after_delete(Line::Row(1)),
+            Some(Reference::Rows(1, 1))
+        );
+        assert_eq!(Reference::Rows(1, 1).after_delete(Line::Row(1)), None);
+    }
+
+    #[test]
+    fn test_a1_name_round_trips() {
+        for (col, name) in [
+            (0, "A"),
+            (25, "Z"),
+            (26, "AA"),
+            (51, "AZ"),
+            (52, "BA"),
+            (701, "ZZ"),
+            (702, "AAA"),
+        ] {
+            let cell_id = CellIdentifier { col, row: 9 };
+            assert_eq!(a1_name(&cell_id), format!("{name}10"));
+            assert_eq!(a1_name(&cell_id).parse::<CellIdentifier>(), Ok(cell_id));
+            assert_eq!(
+                column_name(col),
+                rsheet_lib::cells::column_number_to_name(col)
+            );
+        }
+
+        // The first row, the last row a sheet holds by default and the last
+        // cell a u32 index reaches
+        let cell = |col, row| a1_name(&CellIdentifier { col, row });
+        assert_eq!(cell(0, 0), "A1");
+        assert_eq!(cell(16_383, 1_048_575), "XFD1048576");
+        assert_eq!(cell(u32::MAX, u32::MAX), "MWLQKWV4294967296");
+        assert_eq!(Reference::Rows(0, u32::MAX).name(), "1_4294967296");
+    }
+
+    #[test]
+    fn test_variable_names() {
+        assert_eq!(
+            variable_names("sum(A1_A) + A1 * B2 + A1 + sleep_then(5, C3_D4)"),
+            vec!["A1_A", "A1", "B2", "C3_D4"]
+        );
+        assert!(variable_names(r#""A1" + x"#).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_variables() {
+        assert_eq!(invalid_variables("A1_ + Q * A1"), vec!["A1_", "Q"]);
+        assert_eq!(invalid_variables("x + x.len + 1_000 + 2e5"), vec!["x"]);
+        assert!(invalid_variables(r#"sum(A1_B) + "Q" + if true { 1.5 } else { B2 }"#).is_empty());
+        assert!(invalid_variables("A1_CV100[50][50] + sleep_then(5, C3_D4)").is_empty());
+    }
+
+    #[test]
+    fn test_call_arguments() {
+        assert_eq!(
+            call_arguments("sum(A1_A3) + B1 * sum (C1_C2, D1)", &["sum"]),
+            vec!["A1_A3", "C1_C2", "D1"]
+        );
+        assert_eq!(
+            call_arguments("sleep_then(5, sum(A1_B2))", &["sum"]),
+            vec!["A1_B2"]
+        );
+        assert!(call_arguments(r#"sum2(A1_A3) + "sum(B1_B2)""#, &["sum"]).is_empty());
+    }
+
+    #[test]
+    fn test_map_references_offsets() {
+        let shift = |expr: &str, cols: i64, rows: i64| {
+            map_references(expr, |reference, anchors| {
+                reference.offset(cols, rows, anchors)
+            })
+        };
+
+        assert_eq!(shift("A1 + 1", 0, 1), "A2 + 1");
+        assert_eq!(shift("sum(A1_B5) * C2", 1, 2), "sum(B3_C7) * D4");
+        assert_eq!(shift("sum(A2_B)", 2, 0), "sum(C2_D)");
+        assert_eq!(shift("sum(B1_B3) + B2", -1, 0), "sum(A1_A3) + A2");
+        assert_eq!(shift("A1 + B1", -1, 0), "ref_error(\"A1\") + A1");
+        assert_eq!(shift("\"A1\" + Q1", 0, 1), "\"A1\" + Q2");
+
+        // Anchored parts stay put
+        assert_eq!(
+            shift("$A$1 + A$1 + $A1 + A1", 2, 3),
+            "$A$1 + C$1 + $A4 + C4"
+        );
+        assert_eq!(
+            shift("sum($A$1_B2) + sum(A1_$B)", 1, 1),
+            "sum($A$1_C3) + sum(B2_$B)"
+        );
+        assert_eq!(shift("$B1", -1, 0), "$B1");
+    }
+
+    #[test]
+    fn test_anchored_references() {
+        assert_eq!(
+            parse_anchored_reference("$A1_B$2"),
+            Some((
+                parse_reference("A1_B2").unwrap(),
+                Anchors {
+                    start_col: true,
+                    end_row: true,
+                    ..Anchors::default()
+                }
+            ))
+        );
+        assert_eq!(parse_reference("$A$1_$B"), parse_reference("A1_B"));
+        assert_eq!(parse_reference("A1$"), None);
+        assert_eq!(parse_reference("$$A1"), None);
+        assert_eq!(parse_reference("A$1$"), None);
+        assert_eq!(parse_reference("A1_B$"), None);
+        assert_eq!(
+            strip_anchors("sum($A$1_B$2) + \"$A$1\""),
+            "sum(A1_B2) + \"$A$1\""
+        );
+    }
+
+    #[test]
+    fn test_references_after_line_changes() {
+        let reference = |name: &str| parse_reference(name).unwrap();
+
+        // Inserting before row 2 grows A1_A3, and moves whatever is below
+        assert_eq!(
+            reference("A1_A3").after_insert(Line::Row(1)),
+            reference("A1_A4")
+        );
+        assert_eq!(reference("A2").after_insert(Line::Row(1)), reference("A3"));
+        assert_eq!(reference("A1").after_insert(Line::Row(1)), reference("A1"));
+        assert_eq!(
+            reference("B2_C").after_insert(Line::Col(0)),
+            reference("C2_D")
+        );
+
+        // Deleting row 2 shrinks A1_A3 and removes A2 outright
+        assert_eq!(
+            reference("A1_A3").after_delete(Line::Row(1)),
+            Some(reference("A1_A2"))
+        );
+        assert_eq!(
+            reference("A2_A3").after_delete(Line::Row(1)),
+            Some(reference("A2_A2"))
+        );
+        assert_eq!(reference("A2").after_delete(Line::Row(1)), None);
+        assert_eq!(reference("A2_B2").after_delete(Line::Row(1)), None);
+        assert_eq!(
+            reference("A3_B").after_delete(Line::Row(1)),
+            Some(reference("A2_B"))
+        );
+        assert_eq!(reference("B1_B").after_delete(Line::Col(1)), None);
+        assert_eq!(
+            reference("A1_C3").after_delete(Line::Col(1)),
+            Some(reference("A1_B3"))
+        );
+    }
+
+    #[test]
+    fn test_rewrite_skips_string_literals() {
+        assert_eq!(
+            r1c1_to_a1(r#"R1C1 + "R1C1 \" R2C2" + R2C2"#),
+            r#"A1 + "R1C1 \" R2C2" + B2"#
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::warn;
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::CellIdentifier;
+use serde::{Deserialize, Serialize};
+
+use crate::references;
+
+/**
+ * A saved spreadsheet, holding each cell's expression rather than its value
+ * so loading can rebuild values and dependencies by setting cells again
+ */
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub cells: Vec<SavedCell>, // Saved cells, sorted by (col, row)
+}
+
+/**
+ * One cell of a snapshot
+ */
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedCell {
+    pub cell: String,       // A1-style name of the cell
+    pub expression: String, // Expression exactly as it was set
+}
+
+/**
+ * One cell of a JSON export, with its value as well as its expression
+ */
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedCell {
+    pub cell: String,         // A1-style name of the cell
+    pub expr: String,         // Expression exactly as it was set
+    pub value: ExportedValue, // Value the expression evaluated to
+}
+
+/**
+ * A cell value tagged by its type, e.g. {"type": "int", "value": 5}, so an
+ * integer, a string, an error and an empty cell can't be mistaken for one
+ * another
+ */
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum ExportedValue {
+    Int(i64),       // An integer, including a boolean stored as 1 or 0
+    String(String), // A string, without quotes
+    Error(String),  // An error, with its message
+    Empty,          // No value
+}
+
+impl From<CellValue> for ExportedValue {
+    fn from(value: CellValue) -> Self {
+        match value {
+            CellValue::Int(number) => ExportedValue::Int(number),
+            CellValue::String(text) => ExportedValue::String(text),
+            CellValue::Error(message) => ExportedValue::Error(message),
+            CellValue::None => ExportedValue::Empty,
+        }
+    }
+}
+
+impl Snapshot {
+    /**
+     * Public Function
+     * Reads a snapshot from a JSON file
+     */
+    pub fn read(path: &Path) -> io::Result<Snapshot> {
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /**
+     * Public Function
+     * Writes the snapshot to a JSON file, replacing it only once the whole
+     * snapshot has been written so a failed save keeps the previous one
+     */
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".tmp");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, path)
+    }
+
+    /**
+     * Public Function
+     * Builds a snapshot from a JSON export, keeping each cell's expression
+     * and dropping its value, which is recomputed once the cells are set
+     */
+    pub fn from_export(json: &str) -> serde_json::Result<Snapshot> {
+        let exported: Vec<ExportedCell> = serde_json::from_str(json)?;
+        Ok(Snapshot {
+            cells: exported
+                .into_iter()
+                .map(|exported| SavedCell {
+                    cell: exported.cell,
+                    expression: exported.expr,
+                })
+                .collect(),
+        })
+    }
+
+    /**
+     * Public Function
+     * Returns the saved cells in an order where each cell comes after the
+     * saved cells it reads from
+     *
+     * Procedure:
+     * 1. Parses every cell name, skipping and logging ones that don't parse;
+     *    a cell saved twice keeps its last expression
+     * 2. Finds, for each cell, the saved cells its references cover
+     * 3. Visits cells depth-first in file order, emitting each after its
+     *    inputs, so forward references are set after what they name
+     * 4. Leaves cells in a cycle in visiting order, since no order satisfies them
+     */
+    pub fn load_order(&self) -> Vec<(CellIdentifier, &str)> {
+        // Step 1: Parse the cell names
+        let mut order: Vec<CellIdentifier> = Vec::new();
+        let mut expressions: HashMap<CellIdentifier, &str> = HashMap::new();
+        for saved in &self.cells {
+            match saved.cell.parse::<CellIdentifier>() {
+                Ok(cell_id) => {
+                    if expressions.insert(cell_id, &saved.expression).is_none() {
+                        order.push(cell_id);
+                    }
+                }
+                Err(_) => warn!("event=load_skipped cell={:?}", saved.cell),
+            }
+        }
+
+        // Step 2: Find the saved inputs of each cell
+        let inputs: HashMap<CellIdentifier, Vec<CellIdentifier>> = order
+            .iter()
+            .map(|cell_id| {
+                let reads: Vec<CellIdentifier> = references::variable_names(expressions[cell_id])
+                    .iter()
+                    .filter_map(|name| references::parse_reference(name))
+                    .flat_map(|reference| {
+                        order
+                            .iter()
+                            .copied()
+                            .filter(move |input| reference.contains(input))
+                    })
+                    .collect();
+                (*cell_id, reads)
+            })
+            .collect();
+
+        // Step 3: Depth-first post-order over the saved cells
+        fn visit(
+            cell_id: CellIdentifier,
+            inputs: &HashMap<CellIdentifier, Vec<CellIdentifier>>,
+            visited: &mut HashSet<CellIdentifier>,
+            sorted: &mut Vec<CellIdentifier>,
+        ) {
+            // Cells already emitted or on the current path are skipped
+            if !visited.insert(cell_id) {
+                return;
+            }
+            for input in &inputs[&cell_id] {
+                visit(*input, inputs, visited, sorted);
+            }
+            sorted.push(cell_id);
+        }
+
+        let mut visited = HashSet::new();
+        let mut sorted = Vec::with_capacity(order.len());
+        for cell_id in &order {
+            visit(*cell_id, &inputs, &mut visited, &mut sorted);
+        }
+
+        sorted
+            .into_iter()
+            .map(|cell_id| (cell_id, expressions[&cell_id]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(cells: &[(&str, &str)]) -> Snapshot {
+        Snapshot {
+            cells: cells
+                .iter()
+                .map(|(cell, expression)| SavedCell {
+                    cell: cell.to_string(),
+                    expression: expression.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn names(order: &[(CellIdentifier, &str)]) -> Vec<String> {
+        order
+            .iter()
+            .map(|(id, _)| references::a1_name(id))
+            .collect()
+    }
+
+    #[test]
+    fn test_exported_values_are_tagged() {
+        let exported =
+            |value: CellValue| serde_json::to_string(&ExportedValue::from(value)).unwrap();
+        assert_eq!(exported(CellValue::Int(5)), r#"{"type":"int","value":5}"#);
+        assert_eq!(
+            exported(CellValue::String("5".into())),
+            r#"{"type":"string","value":"5"}"#
+        );
+        assert_eq!(
+            exported(CellValue::Error("oops".into())),
+            r#"{"type":"error","value":"oops"}"#
+        );
+        assert_eq!(exported(CellValue::None), r#"{"type":"empty"}"#);
+
+        // Importing keeps only the expressions
+        let json = r#"[{"cell":"B1","expr":"A1 + 1","value":{"type":"int","value":2}}]"#;
+        assert_eq!(
+            Snapshot::from_export(json).unwrap(),
+            snapshot(&[("B1", "A1 + 1")])
+        );
+        assert!(Snapshot::from_export(r#"[{"cell":"B1"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_load_order_handles_forward_references() {
+        let saved = snapshot(&[
+            ("C1", "B1 + 1"),
+            ("B1", "sum(A1_A2)"),
+            ("A2", "2"),
+            ("A1", "1"),
+        ]);
+        assert_eq!(names(&saved.load_order()), vec!["A2", "A1", "B1", "C1"]);
+    }
+
+    #[test]
+    fn test_load_order_skips_bad_names_and_survives_cycles() {
+        let saved = snapshot(&[
+            ("A1", "B1"),
+            ("not a cell", "1"),
+            ("B1", "A1"),
+            ("A1", "B1 + 1"),
+        ]);
+        let order = saved.load_order();
+
+        // Each cell once, with the last expression saved for it
+        assert_eq!(names(&order), vec!["B1", "A1"]);
+        assert_eq!(order[1].1, "B1 + 1");
+    }
+}
@@ -1,74 +1,624 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
+use log::{info, warn};
 use rsheet_lib::cell_expr::{CellArgument, CellExpr, CellExprEvalError};
 use rsheet_lib::cell_value::CellValue;
 use rsheet_lib::command::CellIdentifier;
 
+use crate::csv;
+use crate::error::{AggError, SpreadsheetError};
+use crate::evaluator::TimedEvaluator;
+use crate::functions;
+use crate::graph::DependencyGraph;
+use crate::references::{self, Line, Reference};
+use crate::snapshot::{ExportedCell, SavedCell, Snapshot};
+use crate::wal::WriteAheadLog;
+
+// Functions whose range arguments must hold only numbers
+const NUMERIC_FUNCTIONS: &[&str] = &["sum"];
+
+// Error rsheet_lib gives for a result it can't store, such as true or false
+const NOT_A_CELL_VALUE: &str = "Could not cast Rhai return back to Cell Value.";
+
+// Update messages the worker's queue holds before set starts blocking
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+// Earlier expressions kept per cell for undo; older ones are forgotten
+const HISTORY_LIMIT: usize = 32;
+
+// Sets each cell remembers for history, unless configured otherwise
+const DEFAULT_EDIT_LOG_LENGTH: usize = 8;
+
+// Range size limit suited to a shared server, see SpreadsheetOptions::max_range_cells
+pub const DEFAULT_MAX_RANGE_CELLS: usize = 100_000;
+
+// Expression length limit suited to a shared server, see
+// SpreadsheetOptions::max_expression_length
+pub const DEFAULT_MAX_EXPRESSION_LENGTH: usize = 1_024;
+
+// Rows and columns a sheet has unless configured otherwise, ending at XFD1048576
+const DEFAULT_MAX_ROWS: u32 = 1_048_576;
+const DEFAULT_MAX_COLS: u32 = 16_384;
+
+// Parameters of the 64-bit FNV-1a hash used by content_hash
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
 /**
  * Represents a message type for the update worker thread
  * Used to communicate cell updates and shutdown signals
  */
 #[derive(Clone, Debug)]
 enum UpdateMessage {
-    // Indicates a cell update
+    // Indicates that one or more cells were updated together
     CellUpdate {
+        cell_ids: Vec<CellIdentifier>,
+    },
+
+    /// Sets or removes the minimum interval between cascades from a root cell
+    Throttle {
         cell_id: CellIdentifier,
+        interval: Option<Duration>,
+    },
+
+    /// Asks the worker to reply once every earlier message has been processed
+    Flush {
+        done: mpsc::Sender<()>,
     },
 
     /// Signals the worker thread to shut down
     Shutdown,
 }
 
+/**
+ * A callback run by the worker with each value it commits, see add_observer
+ */
+type Observer = Arc<dyn Fn(CellIdentifier, &CellValue) + Send + Sync>;
+
+/**
+ * A callback run by the worker with each new value in a watched region, see
+ * watch; returning false removes the watch
+ */
+type Watcher = Arc<dyn Fn(CellIdentifier, &CellValue) -> bool + Send + Sync>;
+
+/**
+ * Callbacks the worker runs with the values it commits
+ */
+#[derive(Default)]
+struct Subscribers {
+    observers: Vec<Observer>,                // Run with each committed value
+    watches: Vec<(u64, Reference, Watcher)>, // Watch id, watched region and callback
+    next_watch_id: u64,                      // Id given to the next watch
+}
+
+/**
+ * Counters shared between the spreadsheet and its worker thread
+ */
+#[derive(Debug, Default)]
+struct WorkerCounters {
+    recomputed: AtomicUsize, // Cells re-evaluated by the worker so far
+    evaluated: AtomicUsize,  // Expressions evaluated outside the worker, e.g. by set
+    passes: AtomicUsize,     // Combined recompute passes run by the worker so far
+    queued: AtomicUsize,     // Messages sent to the worker but not yet received
+    version: AtomicU64,      // Latest version stamped on a changed value, see changed_since
+}
+
+impl WorkerCounters {
+    /**
+     * HELPER FUNCTION
+     * Takes the next sheet version; only called under the cells lock, so
+     * versions are stamped in the order the values become visible
+     */
+    fn next_version(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
 /**
  * Stores information about a cell in the spreadsheet
  */
 #[derive(Debug)]
 pub struct CellInfo {
-    value: CellValue,                    // Current value of the cell
-    expression: String,                  // Original expression string
-    dependencies: Vec<CellIdentifier>,   // Cells that this cell depends on
-    dependents: HashSet<CellIdentifier>, // Cells that depend on this cell
-    last_update_time: Instant,           // Timestamp of last successful update
+    value: CellValue,                      // Current value of the cell
+    expression: String,                    // Original expression string
+    last_update_time: Instant,             // Timestamp of last successful update
+    edits: VecDeque<(SystemTime, String)>, // Latest explicit sets and when they happened, oldest first
+    stale: bool,   // Value was computed from inputs that changed before it was stored
+    version: u64,  // Sheet version at which the value last changed, or the cell moved
+    boolean: bool, // Value is a comparison's result, stored as 1 (true) or 0 (false)
 }
 
 /**
- * Main spreadsheet structure that manages cells and their relationships
+ * The last update time of each cell a value was computed from, or None for
+ * a cell that wasn't set, used to tell whether the value is still current
+ */
+type InputVersions = Vec<(CellIdentifier, Option<Instant>)>;
+
+/**
+ * Variables gathered for an expression, with the single-cell variables
+ * that hold a boolean, see gather_variables
+ */
+#[derive(Debug)]
+struct Gathered {
+    values: HashMap<String, CellArgument>, // Value of every variable
+    booleans: HashSet<String>,             // Variables whose 1 or 0 is true or false
+}
+
+// Variables gathered for an expression, or the error value it evaluates to
+// instead, see gather_variables
+type Variables = Result<Gathered, CellValue>;
+
+/**
+ * A set evaluated against the committed values, ready to be stored
  */
 #[derive(Debug)]
+struct EvaluatedSet {
+    cell_id: CellIdentifier,      // Cell being set
+    value: CellValue,             // Value computed from the expression
+    boolean: bool,                // Value is a comparison's result
+    expression: String,           // Expression as set
+    dependencies: Vec<Reference>, // References the expression reads
+    inputs: InputVersions,        // Versions of the cells the value was computed from
+}
+
+/**
+ * How an update changes the undo history of the cells it stores
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edit {
+    Set,     // A new expression; the old one can be undone and nothing can be redone
+    Undo,    // An undone expression put back; the old one can be redone
+    Redo,    // A redone expression put back; the old one can be undone again
+    Rewrite, // A formula stored again after a name or layout change; history is kept
+}
+
+/**
+ * The expressions a cell held before, for undo, and the ones undone since,
+ * for redo, most recent last
+ */
+#[derive(Debug, Default)]
+struct CellHistory {
+    undo: VecDeque<String>, // At most HISTORY_LIMIT earlier expressions
+    redo: VecDeque<String>, // Undone expressions, dropped by the next set
+}
+
+impl CellHistory {
+    /**
+     * HELPER FUNCTION
+     * Pushes an expression onto one of the stacks, forgetting the oldest
+     * once it holds HISTORY_LIMIT
+     */
+    fn push(stack: &mut VecDeque<String>, expression: String) {
+        if stack.len() == HISTORY_LIMIT {
+            stack.pop_front();
+        }
+        stack.push_back(expression);
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Returns the stack an undo or redo takes its expression from
+     */
+    fn source(&mut self, edit: Edit) -> &mut VecDeque<String> {
+        if edit == Edit::Undo {
+            &mut self.undo
+        } else {
+            &mut self.redo
+        }
+    }
+}
+
+/**
+ * A snapshot of the sheet's size and worker activity, returned by stats
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SheetStats {
+    pub cells: usize,                 // Cells holding a value
+    pub errors: usize,                // Cells whose value is an error
+    pub edges: usize,                 // Reverse dependency edges stored in the graph
+    pub max_chain_depth: usize,       // Edges on the longest dependency chain
+    pub queue_depth: usize,           // Messages sent to the worker but not yet received
+    pub evaluations: usize,           // Expressions evaluated since the sheet was created
+    pub evaluator_threads: usize,     // Threads started to run formulas under an eval_timeout
+    pub abandoned_evaluations: usize, // Timed-out formulas still running on their threads
+    pub uptime: Duration,             // Time since the sheet was created
+}
+
+/**
+ * The state of a sheet's worker and locks, returned by health
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HealthReport {
+    pub worker_alive: bool,   // Whether the update worker thread is still running
+    pub cells_poisoned: bool, // Whether a thread panicked while holding the cells lock
+    pub graph_poisoned: bool, // Whether a thread panicked while holding the graph lock
+    pub pending_updates: usize, // Messages sent to the worker but not yet received
+    pub uptime: Duration,     // Time since the sheet was created
+}
+
+impl HealthReport {
+    /**
+     * Public Function
+     * Checks whether the sheet can still serve requests normally
+     */
+    pub fn is_ok(&self) -> bool {
+        self.worker_alive && !self.cells_poisoned && !self.graph_poisoned
+    }
+}
+
+/**
+ * The cells an aggregate query reads, see Spreadsheet::aggregate
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggTarget {
+    Column(u32),                           // Every cell in a column, by 0-based index
+    Row(u32),                              // Every cell in a row, by 0-based index
+    Range(CellIdentifier, CellIdentifier), // Every cell in the region between two opposite corners
+}
+
+impl AggTarget {
+    /**
+     * HELPER FUNCTION
+     * Checks whether a cell falls inside the target
+     */
+    fn contains(&self, cell_id: &CellIdentifier) -> bool {
+        match *self {
+            AggTarget::Column(col) => cell_id.col == col,
+            AggTarget::Row(row) => cell_id.row == row,
+            AggTarget::Range(start, end) => {
+                (start.col.min(end.col)..=start.col.max(end.col)).contains(&cell_id.col)
+                    && (start.row.min(end.row)..=start.row.max(end.row)).contains(&cell_id.row)
+            }
+        }
+    }
+
+    /**
+     * Public Function
+     * Formats the target as a client writes it: "B" for a column, "3" for a
+     * row and "A1_B2" for a range
+     */
+    pub fn name(&self) -> String {
+        match self {
+            AggTarget::Column(col) => references::column_name(*col),
+            AggTarget::Row(row) => references::row_name(*row),
+            AggTarget::Range(start, end) => {
+                format!(
+                    "{}_{}",
+                    references::a1_name(start),
+                    references::a1_name(end)
+                )
+            }
+        }
+    }
+}
+
+/**
+ * What an aggregate query computes over the integers in its target
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggKind {
+    Sum,   // Total, 0 when there are no integers
+    Min,   // Smallest integer, empty when there are none
+    Max,   // Largest integer, empty when there are none
+    Avg,   // Mean rounded toward zero, empty when there are no integers
+    Count, // Number of integers
+}
+
+impl AggKind {
+    /**
+     * Public Function
+     * The lowercase name of the aggregate, e.g. "sum"
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggKind::Sum => "sum",
+            AggKind::Min => "min",
+            AggKind::Max => "max",
+            AggKind::Avg => "avg",
+            AggKind::Count => "count",
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Computes the aggregate over a list of integers
+     */
+    fn apply(&self, numbers: &[i64]) -> Result<CellValue, AggError> {
+        let total: i128 = numbers.iter().map(|&n| i128::from(n)).sum();
+        let value = match self {
+            AggKind::Sum => CellValue::Int(i64::try_from(total).map_err(|_| AggError::Overflow)?),
+            AggKind::Min => numbers
+                .iter()
+                .min()
+                .map_or(CellValue::None, |&n| CellValue::Int(n)),
+            AggKind::Max => numbers
+                .iter()
+                .max()
+                .map_or(CellValue::None, |&n| CellValue::Int(n)),
+            // The mean of i64s always fits in an i64
+            AggKind::Avg if numbers.is_empty() => CellValue::None,
+            AggKind::Avg => CellValue::Int((total / numbers.len() as i128) as i64),
+            AggKind::Count => CellValue::Int(numbers.len() as i64),
+        };
+        Ok(value)
+    }
+}
+
+/**
+ * Settings fixed when a sheet is created, see Spreadsheet::with_options
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpreadsheetOptions {
+    // Update messages the worker's queue holds. Once it is full, set and every
+    // other call that notifies the worker blocks until the worker catches up.
+    // Zero makes each call wait for the worker to receive its message.
+    pub queue_capacity: usize,
+    // Write-ahead log that every successful set is appended to, see
+    // Spreadsheet::recover for reading it back
+    pub wal_path: Option<PathBuf>,
+    // Where and how often to save a snapshot in the background
+    pub autosave: Option<Autosave>,
+    // Longest chain of dependents one cascade recomputes, counted in edges
+    // from the updated cell. Cells further down are set to a
+    // CascadeDepthExceeded error instead. None means no limit.
+    pub max_cascade_depth: Option<usize>,
+    // Sets each cell remembers, with their wall-clock time, for
+    // Spreadsheet::history. Recomputed values aren't sets and aren't kept.
+    pub edit_log_length: usize,
+    // Most cells the sheet holds at once, counting cells with a non-blank
+    // expression. A set that would add a cell beyond it fails with
+    // CellLimitReached; re-setting or clearing a cell is always allowed.
+    // None means no limit.
+    pub max_cells: Option<usize>,
+    // What empty cells inside a range read as, whether the formula is
+    // evaluated by set or by the worker. Single cells always read as 0.
+    pub empty_cells_in_ranges: EmptyCells,
+    // Most cells one range may cover, e.g. DEFAULT_MAX_RANGE_CELLS. A set
+    // naming a larger range fails with RangeTooLarge, and a whole column or
+    // row that grows past it evaluates to an error. None means no limit.
+    pub max_range_cells: Option<usize>,
+    // Longest expression a set accepts, in characters. A longer one fails
+    // with ExpressionTooLong. None means no limit.
+    pub max_expression_length: Option<usize>,
+    // Rows and columns the sheet has. Setting a cell past them, or naming
+    // one in an expression, fails with OutOfBounds.
+    pub max_rows: u32,
+    pub max_cols: u32,
+    // Longest one formula may take to evaluate, e.g. a runaway sleep_then.
+    // A formula still running then gets an EvaluationTimeout error, and set
+    // or the worker moves on. None means no limit.
+    pub eval_timeout: Option<Duration>,
+}
+
+/**
+ * Background saving of a sheet, see SpreadsheetOptions::autosave
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Autosave {
+    pub path: PathBuf,      // Snapshot file, as written by save_to_path
+    pub interval: Duration, // How long to wait between saves
+}
+
+impl Default for SpreadsheetOptions {
+    fn default() -> Self {
+        Self {
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            wal_path: None,
+            autosave: None,
+            max_cascade_depth: None,
+            edit_log_length: DEFAULT_EDIT_LOG_LENGTH,
+            max_cells: None,
+            empty_cells_in_ranges: EmptyCells::default(),
+            max_range_cells: None,
+            max_expression_length: None,
+            max_rows: DEFAULT_MAX_ROWS,
+            max_cols: DEFAULT_MAX_COLS,
+            eval_timeout: None,
+        }
+    }
+}
+
+/**
+ * What an empty cell, never set or cleared, reads as inside a range, see
+ * SpreadsheetOptions::empty_cells_in_ranges
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmptyCells {
+    #[default]
+    TreatAsZero, // Reads as 0, so sum skips it
+    TreatAsNone, // Reads as an empty value, which sum rejects
+    Error,       // Fails the formula with an EmptyCellError naming the first empty cell
+}
+
+/**
+ * Sheet options the worker needs to recompute cells
+ */
+#[derive(Clone, Debug)]
+struct EvalSettings {
+    max_depth: Option<usize>, // Longest cascade, see SpreadsheetOptions::max_cascade_depth
+    empty_cells: EmptyCells,  // What empty cells inside a range read as
+    max_range_cells: Option<usize>, // Most cells one range may cover
+    evaluator: Option<Arc<TimedEvaluator>>, // Runs formulas under the eval_timeout, if set
+}
+
+/**
+ * Notation accepted for cell names by get_by_name and set_by_name
+ * A1 names are always accepted; R1C1 names ("R2C3" is row 2, column 3)
+ * are only accepted, in names and in formulas, while in R1C1 mode
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressMode {
+    #[default]
+    A1,
+    R1C1,
+}
+
+/**
+ * Main spreadsheet structure that manages cells and their relationships
+ *
+ * Lock ordering: the graph lock and the cells lock are never held at the
+ * same time, so neither can deadlock against the other. The log lock is
+ * only ever taken before either of them, and the subscribers, names and
+ * undo history locks are only ever taken on their own
+ *
+ * Only set_batch, compare_and_set, relocate and clear_all change the graph,
+ * and all of them hold the log lock (whether or not a log is enabled) while
+ * they do. Two sets of the same cell therefore can't interleave, which would
+ * leave the edges of one formula stored beside the expression of the other.
+ * The worker only reads the graph
+ *
+ * Poisoning: a thread that panics while holding a lock doesn't take the
+ * sheet down with it. Every lock is taken with PoisonError::into_inner, so
+ * later callers keep using the data as the panicking thread left it, and
+ * health still reports the lock as poisoned
+ */
 pub struct Spreadsheet {
     cells: Arc<Mutex<HashMap<CellIdentifier, CellInfo>>>, // Thread-safe storage of cells
-    update_sender: mpsc::Sender<UpdateMessage>,           // Channel for sending update messages
+    graph: Arc<Mutex<DependencyGraph>>,                   // Dependency edges between cells
+    update_sender: mpsc::SyncSender<UpdateMessage>, // Bounded channel for sending update messages
+    address_mode: Mutex<AddressMode>,               // Notation accepted for cell names
+    counters: Arc<WorkerCounters>,                  // Worker activity, for introspection
+    worker: Option<thread::JoinHandle<()>>,         // Handle of the update worker thread
+    created: Instant,                               // When the sheet was created
+    wal: Mutex<Option<WriteAheadLog>>,              // Log of successful sets, if enabled
+    dirty: Arc<AtomicBool>, // Whether a cell was set or cleared since the last autosave
+    autosaver: Option<(mpsc::Sender<()>, thread::JoinHandle<()>)>, // Stops and joins autosave
+    subscribers: Arc<Mutex<Subscribers>>, // Callbacks run with the values the worker commits
+    names: Arc<Mutex<HashMap<String, Reference>>>, // Defined names and the cells they stand for
+    undo_history: Mutex<HashMap<CellIdentifier, CellHistory>>, // Expressions each cell can undo or redo
+    edit_log_length: usize,   // Sets each cell remembers for history
+    max_cells: Option<usize>, // Most cells with a non-blank expression, if limited
+    max_expression_length: Option<usize>, // Longest expression a set accepts, if limited
+    settings: EvalSettings,   // How formulas read empty cells and large ranges
+    last_cell: CellIdentifier, // Bottom-right cell of the sheet's bounds
+}
+
+impl std::fmt::Debug for Spreadsheet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Spreadsheet")
+            .field("cells", &self.cells)
+            .field("graph", &self.graph)
+            .field("address_mode", &self.address_mode)
+            .field("counters", &self.counters)
+            .field("created", &self.created)
+            .field("wal", &self.wal)
+            .field("dirty", &self.dirty)
+            .field("names", &self.names)
+            .field("undo_history", &self.undo_history)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Spreadsheet {
+    /**
+     * HELPER FUNCTION
+     * Creates a new spreadsheet instance with the default options
+     */
+    pub fn new() -> Self {
+        Self::with_options(SpreadsheetOptions::default())
+    }
+
     /**
      * HELPER FUNCTION
      * Creates a new spreadsheet instance
      *
      * Procedure:
-     * 1. Creates thread-safe storage for cells using Arc and Mutex
-     * 2. Sets up a channel for communication with worker thread
+     * 1. Creates thread-safe storage for cells and the dependency graph
+     * 2. Sets up a bounded channel for communication with worker thread, so
+     *    a slow worker makes writers wait instead of letting the queue grow
      * 3. Spawns worker thread to handle cell updates
-     * 4. Returns configured spreadsheet instance
+     * 4. Opens the write-ahead log, if one is configured, logging a warning
+     *    and running without it if the file can't be opened
+     * 5. Spawns the autosave thread, if autosave is configured
+     * 6. Returns configured spreadsheet instance
      */
-    pub fn new() -> Self {
+    pub fn with_options(options: SpreadsheetOptions) -> Self {
         let cells = Arc::new(Mutex::new(HashMap::new()));
+        let graph = Arc::new(Mutex::new(DependencyGraph::new()));
 
         // Initialize channels for worker thread communication
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(options.queue_capacity);
 
         // Spawn worker thread to handle cell updates
         let worker_cells = Arc::clone(&cells);
-        thread::spawn(move || {
-            Self::process_cells_update(worker_cells, receiver);
+        let worker_graph = Arc::clone(&graph);
+        let counters = Arc::new(WorkerCounters::default());
+        let worker_counters = Arc::clone(&counters);
+        let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+        let worker_subscribers = Arc::clone(&subscribers);
+        let names: Arc<Mutex<HashMap<String, Reference>>> = Arc::new(Mutex::new(HashMap::new()));
+        let worker_names = Arc::clone(&names);
+        let settings = EvalSettings {
+            max_depth: options.max_cascade_depth,
+            empty_cells: options.empty_cells_in_ranges,
+            max_range_cells: options.max_range_cells,
+            evaluator: options
+                .eval_timeout
+                .map(|timeout| Arc::new(TimedEvaluator::new(timeout))),
+        };
+        let worker_settings = settings.clone();
+        let worker = thread::spawn(move || {
+            Self::process_cells_update(
+                worker_cells,
+                worker_graph,
+                worker_counters,
+                worker_subscribers,
+                worker_names,
+                worker_settings,
+                receiver,
+            );
+        });
+
+        let wal = options
+            .wal_path
+            .and_then(|path| match WriteAheadLog::open(&path) {
+                Ok(wal) => Some(wal),
+                Err(e) => {
+                    warn!("event=wal_unavailable path={} error={}", path.display(), e);
+                    None
+                }
+            });
+
+        let dirty = Arc::new(AtomicBool::new(false));
+        let autosaver = options.autosave.map(|autosave| {
+            let (stop, stopped) = mpsc::channel();
+            let autosave_cells = Arc::clone(&cells);
+            let autosave_dirty = Arc::clone(&dirty);
+            let handle = thread::spawn(move || {
+                Self::autosave(autosave_cells, autosave_dirty, autosave, stopped);
+            });
+            (stop, handle)
         });
 
         Self {
             cells,
+            graph,
             update_sender: sender,
+            address_mode: Mutex::new(AddressMode::default()),
+            counters,
+            worker: Some(worker),
+            created: Instant::now(),
+            wal: Mutex::new(wal),
+            dirty,
+            autosaver,
+            subscribers,
+            names,
+            undo_history: Mutex::new(HashMap::new()),
+            edit_log_length: options.edit_log_length,
+            max_cells: options.max_cells,
+            max_expression_length: options.max_expression_length,
+            settings,
+            last_cell: CellIdentifier {
+                col: options.max_cols.saturating_sub(1),
+                row: options.max_rows.saturating_sub(1),
+            },
         }
     }
 
@@ -78,912 +628,6765 @@ impl Spreadsheet {
      *
      * Procedure:
      * 1. Acquires lock on cells HashMap
-     * 2. Checks if cell exists
-     * 3. If cell exists:
-     *    - Checks dependencies for errors
-     *    - Returns error if any dependency has error
-     *    - Otherwise returns cell value
-     * 4. If cell doesn't exist, returns None
+     * 2. Returns the committed value, which already records whether any
+     *    dependency, however far up the chain, is an error
+     * 3. If cell doesn't exist, returns None
      */
     pub fn get(&self, cell_id: &CellIdentifier) -> CellValue {
-        let cells = self.cells.lock().unwrap();
-        if let Some(cell_info) = cells.get(cell_id) {
-            // Check if any dependencies have errors
-            for dep in &cell_info.dependencies {
-                if let Some(dep_info) = cells.get(dep) {
-                    if matches!(dep_info.value, CellValue::Error(_)) {
-                        return CellValue::Error("VariableDependsOnError".into());
-                    }
-                }
-            }
-            cell_info.value.clone()
-        } else {
-            CellValue::None
-        }
+        self.cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cell_id)
+            .map(|cell_info| cell_info.value.clone())
+            .unwrap_or_default()
     }
 
     /**
      * Public Function
-     * Sets a cell's value based on an expression
+     * Gets every value in a rectangle, all read under one lock so together
+     * they form a consistent snapshot
      *
      * Procedure:
-     * 1. Records current timestamp
-     * 2. Creates CellExpr from input string
-     * 3. Extracts dependencies from expression
-     * 4. Evaluates expression with current variable values
-     * 5. Updates cell info with new value and dependencies
-     * 6. Notifies worker thread of update
+     * 1. Orders the corners so either diagonal describes the same rectangle
+     * 2. Acquires lock on cells once
+     * 3. Returns each cell with its value in row-major order, None for unset cells
      */
-    pub fn set(
+    pub fn get_range(
         &self,
-        cell_id: CellIdentifier,
-        expression: String,
-    ) -> Result<(), CellExprEvalError> {
-        let current_time = Instant::now();
-        let cell_expr = CellExpr::new(&expression);
-
-        // Get all dependencies from the cell expression, including all cells within ranges
-        let mut dependencies = Vec::new();
-        for var_name in cell_expr.find_variable_names() {
-            if !var_name.contains('_') {
-                if let Ok(dep_id) = var_name.parse::<CellIdentifier>() {
-                    dependencies.push(dep_id);
-                }
-            } else if let Some((start, end)) = Self::parse_range(&var_name) {
-                // Add all cells in the range as dependencies
-                for row in start.row..=end.row {
-                    for col in start.col..=end.col {
-                        dependencies.push(CellIdentifier { col, row });
-                    }
-                }
-            }
-        }
-
-        // Resolve variables and evaluate expression
-        let variables = self.resolve_variables(&cell_expr);
-        let value = match cell_expr.evaluate(&variables) {
-            Ok(value) => value,
-            Err(CellExprEvalError::VariableDependsOnError) => {
-                self.update_cell_info(
-                    cell_id,
-                    CellValue::Error("VariableDependsOnError".into()),
-                    expression,
-                    dependencies,
-                    current_time,
-                )?;
-                return Ok(());
-            }
-        };
+        start: CellIdentifier,
+        end: CellIdentifier,
+    ) -> Vec<(CellIdentifier, CellValue)> {
+        let region = Reference::Range(
+            CellIdentifier {
+                col: start.col.min(end.col),
+                row: start.row.min(end.row),
+            },
+            CellIdentifier {
+                col: start.col.max(end.col),
+                row: start.row.max(end.row),
+            },
+        );
 
-        // Update cell info and notify dependents
-        self.update_cell_info(cell_id, value, expression, dependencies, current_time)?;
-        Ok(())
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        region
+            .cells()
+            .into_iter()
+            .map(|cell_id| {
+                let value = cells
+                    .get(&cell_id)
+                    .map(|cell_info| cell_info.value.clone())
+                    .unwrap_or_default();
+                (cell_id, value)
+            })
+            .collect()
     }
 
     /**
-     * HELPER FUNCTION
-     * Updates cell information and manages dependency relationships
+     * Public Function
+     * Returns the value of every cell holding one, as of a single instant
      *
-     * Procedure:
-     * 1. Acquires lock on cells
-     * 2. Collects old dependencies and dependents
-     * 3. Removes cell from old dependencies' dependent lists
-     * 4. Adds cell to new dependencies' dependent lists
-     * 5. Updates/inserts cell info with new value
-     * 6. Notifies worker thread of update
+     * All values are cloned under one acquisition of the cells lock, and the
+     * worker commits each cascade under one acquisition too, so a snapshot
+     * never mixes values from before and after a cascade. A set's own cell is
+     * stored before its dependents are recomputed, so it may be newer than
+     * the dependents beside it
      */
-    fn update_cell_info(
-        &self,
-        cell_id: CellIdentifier,
-        value: CellValue,
-        expression: String,
-        dependencies: Vec<CellIdentifier>,
-        current_time: Instant,
-    ) -> Result<(), CellExprEvalError> {
-        let mut cells = self.cells.lock().unwrap();
-
-        // First collect the old dependencies and dependents
-        let (old_dependencies, old_dependents) = if let Some(old_cell) = cells.get(&cell_id) {
-            (old_cell.dependencies.clone(), old_cell.dependents.clone())
-        } else {
-            (Vec::new(), HashSet::new())
-        };
+    pub fn snapshot(&self) -> HashMap<CellIdentifier, CellValue> {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        cells
+            .iter()
+            .filter(|(_, cell)| cell.value != CellValue::None)
+            .map(|(cell_id, cell)| (*cell_id, cell.value.clone()))
+            .collect()
+    }
 
-        // Remove this cell from old dependencies' dependents lists
-        for old_dep in old_dependencies {
-            if let Some(dep_cell) = cells.get_mut(&old_dep) {
-                dep_cell.dependents.remove(&cell_id);
-            }
-        }
+    /**
+     * Public Function
+     * Returns the sheet's current version, which grows by one for each set,
+     * batch or cascade that changes a value, for use with changed_since
+     */
+    pub fn version(&self) -> u64 {
+        // Taking the cells lock waits out any stamping still in progress
+        let _cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        self.counters.version.load(Ordering::SeqCst)
+    }
 
-        // Add this cell to new dependencies' dependents lists
-        for dep in &dependencies {
-            if let Some(dep_cell) = cells.get_mut(dep) {
-                dep_cell.dependents.insert(cell_id);
-            }
-        }
+    /**
+     * Public Function
+     * Returns every cell whose value changed after the given version, with
+     * its value and the version it changed at, oldest change first
+     *
+     * Procedure:
+     * 1. Reads every cell under one acquisition of the cells lock; versions
+     *    are only stamped under that lock, so no change at or below the
+     *    newest version returned can still be on its way
+     * 2. Keeps the cells stamped with a later version, a cleared cell
+     *    reporting None and a moved cell reporting its new position
+     * 3. Sorts them by version, then by row and column
+     *
+     * A client polls with the newest version it has seen, starting from 0
+     * for every cell, and keeps it when nothing has changed. A cell changed
+     * more than once is reported once, at its latest version
+     */
+    pub fn changed_since(&self, version: u64) -> Vec<(CellIdentifier, CellValue, u64)> {
+        let mut changed: Vec<(CellIdentifier, CellValue, u64)> = self
+            .cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, cell)| cell.version > version)
+            .map(|(cell_id, cell)| (*cell_id, cell.value.clone(), cell.version))
+            .collect();
+        changed.sort_by_key(|(cell_id, _, version)| (*version, cell_id.row, cell_id.col));
+        changed
+    }
 
-        // Update/insert the cell info
-        cells.insert(
-            cell_id,
-            CellInfo {
-                value,
-                expression,
-                dependencies,
-                dependents: old_dependents, // Preserve existing dependents
-                last_update_time: current_time,
+    /**
+     * Public Function
+     * Gets every value in a rectangle as rows of values, top row first, read
+     * under one lock as get_range is; unset cells are None
+     */
+    pub fn get_grid(&self, start: CellIdentifier, end: CellIdentifier) -> Vec<Vec<CellValue>> {
+        let (start, end) = (
+            CellIdentifier {
+                col: start.col.min(end.col),
+                row: start.row.min(end.row),
+            },
+            CellIdentifier {
+                col: start.col.max(end.col),
+                row: start.row.max(end.row),
             },
         );
 
-        // Notify single worker thread
-        self.update_sender
-            .send(UpdateMessage::CellUpdate { cell_id })
-            .map_err(|_| CellExprEvalError::VariableDependsOnError)?;
-
-        Ok(())
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let matrix = Self::get_matrix(&start, &end, &|cell_id| {
+            cells
+                .get(cell_id)
+                .map(|cell| cell.value.clone())
+                .unwrap_or_default()
+        });
+        match matrix {
+            CellArgument::Matrix(rows) => rows,
+            _ => Vec::new(),
+        }
     }
 
     /**
-     * HELPER FUNCTION
-     * Resolves variables used in an expression
-     *
-     * Procedure:
-     * 1. Creates empty variables HashMap
-     * 2. For each variable name in expression:
-     *    - If scalar (A1): gets single cell value
-     *    - If range (A1_B2): gets vector or matrix of values
-     * 3. Returns map of variable names to their values
+     * Public Function
+     * Returns the top-left and bottom-right corners of the smallest rectangle
+     * holding every cell with a value, or None when no cell has one
+     * Cleared cells don't count
      */
-    fn resolve_variables(&self, cell_expr: &CellExpr) -> HashMap<String, CellArgument> {
-        let mut variables: HashMap<String, CellArgument> = HashMap::new();
+    pub fn extent(&self) -> Option<(CellIdentifier, CellIdentifier)> {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        cells
+            .iter()
+            .filter(|(_, cell)| cell.value != CellValue::None)
+            .map(|(cell_id, _)| (*cell_id, *cell_id))
+            .reduce(|(start, end), (cell_id, _)| {
+                (
+                    CellIdentifier {
+                        col: start.col.min(cell_id.col),
+                        row: start.row.min(cell_id.row),
+                    },
+                    CellIdentifier {
+                        col: end.col.max(cell_id.col),
+                        row: end.row.max(cell_id.row),
+                    },
+                )
+            })
+    }
 
-        for var_name in cell_expr.find_variable_names() {
-            if var_name.contains('_') {
-                // Handle range variables (vector or matrix)
-                if let Some((start, end)) = Self::parse_range(&var_name) {
-                    let arg = self.get_range_argument(&start, &end);
-                    variables.insert(var_name.clone(), arg);
-                }
-            } else {
-                // Handle scalar variables
-                if let Ok(cell_id) = var_name.parse::<CellIdentifier>() {
-                    let value = self.get(&cell_id);
-                    variables.insert(var_name.clone(), CellArgument::Value(value));
-                }
-            }
-        }
+    /**
+     * Public Function
+     * Same as extent, for callers written against the sparse-sheet API
+     */
+    pub fn extents(&self) -> Option<(CellIdentifier, CellIdentifier)> {
+        self.extent()
+    }
 
-        variables
+    /**
+     * Public Function
+     * Returns up to the last limit expressions set on a cell, oldest first,
+     * each with the wall-clock time of its set
+     * Only explicit sets are kept, including undo and redo, and only the
+     * last SpreadsheetOptions::edit_log_length of them
+     */
+    pub fn history(&self, cell_id: &CellIdentifier, limit: usize) -> Vec<(SystemTime, String)> {
+        self.cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cell_id)
+            .map(|cell| {
+                let skipped = cell.edits.len().saturating_sub(limit);
+                cell.edits.iter().skip(skipped).cloned().collect()
+            })
+            .unwrap_or_default()
     }
 
     /**
-     * HELP FUNCTION
-     * Parses a range string into start and end cell identifiers
+     * Public Function
+     * Evaluates an expression against the committed values without storing
+     * it anywhere, e.g. to ask for sum(A1_A10) * 2 without using a cell
      *
      * Procedure:
-     * 1. Splits string on underscore
-     * 2. Parses first part as start cell
-     * 3. Parses second part as end cell
-     * 4. Returns tuple of (start, end) if valid
+     * 1. A blank expression gives None
+     * 2. A variable that is neither a cell, a valid range nor a defined name
+     *    gives an error, where set would reject the expression
+     * 3. Otherwise resolves the variables and evaluates the expression as a
+     *    stored formula would be, so an error in a referenced cell gives a
+     *    VariableDependsOnError value; no cell or dependency edge changes
      */
-    fn parse_range(range: &str) -> Option<(CellIdentifier, CellIdentifier)> {
-        let parts: Vec<&str> = range.split('_').collect();
-        if parts.len() != 2 {
-            return None;
+    pub fn evaluate_adhoc(&self, expr: &str) -> CellValue {
+        if expr.trim().is_empty() {
+            return CellValue::None;
         }
+        let names = match self.validated_names([expr]) {
+            Ok(names) => names,
+            Err(e) => return CellValue::Error(e.to_string()),
+        };
 
-        if let (Ok(start), Ok(end)) = (
-            parts[0].parse::<CellIdentifier>(),
-            parts[1].parse::<CellIdentifier>(),
-        ) {
-            Some((start, end))
-        } else {
-            None
+        let references = Self::references_in(expr, &names);
+        match self.resolve_variables(&references) {
+            (bounded, Ok(variables), _) => {
+                Self::evaluate_cell(expr, &bounded, &variables, &self.settings).0
+            }
+            (_, Err(error), _) => error,
         }
     }
 
     /**
-     * HELPER FUNCTION
-     * Converts a cell range into appropriate CellArgument type
+     * Public Function
+     * Evaluates an expression as evaluate_adhoc does, returning the message
+     * of an error value as Err, so a dry run reads like a fallible call
+     * Nothing is inserted, no edge is added and the worker isn't notified
+     */
+    pub fn evaluate_expr(&self, expr: &str) -> Result<CellValue, String> {
+        match self.evaluate_adhoc(expr) {
+            CellValue::Error(message) => Err(message),
+            value => Ok(value),
+        }
+    }
+
+    /**
+     * Public Function
+     * Evaluates a cell as if the given cells held the given values, without
+     * changing the sheet, e.g. what D1 would be if A1 were 100
      *
      * Procedure:
-     * 1. Checks if any cells in range have errors
-     * 2. Returns error if any found
-     * 3. Determines range type (vertical/horizontal/matrix)
-     * 4. Collects values into appropriate structure
-     * 5. Returns vector or matrix argument
+     * 1. Walks the target's dependency closure under a single cells lock,
+     *    ranges and names included, copying each formula and the committed
+     *    value of every other cell; overridden cells are not walked further
+     * 2. Evaluates the closure bottom-up outside the lock, each formula
+     *    against the values computed for its inputs, so an override reaches
+     *    the target through any chain or range. Cells that read no other
+     *    cell keep their committed value
+     * 3. A cycle in the closure gives a CircularReference error naming it,
+     *    e.g. "CircularReference: A1 -> B1 -> A1"
      */
-    fn get_range_argument(&self, start: &CellIdentifier, end: &CellIdentifier) -> CellArgument {
-        let cells = self.cells.lock().unwrap();
+    pub fn evaluate_with_overrides(
+        &self,
+        target: CellIdentifier,
+        overrides: &HashMap<CellIdentifier, CellValue>,
+    ) -> CellValue {
+        let names = self
+            .names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
 
-        // Check if any cells in the range have errors
-        let has_errors = (start.row..=end.row).any(|row| {
-            (start.col..=end.col).any(|col| {
-                let cell_id = CellIdentifier { col, row };
-                if let Some(cell) = cells.get(&cell_id) {
-                    matches!(cell.value, CellValue::Error(_))
+        // Step 1: Copy the closure
+        let mut resolved: HashMap<CellIdentifier, CellValue> = overrides.clone();
+        let mut booleans: HashSet<CellIdentifier> = HashSet::new();
+        let mut formulas: HashMap<CellIdentifier, (String, Vec<(String, Reference)>)> =
+            HashMap::new();
+        {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut pending = vec![target];
+            while let Some(cell_id) = pending.pop() {
+                if resolved.contains_key(&cell_id) || formulas.contains_key(&cell_id) {
+                    continue;
+                }
+                let Some(cell) = cells.get(&cell_id) else {
+                    resolved.insert(cell_id, CellValue::None);
+                    continue;
+                };
+                if cell.boolean {
+                    booleans.insert(cell_id);
+                }
+                let references: Vec<(String, Reference)> =
+                    Self::references_in(&cell.expression, &names)
+                        .into_iter()
+                        .map(|(name, reference)| (name, Self::bound_reference(reference, &cells)))
+                        .collect();
+                if references.is_empty() {
+                    resolved.insert(cell_id, cell.value.clone());
+                    continue;
+                }
+                pending.extend(
+                    references
+                        .iter()
+                        .flat_map(|(_, reference)| reference.cells()),
+                );
+                formulas.insert(cell_id, (cell.expression.clone(), references));
+            }
+        }
+
+        // Step 2: Evaluate each formula once its inputs are resolved, keeping
+        // the path walked so far to spot cycles
+        let mut stack: Vec<(CellIdentifier, bool)> = vec![(target, false)];
+        let mut path: Vec<CellIdentifier> = Vec::new();
+        while let Some((cell_id, inputs_resolved)) = stack.pop() {
+            if inputs_resolved {
+                path.pop();
+                let (expression, references) = &formulas[&cell_id];
+                let variables = Self::gather_variables(
+                    references,
+                    &|id| resolved.get(id).cloned().unwrap_or_default(),
+                    &|id| booleans.contains(id),
+                    &self.settings,
+                );
+                let (value, boolean) = match variables {
+                    Ok(variables) => {
+                        Self::evaluate_cell(expression, references, &variables, &self.settings)
+                    }
+                    Err(error) => (error, false),
+                };
+                if boolean {
+                    booleans.insert(cell_id);
                 } else {
-                    false
+                    booleans.remove(&cell_id);
                 }
-            })
-        });
+                resolved.insert(cell_id, value);
+                continue;
+            }
+            if resolved.contains_key(&cell_id) {
+                continue;
+            }
 
-        if has_errors {
-            return CellArgument::Value(CellValue::Error("VariableDependsOnError".into()));
+            // Step 3: Reaching a cell still on the path closes a cycle
+            if let Some(start) = path.iter().position(|id| *id == cell_id) {
+                let cycle: Vec<String> = path[start..]
+                    .iter()
+                    .chain([&cell_id])
+                    .map(references::a1_name)
+                    .collect();
+                return CellValue::Error(format!("CircularReference: {}", cycle.join(" -> ")));
+            }
+            path.push(cell_id);
+            stack.push((cell_id, true));
+            for (_, reference) in &formulas[&cell_id].1 {
+                stack.extend(
+                    reference
+                        .cells()
+                        .into_iter()
+                        .map(|input_id| (input_id, false)),
+                );
+            }
         }
-        drop(cells); // Release the lock before calling other functions
 
-        if start.col == end.col {
-            // Vertical vector
-            self.get_vertical_vector(start, end)
-        } else if start.row == end.row {
-            // Horizontal vector
-            self.get_horizontal_vector(start, end)
-        } else {
-            // Matrix
-            self.get_matrix(start, end)
+        resolved.remove(&target).unwrap_or_default()
+    }
+
+    /**
+     * Public Function
+     * Gets the expression a cell holds, exactly as it was set, or None for a
+     * cell that was never set
+     */
+    pub fn get_expression(&self, cell_id: &CellIdentifier) -> Option<String> {
+        self.cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cell_id)
+            .map(|cell_info| cell_info.expression.clone())
+    }
+
+    /**
+     * Public Function
+     * Gets the value of a cell along with whether it has ever been set
+     * A cell set and then cleared is present with a None value, while a cell
+     * never set is absent, which get alone can't tell apart
+     */
+    pub fn get_with_presence(&self, cell_id: &CellIdentifier) -> (bool, CellValue) {
+        match self
+            .cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cell_id)
+        {
+            Some(cell_info) => (true, cell_info.value.clone()),
+            None => (false, CellValue::None),
         }
     }
 
     /**
-     * HELP FUNCTION
-     * Get vertical vector from range
+     * Public Function
+     * Sets a cell's value based on an expression
      *
      * Procedure:
-     * 1. Creates vector to store values
-     * 2. Iterates through rows at fixed column
-     * 3. Gets value for each cell
-     * 4. Returns vector as CellArgument
+     * 1. Rejects the update if the cell, or any cell the expression names,
+     *    is outside the sheet's bounds, or if any variable is neither a cell
+     *    nor a valid range, e.g. "Q" or "A1_"
+     * 2. Evaluates expression with current variable values, clears the cell
+     *    if the expression is blank, or stores a SelfReference error if the
+     *    expression reads the cell itself. Empty cells read as 0, here and
+     *    when the worker recomputes the cell
+     * 3. Updates cell info with new value and dependencies
+     * 4. Notifies worker thread of update, waiting while its queue is full
+     * 5. Appends the set to the write-ahead log, if enabled
      */
-    fn get_vertical_vector(&self, start: &CellIdentifier, end: &CellIdentifier) -> CellArgument {
-        let values: Vec<CellValue> = (start.row..=end.row)
-            .map(|row| {
-                self.get(&CellIdentifier {
-                    col: start.col,
-                    row,
-                })
-            })
-            .collect();
-        CellArgument::Vector(values)
+    pub fn set(&self, cell_id: CellIdentifier, expression: String) -> Result<(), SpreadsheetError> {
+        self.set_batch(vec![(cell_id, expression)])
     }
 
     /**
-     * HELP FUNCTION
-     * Get horizontal vector from range
-     *
-     * Procedure:
-     * 1. Creates vector to store values
-     * 2. Iterates through columns at fixed row
-     * 3. Gets value for each cell
-     * 4. Returns vector as CellArgument
+     * Public Function
+     * Sets a cell like set, then blocks until the worker has recomputed
+     * every dependent the set affects, see flush
      */
-    fn get_horizontal_vector(&self, start: &CellIdentifier, end: &CellIdentifier) -> CellArgument {
-        let values: Vec<CellValue> = (start.col..=end.col)
-            .map(|col| {
-                self.get(&CellIdentifier {
-                    col,
-                    row: start.row,
-                })
-            })
-            .collect();
-        CellArgument::Vector(values)
+    pub fn set_and_wait(
+        &self,
+        cell_id: CellIdentifier,
+        expression: String,
+    ) -> Result<(), SpreadsheetError> {
+        self.set(cell_id, expression)?;
+        self.flush();
+        Ok(())
     }
 
     /**
-     * HELP FUNCTION
-     * Get matrix from range
+     * Public Function
+     * Sets several cells as one update, so no dependent is ever computed
+     * from some of the new values but not the others
      *
      * Procedure:
-     * 1. Creates nested vectors for matrix
-     * 2. Iterates through rows
-     * 3. For each row, iterates through columns
-     * 4. Gets value for each cell
-     * 5. Returns matrix as CellArgument
+     * 1. Rejects the whole batch if any cell is outside the sheet's bounds,
+     *    or any expression has a variable that is neither a cell, a valid
+     *    range nor a defined name, see validated_names
+     * 2. Applies the batch, see apply_batch
+     *
+     * A cell named twice takes the expression given last. Each cell's old
+     * expression can be brought back with undo
      */
-    fn get_matrix(&self, start: &CellIdentifier, end: &CellIdentifier) -> CellArgument {
-        let matrix: Vec<Vec<CellValue>> = (start.row..=end.row)
-            .map(|row| {
-                (start.col..=end.col)
-                    .map(|col| self.get(&CellIdentifier { col, row }))
-                    .collect()
-            })
-            .collect();
-        CellArgument::Matrix(matrix)
+    pub fn set_batch(
+        &self,
+        assignments: Vec<(CellIdentifier, String)>,
+    ) -> Result<(), SpreadsheetError> {
+        self.validate_and_apply(assignments, Edit::Set)
+    }
+
+    /**
+     * Public Function
+     * Sets a cell back to the expression it held before its last set,
+     * recomputing its dependents as set does
+     * Returns NothingToUndo once the cell's history is used up; only the
+     * last HISTORY_LIMIT expressions are kept
+     */
+    pub fn undo(&self, cell_id: CellIdentifier) -> Result<(), SpreadsheetError> {
+        self.step_history(cell_id, Edit::Undo)
+    }
+
+    /**
+     * Public Function
+     * Sets a cell back to the expression its last undo replaced
+     * Returns NothingToRedo when nothing was undone since the cell's last set
+     */
+    pub fn redo(&self, cell_id: CellIdentifier) -> Result<(), SpreadsheetError> {
+        self.step_history(cell_id, Edit::Redo)
     }
 
     /**
      * HELPER FUNCTION
-     * Worker thread function that processes cell updates
+     * Moves a cell one step through its history
      *
      * Procedure:
-     * 1. Receives update messages from channel
-     * 2. For each update:
-     *    a. Builds dependency graph using BFS
-     *    b. Performs topological sort of dependencies
-     *    c. Updates cells in sorted order
-     *    d. Handles timestamp ordering to prevent old updates overwriting new ones
-     * 3. Continues until shutdown message received
+     * 1. Pops the expression to restore off the undo or redo stack
+     * 2. Sets it through the normal set path, which pushes the replaced
+     *    expression onto the other stack
+     * 3. Puts the expression back if the set is rejected, e.g. because it
+     *    uses a name that has since been removed
      */
-    fn process_cells_update(
-        cells: Arc<Mutex<HashMap<CellIdentifier, CellInfo>>>,
-        receiver: mpsc::Receiver<UpdateMessage>,
-    ) {
-        while let Ok(msg) = receiver.recv() {
-            match msg {
-                UpdateMessage::Shutdown => break,
-                UpdateMessage::CellUpdate { cell_id } => {
-                    // Step 1: Build dependency graph
-                    let mut dependency_graph: HashMap<CellIdentifier, HashSet<CellIdentifier>> =
-                        HashMap::new();
-                    let mut to_process = VecDeque::new();
-                    let mut discovered = HashSet::new();
-
-                    // Initialize with the changed cell
-                    to_process.push_back(cell_id);
-                    discovered.insert(cell_id);
-
-                    // Build complete dependency graph by doing a BFS
-                    while let Some(current_id) = to_process.pop_front() {
-                        let dependents = {
-                            let cells_lock = cells.lock().unwrap();
-                            cells_lock
-                                .get(&current_id)
-                                .map(|cell| cell.dependents.clone())
-                                .unwrap_or_default()
-                        };
+    fn step_history(&self, cell_id: CellIdentifier, edit: Edit) -> Result<(), SpreadsheetError> {
+        // Step 1: Find the expression to restore
+        let expression = self
+            .undo_history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&cell_id)
+            .and_then(|history| history.source(edit).pop_back())
+            .ok_or(if edit == Edit::Undo {
+                SpreadsheetError::NothingToUndo(cell_id)
+            } else {
+                SpreadsheetError::NothingToRedo(cell_id)
+            })?;
 
-                        for &dep_id in &dependents {
-                            dependency_graph
-                                .entry(dep_id)
-                                .or_default()
-                                .insert(current_id);
+        // Step 2: Restore it
+        let result = self.validate_and_apply(vec![(cell_id, expression.clone())], edit);
 
-                            if discovered.insert(dep_id) {
-                                to_process.push_back(dep_id);
-                            }
-                        }
-                    }
+        // Step 3: Keep it for another try
+        if result.is_err() {
+            self.undo_history
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(cell_id)
+                .or_default()
+                .source(edit)
+                .push_back(expression);
+        }
+        result
+    }
 
-                    // Step 2: Perform topological sort
-                    let mut update_order = Vec::new();
-                    let mut permanent_marks = HashSet::new();
-                    let mut temporary_marks = HashSet::new();
-
-                    // DFS-based topological sort
-                    fn visit(
-                        node: CellIdentifier,
-                        graph: &HashMap<CellIdentifier, HashSet<CellIdentifier>>,
-                        permanent_marks: &mut HashSet<CellIdentifier>,
-                        temporary_marks: &mut HashSet<CellIdentifier>,
-                        sorted: &mut Vec<CellIdentifier>,
-                    ) {
-                        // Skip if already fully processed
-                        if permanent_marks.contains(&node) {
-                            return;
-                        }
+    /**
+     * HELPER FUNCTION
+     * Rejects the batch if any expression has an unresolvable variable,
+     * otherwise applies it, see set_batch
+     */
+    fn validate_and_apply(
+        &self,
+        assignments: Vec<(CellIdentifier, String)>,
+        edit: Edit,
+    ) -> Result<(), SpreadsheetError> {
+        for (cell_id, _) in &assignments {
+            self.check_bounds(cell_id)?;
+        }
+        let names = self.validated_names(
+            assignments
+                .iter()
+                .map(|(_, expression)| expression.as_str()),
+        )?;
+        self.apply_batch(assignments, &names, edit)
+    }
 
-                        // Check for cycles (should never happen in this application)
-                        if temporary_marks.contains(&node) {
-                            return;
-                        }
+    /**
+     * HELPER FUNCTION
+     * Returns the defined names, or an error for the first expression the
+     * sheet won't accept
+     *
+     * Procedure:
+     * 1. Rejects an expression longer than max_expression_length with
+     *    ExpressionTooLong
+     * 2. Rejects a variable that is neither a cell, a valid range nor a
+     *    defined name with InvalidReference
+     * 3. Rejects a range, written out or behind a defined name, covering
+     *    more than max_range_cells cells with RangeTooLarge, before any of
+     *    its cells are listed
+     * 4. Rejects a cell or range reaching past the sheet's bounds with
+     *    OutOfBounds, naming its bottom-right cell
+     */
+    fn validated_names<'a>(
+        &self,
+        expressions: impl IntoIterator<Item = &'a str>,
+    ) -> Result<HashMap<String, Reference>, SpreadsheetError> {
+        let names = self
+            .names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for expression in expressions {
+            // Step 1: Expression length
+            if let Some(max_length) = self.max_expression_length {
+                if expression.chars().count() > max_length {
+                    return Err(SpreadsheetError::ExpressionTooLong(max_length));
+                }
+            }
 
-                        // Mark temporarily for cycle detection
-                        temporary_marks.insert(node);
+            // Step 2: Reject variables that can't be resolved instead of ignoring them
+            if let Some(name) = references::invalid_variables(expression)
+                .into_iter()
+                .find(|name| !names.contains_key(name))
+            {
+                return Err(SpreadsheetError::InvalidReference(name));
+            }
 
-                        // Visit all dependencies
-                        if let Some(deps) = graph.get(&node) {
-                            for &dep in deps {
-                                visit(dep, graph, permanent_marks, temporary_marks, sorted);
-                            }
-                        }
+            // Step 3: Range sizes
+            let references = Self::references_in(expression, &names);
+            if let Some(max_cells) = self.settings.max_range_cells {
+                if let Some((name, _)) = references
+                    .iter()
+                    .find(|(_, reference)| Self::exceeds(reference, max_cells))
+                {
+                    return Err(SpreadsheetError::RangeTooLarge(name.clone(), max_cells));
+                }
+            }
 
-                        // Remove temporary mark and add permanent mark
-                        temporary_marks.remove(&node);
-                        permanent_marks.insert(node);
-                        sorted.push(node);
-                    }
+            // Step 4: Sheet bounds
+            for (_, reference) in &references {
+                self.check_bounds(&reference.corners().1)?;
+            }
+        }
+        Ok(names)
+    }
 
-                    // Perform topological sort starting from all nodes
-                    for &node in dependency_graph.keys() {
-                        if !permanent_marks.contains(&node) {
-                            visit(
-                                node,
-                                &dependency_graph,
-                                &mut permanent_marks,
-                                &mut temporary_marks,
-                                &mut update_order,
-                            );
-                        }
-                    }
+    /**
+     * Public Function
+     * Checks that a cell lies within the sheet's rows and columns, returning
+     * OutOfBounds naming the sheet's last cell if it doesn't
+     */
+    pub fn check_bounds(&self, cell_id: &CellIdentifier) -> Result<(), SpreadsheetError> {
+        if cell_id.col > self.last_cell.col || cell_id.row > self.last_cell.row {
+            return Err(SpreadsheetError::OutOfBounds(*cell_id, self.last_cell));
+        }
+        Ok(())
+    }
 
-                    // Step 3: Process cells in topologically sorted order
-                    for cell_id in update_order {
-                        let (expr, _deps) = {
-                            let cells_lock = cells.lock().unwrap();
-                            if let Some(cell) = cells_lock.get(&cell_id) {
-                                (cell.expression.clone(), cell.dependencies.clone())
-                            } else {
-                                continue;
-                            }
-                        };
+    /**
+     * HELPER FUNCTION
+     * Checks whether a closed range covers more than max_cells cells
+     * Open ranges and whole columns or rows are measured once bounded, see
+     * gather_variables
+     */
+    fn exceeds(reference: &Reference, max_cells: usize) -> bool {
+        matches!(reference, Reference::Range(..)) && reference.cell_count() > max_cells as u64
+    }
 
-                        // Create cell expression evaluator
-                        let cell_expr = CellExpr::new(&expr);
-
-                        // Gather all required variables
-                        let variables = {
-                            let cells_lock = cells.lock().unwrap();
-                            let mut vars = HashMap::new();
-
-                            for var_name in cell_expr.find_variable_names() {
-                                if !var_name.contains('_') {
-                                    // Handle scalar variables
-                                    if let Ok(var_id) = var_name.parse::<CellIdentifier>() {
-                                        if let Some(cell) = cells_lock.get(&var_id) {
-                                            vars.insert(
-                                                var_name,
-                                                CellArgument::Value(cell.value.clone()),
-                                            );
-                                        }
-                                    }
-                                } else if let Some((start, end)) = Self::parse_range(&var_name) {
-                                    // Handle range variables
-                                    let arg = if start.col == end.col {
-                                        // Vertical vector
-                                        let values: Vec<CellValue> = (start.row..=end.row)
-                                            .map(|row| {
-                                                let id = CellIdentifier {
-                                                    col: start.col,
-                                                    row,
-                                                };
-                                                cells_lock
-                                                    .get(&id)
-                                                    .map(|c| c.value.clone())
-                                                    .unwrap_or(CellValue::None)
-                                            })
-                                            .collect();
-                                        CellArgument::Vector(values)
-                                    } else if start.row == end.row {
-                                        // Horizontal vector
-                                        let values: Vec<CellValue> = (start.col..=end.col)
-                                            .map(|col| {
-                                                let id = CellIdentifier {
-                                                    col,
-                                                    row: start.row,
-                                                };
-                                                cells_lock
-                                                    .get(&id)
-                                                    .map(|c| c.value.clone())
-                                                    .unwrap_or(CellValue::None)
-                                            })
-                                            .collect();
-                                        CellArgument::Vector(values)
-                                    } else {
-                                        // Matrix
-                                        let matrix: Vec<Vec<CellValue>> = (start.row..=end.row)
-                                            .map(|row| {
-                                                (start.col..=end.col)
-                                                    .map(|col| {
-                                                        let id = CellIdentifier { col, row };
-                                                        cells_lock
-                                                            .get(&id)
-                                                            .map(|c| c.value.clone())
-                                                            .unwrap_or(CellValue::None)
-                                                    })
-                                                    .collect()
-                                            })
-                                            .collect();
-                                        CellArgument::Matrix(matrix)
-                                    };
-                                    vars.insert(var_name, arg);
-                                }
-                            }
-                            vars
-                        };
+    /**
+     * Public Function
+     * Sets a cell only if its value is the expected one, returning whether
+     * it was set
+     *
+     * Procedure:
+     * 1. Rejects a cell outside the sheet's bounds, or an expression with an
+     *    unresolvable variable, as set does
+     * 2. Takes the log lock, so no other set, and no other compare_and_set,
+     *    can change the cell between the check and the store
+     * 3. Compares the cell's committed value with the expected one, a cell
+     *    that was never set holding None
+     * 4. Evaluates and stores the expression as set does if they match
+     *
+     * Only committed values count: a cascade that is queued or running
+     * hasn't changed the cell yet, and may still overwrite a formula cell's
+     * value after the check
+     */
+    pub fn compare_and_set(
+        &self,
+        cell_id: CellIdentifier,
+        expected: &CellValue,
+        expression: String,
+    ) -> Result<bool, SpreadsheetError> {
+        self.check_bounds(&cell_id)?;
+        let assignments = vec![(cell_id, expression)];
+        let names = self.validated_names(
+            assignments
+                .iter()
+                .map(|(_, expression)| expression.as_str()),
+        )?;
 
-                        // Evaluate cell with gathered variables
-                        let current_time = Instant::now();
-                        match cell_expr.evaluate(&variables) {
-                            Ok(new_value) => {
-                                let mut cells_lock = cells.lock().unwrap();
-                                if let Some(cell) = cells_lock.get_mut(&cell_id) {
-                                    // Only update if this evaluation is newer than the last update
-                                    if current_time > cell.last_update_time {
-                                        cell.value = new_value;
-                                        cell.last_update_time = current_time;
-                                    }
-                                }
-                            }
-                            Err(CellExprEvalError::VariableDependsOnError) => {
-                                let mut cells_lock = cells.lock().unwrap();
-                                if let Some(cell) = cells_lock.get_mut(&cell_id) {
-                                    if current_time > cell.last_update_time {
-                                        cell.value =
-                                            CellValue::Error("VariableDependsOnError".into());
-                                        cell.last_update_time = current_time;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        let current_time = Instant::now();
+        let mut wal = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.get(&cell_id) != *expected {
+            return Ok(false);
         }
+        let updates = self.evaluate_batch(assignments, &names);
+        self.store_batch(updates, current_time, Edit::Set, &mut wal)?;
+        Ok(true)
     }
-}
 
-impl Drop for Spreadsheet {
-    fn drop(&mut self) {
-        // Send shutdown message to worker thread
-        let _ = self.update_sender.send(UpdateMessage::Shutdown);
+    /**
+     * HELPER FUNCTION
+     * Sets several already validated cells as one update
+     *
+     * Procedure:
+     * 1. Records current timestamp
+     * 2. Evaluates each expression, see evaluate_batch
+     * 3. Takes the log lock and stores the batch, see store_batch
+     */
+    fn apply_batch(
+        &self,
+        assignments: Vec<(CellIdentifier, String)>,
+        names: &HashMap<String, Reference>,
+        edit: Edit,
+    ) -> Result<(), SpreadsheetError> {
+        let current_time = Instant::now();
+        let updates = self.evaluate_batch(assignments, names);
+        let mut wal = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+        self.store_batch(updates, current_time, edit, &mut wal)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::thread::sleep;
-    use std::time::Duration;
 
-    #[test]
-    fn test_basic_set_get() {
-        let sheet = Spreadsheet::new();
-        let cell = CellIdentifier { col: 0, row: 0 }; // A1
+    /**
+     * HELPER FUNCTION
+     * Evaluates each expression of a batch against the committed values,
+     * resolving defined names with the given registry, and finds the
+     * references each one depends on
+     */
+    fn evaluate_batch(
+        &self,
+        assignments: Vec<(CellIdentifier, String)>,
+        names: &HashMap<String, Reference>,
+    ) -> Vec<EvaluatedSet> {
+        assignments
+            .into_iter()
+            .map(|(cell_id, expression)| self.evaluate_set(cell_id, expression, names))
+            .collect()
+    }
 
-        assert!(sheet.set(cell, "42".to_string()).is_ok());
-        sleep(Duration::from_millis(50)); // Allow time for processing
-        assert_eq!(sheet.get(&cell), CellValue::Int(42));
+    /**
+     * HELPER FUNCTION
+     * Evaluates one expression against the committed values, finding every
+     * reference it depends on, ranges and names included, and the versions
+     * of the cells it read
+     */
+    fn evaluate_set(
+        &self,
+        cell_id: CellIdentifier,
+        expression: String,
+        names: &HashMap<String, Reference>,
+    ) -> EvaluatedSet {
+        let references: Vec<(String, Reference)> = Self::references_in(&expression, names);
+        let (value, boolean, inputs) = self.compute_value(cell_id, &expression, &references);
+        EvaluatedSet {
+            cell_id,
+            value,
+            boolean,
+            expression,
+            dependencies: references.iter().map(|(_, r)| *r).collect(),
+            inputs,
+        }
     }
 
-    #[test]
-    fn test_sleep_then() {
-        let sheet = Spreadsheet::new();
-        let cell = CellIdentifier { col: 0, row: 0 }; // A1
+    /**
+     * HELPER FUNCTION
+     * Counts the cells that would hold a non-blank expression once a batch
+     * is stored; the last expression given for a cell is the one kept
+     */
+    fn cells_after(&self, updates: &[EvaluatedSet]) -> usize {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let occupied = |cell_id: &CellIdentifier| {
+            cells
+                .get(cell_id)
+                .is_some_and(|cell| !cell.expression.trim().is_empty())
+        };
+        let mut batch: HashMap<CellIdentifier, bool> = HashMap::new();
+        for update in updates {
+            batch.insert(update.cell_id, !update.expression.trim().is_empty());
+        }
 
-        // Set A1 with a 500ms sleep, then value 5
-        assert!(sheet.set(cell, "sleep_then(500, 5)".to_string()).is_ok());
+        let unchanged = cells
+            .keys()
+            .filter(|cell_id| !batch.contains_key(cell_id) && occupied(cell_id))
+            .count();
+        unchanged + batch.values().filter(|filled| **filled).count()
+    }
 
-        // Immediately set A1 with a 200ms sleep, then value 10
-        assert!(sheet.set(cell, "sleep_then(200, 10)".to_string()).is_ok());
+    /**
+     * HELPER FUNCTION
+     * Stores an evaluated batch, given the held log lock
+     *
+     * Procedure:
+     * 1. Rejects the whole batch with CellLimitReached if it would leave
+     *    more than max_cells cells with a non-blank expression
+     * 2. Replaces the dependency edges of every cell, then inserts every cell
+     *    under a single acquisition of the cells lock
+     * 3. Notifies the worker with one message naming every cell, so their
+     *    dependents are recomputed in one pass
+     * 4. Appends each set to the write-ahead log, if enabled; the caller
+     *    holds the log lock throughout, so the log records sets in the order
+     *    applied, and the graph and cells are updated in that same order
+     */
+    fn store_batch(
+        &self,
+        updates: Vec<EvaluatedSet>,
+        current_time: Instant,
+        edit: Edit,
+        wal: &mut Option<WriteAheadLog>,
+    ) -> Result<(), SpreadsheetError> {
+        // Only sets hold the log lock, so the count can't change meanwhile
+        if let Some(max_cells) = self.max_cells {
+            if self.cells_after(&updates) > max_cells {
+                return Err(SpreadsheetError::CellLimitReached(max_cells));
+            }
+        }
 
-        // Wait for both operations to complete
-        sleep(Duration::from_millis(700));
+        // Update cell info and notify dependents
+        let logged: Vec<(CellIdentifier, String)> = if wal.is_some() {
+            updates
+                .iter()
+                .map(|update| (update.cell_id, update.expression.clone()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.update_cell_info(updates, current_time, edit)?;
 
-        // Should have value 10 since it was the more recent update
-        assert_eq!(sheet.get(&cell), CellValue::Int(10));
+        // Record the sets once they have been applied
+        if let Some(wal) = wal.as_mut() {
+            for (cell_id, expression) in logged {
+                if let Err(e) = wal.append(&cell_id, &expression) {
+                    warn!(
+                        "event=wal_write_failed cell={} error={}",
+                        references::a1_name(&cell_id),
+                        e
+                    );
+                }
+            }
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_dependencies() {
-        let sheet = Spreadsheet::new();
+    /**
+     * Public Function
+     * Defines a name that expressions can use in place of a cell or range,
+     * e.g. after define_name("revenue", "A1_A12"), "sum(revenue)" sums
+     * A1_A12
+     *
+     * Procedure:
+     * 1. Rejects names that are cell or range references, keywords, or not
+     *    identifiers, and targets that aren't a cell or range or that reach
+     *    past the sheet's bounds
+     * 2. Stores the name, replacing any earlier definition
+     * 3. Re-applies every formula that uses the name, so its value and
+     *    dependency edges follow the new cells
+     */
+    pub fn define_name(&self, name: &str, target: &str) -> Result<(), SpreadsheetError> {
+        if !references::is_valid_name(name) {
+            return Err(SpreadsheetError::InvalidName(name.to_string()));
+        }
+        let reference = references::parse_reference(target)
+            .ok_or_else(|| SpreadsheetError::InvalidReference(target.to_string()))?;
+        self.check_bounds(&reference.corners().1)?;
+
+        self.names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_string(), reference);
+        self.reapply_users_of(name)
+    }
+
+    /**
+     * Public Function
+     * Removes a defined name; formulas still using it become errors
+     */
+    pub fn remove_name(&self, name: &str) -> Result<(), SpreadsheetError> {
+        if self
+            .names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+            .is_none()
+        {
+            return Err(SpreadsheetError::UnknownName(name.to_string()));
+        }
+        self.reapply_users_of(name)
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Re-applies the expression of every cell that mentions a name, after
+     * the name was defined, redefined or removed
+     */
+    fn reapply_users_of(&self, name: &str) -> Result<(), SpreadsheetError> {
+        let users: Vec<(CellIdentifier, String)> = self
+            .cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, cell)| {
+                references::identifiers(&cell.expression)
+                    .iter()
+                    .any(|token| token == name)
+            })
+            .map(|(cell_id, cell)| (*cell_id, cell.expression.clone()))
+            .collect();
+        if users.is_empty() {
+            return Ok(());
+        }
+
+        let names = self
+            .names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        self.apply_batch(users, &names, Edit::Rewrite)
+    }
+
+    /**
+     * Public Function
+     * Copies the cells between two corners so the corner nearest A1 lands
+     * on dest, shifting every reference in their expressions by the same
+     * number of columns and rows, e.g. B1 = A1 + 1 copied down one row
+     * gives B2 = A2 + 1
+     *
+     * Procedure:
+     * 1. Reads the expression of every set cell in the source rectangle;
+     *    empty source cells leave their destination untouched
+     * 2. Shifts each cell and the references in its expression, replacing a
+     *    reference that would move off the sheet with a ref_error call so
+     *    the destination cell holds a reference error. A cell landing past
+     *    the largest column or row number fails the copy with OutOfBounds,
+     *    as one past the sheet's bounds does in step 3
+     * 3. Sets every destination cell in one batch, so copying onto an
+     *    overlapping rectangle reads only the original expressions
+     */
+    pub fn copy_range(
+        &self,
+        start: CellIdentifier,
+        end: CellIdentifier,
+        dest: CellIdentifier,
+    ) -> Result<(), SpreadsheetError> {
+        let corner = CellIdentifier {
+            col: start.col.min(end.col),
+            row: start.row.min(end.row),
+        };
+        let source = Reference::Range(
+            corner,
+            CellIdentifier {
+                col: start.col.max(end.col),
+                row: start.row.max(end.row),
+            },
+        );
+        let cols = i64::from(dest.col) - i64::from(corner.col);
+        let rows = i64::from(dest.row) - i64::from(corner.row);
+
+        // Step 1: Read the source expressions
+        let mut copied: Vec<(CellIdentifier, String)> = {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            cells
+                .iter()
+                .filter(|(id, cell)| source.contains(id) && !cell.expression.trim().is_empty())
+                .map(|(id, cell)| (*id, cell.expression.clone()))
+                .collect()
+        };
+        copied.sort();
+
+        // Step 2: Shift the cells and their references
+        let assignments: Vec<(CellIdentifier, String)> = copied
+            .into_iter()
+            .map(|(cell_id, expression)| {
+                let (col, row) = (cell_id.col - corner.col, cell_id.row - corner.row);
+                let target = match (dest.col.checked_add(col), dest.row.checked_add(row)) {
+                    (Some(col), Some(row)) => CellIdentifier { col, row },
+                    _ => {
+                        let past = CellIdentifier {
+                            col: dest.col.saturating_add(col),
+                            row: dest.row.saturating_add(row),
+                        };
+                        return Err(SpreadsheetError::OutOfBounds(past, self.last_cell));
+                    }
+                };
+                let shifted = references::map_references(&expression, |reference, anchors| {
+                    reference.offset(cols, rows, anchors)
+                });
+                Ok((target, shifted))
+            })
+            .collect::<Result<_, _>>()?;
+
+        // Step 3: Set the destination cells together
+        self.set_batch(assignments)
+    }
+
+    /**
+     * Public Function
+     * Copies one cell's expression to another cell, shifting its references
+     * by the distance between the two, see copy_range
+     */
+    pub fn copy_cell(
+        &self,
+        src: CellIdentifier,
+        dst: CellIdentifier,
+    ) -> Result<(), SpreadsheetError> {
+        self.copy_range(src, src, dst)
+    }
+
+    /**
+     * Public Function
+     * Moves a cell's expression and value to another cell, pointing every
+     * reference to the source at the destination, e.g. "A1 * 2" becomes
+     * "D4 * 2" when A1 moves to D4
+     *
+     * Procedure:
+     * 1. Rejects a source or destination outside the sheet, and a source
+     *    that holds no expression with CellNotSet; moving a cell onto
+     *    itself changes nothing
+     * 2. Rejects a destination holding an expression with CellOccupied,
+     *    unless overwrite is set
+     * 3. Moves the cell, its history and any name for it as inserting a row
+     *    moves cells, see relocate; an overwritten destination is dropped,
+     *    so references to it become reference errors. Ranges over the
+     *    source keep their cells, as in Excel
+     */
+    pub fn move_cell(
+        &self,
+        src: CellIdentifier,
+        dst: CellIdentifier,
+        overwrite: bool,
+    ) -> Result<(), SpreadsheetError> {
+        // Step 1: Check the cells
+        self.check_bounds(&src)?;
+        self.check_bounds(&dst)?;
+        let expression_in = |cell_id: &CellIdentifier| {
+            self.get_expression(cell_id)
+                .filter(|expression| !expression.trim().is_empty())
+        };
+        if expression_in(&src).is_none() {
+            return Err(SpreadsheetError::CellNotSet(src));
+        }
+        if src == dst {
+            return Ok(());
+        }
+
+        // Step 2: Keep what the destination holds
+        if !overwrite && expression_in(&dst).is_some() {
+            return Err(SpreadsheetError::CellOccupied(dst));
+        }
+
+        // Step 3: Move it
+        self.relocate(|reference| match reference {
+            Reference::Cell(cell_id) if cell_id == src => Some(Reference::Cell(dst)),
+            Reference::Cell(cell_id) if cell_id == dst => None,
+            reference => Some(reference),
+        })
+    }
+
+    /**
+     * Public Function
+     * Inserts an empty row before the given 0-based row, moving every cell
+     * at or below it down one row
+     * References move with the cells they name, so a range grows when the
+     * row is inserted inside it, as in Excel: sum(A1_A3) becomes
+     * sum(A1_A4) when a row is inserted before row 2
+     * Fails with OutOfBounds, changing nothing, if the row is past the
+     * sheet, or if the last row holds a cell or is referenced, as those
+     * would move off the sheet
+     */
+    pub fn insert_row(&self, row: u32) -> Result<(), SpreadsheetError> {
+        self.check_bounds(&CellIdentifier { col: 0, row })?;
+        self.relocate(|reference| Some(reference.after_insert(Line::Row(row))))
+    }
+
+    /**
+     * Public Function
+     * Deletes the given 0-based row, moving every cell below it up one row
+     * Ranges spanning the row shrink; a reference to nothing but cells in
+     * the row becomes a reference error
+     * Fails with OutOfBounds if the row is past the sheet
+     */
+    pub fn delete_row(&self, row: u32) -> Result<(), SpreadsheetError> {
+        self.check_bounds(&CellIdentifier { col: 0, row })?;
+        self.relocate(|reference| reference.after_delete(Line::Row(row)))
+    }
+
+    /**
+     * Public Function
+     * Inserts an empty column before the given 0-based column, see insert_row
+     */
+    pub fn insert_col(&self, col: u32) -> Result<(), SpreadsheetError> {
+        self.check_bounds(&CellIdentifier { col, row: 0 })?;
+        self.relocate(|reference| Some(reference.after_insert(Line::Col(col))))
+    }
+
+    /**
+     * Public Function
+     * Deletes the given 0-based column, see delete_row
+     */
+    pub fn delete_col(&self, col: u32) -> Result<(), SpreadsheetError> {
+        self.check_bounds(&CellIdentifier { col, row: 0 })?;
+        self.relocate(|reference| reference.after_delete(Line::Col(col)))
+    }
+
+    /**
+     * Public Function
+     * Removes every cell from the sheet, leaving it as if newly created
+     *
+     * Procedure:
+     * 1. Replaces the dependency graph with an empty one, so no later
+     *    cascade can reach a cleared cell, and forgets every defined name
+     * 2. Drains the cells map and resets the sheet version to 0 under the
+     *    cells lock, where versions are stamped, then forgets every cell's
+     *    undo history. A changed_since caller holding an older version
+     *    should read from 0 again
+     * 3. Leaves pending updates queued; a cascade only commits to cells that
+     *    still exist, so one in flight finds nothing to write back
+     * 4. Empties the write-ahead log, if enabled, so recovery starts empty too
+     */
+    pub fn clear_all(&self) {
+        let mut wal = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+        *self.graph.lock().unwrap_or_else(PoisonError::into_inner) = DependencyGraph::new();
+        self.names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        {
+            let mut cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            cells.clear();
+            self.counters.version.store(0, Ordering::SeqCst);
+        }
+        self.undo_history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.dirty.store(true, Ordering::SeqCst);
+
+        if let Some(Err(e)) = wal.as_mut().map(|wal| wal.compact(&[])) {
+            warn!("event=wal_write_failed cell=* error={}", e);
+        }
+    }
+
+    /**
+     * Public Function
+     * Re-evaluates a cell from its dependencies' current values, ignoring and
+     * leaving alone its cached value
+     *
+     * Procedure:
+     * 1. Reads the cell's expression, failing if the cell was never set
+     * 2. Resolves and evaluates it exactly as set does
+     * 3. Logs a warning if the fresh value differs from the cached one
+     * 4. Returns the fresh value
+     */
+    pub fn force_recompute_value(
+        &self,
+        cell_id: &CellIdentifier,
+    ) -> Result<CellValue, SpreadsheetError> {
+        let (expression, cached) = {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let cell = cells
+                .get(cell_id)
+                .ok_or(SpreadsheetError::CellNotSet(*cell_id))?;
+            (cell.expression.clone(), cell.value.clone())
+        };
+
+        let names = self
+            .names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let references = Self::references_in(&expression, &names);
+        let (value, _, _) = self.compute_value(*cell_id, &expression, &references);
+
+        if value != cached {
+            warn!(
+                "event=cache_divergence cell={} cached={:?} fresh={:?}",
+                references::a1_name(cell_id),
+                cached,
+                value
+            );
+        }
+        Ok(value)
+    }
+
+    /**
+     * Public Function
+     * Blocks until the worker has finished every update queued before this call
+     *
+     * Procedure:
+     * 1. Sends a flush message carrying a one-shot reply channel
+     * 2. Waits for the worker to answer, which it does only after processing
+     *    every earlier message
+     * 3. Returns immediately if the worker has already shut down
+     */
+    pub fn flush(&self) {
+        let (done_sender, done_receiver) = mpsc::channel();
+        if self
+            .notify_worker(UpdateMessage::Flush { done: done_sender })
+            .is_ok()
+        {
+            let _ = done_receiver.recv();
+        }
+    }
+
+    /**
+     * Public Function
+     * Limits how often a cell's dependents are recomputed
+     *
+     * Procedure:
+     * 1. With Some(interval), a cascade from the cell runs at most once per
+     *    interval; updates in between are collapsed into one deferred cascade
+     *    that still sees the cell's latest value
+     * 2. With None, removes the limit and runs any deferred cascade at once
+     */
+    pub fn set_throttle(&self, cell_id: CellIdentifier, interval: Option<Duration>) {
+        let _ = self.notify_worker(UpdateMessage::Throttle { cell_id, interval });
+    }
+
+    /**
+     * Public Function
+     * Registers a callback that the worker runs with each value it commits,
+     * after releasing the cells lock so the callback can read the sheet
+     *
+     * A set is reported once the worker picks it up, followed by every
+     * dependent it recomputed, in cascade order. An observer only sees
+     * commits made after it was added
+     */
+    pub fn add_observer(
+        &self,
+        observer: impl Fn(CellIdentifier, &CellValue) + Send + Sync + 'static,
+    ) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .observers
+            .push(Arc::new(observer));
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Registers an observer as add_observer does, returning the spreadsheet,
+     * e.g. Spreadsheet::new().with_observer(...)
+     */
+    pub fn with_observer(
+        self,
+        observer: impl Fn(CellIdentifier, &CellValue) + Send + Sync + 'static,
+    ) -> Self {
+        self.add_observer(observer);
+        self
+    }
+
+    /**
+     * Public Function
+     * Registers a callback that the worker runs with each new value of a
+     * cell in the rectangle between two corners, returning an id for unwatch
+     *
+     * Like an observer, a watch sees the value a set stores in its own cell
+     * once the worker picks up that set. The worker runs each watch with a
+     * cell's values in the order they were committed, after releasing the
+     * cells lock, and drops the watch as soon as it returns false, e.g.
+     * because the client it writes to has disconnected
+     */
+    pub fn watch(
+        &self,
+        start: CellIdentifier,
+        end: CellIdentifier,
+        watcher: impl Fn(CellIdentifier, &CellValue) -> bool + Send + Sync + 'static,
+    ) -> u64 {
+        let region = Reference::Range(
+            CellIdentifier {
+                col: start.col.min(end.col),
+                row: start.row.min(end.row),
+            },
+            CellIdentifier {
+                col: start.col.max(end.col),
+                row: start.row.max(end.row),
+            },
+        );
+
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let id = subscribers.next_watch_id;
+        subscribers.next_watch_id += 1;
+        subscribers.watches.push((id, region, Arc::new(watcher)));
+        id
+    }
+
+    /**
+     * Public Function
+     * Returns a channel that receives every value the worker commits to any
+     * cell, in the order a watch sees them, see watch
+     *
+     * Each call makes an independent subscription. Dropping the receiver
+     * ends it; the worker notices on its next send and stops sending
+     */
+    pub fn subscribe(&self) -> mpsc::Receiver<(CellIdentifier, CellValue)> {
+        let (sender, receiver) = mpsc::channel();
+        self.watch(
+            CellIdentifier { col: 0, row: 0 },
+            CellIdentifier {
+                col: u32::MAX,
+                row: u32::MAX,
+            },
+            move |cell_id, value| sender.send((cell_id, value.clone())).is_ok(),
+        );
+        receiver
+    }
+
+    /**
+     * Public Function
+     * Removes a watch, returning whether it was still registered
+     */
+    pub fn unwatch(&self, id: u64) -> bool {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let before = subscribers.watches.len();
+        subscribers
+            .watches
+            .retain(|(watch_id, _, _)| *watch_id != id);
+        subscribers.watches.len() < before
+    }
+
+    /**
+     * Public Function
+     * Returns how many cell re-evaluations the worker has performed
+     */
+    pub fn recomputations(&self) -> usize {
+        self.counters.recomputed.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Public Function
+     * Returns how many recompute passes the worker has run, where updates
+     * that arrive together share a single pass
+     */
+    pub fn recompute_passes(&self) -> usize {
+        self.counters.passes.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Public Function
+     * Returns the number of messages waiting in each update worker's queue
+     * The sheet runs a single worker, so this always has one entry
+     */
+    pub fn worker_backlogs(&self) -> Vec<usize> {
+        vec![self.counters.queued.load(Ordering::SeqCst)]
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Sends a message to the worker, counting it as queued until received
+     */
+    fn notify_worker(&self, msg: UpdateMessage) -> Result<(), mpsc::SendError<UpdateMessage>> {
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        self.update_sender.send(msg).inspect_err(|_| {
+            self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+
+    /**
+     * Public Function
+     * Switches the notation accepted by get_by_name and set_by_name
+     */
+    pub fn set_address_mode(&self, mode: AddressMode) {
+        *self
+            .address_mode
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = mode;
+    }
+
+    /**
+     * Public Function
+     * Returns the notation currently accepted for cell names
+     */
+    pub fn address_mode(&self) -> AddressMode {
+        *self
+            .address_mode
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /**
+     * Public Function
+     * Parses a cell name in the current address mode
+     *
+     * Procedure:
+     * 1. Tries A1 notation, which is accepted in every mode
+     * 2. In R1C1 mode, falls back to R1C1 notation
+     * 3. Returns an error naming the input if neither applies
+     */
+    pub fn parse_cell_name(&self, name: &str) -> Result<CellIdentifier, String> {
+        if let Ok(cell_id) = name.parse::<CellIdentifier>() {
+            return Ok(cell_id);
+        }
+
+        match self.address_mode() {
+            AddressMode::R1C1 => references::parse_r1c1(name)
+                .ok_or_else(|| format!("Error parsing cell position: {name}")),
+            AddressMode::A1 => Err(format!("Error parsing cell position: {name}")),
+        }
+    }
+
+    /**
+     * Public Function
+     * Gets the value of a cell addressed by name
+     */
+    pub fn get_by_name(&self, name: &str) -> Result<CellValue, String> {
+        let cell_id = self.parse_cell_name(name)?;
+        Ok(self.get(&cell_id))
+    }
+
+    /**
+     * Public Function
+     * Sets a cell addressed by name
+     *
+     * Procedure:
+     * 1. Parses the cell name in the current address mode
+     * 2. In R1C1 mode, rewrites R1C1 references in the expression to A1 so
+     *    stored formulas are always in one notation
+     * 3. Sets the cell as normal
+     */
+    pub fn set_by_name(&self, name: &str, expression: String) -> Result<(), String> {
+        let cell_id = self.parse_cell_name(name)?;
+        let expression = match self.address_mode() {
+            AddressMode::R1C1 => references::r1c1_to_a1(&expression),
+            AddressMode::A1 => expression,
+        };
+
+        self.set(cell_id, expression)
+            .map_err(|e| format!("Error: {}", e))
+    }
+
+    /**
+     * Public Function
+     * Lists every populated cell with its expression and current value
+     *
+     * Procedure:
+     * 1. Acquires lock on cells
+     * 2. Skips cells whose value is empty, e.g. ones cleared with an empty expression
+     * 3. Returns the rest sorted by (row, col), so cells read like the sheet
+     */
+    pub fn list_cells(&self) -> Vec<(CellIdentifier, String, CellValue)> {
+        self.populated_cells(|_| true)
+    }
+
+    /**
+     * Public Function
+     * Lists the populated cells inside a region, given by any two opposite
+     * corners, as list_cells does
+     */
+    pub fn list_cells_in_range(
+        &self,
+        start: CellIdentifier,
+        end: CellIdentifier,
+    ) -> Vec<(CellIdentifier, String, CellValue)> {
+        let region = Reference::Range(
+            CellIdentifier {
+                col: start.col.min(end.col),
+                row: start.row.min(end.row),
+            },
+            CellIdentifier {
+                col: start.col.max(end.col),
+                row: start.row.max(end.row),
+            },
+        );
+        self.populated_cells(|cell_id| region.contains(cell_id))
+    }
+
+    /**
+     * Public Function
+     * Counts the cells holding a value; cleared cells don't count
+     */
+    pub fn count_nonempty(&self) -> usize {
+        self.cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .filter(|cell| cell.value != CellValue::None)
+            .count()
+    }
+
+    /**
+     * Public Function
+     * Checks whether a cell holds a value, without copying it; a cleared
+     * cell, like one never set, isn't contained
+     */
+    pub fn contains(&self, cell_id: &CellIdentifier) -> bool {
+        self.cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cell_id)
+            .is_some_and(|cell| cell.value != CellValue::None)
+    }
+
+    /**
+     * Public Function
+     * Counts the populated cells, the same ones count_nonempty counts
+     */
+    pub fn len(&self) -> usize {
+        self.count_nonempty()
+    }
+
+    /**
+     * Public Function
+     * Checks whether no cell holds a value
+     */
+    pub fn is_empty(&self) -> bool {
+        !self
+            .cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .any(|cell| cell.value != CellValue::None)
+    }
+
+    /**
+     * Public Function
+     * Computes a sum, minimum, maximum, mean or count over the integers in a
+     * column, row or range
+     * See aggregate_many for how cells are read
+     */
+    pub fn aggregate(
+        &self,
+        target: AggTarget,
+        kind: AggKind,
+        skip_errors: bool,
+    ) -> Result<CellValue, AggError> {
+        Ok(self.aggregate_many(target, &[kind], skip_errors)?.remove(0))
+    }
+
+    /**
+     * Public Function
+     * Computes several aggregates over the same cells, in the order given
+     *
+     * Procedure:
+     * 1. Acquires lock on cells once and collects the integers in the target,
+     *    skipping empty cells and ones holding text
+     * 2. Fails with the first cell in the target, by (row, col), that holds
+     *    an error, unless skip_errors is set, in which case errors are skipped
+     *    like empty cells
+     * 3. Applies each aggregate to the integers collected
+     */
+    pub fn aggregate_many(
+        &self,
+        target: AggTarget,
+        kinds: &[AggKind],
+        skip_errors: bool,
+    ) -> Result<Vec<CellValue>, AggError> {
+        // Step 1: Integers in the target
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut numbers = Vec::new();
+        let mut errors = Vec::new();
+        for (cell_id, cell) in cells.iter().filter(|(cell_id, _)| target.contains(cell_id)) {
+            match cell.value {
+                CellValue::Int(number) => numbers.push(number),
+                CellValue::Error(_) => errors.push(*cell_id),
+                _ => {}
+            }
+        }
+        drop(cells);
+
+        // Step 2: Error cells
+        if !skip_errors {
+            if let Some(cell_id) = errors
+                .iter()
+                .min_by_key(|cell_id| (cell_id.row, cell_id.col))
+            {
+                return Err(AggError::ErrorCell(*cell_id));
+            }
+        }
+
+        // Step 3: Aggregates
+        kinds.iter().map(|kind| kind.apply(&numbers)).collect()
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Collects the populated cells the filter keeps, sorted by (row, col),
+     * under a single acquisition of the cells lock
+     */
+    fn populated_cells(
+        &self,
+        keep: impl Fn(&CellIdentifier) -> bool,
+    ) -> Vec<(CellIdentifier, String, CellValue)> {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut listed: Vec<(CellIdentifier, String, CellValue)> = cells
+            .iter()
+            .filter(|(cell_id, cell)| cell.value != CellValue::None && keep(cell_id))
+            .map(|(cell_id, cell)| (*cell_id, cell.expression.clone(), cell.value.clone()))
+            .collect();
+        listed.sort_by_key(|(cell_id, _, _)| (cell_id.row, cell_id.col));
+        listed
+    }
+
+    /**
+     * Public Function
+     * Serializes the sheet's dependencies as JSON, with ranges kept compact
+     * See DependencyGraph::to_json for the format
+     */
+    pub fn dependencies_json(&self) -> String {
+        self.graph
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .to_json()
+    }
+
+    /**
+     * Public Function
+     * Serializes the sheet's dependencies as a Graphviz DOT digraph, with
+     * ranges kept compact
+     * See DependencyGraph::to_dot for the format
+     */
+    pub fn dependencies_dot(&self) -> String {
+        self.graph
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .to_dot()
+    }
+
+    /**
+     * Public Function
+     * Lists the cells holding an error inside a rectangular region
+     *
+     * Procedure:
+     * 1. Orders the corners so either diagonal describes the same region
+     * 2. Acquires lock on cells and keeps error values inside the region,
+     *    dependency errors included
+     * 3. Returns them with their messages, sorted by (col, row)
+     */
+    pub fn error_cells_in_range(
+        &self,
+        start: CellIdentifier,
+        end: CellIdentifier,
+    ) -> Vec<(CellIdentifier, String)> {
+        let region = Reference::Range(
+            CellIdentifier {
+                col: start.col.min(end.col),
+                row: start.row.min(end.row),
+            },
+            CellIdentifier {
+                col: start.col.max(end.col),
+                row: start.row.max(end.row),
+            },
+        );
+
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut errors: Vec<(CellIdentifier, String)> = cells
+            .iter()
+            .filter(|(cell_id, _)| region.contains(cell_id))
+            .filter_map(|(cell_id, cell)| match &cell.value {
+                CellValue::Error(message) => Some((*cell_id, message.clone())),
+                _ => None,
+            })
+            .collect();
+        errors.sort_by_key(|(cell_id, _)| *cell_id);
+        errors
+    }
+
+    /**
+     * Public Function
+     * Returns the cells whose current value equals the given one, sorted by
+     * (row, col) so matches read like the sheet
+     * Values computed by the worker are matched as soon as they are committed
+     */
+    pub fn find_by_value(&self, value: &CellValue) -> Vec<CellIdentifier> {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut found: Vec<CellIdentifier> = cells
+            .iter()
+            .filter(|(_, cell)| cell.value == *value)
+            .map(|(cell_id, _)| *cell_id)
+            .collect();
+        found.sort_by_key(|cell_id| (cell_id.row, cell_id.col));
+        found
+    }
+
+    /**
+     * Public Function
+     * Returns the cells whose expression, as typed, contains the given text,
+     * sorted by (row, col)
+     * The match is on the text alone, so "A1" also finds "A10 + 1"
+     */
+    pub fn find_by_expression(&self, needle: &str) -> Vec<CellIdentifier> {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut found: Vec<CellIdentifier> = cells
+            .iter()
+            .filter(|(_, cell)| cell.expression.contains(needle))
+            .map(|(cell_id, _)| *cell_id)
+            .collect();
+        found.sort_by_key(|cell_id| (cell_id.row, cell_id.col));
+        found
+    }
+
+    /**
+     * Public Function
+     * Returns the cells a cell reads from, sorted by (col, row)
+     *
+     * Procedure:
+     * 1. Copies the cell's stored references under the graph lock
+     * 2. Bounds open ranges to their last populated row under the cells lock
+     * 3. Expands every reference into its cells, dropping duplicates
+     */
+    pub fn dependencies_of(&self, cell_id: &CellIdentifier) -> Vec<CellIdentifier> {
+        let references: Vec<Reference> = self
+            .graph
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .references_of(*cell_id)
+            .to_vec();
+
+        let bounded: Vec<Reference> = {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            references
+                .into_iter()
+                .map(|reference| Self::bound_reference(reference, &cells))
+                .collect()
+        };
+
+        let mut dependencies: Vec<CellIdentifier> =
+            bounded.iter().flat_map(Reference::cells).collect();
+        dependencies.sort();
+        dependencies.dedup();
+        dependencies
+    }
+
+    /**
+     * Public Function
+     * Returns the cells that read from a cell, sorted by (col, row)
+     * Only direct readers are returned unless transitive is set, in which
+     * case every cell the worker would recompute after a change is returned
+     */
+    pub fn dependents_of(&self, cell_id: &CellIdentifier, transitive: bool) -> Vec<CellIdentifier> {
+        let graph = self.graph.lock().unwrap_or_else(PoisonError::into_inner);
+        if !transitive {
+            return graph.dependents_of(*cell_id);
+        }
+
+        let mut dependents = graph.topo_order_from(&[*cell_id]);
+        dependents.sort();
+        dependents
+    }
+
+    /**
+     * Public Function
+     * Returns the number of reverse dependency edges stored for the sheet
+     */
+    pub fn dependency_edges(&self) -> usize {
+        self.graph
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .edge_count()
+    }
+
+    /**
+     * Public Function
+     * Finds the cell whose own error a VariableDependsOnError cell inherits
+     *
+     * Procedure:
+     * 1. Snapshots every error value under the cells lock
+     * 2. Walks the cell's references breadth-first under the graph lock,
+     *    stepping only through cells that inherited their error
+     * 3. At the first level holding a genuine error, returns the smallest
+     *    such cell by (col, row) with its error message, so the answer is stable
+     * 4. Returns None if no genuine error is reachable
+     */
+    pub fn error_source(&self, cell_id: &CellIdentifier) -> Option<(CellIdentifier, String)> {
+        let errors: Vec<(CellIdentifier, String)> = {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut errors: Vec<(CellIdentifier, String)> = cells
+                .iter()
+                .filter_map(|(id, cell)| match &cell.value {
+                    CellValue::Error(message) => Some((*id, message.clone())),
+                    _ => None,
+                })
+                .collect();
+            errors.sort();
+            errors
+        };
+
+        let graph = self.graph.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut visited = HashSet::from([*cell_id]);
+        let mut level = vec![*cell_id];
+
+        while !level.is_empty() {
+            let mut genuine: Option<&(CellIdentifier, String)> = None;
+            let mut next_level = Vec::new();
+
+            for node in &level {
+                let references = graph.references_of(*node);
+                for error in &errors {
+                    if !references
+                        .iter()
+                        .any(|reference| reference.contains(&error.0))
+                    {
+                        continue;
+                    }
+                    if error.1 != "VariableDependsOnError" {
+                        if genuine.is_none_or(|current| error < current) {
+                            genuine = Some(error);
+                        }
+                    } else if visited.insert(error.0) {
+                        next_level.push(error.0);
+                    }
+                }
+            }
+
+            if let Some(error) = genuine {
+                return Some(error.clone());
+            }
+            next_level.sort();
+            level = next_level;
+        }
+
+        None
+    }
+
+    /**
+     * Public Function
+     * Reports whether the sheet's worker and locks are healthy
+     *
+     * Procedure:
+     * 1. Checks whether the worker thread has exited
+     * 2. Checks both locks for poisoning without acquiring them
+     * 3. Reads the pending update count and the sheet's age
+     */
+    pub fn health(&self) -> HealthReport {
+        HealthReport {
+            worker_alive: self
+                .worker
+                .as_ref()
+                .is_some_and(|worker| !worker.is_finished()),
+            cells_poisoned: self.cells.is_poisoned(),
+            graph_poisoned: self.graph.is_poisoned(),
+            pending_updates: self.counters.queued.load(Ordering::SeqCst),
+            uptime: self.created.elapsed(),
+        }
+    }
+
+    /**
+     * Public Function
+     * Collects statistics about the sheet
+     *
+     * Procedure:
+     * 1. Counts the cells holding a value, and those holding an error, under
+     *    the cells lock
+     * 2. Releases it, then counts edges and measures the longest chain under
+     *    the graph lock
+     * 3. Reads the worker's queue depth, the evaluations made by set and by
+     *    the worker so far, and the sheet's age
+     */
+    pub fn stats(&self) -> SheetStats {
+        let (populated, errors) = {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let populated: Vec<CellIdentifier> = cells
+                .iter()
+                .filter(|(_, cell)| cell.value != CellValue::None)
+                .map(|(cell_id, _)| *cell_id)
+                .collect();
+            let errors = cells
+                .values()
+                .filter(|cell| matches!(cell.value, CellValue::Error(_)))
+                .count();
+            (populated, errors)
+        };
+
+        let evaluator = self.settings.evaluator.as_deref();
+        let graph = self.graph.lock().unwrap_or_else(PoisonError::into_inner);
+        SheetStats {
+            cells: populated.len(),
+            errors,
+            edges: graph.edge_count(),
+            max_chain_depth: graph.longest_chain(&populated),
+            queue_depth: self.counters.queued.load(Ordering::SeqCst),
+            evaluations: self.counters.evaluated.load(Ordering::Relaxed)
+                + self.counters.recomputed.load(Ordering::Relaxed),
+            evaluator_threads: evaluator.map_or(0, |evaluator| evaluator.threads_started()),
+            abandoned_evaluations: evaluator.map_or(0, |evaluator| evaluator.abandoned()),
+            uptime: self.created.elapsed(),
+        }
+    }
+
+    /**
+     * Public Function
+     * Computes a stable hash of the sheet's structure
+     *
+     * Procedure:
+     * 1. Acquires lock on cells and collects each cell's coordinates and expression
+     * 2. Sorts the cells by (col, row) so HashMap order doesn't matter
+     * 3. Feeds every entry through 64-bit FNV-1a, which is stable across runs
+     *    and Rust versions
+     * 4. Returns the hash, which ignores evaluated values entirely
+     */
+    pub fn content_hash(&self) -> u64 {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut entries: Vec<(&CellIdentifier, &str)> = cells
+            .iter()
+            .map(|(cell_id, cell)| (cell_id, cell.expression.as_str()))
+            .collect();
+        entries.sort();
+
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut feed = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+
+        for (cell_id, expression) in entries {
+            feed(&cell_id.col.to_le_bytes());
+            feed(&cell_id.row.to_le_bytes());
+            // Length prefix keeps adjacent expressions from running together
+            feed(&(expression.len() as u64).to_le_bytes());
+            feed(expression.as_bytes());
+        }
+
+        hash
+    }
+
+    /**
+     * Public Function
+     * Writes the evaluated values of the sheet as CSV
+     */
+    pub fn export_csv(&self, writer: impl Write) -> io::Result<()> {
+        self.write_csv(writer).map(|_| ())
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Writes the bounding box of every non-empty cell as CSV and returns the
+     * number of rows written
+     *
+     * Procedure:
+     * 1. Acquires lock on cells once, finding the used extents and copying
+     *    the values inside them, so a concurrent set can't tear a row
+     * 2. Writes one line per row in order, one field per column; empty cells
+     *    become empty fields, errors become #ERROR, booleans become 1 or 0
+     *    as get shows them, and strings are quoted when they hold a comma,
+     *    quote or line break
+     * 3. Writes nothing for a sheet without values
+     */
+    pub(crate) fn write_csv(&self, mut writer: impl Write) -> io::Result<usize> {
+        // Step 1: Snapshot the used region
+        let values: HashMap<CellIdentifier, CellValue> = {
+            let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            cells
+                .iter()
+                .filter(|(_, cell)| cell.value != CellValue::None)
+                .map(|(cell_id, cell)| (*cell_id, cell.value.clone()))
+                .collect()
+        };
+        let (Some(min_col), Some(max_col), Some(min_row), Some(max_row)) = (
+            values.keys().map(|id| id.col).min(),
+            values.keys().map(|id| id.col).max(),
+            values.keys().map(|id| id.row).min(),
+            values.keys().map(|id| id.row).max(),
+        ) else {
+            return Ok(0);
+        };
+
+        // Step 2: Write the rows
+        for row in min_row..=max_row {
+            let fields: Vec<String> = (min_col..=max_col)
+                .map(|col| match values.get(&CellIdentifier { col, row }) {
+                    Some(CellValue::Int(n)) => n.to_string(),
+                    Some(CellValue::String(text)) => csv::quote(text),
+                    Some(CellValue::Error(_)) => "#ERROR".to_string(),
+                    Some(CellValue::None) | None => String::new(),
+                })
+                .collect();
+            writeln!(writer, "{}", fields.join(","))?;
+        }
+        writer.flush()?;
+
+        Ok((max_row - min_row + 1) as usize)
+    }
+
+    /**
+     * Public Function
+     * Sets a block of cells from CSV, with its first field at the anchor
+     *
+     * Procedure:
+     * 1. Reads and parses the whole CSV before setting anything, so a
+     *    malformed file changes nothing
+     * 2. Sets the field in CSV row i, column j at (anchor.col + j,
+     *    anchor.row + i); integers are set as numbers and every other
+     *    non-empty field as a string literal, while empty fields are skipped
+     * 3. Collects each field that fails to set, lands past the sheet's
+     *    bounds or evaluates to an error as (row, col, error) with 0-based
+     *    CSV positions, and carries on
+     * 4. Flushes once at the end so formulas over the region recompute
+     */
+    pub fn import_csv(
+        &self,
+        anchor: CellIdentifier,
+        mut reader: impl Read,
+    ) -> io::Result<Vec<(u32, u32, String)>> {
+        // Step 1: Parse the CSV
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let rows = csv::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Step 2: Set each field
+        let mut failures = Vec::new();
+        for (i, row) in (0u32..).zip(&rows) {
+            for (j, field) in (0u32..).zip(row) {
+                if field.is_empty() {
+                    continue;
+                }
+
+                let (Some(col), Some(row)) = (anchor.col.checked_add(j), anchor.row.checked_add(i))
+                else {
+                    let message = format!(
+                        "cell is outside the sheet bounds (max {})",
+                        references::a1_name(&self.last_cell)
+                    );
+                    failures.push((i, j, message));
+                    continue;
+                };
+                let cell_id = CellIdentifier { col, row };
+                let expression = match field.trim().parse::<i64>() {
+                    Ok(n) => n.to_string(),
+                    Err(_) => csv::string_literal(field),
+                };
+
+                // Step 3: Record failures without stopping
+                match self.set(cell_id, expression) {
+                    Err(e) => failures.push((i, j, e.to_string())),
+                    Ok(()) => {
+                        if let CellValue::Error(message) = self.get(&cell_id) {
+                            failures.push((i, j, message));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Step 4: Let dependents of the region catch up
+        self.flush();
+        Ok(failures)
+    }
+
+    /**
+     * Public Function
+     * Rebuilds a spreadsheet from a write-ahead log and keeps logging to it
+     *
+     * Procedure:
+     * 1. Reads the log, skipping partial or corrupt lines with a warning; a
+     *    missing log gives an empty sheet
+     * 2. Re-applies every set in log order, logging and skipping any that fail
+     * 3. Waits for the worker to settle
+     * 4. Compacts the log to one entry per non-blank cell, sorted by
+     *    (col, row), and appends later sets to it
+     */
+    pub fn recover(path: impl AsRef<Path>) -> io::Result<Spreadsheet> {
+        let path = path.as_ref();
+        let sheet = Spreadsheet::new();
+
+        // Steps 1-3: Replay the log without logging the replay itself
+        for (cell_id, expression) in WriteAheadLog::read(path)? {
+            if let Err(e) = sheet.set(cell_id, expression) {
+                warn!(
+                    "event=wal_replay_failed cell={} error={}",
+                    references::a1_name(&cell_id),
+                    e
+                );
+            }
+        }
+        sheet.flush();
+
+        // Step 4: Compact the log and attach it
+        let mut wal = WriteAheadLog::open(path)?;
+        wal.compact(&Self::saved_expressions(&sheet.cells))?;
+        *sheet.wal.lock().unwrap_or_else(PoisonError::into_inner) = Some(wal);
+
+        Ok(sheet)
+    }
+
+    /**
+     * Public Function
+     * Saves every cell's expression to a JSON snapshot file, replacing the
+     * file only once the snapshot is fully written
+     */
+    pub fn save_to_path(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        Self::snapshot_of(&self.cells).write(path.as_ref())
+    }
+
+    /**
+     * Public Function
+     * Creates a spreadsheet from a JSON snapshot file written by save_to_path
+     *
+     * Procedure, shared with from_json:
+     * 1. Reads the snapshot, failing only if the file can't be read or parsed
+     * 2. Sets every cell in dependency order, so forward references are set
+     *    after the cells they name and values and dependents are rebuilt
+     * 3. Logs and skips any cell that can't be set, instead of aborting the
+     *    load; expressions that evaluate to an error are stored as usual
+     * 4. Waits for the worker to settle before returning the sheet
+     */
+    pub fn load_from_path(path: impl AsRef<Path>) -> io::Result<Spreadsheet> {
+        Self::load_from_path_with_options(path, SpreadsheetOptions::default())
+    }
+
+    /**
+     * Public Function
+     * Creates a spreadsheet with the given options from a JSON snapshot
+     * file, as load_from_path does; a cell the options' limits reject is
+     * logged and skipped
+     */
+    pub fn load_from_path_with_options(
+        path: impl AsRef<Path>,
+        options: SpreadsheetOptions,
+    ) -> io::Result<Spreadsheet> {
+        Ok(Self::from_snapshot(
+            &Snapshot::read(path.as_ref())?,
+            options,
+        ))
+    }
+
+    /**
+     * Public Function
+     * Serializes every non-blank cell as a JSON array of
+     * {"cell", "expr", "value"} objects sorted by (col, row), each value
+     * tagged by its type, e.g.
+     * [{"cell":"A1","expr":"5","value":{"type":"int","value":5}}]
+     * Values are read under one acquisition of the cells lock, so call
+     * flush first for values every pending update has reached
+     */
+    pub fn to_json(&self) -> String {
+        let mut exported: Vec<(CellIdentifier, ExportedCell)> = self
+            .cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, cell)| !cell.expression.trim().is_empty())
+            .map(|(cell_id, cell)| {
+                let exported = ExportedCell {
+                    cell: references::a1_name(cell_id),
+                    expr: cell.expression.clone(),
+                    value: cell.value.clone().into(),
+                };
+                (*cell_id, exported)
+            })
+            .collect();
+        exported.sort_by_key(|(cell_id, _)| *cell_id);
+
+        let cells: Vec<ExportedCell> = exported.into_iter().map(|(_, cell)| cell).collect();
+        serde_json::to_string(&cells).unwrap_or_default()
+    }
+
+    /**
+     * Public Function
+     * Creates a spreadsheet from JSON written by to_json, setting every
+     * expression as load_from_path does; the exported values are ignored
+     * and recomputed. Fails only if the JSON doesn't parse
+     */
+    pub fn from_json(json: &str) -> serde_json::Result<Spreadsheet> {
+        Ok(Self::from_snapshot(
+            &Snapshot::from_export(json)?,
+            SpreadsheetOptions::default(),
+        ))
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Creates a spreadsheet with the given options holding a snapshot's
+     * cells, shared by load_from_path and from_json
+     */
+    fn from_snapshot(snapshot: &Snapshot, options: SpreadsheetOptions) -> Spreadsheet {
+        let sheet = Spreadsheet::with_options(options);
+
+        for (cell_id, expression) in snapshot.load_order() {
+            if let Err(e) = sheet.set(cell_id, expression.to_string()) {
+                warn!(
+                    "event=load_failed cell={} error={:?}",
+                    references::a1_name(&cell_id),
+                    e
+                );
+            }
+        }
+        sheet.flush();
+
+        sheet
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Collects every non-blank expression under the cells lock, sorted by
+     * (col, row), leaving out cleared cells
+     */
+    fn saved_expressions(
+        cells: &Mutex<HashMap<CellIdentifier, CellInfo>>,
+    ) -> Vec<(CellIdentifier, String)> {
+        let mut saved: Vec<(CellIdentifier, String)> = cells
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, cell)| !cell.expression.trim().is_empty())
+            .map(|(cell_id, cell)| (*cell_id, cell.expression.clone()))
+            .collect();
+        saved.sort_by_key(|(cell_id, _)| *cell_id);
+        saved
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Builds a snapshot of every non-blank cell, shared by save_to_path and
+     * the autosave thread
+     */
+    fn snapshot_of(cells: &Mutex<HashMap<CellIdentifier, CellInfo>>) -> Snapshot {
+        Snapshot {
+            cells: Self::saved_expressions(cells)
+                .into_iter()
+                .map(|(cell_id, expression)| SavedCell {
+                    cell: references::a1_name(&cell_id),
+                    expression,
+                })
+                .collect(),
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Autosave thread function that periodically saves the sheet
+     *
+     * Procedure:
+     * 1. Sleeps for the interval, waking early when the sheet shuts down
+     * 2. Skips the save when no cell was set or cleared since the last one
+     * 3. Otherwise writes the snapshot to a temporary file and renames it
+     *    over the target, so a crash never leaves a half-written file
+     * 4. Marks the sheet dirty again if the save fails, so it is retried
+     * 5. Saves one final time, if needed, before exiting on shutdown
+     */
+    fn autosave(
+        cells: Arc<Mutex<HashMap<CellIdentifier, CellInfo>>>,
+        dirty: Arc<AtomicBool>,
+        autosave: Autosave,
+        stop: mpsc::Receiver<()>,
+    ) {
+        loop {
+            let stopping = !matches!(
+                stop.recv_timeout(autosave.interval),
+                Err(mpsc::RecvTimeoutError::Timeout)
+            );
+
+            if dirty.swap(false, Ordering::SeqCst) {
+                if let Err(e) = Self::snapshot_of(&cells).write(&autosave.path) {
+                    dirty.store(true, Ordering::SeqCst);
+                    warn!(
+                        "event=autosave_failed path={} error={}",
+                        autosave.path.display(),
+                        e
+                    );
+                }
+            }
+
+            if stopping {
+                break;
+            }
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Moves every cell and rewrites every reference after rows or columns
+     * are inserted or deleted, or a cell is moved
+     *
+     * Procedure:
+     * 1. Takes the log lock, so no set can interleave, and fails with
+     *    OutOfBounds, changing nothing, if the mapping would send a cell
+     *    holding an expression, or a reference, past the sheet's last cell
+     * 2. Moves the cells each defined name stands for, dropping names whose
+     *    cells are all deleted
+     * 3. Moves each cell to where the mapping sends it, stamping it with a
+     *    new sheet version, dropping cells it deletes or sends off the
+     *    sheet, which can only be cleared ones, and rewrites the
+     *    references in each expression, turning deleted ones into ref_error
+     *    calls; each cell's undo history moves and is rewritten the same way
+     * 4. Empties the dependency graph, since every edge may have moved
+     * 5. Re-evaluates every cell and stores it with its new edges as set
+     *    does, so the worker recomputes every dependent in one pass
+     * 6. Rewrites the write-ahead log, if enabled, to the moved cells
+     */
+    fn relocate(
+        &self,
+        map: impl Fn(Reference) -> Option<Reference>,
+    ) -> Result<(), SpreadsheetError> {
+        let current_time = Instant::now();
+
+        // Step 1: Hold off sets, and keep everything on the sheet
+        let mut wal = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+        self.check_relocation(&map)?;
+
+        // Step 2: Move the names
+        let names: HashMap<String, Reference> = {
+            let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+            *names = names
+                .drain()
+                .filter_map(|(name, reference)| Some((name, map(reference)?)))
+                .collect();
+            names.clone()
+        };
+
+        // Step 3: Move the cells
+        let moved: Vec<(CellIdentifier, String)> = {
+            let mut cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut moved: Vec<(CellIdentifier, String)> = Vec::with_capacity(cells.len());
+            let mut relocated: HashMap<CellIdentifier, CellInfo> = HashMap::new();
+            let mut version: Option<u64> = None; // Shared by every cell that moves
+            for (cell_id, mut cell) in cells.drain() {
+                let Some(Reference::Cell(new_id)) = map(Reference::Cell(cell_id)) else {
+                    continue;
+                };
+                if self.check_bounds(&new_id).is_err() {
+                    continue;
+                }
+                cell.expression =
+                    references::map_references(&cell.expression, |reference, _| map(reference));
+                if new_id != cell_id {
+                    cell.version = *version.get_or_insert_with(|| self.counters.next_version());
+                }
+                moved.push((new_id, cell.expression.clone()));
+                relocated.insert(new_id, cell);
+            }
+            *cells = relocated;
+            moved
+        };
+        {
+            let mut history = self
+                .undo_history
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            *history = history
+                .drain()
+                .filter_map(|(cell_id, mut cell_history)| {
+                    let Some(Reference::Cell(new_id)) = map(Reference::Cell(cell_id)) else {
+                        return None;
+                    };
+                    for expression in cell_history.undo.iter_mut().chain(&mut cell_history.redo) {
+                        *expression =
+                            references::map_references(expression, |reference, _| map(reference));
+                    }
+                    Some((new_id, cell_history))
+                })
+                .collect();
+        }
+
+        // Step 4: Drop the old edges
+        *self.graph.lock().unwrap_or_else(PoisonError::into_inner) = DependencyGraph::new();
+
+        // Step 5: Re-evaluate and store every cell
+        let updates: Vec<EvaluatedSet> = moved
+            .into_iter()
+            .map(|(cell_id, expression)| self.evaluate_set(cell_id, expression, &names))
+            .collect();
+        self.update_cell_info(updates, current_time, Edit::Rewrite)?;
+
+        // Step 6: Log the new layout
+        if let Some(Err(e)) = wal
+            .as_mut()
+            .map(|wal| wal.compact(&Self::saved_expressions(&self.cells)))
+        {
+            warn!("event=wal_write_failed cell=* error={}", e);
+        }
+        Ok(())
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Checks that a relocation keeps every cell holding an expression, every
+     * reference in those expressions and every defined name within the
+     * sheet's bounds, returning OutOfBounds for the first that it wouldn't
+     */
+    fn check_relocation(
+        &self,
+        map: &impl Fn(Reference) -> Option<Reference>,
+    ) -> Result<(), SpreadsheetError> {
+        let in_bounds = |reference: Reference| match map(reference) {
+            Some(moved) => {
+                let (start, end) = moved.corners();
+                self.check_bounds(&start)?;
+                self.check_bounds(&end)
+            }
+            None => Ok(()),
+        };
+
+        let names: Vec<Reference> = self
+            .names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .copied()
+            .collect();
+        for reference in names {
+            in_bounds(reference)?;
+        }
+
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        for (cell_id, cell) in cells
+            .iter()
+            .filter(|(_, cell)| !cell.expression.trim().is_empty())
+        {
+            in_bounds(Reference::Cell(*cell_id))?;
+            let mut checked = Ok(());
+            references::map_references(&cell.expression, |reference, _| {
+                if checked.is_ok() {
+                    checked = in_bounds(reference);
+                }
+                Some(reference)
+            });
+            checked?;
+        }
+        Ok(())
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Updates cell information and manages dependency relationships
+     *
+     * Procedure:
+     * 1. Acquires lock on the dependency graph
+     * 2. Replaces each cell's old dependency edges with the new ones
+     * 3. Acquires lock on cells once and updates/inserts every cell's info,
+     *    adding each explicit set to the cell's edit log and stamping every
+     *    changed value with one new sheet version. A value whose
+     *    inputs were updated since it was computed is marked stale, so the
+     *    worker re-evaluates it rather than letting an older input win
+     * 4. Records each replaced expression in the cell's undo history, as the
+     *    edit says; a cell that was never set replaces a blank expression
+     * 5. Notifies worker thread of the update with every cell's id, returning
+     *    WorkerUnavailable if the worker has stopped; the cells keep their new
+     *    values but their dependents can no longer be recomputed
+     */
+    fn update_cell_info(
+        &self,
+        updates: Vec<EvaluatedSet>,
+        current_time: Instant,
+        edit: Edit,
+    ) -> Result<(), SpreadsheetError> {
+        {
+            let mut graph = self.graph.lock().unwrap_or_else(PoisonError::into_inner);
+            for update in &updates {
+                graph.remove_edges(update.cell_id);
+                graph.add_edges(update.cell_id, &update.dependencies);
+            }
+        }
+
+        // Update/insert the cell info
+        let mut cell_ids: Vec<CellIdentifier> = Vec::with_capacity(updates.len());
+        let mut replaced: Vec<(CellIdentifier, String)> = Vec::new();
+        {
+            let mut cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut version: Option<u64> = None; // Shared by every value the batch changes
+            for update in updates {
+                let stale = update.inputs.iter().any(|(input_id, version)| {
+                    cells.get(input_id).map(|cell| cell.last_update_time) != *version
+                });
+                let (cell_id, expression) = (update.cell_id, update.expression);
+                let cell = cells.entry(cell_id).or_insert_with(|| CellInfo {
+                    value: CellValue::None,
+                    expression: String::new(),
+                    last_update_time: current_time,
+                    edits: VecDeque::new(),
+                    stale: false,
+                    version: 0,
+                    boolean: false,
+                });
+                if edit != Edit::Rewrite && self.edit_log_length > 0 {
+                    if cell.edits.len() >= self.edit_log_length {
+                        cell.edits.pop_front();
+                    }
+                    cell.edits
+                        .push_back((SystemTime::now(), expression.clone()));
+                }
+                let previous = std::mem::replace(&mut cell.expression, expression);
+                if cell.value != update.value || cell.boolean != update.boolean {
+                    cell.version = *version.get_or_insert_with(|| self.counters.next_version());
+                }
+                cell.value = update.value;
+                cell.boolean = update.boolean;
+                cell.last_update_time = current_time;
+                cell.stale = stale;
+                if edit != Edit::Rewrite && cell.expression != previous {
+                    replaced.push((cell_id, previous));
+                }
+                cell_ids.push(cell_id);
+            }
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+
+        // Remember what each cell held before
+        if !replaced.is_empty() {
+            let mut history = self
+                .undo_history
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            for (cell_id, previous) in replaced {
+                let cell_history = history.entry(cell_id).or_default();
+                match edit {
+                    Edit::Set => {
+                        CellHistory::push(&mut cell_history.undo, previous);
+                        cell_history.redo.clear();
+                    }
+                    Edit::Undo => CellHistory::push(&mut cell_history.redo, previous),
+                    Edit::Redo => CellHistory::push(&mut cell_history.undo, previous),
+                    Edit::Rewrite => {}
+                }
+            }
+        }
+
+        // Notify single worker thread; the send only fails once the worker
+        // has exited, e.g. after a panic, which dropped its receiver
+        self.notify_worker(UpdateMessage::CellUpdate { cell_ids })
+            .map_err(|_| {
+                warn!("event=worker_unavailable");
+                SpreadsheetError::WorkerUnavailable
+            })
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Computes a cell's value from the committed values of its dependencies
+     *
+     * Procedure:
+     * 1. A blank expression clears the cell
+     * 2. A cell can never be computed from its own value, so an expression
+     *    that reads the cell itself gives a SelfReference error
+     * 3. Otherwise resolves the variables and evaluates the expression
+     * 4. Returns the value, whether it is a boolean, and the versions of
+     *    the inputs it was computed from
+     */
+    fn compute_value(
+        &self,
+        cell_id: CellIdentifier,
+        expression: &str,
+        references: &[(String, Reference)],
+    ) -> (CellValue, bool, InputVersions) {
+        if expression.trim().is_empty() {
+            return (CellValue::None, false, Vec::new());
+        }
+
+        let dependencies: Vec<Reference> = references.iter().map(|(_, r)| *r).collect();
+        if Self::refers_to_itself(cell_id, &dependencies) {
+            return (CellValue::Error("SelfReference".into()), false, Vec::new());
+        }
+
+        let (bounded, variables, inputs) = self.resolve_variables(references);
+        self.counters.evaluated.fetch_add(1, Ordering::Relaxed);
+        let (value, boolean) = match variables {
+            Ok(variables) => Self::evaluate_cell(expression, &bounded, &variables, &self.settings),
+            Err(error) => (error, false),
+        };
+        (value, boolean, inputs)
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Resolves variables used in an expression against the committed cell values
+     *
+     * Procedure:
+     * 1. Acquires lock on cells
+     * 2. Bounds open-ended ranges and gathers every variable under that
+     *    single lock acquisition
+     * 3. Returns the bounded references, the map of variable names to their
+     *    values (or the error a range with an empty cell gives, see
+     *    gather_variables), and the last update time of every cell read
+     */
+    fn resolve_variables(
+        &self,
+        references: &[(String, Reference)],
+    ) -> (Vec<(String, Reference)>, Variables, InputVersions) {
+        let cells = self.cells.lock().unwrap_or_else(PoisonError::into_inner);
+        let bounded: Vec<(String, Reference)> = references
+            .iter()
+            .map(|(name, reference)| (name.clone(), Self::bound_reference(*reference, &cells)))
+            .collect();
+
+        let variables = Self::gather_variables(
+            &bounded,
+            &|cell_id| {
+                cells
+                    .get(cell_id)
+                    .map(|cell| cell.value.clone())
+                    .unwrap_or_default()
+            },
+            &|cell_id| cells.get(cell_id).is_some_and(|cell| cell.boolean),
+            &self.settings,
+        );
+        let inputs: InputVersions = bounded
+            .iter()
+            .flat_map(|(_, reference)| reference.cells())
+            .map(|input_id| {
+                let version = cells.get(&input_id).map(|cell| cell.last_update_time);
+                (input_id, version)
+            })
+            .collect();
+        (bounded, variables, inputs)
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Checks whether a value is an error inherited from a dependency
+     */
+    fn is_dependency_error(value: &CellValue) -> bool {
+        matches!(value, CellValue::Error(message) if message == "VariableDependsOnError")
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Checks whether any of a cell's references, ranges included, covers the cell
+     */
+    fn refers_to_itself(cell_id: CellIdentifier, references: &[Reference]) -> bool {
+        references
+            .iter()
+            .any(|reference| reference.contains(&cell_id))
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Finds every cell reference in an expression, paired with its variable
+     * name with any "$" anchors removed, as evaluate_cell expects
+     * A defined name stands for the cells it was defined over, and takes
+     * precedence over parsing the variable as a reference
+     */
+    fn references_in(
+        expression: &str,
+        names: &HashMap<String, Reference>,
+    ) -> Vec<(String, Reference)> {
+        references::identifiers(&references::strip_anchors(expression))
+            .into_iter()
+            .filter_map(|name| {
+                let reference = match names.get(&name) {
+                    Some(reference) => *reference,
+                    None => references::parse_reference(&name)?,
+                };
+                Some((name, reference))
+            })
+            .collect()
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Evaluates an expression against its gathered variables
+     * Shared by set and the worker so both report failures identically
+     *
+     * Procedure:
+     * 1. Removes "$" anchors, which only matter when copying
+     * 2. Rejects ranges passed to numeric functions that hold a string,
+     *    naming the first offending cell
+     * 3. Expands calls to functions implemented in this crate, e.g. sumif
+     * 4. Rejects arithmetic between a string and a number, or on a boolean,
+     *    with a TypeError, see functions::mixed_operands
+     * 5. Evaluates the expression, with boolean variables read as true or
+     *    false rather than 1 or 0
+     * 6. rsheet_lib has no boolean value, so an expression giving true or
+     *    false, e.g. "A1 > B1", is evaluated again as 1 or 0 and marked as
+     *    a boolean
+     * 7. Turns an error in any remaining variable into a VariableDependsOnError value
+     *
+     * With an eval_timeout set, each evaluation runs on the sheet's
+     * TimedEvaluator thread and gives an EvaluationTimeout error if it is
+     * still running once the timeout has passed, see TimedEvaluator::evaluate
+     */
+    fn evaluate_cell(
+        expression: &str,
+        references: &[(String, Reference)],
+        gathered: &Gathered,
+        settings: &EvalSettings,
+    ) -> (CellValue, bool) {
+        let variables = &gathered.values;
+        let expression = &references::strip_anchors(expression);
+        let numeric_arguments = references::call_arguments(expression, NUMERIC_FUNCTIONS);
+        for (name, reference) in references {
+            if !numeric_arguments.contains(name) {
+                continue;
+            }
+            let values: Vec<&CellValue> = match variables.get(name) {
+                Some(CellArgument::Vector(values)) => values.iter().collect(),
+                Some(CellArgument::Matrix(rows)) => rows.iter().flatten().collect(),
+                _ => continue,
+            };
+
+            // Both the values and the range's cells run row by row
+            let offending = reference
+                .cells()
+                .into_iter()
+                .zip(values)
+                .find(|(_, value)| matches!(value, CellValue::String(_)));
+            if let Some((cell_id, _)) = offending {
+                return (
+                    CellValue::Error(format!(
+                        "TypeError in range {}: {} is not a number",
+                        name,
+                        references::a1_name(&cell_id)
+                    )),
+                    false,
+                );
+            }
+        }
+
+        // Expand functions rsheet_lib doesn't provide, then drop variables they
+        // consumed so errors they skipped don't fail the whole expression
+        let expression = match functions::expand_calls(expression, variables) {
+            Ok(expression) => expression,
+            Err(message) => return (CellValue::Error(message), false),
+        };
+        let variables = functions::used_variables(&expression, variables);
+        if let Some(message) =
+            functions::mixed_operands(&expression, &variables, &gathered.booleans)
+        {
+            return (CellValue::Error(message), false);
+        }
+        let expression = functions::as_booleans(&expression, &gathered.booleans);
+
+        let evaluate = |expression: &str| {
+            let result = match &settings.evaluator {
+                Some(evaluator) => match evaluator.evaluate(expression, &variables) {
+                    Some(result) => result,
+                    None => return CellValue::Error("EvaluationTimeout".into()),
+                },
+                None => CellExpr::new(expression).evaluate(&variables),
+            };
+            match result {
+                Ok(value) => value,
+                Err(CellExprEvalError::VariableDependsOnError) => {
+                    CellValue::Error("VariableDependsOnError".into())
+                }
+            }
+        };
+        match evaluate(&expression) {
+            CellValue::Error(message) if message == NOT_A_CELL_VALUE => {
+                match evaluate(&format!("if ({expression}) {{ 1 }} else {{ 0 }}")) {
+                    value @ CellValue::Int(_) => (value, true),
+                    _ => (CellValue::Error(message), false),
+                }
+            }
+            value => (value, false),
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Turns an open-ended range into a closed one over the current cells
+     *
+     * Procedure:
+     * 1. Leaves single cells and closed ranges unchanged
+     * 2. For an open range or whole columns, finds the last populated row in
+     *    its columns at or below its start row, and for whole rows, the last
+     *    populated column in its rows
+     * 3. Returns the range from its start to that row or column, or just its
+     *    first row or column if none of its cells are populated
+     */
+    fn bound_reference(
+        reference: Reference,
+        cells: &HashMap<CellIdentifier, CellInfo>,
+    ) -> Reference {
+        let last = |position: fn(&CellIdentifier) -> u32| {
+            cells
+                .keys()
+                .filter(|id| reference.contains(id))
+                .map(position)
+                .max()
+                .unwrap_or(0)
+        };
+
+        match reference {
+            Reference::Cell(_) | Reference::Range(..) => reference,
+            Reference::ColumnsFrom(start, end_col) => Reference::Range(
+                start,
+                CellIdentifier {
+                    col: end_col,
+                    row: last(|id| id.row).max(start.row),
+                },
+            ),
+            Reference::Columns(start_col, end_col) => Reference::Range(
+                CellIdentifier {
+                    col: start_col,
+                    row: 0,
+                },
+                CellIdentifier {
+                    col: end_col,
+                    row: last(|id| id.row),
+                },
+            ),
+            Reference::Rows(start_row, end_row) => Reference::Range(
+                CellIdentifier {
+                    col: 0,
+                    row: start_row,
+                },
+                CellIdentifier {
+                    col: last(|id| id.col),
+                    row: end_row,
+                },
+            ),
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Turns the bounded references of an expression into CellArguments
+     * Shared by set and the worker so both resolve variables identically
+     *
+     * Procedure:
+     * 1. Creates empty variables HashMap
+     * 2. For each variable name in expression:
+     *    - If scalar (A1): looks up a single cell value
+     *    - If range (A1_B2): builds a vector or matrix of values
+     * 3. Empty cells, whether never set or cleared, read as 0 on their own,
+     *    so "A1 + 1" gives 1; inside a range they read as empty_cells says,
+     *    whether a formula is evaluated by set or by the worker
+     * 4. Returns map of variable names to their values, with the single
+     *    cells is_boolean says hold a boolean, or, when empty cells are
+     *    errors, an EmptyCellError value naming the first empty cell of the
+     *    first range holding one
+     *
+     * A range covering more than max_range_cells cells, such as a whole
+     * column that has grown since it was set, fails the formula before any
+     * of its values are read
+     */
+    fn gather_variables(
+        references: &[(String, Reference)],
+        value_of: &dyn Fn(&CellIdentifier) -> CellValue,
+        is_boolean: &dyn Fn(&CellIdentifier) -> bool,
+        settings: &EvalSettings,
+    ) -> Variables {
+        let empty_cells = settings.empty_cells;
+        let mut variables: HashMap<String, CellArgument> = HashMap::new();
+        let mut booleans: HashSet<String> = HashSet::new();
+        let scalar_of = |cell_id: &CellIdentifier| match value_of(cell_id) {
+            CellValue::None => CellValue::Int(0),
+            value => value,
+        };
+        let in_range = |cell_id: &CellIdentifier| match (value_of(cell_id), empty_cells) {
+            (CellValue::None, EmptyCells::TreatAsZero) => CellValue::Int(0),
+            (value, _) => value,
+        };
+
+        for (var_name, reference) in references {
+            let arg = match reference {
+                // Handle scalar variables
+                Reference::Cell(cell_id) => {
+                    if is_boolean(cell_id) {
+                        booleans.insert(var_name.clone());
+                    }
+                    CellArgument::Value(scalar_of(cell_id))
+                }
+                // Handle range variables (vector or matrix)
+                Reference::Range(start, end) => {
+                    if let Some(max_cells) = settings
+                        .max_range_cells
+                        .filter(|max_cells| Self::exceeds(reference, *max_cells))
+                    {
+                        return Err(CellValue::Error(format!(
+                            "range {} exceeds the {}-cell limit",
+                            var_name, max_cells
+                        )));
+                    }
+                    let empty = (empty_cells == EmptyCells::Error)
+                        .then(|| {
+                            reference
+                                .cells()
+                                .into_iter()
+                                .find(|cell_id| value_of(cell_id) == CellValue::None)
+                        })
+                        .flatten();
+                    if let Some(cell_id) = empty {
+                        return Err(CellValue::Error(format!(
+                            "EmptyCellError in range {}: {} is empty",
+                            var_name,
+                            references::a1_name(&cell_id)
+                        )));
+                    }
+                    Self::get_range_argument(start, end, &in_range)
+                }
+                // Open ranges, whole columns and whole rows are bounded before they get here
+                Reference::ColumnsFrom(..) | Reference::Columns(..) | Reference::Rows(..) => {
+                    continue
+                }
+            };
+            variables.insert(var_name.clone(), arg);
+        }
+
+        Ok(Gathered {
+            values: variables,
+            booleans,
+        })
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Converts a cell range into appropriate CellArgument type
+     *
+     * Procedure:
+     * 1. Determines range type (vertical/horizontal/matrix)
+     * 2. Collects values into appropriate structure
+     * 3. Returns vector or matrix argument
+     *
+     * What empty cells read as is up to value_of, see gather_variables, and
+     * errors inside the range are reported by CellExpr::evaluate. start is
+     * always the top-left corner, as references::parse_reference puts a
+     * reversed range such as "B3_A1" in order
+     */
+    fn get_range_argument(
+        start: &CellIdentifier,
+        end: &CellIdentifier,
+        value_of: &dyn Fn(&CellIdentifier) -> CellValue,
+    ) -> CellArgument {
+        if start.col == end.col {
+            // Vertical vector
+            Self::get_vertical_vector(start, end, value_of)
+        } else if start.row == end.row {
+            // Horizontal vector
+            Self::get_horizontal_vector(start, end, value_of)
+        } else {
+            // Matrix
+            Self::get_matrix(start, end, value_of)
+        }
+    }
+
+    /**
+     * HELP FUNCTION
+     * Get vertical vector from range
+     *
+     * Procedure:
+     * 1. Creates vector to store values
+     * 2. Iterates through rows at fixed column
+     * 3. Gets value for each cell
+     * 4. Returns vector as CellArgument
+     */
+    fn get_vertical_vector(
+        start: &CellIdentifier,
+        end: &CellIdentifier,
+        value_of: &dyn Fn(&CellIdentifier) -> CellValue,
+    ) -> CellArgument {
+        let values: Vec<CellValue> = (start.row..=end.row)
+            .map(|row| {
+                value_of(&CellIdentifier {
+                    col: start.col,
+                    row,
+                })
+            })
+            .collect();
+        CellArgument::Vector(values)
+    }
+
+    /**
+     * HELP FUNCTION
+     * Get horizontal vector from range
+     *
+     * Procedure:
+     * 1. Creates vector to store values
+     * 2. Iterates through columns at fixed row
+     * 3. Gets value for each cell
+     * 4. Returns vector as CellArgument
+     */
+    fn get_horizontal_vector(
+        start: &CellIdentifier,
+        end: &CellIdentifier,
+        value_of: &dyn Fn(&CellIdentifier) -> CellValue,
+    ) -> CellArgument {
+        let values: Vec<CellValue> = (start.col..=end.col)
+            .map(|col| {
+                value_of(&CellIdentifier {
+                    col,
+                    row: start.row,
+                })
+            })
+            .collect();
+        CellArgument::Vector(values)
+    }
+
+    /**
+     * HELP FUNCTION
+     * Get matrix from range
+     *
+     * Procedure:
+     * 1. Creates nested vectors for matrix
+     * 2. Iterates through rows
+     * 3. For each row, iterates through columns
+     * 4. Gets value for each cell
+     * 5. Returns matrix as CellArgument
+     */
+    fn get_matrix(
+        start: &CellIdentifier,
+        end: &CellIdentifier,
+        value_of: &dyn Fn(&CellIdentifier) -> CellValue,
+    ) -> CellArgument {
+        let matrix: Vec<Vec<CellValue>> = (start.row..=end.row)
+            .map(|row| {
+                (start.col..=end.col)
+                    .map(|col| value_of(&CellIdentifier { col, row }))
+                    .collect()
+            })
+            .collect();
+        CellArgument::Matrix(matrix)
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Worker thread function that processes cell updates
+     *
+     * Procedure:
+     * 1. Receives update messages from channel, waking early when a
+     *    throttled cascade falls due, then drains every message already
+     *    waiting so a burst is handled together
+     * 2. For each update to a throttled root that cascaded within its
+     *    interval, defers the cascade until the interval has passed,
+     *    collapsing any further updates to that root into it
+     * 3. Collects every other updated root, without duplicates, and
+     *    recomputes them all in one combined pass
+     * 4. Runs collected and deferred cascades before answering a flush
+     * 5. Continues until shutdown message received
+     *
+     * A cascade that panics, e.g. in an observer, is logged and abandoned
+     * rather than stopping the worker, so later updates are still
+     * recomputed. If it panicked while holding the cells lock, the cells
+     * keep whatever part of the cascade was committed, and health reports
+     * the lock as poisoned
+     */
+    fn process_cells_update(
+        cells: Arc<Mutex<HashMap<CellIdentifier, CellInfo>>>,
+        graph: Arc<Mutex<DependencyGraph>>,
+        counters: Arc<WorkerCounters>,
+        subscribers: Arc<Mutex<Subscribers>>,
+        names: Arc<Mutex<HashMap<String, Reference>>>,
+        settings: EvalSettings,
+        receiver: mpsc::Receiver<UpdateMessage>,
+    ) {
+        let mut throttles: HashMap<CellIdentifier, Duration> = HashMap::new();
+        let mut last_cascade: HashMap<CellIdentifier, Instant> = HashMap::new();
+        let mut deferred: HashMap<CellIdentifier, Instant> = HashMap::new(); // Root -> due time
+        let cascade = |roots: &mut BTreeSet<CellIdentifier>| {
+            if !roots.is_empty() {
+                let roots: Vec<CellIdentifier> = std::mem::take(roots).into_iter().collect();
+                counters.passes.fetch_add(1, Ordering::Relaxed);
+                let pass = panic::catch_unwind(AssertUnwindSafe(|| {
+                    Self::run_cascade(
+                        &cells,
+                        &graph,
+                        &counters,
+                        &subscribers,
+                        &names,
+                        &settings,
+                        &roots,
+                    )
+                }));
+                if pass.is_err() {
+                    warn!(
+                        "event=cascade_panicked trigger={}",
+                        roots
+                            .iter()
+                            .map(references::a1_name)
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    );
+                }
+            }
+        };
+
+        loop {
+            // Wait for the next message, or until the earliest deferred cascade is due
+            let first = match deferred.values().min() {
+                Some(&due) => {
+                    match receiver.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                        Ok(msg) => Some(msg),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => break,
+                },
+            };
+
+            // Drain everything already waiting so repeated updates coalesce
+            let mut batch: Vec<UpdateMessage> = first.into_iter().collect();
+            while let Ok(msg) = receiver.try_recv() {
+                batch.push(msg);
+            }
+            counters.queued.fetch_sub(batch.len(), Ordering::SeqCst);
+
+            // Deferred cascades that have fallen due join this pass
+            let now = Instant::now();
+            let mut roots: BTreeSet<CellIdentifier> = deferred
+                .iter()
+                .filter(|(_, &due)| due <= now)
+                .map(|(&root, _)| root)
+                .collect();
+            for root in &roots {
+                deferred.remove(root);
+                last_cascade.insert(*root, now);
+            }
+
+            for msg in batch {
+                match msg {
+                    UpdateMessage::Shutdown => {
+                        cascade(&mut roots);
+                        return;
+                    }
+                    UpdateMessage::Flush { done } => {
+                        for (root, _) in deferred.drain() {
+                            last_cascade.insert(root, Instant::now());
+                            roots.insert(root);
+                        }
+                        cascade(&mut roots);
+                        let _ = done.send(());
+                    }
+                    UpdateMessage::Throttle { cell_id, interval } => match interval {
+                        Some(interval) => {
+                            throttles.insert(cell_id, interval);
+                        }
+                        None => {
+                            throttles.remove(&cell_id);
+                            last_cascade.remove(&cell_id);
+                            if deferred.remove(&cell_id).is_some() {
+                                roots.insert(cell_id);
+                            }
+                        }
+                    },
+                    UpdateMessage::CellUpdate { cell_ids } => {
+                        for cell_id in cell_ids {
+                            if let Some(&interval) = throttles.get(&cell_id) {
+                                if deferred.contains_key(&cell_id) || roots.contains(&cell_id) {
+                                    // Already scheduled, and it will read the latest value
+                                    continue;
+                                }
+
+                                let now = Instant::now();
+                                match last_cascade.get(&cell_id) {
+                                    Some(&last) if now < last + interval => {
+                                        deferred.insert(cell_id, last + interval);
+                                        continue;
+                                    }
+                                    _ => {
+                                        last_cascade.insert(cell_id, now);
+                                    }
+                                }
+                            }
+                            roots.insert(cell_id);
+                        }
+                    }
+                }
+            }
+            cascade(&mut roots);
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Recomputes every transitive dependent of a set of updated cells
+     *
+     * Procedure:
+     * 1. Computes the topological order of dependents under the graph lock,
+     *    and how deep each one is when a depth limit is set
+     * 2. Reads the expressions of the cascade under one cells lock
+     * 3. Snapshots the inputs of the whole cascade, and the value each of
+     *    its cells holds, under one cells lock
+     * 4. Evaluates cells in sorted order, staging the new values; cells
+     *    deeper than the limit are staged as a CascadeDepthExceeded error
+     *    instead, with one warning for the whole cascade. A cell is skipped
+     *    when none of the cells it reads changed value in this pass, e.g.
+     *    every cell past B1 when A1 changes but B1 is "A1 * 0", unless it is
+     *    a root, is stale or holds an error
+     * 5. Commits every staged value under one acquisition of the cells lock,
+     *    skipping cells that were set again after their expression was read,
+     *    except that a dependency error is always replaced while the cell's
+     *    formula is unchanged; every value that changes is stamped with one
+     *    new sheet version
+     * 6. Once the cells lock is released, runs the observers and then the
+     *    watches with the roots' values followed by every committed value,
+     *    in cascade order, dropping watches that ask to stop
+     * 7. Logs an event=recompute line with the trigger, cell count, error
+     *    count and elapsed time, plus a warning for each cycle or new error
+     */
+    fn run_cascade(
+        cells: &Mutex<HashMap<CellIdentifier, CellInfo>>,
+        graph: &Mutex<DependencyGraph>,
+        counters: &WorkerCounters,
+        subscribers: &Mutex<Subscribers>,
+        names: &Mutex<HashMap<String, Reference>>,
+        settings: &EvalSettings,
+        roots: &[CellIdentifier],
+    ) {
+        let started = Instant::now();
+        let names = names.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        let (observers, watches) = {
+            let subscribers = subscribers.lock().unwrap_or_else(PoisonError::into_inner);
+            (subscribers.observers.clone(), subscribers.watches.clone())
+        };
+
+        // Step 1: Find dependents, sort them topologically and find the ones
+        // past the depth limit
+        let (update_order, too_deep) = {
+            let graph = graph.lock().unwrap_or_else(PoisonError::into_inner);
+            for root in roots {
+                if let Some(cycle) = graph.detect_cycle(*root) {
+                    warn!(
+                        "event=cycle trigger={} cells={}",
+                        references::a1_name(root),
+                        cycle
+                            .iter()
+                            .map(references::a1_name)
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    );
+                }
+            }
+            let update_order = graph.topo_order_from(roots);
+            let too_deep: HashSet<CellIdentifier> = match settings.max_depth {
+                Some(max_depth) => graph
+                    .depths_from(roots, &update_order)
+                    .into_iter()
+                    .filter(|(_, depth)| *depth > max_depth)
+                    .map(|(cell_id, _)| cell_id)
+                    .collect(),
+                None => HashSet::new(),
+            };
+            (update_order, too_deep)
+        };
+        if let Some(max_depth) = settings.max_depth.filter(|_| !too_deep.is_empty()) {
+            warn!(
+                "event=cascade_depth_exceeded trigger={} limit={} cells={}",
+                roots
+                    .iter()
+                    .map(references::a1_name)
+                    .collect::<Vec<String>>()
+                    .join(","),
+                max_depth,
+                too_deep.len()
+            );
+        }
+
+        // Step 2: Read the expressions of every cell in the cascade. A root
+        // still holding a dependency error, or marked stale by set, was
+        // evaluated against inputs that may have changed since, so it is
+        // re-evaluated first. Subscribers
+        // also get the value set stored in each root that isn't re-evaluated
+        let (expressions, root_values, read_time) = {
+            let cells_lock = cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let in_order: HashSet<&CellIdentifier> = update_order.iter().collect();
+            let stale_roots = roots.iter().filter(|root| {
+                !in_order.contains(root)
+                    && cells_lock
+                        .get(root)
+                        .is_some_and(|cell| cell.stale || Self::is_dependency_error(&cell.value))
+            });
+            let expressions: Vec<(CellIdentifier, String)> = stale_roots
+                .chain(update_order.iter())
+                .filter_map(|id| {
+                    cells_lock
+                        .get(id)
+                        .map(|cell| (*id, cell.expression.clone()))
+                })
+                .collect();
+            let root_values: Vec<(CellIdentifier, CellValue)> = if observers.is_empty()
+                && watches.is_empty()
+            {
+                Vec::new()
+            } else {
+                roots
+                    .iter()
+                    .filter(|root| !expressions.iter().any(|(id, _)| id == *root))
+                    .filter_map(|root| cells_lock.get(root).map(|cell| (*root, cell.value.clone())))
+                    .collect()
+            };
+            (expressions, root_values, Instant::now())
+        };
+
+        // Step 3: Snapshot every input of the cascade under a single lock so
+        // a concurrent set can't feed different values to different cells,
+        // noting each input's version and which inputs each cell reads
+        let (cell_exprs, inputs, mut booleans, versions, reads, previous) = {
+            let cells_lock = cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut inputs: HashMap<CellIdentifier, CellValue> = HashMap::new();
+            let mut previous: HashMap<CellIdentifier, (CellValue, bool)> = HashMap::new();
+            let mut booleans: HashSet<CellIdentifier> = HashSet::new();
+            let mut versions: HashMap<CellIdentifier, Option<Instant>> = HashMap::new();
+            let mut reads: HashMap<CellIdentifier, Vec<CellIdentifier>> = HashMap::new();
+            let mut cell_exprs = Vec::with_capacity(expressions.len());
+
+            for (id, expression) in &expressions {
+                let references: Vec<(String, Reference)> = Self::references_in(expression, &names)
+                    .into_iter()
+                    .map(|(name, reference)| (name, Self::bound_reference(reference, &cells_lock)))
+                    .collect();
+
+                if let Some(cell) = cells_lock.get(id) {
+                    let settled = !cell.stale && !matches!(cell.value, CellValue::Error(_));
+                    if settled {
+                        previous.insert(*id, (cell.value.clone(), cell.boolean));
+                    }
+                }
+                let cell_reads = reads.entry(*id).or_default();
+                for (_, reference) in &references {
+                    for input_id in reference.cells() {
+                        let cell = cells_lock.get(&input_id);
+                        if let Some(cell) = cell {
+                            inputs.insert(input_id, cell.value.clone());
+                            if cell.boolean {
+                                booleans.insert(input_id);
+                            }
+                        }
+                        versions.insert(input_id, cell.map(|cell| cell.last_update_time));
+                        cell_reads.push(input_id);
+                    }
+                }
+                cell_exprs.push((*id, expression, references));
+            }
+            (cell_exprs, inputs, booleans, versions, reads, previous)
+        };
+
+        // Step 4: Evaluate cells in topologically sorted order, staging
+        // results so later cells in the cascade see the new values, and
+        // keeping booleans up to date for them
+        let mut staged: HashMap<CellIdentifier, CellValue> = HashMap::new();
+        let mut changed: HashSet<CellIdentifier> = roots.iter().copied().collect();
+        let mut unchanged: HashSet<CellIdentifier> = HashSet::new();
+
+        for (cell_id, expression, references) in cell_exprs {
+            // Nothing this cell reads changed, so neither can its value
+            let inputs_changed = reads
+                .get(&cell_id)
+                .is_some_and(|cell_reads| cell_reads.iter().any(|id| changed.contains(id)));
+            let before = previous.get(&cell_id);
+            if !inputs_changed && !changed.contains(&cell_id) && before.is_some() {
+                unchanged.insert(cell_id);
+                continue;
+            }
+
+            // Gather all required variables, preferring staged values
+            let variables = Self::gather_variables(
+                &references,
+                &|id| {
+                    staged
+                        .get(id)
+                        .or_else(|| inputs.get(id))
+                        .cloned()
+                        .unwrap_or_default()
+                },
+                &|id| booleans.contains(id),
+                settings,
+            );
+
+            // Evaluate cell with gathered variables
+            let own_references: Vec<Reference> = references.iter().map(|(_, r)| *r).collect();
+            let (new_value, boolean) = if too_deep.contains(&cell_id) {
+                (CellValue::Error("CascadeDepthExceeded".into()), false)
+            } else if Self::refers_to_itself(cell_id, &own_references) {
+                (CellValue::Error("SelfReference".into()), false)
+            } else {
+                match variables {
+                    Ok(variables) => {
+                        Self::evaluate_cell(expression, &references, &variables, settings)
+                    }
+                    Err(error) => (error, false),
+                }
+            };
+            if boolean {
+                booleans.insert(cell_id);
+            } else {
+                booleans.remove(&cell_id);
+            }
+            if before != Some(&(new_value.clone(), boolean)) {
+                changed.insert(cell_id);
+            }
+            staged.insert(cell_id, new_value);
+            counters.recomputed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Step 5: Commit the whole cascade in a single critical section so
+        // readers never observe a mix of old and new values. A cell whose
+        // inputs were set while it was evaluated is discarded, as are the
+        // cells reading it; the newer set queued its own cascade, so an
+        // older input never overwrites a value computed from a newer one
+        let evaluated = staged.len();
+        let read_expressions: HashMap<CellIdentifier, &String> =
+            expressions.iter().map(|(id, expr)| (*id, expr)).collect();
+        let mut errors = 0;
+        let mut committed: Vec<(CellIdentifier, CellValue)> = Vec::new();
+        let mut version: Option<u64> = None; // Shared by every value the cascade changes
+        {
+            let mut cells_lock = cells.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut outdated: HashSet<CellIdentifier> = versions
+                .iter()
+                .filter(|(id, version)| {
+                    cells_lock.get(id).map(|cell| cell.last_update_time) != **version
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            for (cell_id, _) in &expressions {
+                let current = reads
+                    .get(cell_id)
+                    .is_none_or(|cell_reads| !cell_reads.iter().any(|id| outdated.contains(id)));
+                let mut stored = false;
+                if let (Some(new_value), Some(cell)) =
+                    (staged.remove(cell_id), cells_lock.get_mut(cell_id))
+                {
+                    // Skip cells that were set again after we read their expression,
+                    // unless they still hold a dependency error or a stale value
+                    // from the same formula
+                    let recovers = (cell.stale || Self::is_dependency_error(&cell.value))
+                        && read_expressions.get(cell_id) == Some(&&cell.expression);
+                    if current && (read_time > cell.last_update_time || recovers) {
+                        if let CellValue::Error(message) = &new_value {
+                            errors += 1;
+                            if message != "VariableDependsOnError" {
+                                warn!(
+                                    "event=recompute_error cell={} error={:?}",
+                                    references::a1_name(cell_id),
+                                    message
+                                );
+                            }
+                        }
+                        if !observers.is_empty() || !watches.is_empty() {
+                            committed.push((*cell_id, new_value.clone()));
+                        }
+                        let boolean = booleans.contains(cell_id);
+                        if cell.value != new_value || cell.boolean != boolean {
+                            cell.version = *version.get_or_insert_with(|| counters.next_version());
+                        }
+                        cell.value = new_value;
+                        cell.boolean = boolean;
+                        cell.last_update_time = read_time;
+                        cell.stale = false;
+                        stored = true;
+                    }
+                }
+                if !stored && !unchanged.contains(cell_id) {
+                    outdated.insert(*cell_id);
+                }
+            }
+        }
+
+        // Step 6: Report the committed values outside the lock, so an
+        // observer can read the sheet
+        for (cell_id, value) in root_values.iter().chain(&committed) {
+            for observer in &observers {
+                observer(*cell_id, value);
+            }
+        }
+        let mut stopped: Vec<u64> = Vec::new();
+        for (cell_id, value) in root_values.iter().chain(&committed) {
+            for (id, region, watcher) in &watches {
+                if region.contains(cell_id) && !stopped.contains(id) && !watcher(*cell_id, value) {
+                    stopped.push(*id);
+                }
+            }
+        }
+        if !stopped.is_empty() {
+            subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .watches
+                .retain(|(id, _, _)| !stopped.contains(id));
+        }
+
+        // The log macros only format their arguments when the level is enabled
+        if evaluated > 0 {
+            info!(
+                "event=recompute trigger={} cells={} errors={} elapsed_us={}",
+                roots
+                    .iter()
+                    .map(references::a1_name)
+                    .collect::<Vec<String>>()
+                    .join(","),
+                evaluated,
+                errors,
+                started.elapsed().as_micros()
+            );
+        }
+    }
+}
+
+impl Default for Spreadsheet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Spreadsheet {
+    fn drop(&mut self) {
+        // Send shutdown message to worker thread and wait for it to finish,
+        // unless the last handle is being dropped by the worker itself
+        let _ = self.update_sender.send(UpdateMessage::Shutdown);
+        if let Some(worker) = self.worker.take() {
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
+        }
+
+        // Stop the autosave thread, waiting for its final save
+        if let Some((stop, handle)) = self.autosaver.take() {
+            let _ = stop.send(());
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_basic_set_get() {
+        let sheet = Spreadsheet::new();
+        let cell = CellIdentifier { col: 0, row: 0 }; // A1
+
+        assert!(sheet.set(cell, "42".to_string()).is_ok());
+        sleep(Duration::from_millis(50)); // Allow time for processing
+        assert_eq!(sheet.get(&cell), CellValue::Int(42));
+    }
+
+    #[test]
+    fn test_sleep_then() {
+        let sheet = Spreadsheet::new();
+        let cell = CellIdentifier { col: 0, row: 0 }; // A1
+
+        // Set A1 with a 500ms sleep, then value 5
+        assert!(sheet.set(cell, "sleep_then(500, 5)".to_string()).is_ok());
+
+        // Immediately set A1 with a 200ms sleep, then value 10
+        assert!(sheet.set(cell, "sleep_then(200, 10)".to_string()).is_ok());
+
+        // Wait for both operations to complete
+        sleep(Duration::from_millis(700));
+
+        // Should have value 10 since it was the more recent update
+        assert_eq!(sheet.get(&cell), CellValue::Int(10));
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        // Set up chain: C1 depends on B1 depends on A1
+        assert!(sheet.set(a1, "5".to_string()).is_ok());
+        assert!(sheet.set(b1, "A1 + 1".to_string()).is_ok());
+        assert!(sheet.set(c1, "B1 * 2".to_string()).is_ok());
+
+        sleep(Duration::from_millis(50)); // Allow time for processing
+
+        assert_eq!(sheet.get(&a1), CellValue::Int(5));
+        assert_eq!(sheet.get(&b1), CellValue::Int(6));
+        assert_eq!(sheet.get(&c1), CellValue::Int(12));
+
+        // Update A1 and verify cascade
+        assert!(sheet.set(a1, "10".to_string()).is_ok());
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(sheet.get(&a1), CellValue::Int(10));
+        assert_eq!(sheet.get(&b1), CellValue::Int(11));
+        assert_eq!(sheet.get(&c1), CellValue::Int(22));
+    }
+
+    #[test]
+    fn test_vector_and_matrix() {
+        let sheet = Spreadsheet::new();
+
+        // Set up a 2x2 matrix
+        assert!(sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
+            .is_ok()); // A1
+        assert!(sheet
+            .set(CellIdentifier { col: 1, row: 0 }, "2".to_string())
+            .is_ok()); // B1
+        assert!(sheet
+            .set(CellIdentifier { col: 0, row: 1 }, "3".to_string())
+            .is_ok()); // A2
+        assert!(sheet
+            .set(CellIdentifier { col: 1, row: 1 }, "4".to_string())
+            .is_ok()); // B2
+
+        // Test horizontal vector sum
+        assert!(sheet
+            .set(
+                CellIdentifier { col: 2, row: 0 }, // C1
+                "sum(A1_B1)".to_string()
+            )
+            .is_ok());
+
+        // Test vertical vector sum
+        assert!(sheet
+            .set(
+                CellIdentifier { col: 2, row: 1 }, // C2
+                "sum(A1_A2)".to_string()
+            )
+            .is_ok());
+
+        // Test matrix sum
+        assert!(sheet
+            .set(
+                CellIdentifier { col: 2, row: 2 }, // C3
+                "sum(A1_B2)".to_string()
+            )
+            .is_ok());
+
+        sleep(Duration::from_millis(50));
+
+        assert_eq!(
+            sheet.get(&CellIdentifier { col: 2, row: 0 }),
+            CellValue::Int(3)
+        ); // C1 = 1 + 2
+        assert_eq!(
+            sheet.get(&CellIdentifier { col: 2, row: 1 }),
+            CellValue::Int(4)
+        ); // C2 = 1 + 3
+        assert_eq!(
+            sheet.get(&CellIdentifier { col: 2, row: 2 }),
+            CellValue::Int(10)
+        ); // C3 = 1 + 2 + 3 + 4
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        // Set A1 to an invalid expression
+        assert!(sheet.set(a1, "1 +".to_string()).is_ok());
+        assert!(sheet.set(b1, "A1 + 1".to_string()).is_ok());
+
+        sleep(Duration::from_millis(50));
+
+        match sheet.get(&a1) {
+            CellValue::Error(_) => (), // Expected
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        match sheet.get(&b1) {
+            CellValue::Error(msg) if msg == "VariableDependsOnError" => (), // Expected
+            other => panic!("Expected VariableDependsOnError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_range_sum_with_updates() {
+        let spreadsheet = Spreadsheet::new();
+
+        // Initial setup: Set values for A1, B1, C1
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 1, row: 0 }, // B1
+                "2".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 2, row: 0 }, // C1
+                "3".to_string(),
+            )
+            .unwrap();
+
+        // Set D1 to sum of range A1_C1
+        spreadsheet
+            .set(
+                CellIdentifier { col: 3, row: 0 }, // D1
+                "sum(A1_C1)".to_string(),
+            )
+            .unwrap();
+
+        // Check initial sum (should be 6)
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 3, row: 0 }), // D1
+            CellValue::Int(6)
+        );
+
+        // Update A1 to 4
+        spreadsheet
+            .set_and_wait(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "4".to_string(),
+            )
+            .unwrap();
+
+        // Check sum after A1 update (should be 9)
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 3, row: 0 }), // D1
+            CellValue::Int(9)
+        );
+
+        // Update C1 to 10
+        spreadsheet
+            .set_and_wait(
+                CellIdentifier { col: 2, row: 0 }, // C1
+                "10".to_string(),
+            )
+            .unwrap();
+
+        // Check sum after C1 update (should be 16)
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 3, row: 0 }), // D1
+            CellValue::Int(16)
+        );
+    }
+
+    #[test]
+    fn test_range_sum_with_error() {
+        let spreadsheet = Spreadsheet::new();
+
+        // Set up initial values
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 1, row: 0 }, // B1
+                "1 +".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 2, row: 0 }, // C1
+                "3".to_string(),
+            )
+            .unwrap();
+
+        // Set D1 to sum of range A1_C1
+        spreadsheet
+            .set(
+                CellIdentifier { col: 3, row: 0 }, // D1
+                "sum(A1_C1)".to_string(),
+            )
+            .unwrap();
+
+        // D1 should have an error since B1 contains an invalid expression
+        match spreadsheet.get(&CellIdentifier { col: 3, row: 0 }) {
+            CellValue::Error(_) => (),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_is_consistent_mid_cascade() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet
+            .set(cell("B1"), "sleep_then(100, A1)".to_string())
+            .unwrap();
+        sheet.set(cell("C1"), "B1 + 1".to_string()).unwrap();
+        sheet.set(cell("D1"), "C1 * 2".to_string()).unwrap();
+        sheet.flush();
+
+        // Snapshot repeatedly while the worker is recomputing B1, C1 and D1
+        sheet.set(cell("A1"), "2".to_string()).unwrap();
+        let mut seen_b1 = HashSet::new();
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(300) {
+            let snapshot = sheet.snapshot();
+            let int = |name: &str| match snapshot[&cell(name)] {
+                CellValue::Int(value) => value,
+                ref other => panic!("{} holds {:?}", name, other),
+            };
+            assert_eq!(int("C1"), int("B1") + 1);
+            assert_eq!(int("D1"), int("C1") * 2);
+            seen_b1.insert(int("B1"));
+            sleep(Duration::from_millis(5));
+        }
+
+        // Both sides of the cascade were observed, never a mix of them
+        assert_eq!(seen_b1, HashSet::from([1, 2]));
+        assert_eq!(sheet.snapshot().len(), 4);
+    }
+
+    #[test]
+    fn test_long_dependency_chain() {
+        let spreadsheet = Spreadsheet::new();
+
+        // Set up chain A1 -> A2 -> A3 -> A4 -> A5
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 1 }, // A2
+                "A1 + 1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 2 }, // A3
+                "A2 + 1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 3 }, // A4
+                "A3 + 1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 4 }, // A5
+                "A4 + 1".to_string(),
+            )
+            .unwrap();
+
+        // Check initial value of A5
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 0, row: 4 }), // A5
+            CellValue::Int(5)                                    // 1 + 1 + 1 + 1 + 1 = 5
+        );
+
+        // Update A1 and check propagation
+        spreadsheet
+            .set_and_wait(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "7".to_string(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 0, row: 4 }), // A5
+            CellValue::Int(11)                                   // 7 + 1 + 1 + 1 + 1 = 11
+        );
+    }
+
+    #[test]
+    fn test_cascade_depth_limit() {
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            max_cascade_depth: Some(3),
+            ..Default::default()
+        });
+        let a = |row: u32| CellIdentifier { col: 0, row };
+
+        // A1 -> A2 -> ... -> A6, built one set at a time so each is in reach
+        sheet.set(a(0), "1".to_string()).unwrap();
+        for row in 1..6 {
+            sheet.set(a(row), format!("A{} + 1", row)).unwrap();
+        }
+        sheet.flush();
+        assert_eq!(sheet.get(&a(5)), CellValue::Int(6));
+
+        // Changing A1 reaches A2 to A4, and cuts off A5 and A6
+        sheet.set(a(0), "10".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&a(3)), CellValue::Int(13));
+        for row in [4, 5] {
+            assert_eq!(
+                sheet.get(&a(row)),
+                CellValue::Error("CascadeDepthExceeded".into())
+            );
+        }
+
+        // A change nearer the end stays within the limit
+        sheet.set(a(2), "0".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&a(5)), CellValue::Int(3));
+    }
+
+    #[test]
+    fn test_set_reports_a_dead_worker() {
+        let spreadsheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        spreadsheet.set(a1, "1".to_string()).unwrap();
+        spreadsheet
+            .set_and_wait(CellIdentifier { col: 1, row: 0 }, "A1 + 1".to_string())
+            .unwrap();
+
+        // Stop the worker behind the sheet's back
+        spreadsheet
+            .update_sender
+            .send(UpdateMessage::Shutdown)
+            .unwrap();
+        for _ in 0..100 {
+            if !spreadsheet.health().worker_alive {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert!(!spreadsheet.health().worker_alive);
+
+        assert_eq!(
+            spreadsheet.set(a1, "3".to_string()),
+            Err(SpreadsheetError::WorkerUnavailable)
+        );
+    }
+
+    #[test]
+    fn test_worker_survives_a_panicking_cascade() {
+        let spreadsheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        spreadsheet.set(a1, "1".to_string()).unwrap();
+        spreadsheet.set_and_wait(b1, "A1 + 1".to_string()).unwrap();
+
+        // An observer panics on the first cascade only
+        let panicked = Arc::new(AtomicBool::new(false));
+        let first = Arc::clone(&panicked);
+        spreadsheet.add_observer(move |_, _| {
+            if !first.swap(true, Ordering::SeqCst) {
+                panic!("observer failed");
+            }
+        });
+        spreadsheet.set_and_wait(a1, "2".to_string()).unwrap();
+        assert!(panicked.load(Ordering::SeqCst));
+        assert_eq!(spreadsheet.get(&b1), CellValue::Int(3));
+
+        // The worker goes on recomputing later updates
+        spreadsheet.set_and_wait(a1, "5".to_string()).unwrap();
+        assert_eq!(spreadsheet.get(&b1), CellValue::Int(6));
+        assert!(spreadsheet.health().is_ok());
+    }
+
+    #[test]
+    fn test_multi_level_dependency() {
+        let spreadsheet = Spreadsheet::new();
+
+        // Initial setup
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 1 }, // A2
+                "A1 + 1".to_string(),
+            )
+            .unwrap();
+
+        spreadsheet
+            .set(
+                CellIdentifier { col: 0, row: 2 }, // A3
+                "A1 + A2 + 1".to_string(),
+            )
+            .unwrap();
+
+        // Check initial value of A3
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 0, row: 2 }), // A3
+            CellValue::Int(4)                                    // 1 + 2 + 1 = 4
+        );
+
+        // Update A1 and check propagation
+        spreadsheet
+            .set_and_wait(
+                CellIdentifier { col: 0, row: 0 }, // A1
+                "2".to_string(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            spreadsheet.get(&CellIdentifier { col: 0, row: 2 }), // A3
+            CellValue::Int(6)                                    // 2 + 3 + 1 = 6
+        );
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        // Same cells set in a different order
+        let first = Spreadsheet::new();
+        first.set(a1, "5".to_string()).unwrap();
+        first.set(b1, "A1 + 1".to_string()).unwrap();
+
+        let second = Spreadsheet::new();
+        second.set(b1, "A1 + 1".to_string()).unwrap();
+        second.set(a1, "5".to_string()).unwrap();
+
+        assert_eq!(first.content_hash(), second.content_hash());
+
+        // Changing a single expression changes the hash
+        second.set(a1, "6".to_string()).unwrap();
+        assert_ne!(first.content_hash(), second.content_hash());
+
+        // Moving the same expression to another cell changes the hash
+        let third = Spreadsheet::new();
+        third.set(a1, "A1 + 1".to_string()).unwrap();
+        third.set(b1, "5".to_string()).unwrap();
+        assert_ne!(first.content_hash(), third.content_hash());
+    }
+
+    #[test]
+    fn test_diamond_cascade_is_never_torn() {
+        let sheet = Arc::new(Spreadsheet::new());
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+        let d1 = CellIdentifier { col: 3, row: 0 };
+
+        // A1 feeds B1 and C1, D1 reads both; C1 is slow to widen the window
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(b1, "A1 * 10".to_string()).unwrap();
+        sheet
+            .set(c1, "sleep_then(5, A1 * 100)".to_string())
+            .unwrap();
+        sheet.set(d1, "B1 + C1".to_string()).unwrap();
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let sheet = Arc::clone(&sheet);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    // Read the three cells under one lock to see exactly what was committed
+                    let cells = sheet.cells.lock().unwrap();
+                    let value = |id| match cells.get(&id).map(|c| &c.value) {
+                        Some(CellValue::Int(i)) => *i,
+                        other => panic!("Expected Int, got {:?}", other),
+                    };
+                    let (b, c, d) = (value(b1), value(c1), value(d1));
+                    assert_eq!(b * 10, c, "B1 and C1 come from different cascades");
+                    assert_eq!(b + c, d, "D1 does not match B1 + C1");
+                }
+            })
+        };
+
+        for i in 0..30 {
+            sheet.set(a1, format!("{}", i % 9 + 1)).unwrap();
+            sleep(Duration::from_millis(3));
+        }
+        sleep(Duration::from_millis(500));
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+        reader.join().unwrap();
+
+        // The last value written was 29 % 9 + 1 = 3
+        assert_eq!(sheet.get(&d1), CellValue::Int(330));
+    }
+
+    #[test]
+    fn test_set_time_and_worker_resolution_agree() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let a2 = CellIdentifier { col: 0, row: 1 };
+        let a3 = CellIdentifier { col: 0, row: 2 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(a2, "2".to_string()).unwrap();
+        sheet.set(a3, "3".to_string()).unwrap();
+        sheet.set(b1, "sum(A1_A3) + A4".to_string()).unwrap();
+
+        // B1 is recomputed by the worker once A2 changes
+        sheet.set(a2, "20".to_string()).unwrap();
+        sleep(Duration::from_millis(100));
+
+        // C1 evaluates the same formula at set time
+        sheet.set(c1, "sum(A1_A3) + A4".to_string()).unwrap();
+
+        // Both paths must agree, including on the unset A4
+        assert_eq!(sheet.get(&b1), sheet.get(&c1));
+    }
+
+    #[test]
+    fn test_r1c1_names_address_same_cells() {
+        let sheet = Spreadsheet::new();
+        sheet.set_address_mode(AddressMode::R1C1);
+
+        sheet.set_by_name("R2C3", "7".to_string()).unwrap();
+        assert_eq!(sheet.get_by_name("C2"), Ok(CellValue::Int(7)));
+        assert_eq!(sheet.get_by_name("R2C3"), Ok(CellValue::Int(7)));
+
+        sheet.set_by_name("A1", "8".to_string()).unwrap();
+        assert_eq!(sheet.get_by_name("R1C1"), Ok(CellValue::Int(8)));
+
+        // R1C1 names are rejected once back in A1 mode
+        sheet.set_address_mode(AddressMode::A1);
+        assert!(sheet.get_by_name("R2C3").is_err());
+        assert!(sheet.set_by_name("R2C3", "1".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_r1c1_formulas() {
+        let sheet = Spreadsheet::new();
+        sheet.set_address_mode(AddressMode::R1C1);
+
+        sheet.set_by_name("R1C1", "1".to_string()).unwrap();
+        sheet.set_by_name("R2C1", "2".to_string()).unwrap();
+        sheet.set_by_name("R3C1", "3".to_string()).unwrap();
+
+        // Both notations can be mixed and are stored as A1
+        sheet
+            .set_by_name("R1C2", "sum(R1C1_R3C1) + A1".to_string())
+            .unwrap();
+        sheet
+            .set_by_name("C1", "sum(A1_A3) + R1C1".to_string())
+            .unwrap();
+
+        assert_eq!(sheet.get_by_name("B1"), Ok(CellValue::Int(7)));
+        assert_eq!(sheet.get_by_name("R1C3"), Ok(CellValue::Int(7)));
+
+        // Dependencies recorded from R1C1 formulas still cascade
+        sheet.set_by_name("R1C1", "10".to_string()).unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(sheet.get_by_name("B1"), Ok(CellValue::Int(25)));
+        assert_eq!(sheet.get_by_name("C1"), Ok(CellValue::Int(25)));
+    }
+
+    #[test]
+    fn test_range_set_before_its_cells_exist() {
+        let sheet = Spreadsheet::new();
+        let d1 = CellIdentifier { col: 3, row: 0 };
+
+        // D1 is set while A1..C1 are still empty
+        sheet.set(d1, "sum(A1_C1)".to_string()).unwrap();
+
+        sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 1, row: 0 }, "2".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 2, row: 0 }, "3".to_string())
+            .unwrap();
+        sheet.flush();
+
+        assert_eq!(sheet.get(&d1), CellValue::Int(6));
+    }
+
+    #[test]
+    fn test_empty_cells_read_as_zero() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        // Evaluated by set, against cells that were never set
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+        sheet.set(cell("B2"), "A1".to_string()).unwrap();
+        sheet.set(cell("C1"), "7".to_string()).unwrap();
+        sheet.set(cell("B3"), "sum(C1_C3)".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(1));
+        assert_eq!(sheet.get(&cell("B2")), CellValue::Int(0));
+        assert_eq!(sheet.get(&cell("B3")), CellValue::Int(7));
+
+        // Recomputed by the worker, after A1 is set and then cleared
+        sheet.set_and_wait(cell("A1"), "4".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(5));
+        sheet.set_and_wait(cell("A1"), String::new()).unwrap();
+        sheet.set_and_wait(cell("C1"), String::new()).unwrap();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::None);
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(1));
+        assert_eq!(sheet.get(&cell("B2")), CellValue::Int(0));
+        assert_eq!(sheet.get(&cell("B3")), CellValue::Int(0));
+
+        // A 0 is a number, so it can't be joined to a string
+        sheet.set(cell("B4"), "A1 + \"!\"".to_string()).unwrap();
+        assert!(
+            matches!(sheet.get(&cell("B4")), CellValue::Error(e) if e.starts_with("TypeError"))
+        );
+    }
+
+    #[test]
+    fn test_comparisons_give_booleans() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("B1"), "5".to_string()).unwrap();
+        sheet.set(cell("C1"), "3".to_string()).unwrap();
+
+        // A comparison is stored as 1 for true and 0 for false
+        sheet.set(cell("A1"), "B1 > C1".to_string()).unwrap();
+        sheet.set(cell("A2"), "B1 == C1".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(1));
+        assert_eq!(sheet.get(&cell("A2")), CellValue::Int(0));
+
+        // A boolean can be combined and tested, but not used as a number
+        sheet.set(cell("D1"), "A1 + 1".to_string()).unwrap();
+        assert_eq!(
+            sheet.get(&cell("D1")),
+            CellValue::Error("TypeError: A1 + 1 uses a boolean in arithmetic".into())
+        );
+        sheet.set(cell("D2"), "A1 && !A2".to_string()).unwrap();
+        sheet
+            .set(cell("D3"), "if A2 { \"yes\" } else { \"no\" }".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&cell("D2")), CellValue::Int(1));
+        assert_eq!(sheet.get(&cell("D3")), CellValue::String("no".into()));
+
+        // The worker keeps the flag as the comparison is recomputed
+        sheet.set_and_wait(cell("C1"), "9".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(0));
+        assert_eq!(sheet.get(&cell("D2")), CellValue::Int(0));
+        assert!(
+            matches!(sheet.get(&cell("D1")), CellValue::Error(e) if e.starts_with("TypeError"))
+        );
+
+        // Exported as get shows them
+        let mut csv = Vec::new();
+        sheet.export_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "0,5,9,#ERROR\n0,,,0\n,,,no\n"
+        );
+
+        // A plain 1 is still a number
+        sheet.set_and_wait(cell("A1"), "1".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(2));
+    }
+
+    #[test]
+    fn test_reversed_ranges_match_canonical() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (index, name) in ["A1", "A2", "A3", "B1", "B2", "B3"].iter().enumerate() {
+            sheet.set(cell(name), (index + 1).to_string()).unwrap();
+        }
+
+        // Reversed fully, in rows only and in columns only
+        let formulas = [
+            ("D1", "sum(A1_B3)"),
+            ("D2", "sum(B3_A1)"),
+            ("D3", "sum(A3_B1)"),
+            ("D4", "sum(B1_A3)"),
+        ];
+        for (name, expression) in formulas {
+            sheet.set(cell(name), expression.to_string()).unwrap();
+        }
+        let canonical = sheet.dependencies_of(&cell("D1"));
+        assert_eq!(canonical.len(), 6);
+        for (name, _) in formulas {
+            assert_eq!(sheet.get(&cell(name)), CellValue::Int(21));
+            assert_eq!(sheet.dependencies_of(&cell(name)), canonical);
+        }
+
+        // Every form is recomputed when a cell inside it changes
+        assert_eq!(
+            sheet.dependents_of(&cell("B2"), false),
+            ["D1", "D2", "D3", "D4"].map(cell)
+        );
+        sheet.set_and_wait(cell("B2"), "15".to_string()).unwrap();
+        for (name, _) in formulas {
+            assert_eq!(sheet.get(&cell(name)), CellValue::Int(31));
+        }
+    }
+
+    #[test]
+    fn test_column_range_grows_with_new_rows() {
+        let sheet = Spreadsheet::new();
+        let a = |row| CellIdentifier { col: 0, row };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        sheet.set(a(0), "1".to_string()).unwrap();
+        sheet.set(a(1), "2".to_string()).unwrap();
+        sheet.set(b1, "sum(A1_A)".to_string()).unwrap();
+        assert_eq!(sheet.get(&b1), CellValue::Int(3));
+
+        // Rows added below the last populated row join the range
+        sheet.set(a(2), "3".to_string()).unwrap();
+        sheet.set(a(3), "4".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(10));
+
+        // A range starting lower down only covers the rows from its start
+        sheet.set(b1, "sum(A3_A)".to_string()).unwrap();
+        assert_eq!(sheet.get(&b1), CellValue::Int(7));
+
+        // Gaps count as empty cells, which read as 0
+        sheet.set(a(9), "5".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(12));
+    }
+
+    #[test]
+    fn test_empty_cells_in_ranges_modes() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        // A2 and A4 stay unset; B1 is computed by set, then by the worker
+        let sum_with_holes = |empty_cells_in_ranges: EmptyCells| {
+            let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+                empty_cells_in_ranges,
+                ..Default::default()
+            });
+            for (name, expression) in [("A1", "1"), ("A3", "3"), ("A5", "5")] {
+                sheet.set(cell(name), expression.to_string()).unwrap();
+            }
+            sheet.set(cell("B1"), "sum(A1_A5)".to_string()).unwrap();
+            let by_set = sheet.get(&cell("B1"));
+            sheet.set(cell("A1"), "2".to_string()).unwrap();
+            sheet.flush();
+            (by_set, sheet.get(&cell("B1")))
+        };
+
+        assert_eq!(
+            sum_with_holes(EmptyCells::TreatAsZero),
+            (CellValue::Int(9), CellValue::Int(10))
+        );
+        let (by_set, by_worker) = sum_with_holes(EmptyCells::TreatAsNone);
+        assert!(matches!(&by_set, CellValue::Error(message) if message.contains("Unknown value")));
+        assert_eq!(by_worker, by_set);
+        let error = CellValue::Error("EmptyCellError in range A1_A5: A2 is empty".into());
+        assert_eq!(sum_with_holes(EmptyCells::Error), (error.clone(), error));
+
+        // Single cells read as 0 whatever the mode
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            empty_cells_in_ranges: EmptyCells::Error,
+            ..Default::default()
+        });
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(1));
+    }
+
+    #[test]
+    fn test_eval_timeout_stops_runaway_formulas() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            eval_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+
+        // A slow formula gives up at the timeout, whether set or recomputed
+        let started = Instant::now();
+        sheet
+            .set(cell("C1"), "sleep_then(5000, A1)".to_string())
+            .unwrap();
+        sheet.set_and_wait(cell("A1"), "2".to_string()).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            sheet.get(&cell("C1")),
+            CellValue::Error("EvaluationTimeout".into())
+        );
+
+        // Other cells carry on, and quick formulas aren't affected
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(3));
+        sheet
+            .set(cell("D1"), "sleep_then(1, B1 * 2)".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(6));
+    }
+
+    #[test]
+    fn test_runaway_formulas_leave_other_cells_alone() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            eval_timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+
+        // More runaway cells than may be left running at once
+        for row in 1..=6 {
+            sheet
+                .set(cell(&format!("A{row}")), "sleep_then(2000, 5)".to_string())
+                .unwrap();
+            assert_eq!(
+                sheet.get(&cell(&format!("A{row}"))),
+                CellValue::Error("EvaluationTimeout".into())
+            );
+        }
+        assert_eq!(
+            sheet.stats().abandoned_evaluations,
+            crate::evaluator::MAX_ABANDONED_EVALUATIONS
+        );
+
+        // An ordinary cell still evaluates, and so do its dependents
+        sheet.set(cell("B1"), "1 + 1".to_string()).unwrap();
+        sheet
+            .set_and_wait(cell("B2"), "B1 * 3".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(2));
+        assert_eq!(sheet.get(&cell("B2")), CellValue::Int(6));
+    }
+
+    #[test]
+    fn test_eval_timeout_reuses_one_thread() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            eval_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        });
+
+        // Sets and the worker's recomputes all share the evaluator's thread
+        for n in 1..=200 {
+            sheet.set(cell("A1"), n.to_string()).unwrap();
+            sheet.set(cell("B1"), "A1 * 2".to_string()).unwrap();
+        }
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(400));
+        let stats = sheet.stats();
+        assert_eq!(stats.evaluator_threads, 1);
+        assert_eq!(stats.abandoned_evaluations, 0);
+    }
+
+    #[test]
+    fn test_range_and_expression_limits() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            max_range_cells: Some(DEFAULT_MAX_RANGE_CELLS),
+            max_expression_length: Some(20),
+            ..Default::default()
+        });
+
+        // Over-limit sets are rejected unapplied, and the sheet carries on
+        let error = sheet.set(cell("B1"), "sum(A1_ZZ99999)".to_string());
+        assert_eq!(
+            error,
+            Err(SpreadsheetError::RangeTooLarge(
+                "A1_ZZ99999".into(),
+                100_000
+            ))
+        );
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "range A1_ZZ99999 exceeds the 100000-cell limit"
+        );
+        assert_eq!(
+            sheet.set(cell("B1"), format!("1{}", " + 1".repeat(5))),
+            Err(SpreadsheetError::ExpressionTooLong(20))
+        );
+        assert_eq!(sheet.get(&cell("B1")), CellValue::None);
+        sheet.set(cell("A1"), "4".to_string()).unwrap();
+        sheet
+            .set(cell("CW1"), "sum(A1_CV1000)".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&cell("CW1")), CellValue::Int(4));
+
+        // A whole column is measured once it has grown
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            max_range_cells: Some(3),
+            ..Default::default()
+        });
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("C1"), "sum(A_A)".to_string()).unwrap();
+        sheet.set(cell("A4"), "4".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(
+            sheet.get(&cell("C1")),
+            CellValue::Error("range A_A exceeds the 3-cell limit".into())
+        );
+
+        // New sheets are permissive
+        let sheet = Spreadsheet::new();
+        sheet
+            .set(cell("B1"), "sum(A1_ZZ99999)".to_string())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        assert_eq!(sheet.version(), 0);
+
+        for (name, expression) in [("A1", "1"), ("A2", "2"), ("B1", "A1 * 10"), ("C1", "7")] {
+            sheet.set(cell(name), expression.to_string()).unwrap();
+        }
+        sheet.flush();
+        let seen = sheet.version();
+        assert_eq!(sheet.changed_since(seen), []);
+
+        // A1's change reaches its dependent B1; setting C1 to the value it
+        // already holds, and A2 to an equal expression, changes nothing
+        sheet.set(cell("A1"), "3".to_string()).unwrap();
+        sheet.set(cell("C1"), "7".to_string()).unwrap();
+        sheet.set(cell("A2"), "1 + 1".to_string()).unwrap();
+        sheet
+            .set_and_wait(cell("D4"), "\"new\"".to_string())
+            .unwrap();
+        let changes = sheet.changed_since(seen);
+        let mut cells: Vec<(CellIdentifier, CellValue)> = changes
+            .iter()
+            .map(|(cell_id, value, _)| (*cell_id, value.clone()))
+            .collect();
+        cells.sort_by_key(|(cell_id, _)| (cell_id.row, cell_id.col));
+        assert_eq!(
+            cells,
+            [
+                (cell("A1"), CellValue::Int(3)),
+                (cell("B1"), CellValue::Int(30)),
+                (cell("D4"), CellValue::String("new".into())),
+            ]
+        );
+
+        // Changes come oldest first, and the newest is the sheet's version
+        assert!(changes.windows(2).all(|pair| pair[0].2 <= pair[1].2));
+        assert_eq!(changes.last().unwrap().2, sheet.version());
+        assert_eq!(sheet.changed_since(sheet.version()), []);
+
+        // A cleared cell reports None
+        let seen = sheet.version();
+        sheet.set(cell("C1"), String::new()).unwrap();
+        assert_eq!(
+            sheet.changed_since(seen),
+            [(cell("C1"), CellValue::None, seen + 1)]
+        );
+    }
+
+    #[test]
+    fn test_sheet_bounds() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            max_rows: 10,
+            max_cols: 3,
+            ..Default::default()
+        });
+        let out_of_bounds = |name: &str| SpreadsheetError::OutOfBounds(cell(name), cell("C10"));
+
+        // The last row and column are inside the sheet, one further isn't
+        sheet.set(cell("C10"), "7".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("C10")), CellValue::Int(7));
+        assert_eq!(
+            sheet.set(cell("D1"), "1".to_string()),
+            Err(out_of_bounds("D1"))
+        );
+        assert_eq!(
+            sheet
+                .set(cell("A11"), "1".to_string())
+                .unwrap_err()
+                .to_string(),
+            "cell A11 is outside the sheet bounds (max C10)"
+        );
+        assert!(sheet.list_cells().iter().all(|(id, ..)| *id == cell("C10")));
+
+        // Expressions can't name cells past the edge either, and a range
+        // may give its corners in any order
+        assert_eq!(
+            sheet.set(cell("A1"), "sum(A1_C11)".to_string()),
+            Err(out_of_bounds("C11"))
+        );
+        assert_eq!(
+            sheet.compare_and_set(cell("D2"), &CellValue::None, "1".to_string()),
+            Err(out_of_bounds("D2"))
+        );
+        sheet.set(cell("A10"), "3".to_string()).unwrap();
+        sheet.set(cell("A1"), "sum(C10_A9)".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(10));
+        sheet.set(cell("B10"), "5".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(15));
+    }
+
+    #[test]
+    fn test_whole_column_and_row_ranges() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("A2"), "2".to_string()).unwrap();
+        sheet.set(cell("C1"), "sum(A_A)".to_string()).unwrap();
+        sheet.set(cell("D3"), "sum(2_2) * 10".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(3));
+        assert_eq!(sheet.get(&cell("D3")), CellValue::Int(20));
+
+        // Values set far past the used extent still join the sums
+        sheet.set(cell("A50"), "4".to_string()).unwrap();
+        sheet.set(cell("F2"), "5".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(7));
+        assert_eq!(sheet.get(&cell("D3")), CellValue::Int(70));
+
+        // A cell inside the row it sums reads itself
+        assert_eq!(sheet.set(cell("D2"), "sum(1_2)".to_string()), Ok(()));
+        assert_eq!(
+            sheet.get(&cell("D2")),
+            CellValue::Error("SelfReference".into())
+        );
+    }
+
+    #[test]
+    fn test_malformed_ranges_are_not_references() {
+        let sheet = Spreadsheet::new();
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        sheet
+            .set(CellIdentifier { col: 2, row: 0 }, "1".to_string())
+            .unwrap();
+
+        // An open range can't end left of its start column
+        assert_eq!(
+            sheet.set(b1, "C1_A".to_string()),
+            Err(SpreadsheetError::InvalidReference("C1_A".into()))
+        );
+        assert!(sheet.set(b1, "A1_B2_C3".to_string()).is_err());
+        assert_eq!(sheet.get_with_presence(&b1), (false, CellValue::None));
+    }
+
+    #[test]
+    fn test_set_rejects_unknown_variables() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(b1, "A1 * 2".to_string()).unwrap();
+
+        // A malformed range and a stray token are rejected, not dropped
+        assert_eq!(
+            sheet.set(b1, "sum(A1_) + 1".to_string()),
+            Err(SpreadsheetError::InvalidReference("A1_".into()))
+        );
+        let error = sheet.set(b1, "A1 + Q".to_string()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Q is not a valid cell or range reference"
+        );
+
+        // The rejected updates leave the cell as it was
+        assert_eq!(sheet.get(&b1), CellValue::Int(2));
+    }
+
+    #[test]
+    fn test_list_cells() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let a2 = CellIdentifier { col: 0, row: 1 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c3 = CellIdentifier { col: 2, row: 2 };
+
+        sheet.set(c3, "A1 * 2".to_string()).unwrap();
+        sheet.set(b1, "7".to_string()).unwrap();
+        sheet.set(a2, "\"hi\"".to_string()).unwrap();
+        sheet.set(a1, "3".to_string()).unwrap();
+
+        // Clearing B1 removes it from the listing
+        sheet.set(b1, "".to_string()).unwrap();
+        sheet.flush();
+
+        assert_eq!(
+            sheet.list_cells(),
+            vec![
+                (a1, "3".to_string(), CellValue::Int(3)),
+                (
+                    a2,
+                    "\"hi\"".to_string(),
+                    CellValue::String("hi".to_string())
+                ),
+                (c3, "A1 * 2".to_string(), CellValue::Int(6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cascade_stops_where_values_settle() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, expression) in [
+            ("A1", "1"),
+            ("B1", "A1 * 0"),
+            ("C1", "B1 + 1"),
+            ("D1", "C1 * 2"),
+            ("E1", "A1 + D1"),
+        ] {
+            sheet.set(cell(name), expression.to_string()).unwrap();
+        }
+        sheet.flush();
+        let before = sheet.recomputations();
+
+        // B1 stays 0, so C1 and D1 are skipped; E1 reads A1 itself
+        sheet.set_and_wait(cell("A1"), "5".to_string()).unwrap();
+        assert_eq!(sheet.recomputations() - before, 2);
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(2));
+        assert_eq!(sheet.get(&cell("E1")), CellValue::Int(7));
+
+        // Once B1 changes, the whole chain is recomputed again
+        let before = sheet.recomputations();
+        sheet
+            .set_and_wait(cell("B1"), "A1 - 1".to_string())
+            .unwrap();
+        assert_eq!(sheet.recomputations() - before, 3);
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(10));
+        assert_eq!(sheet.get(&cell("E1")), CellValue::Int(15));
+    }
+
+    #[test]
+    fn test_throttled_root_collapses_cascades() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        sheet.set(a1, "0".to_string()).unwrap();
+        sheet.set(b1, "A1 * 2".to_string()).unwrap();
+        sheet.set_throttle(a1, Some(Duration::from_millis(200)));
+        sheet.flush();
+        let before = sheet.recomputations();
+
+        // Stream updates, ideally well within a single interval
+        let started = Instant::now();
+        for i in 1..=50 {
+            sheet.set(a1, i.to_string()).unwrap();
+            sleep(Duration::from_millis(1));
+        }
+        let intervals = (started.elapsed().as_millis() / 200) as usize;
+
+        // Without a flush, the deferred cascade still lands after the interval
+        sleep(Duration::from_millis(400));
+        assert_eq!(sheet.get(&b1), CellValue::Int(100));
+
+        // One immediate cascade, then at most one per elapsed interval
+        let cascades = sheet.recomputations() - before;
+        assert!(cascades <= intervals + 2, "{cascades} cascades");
+        assert!(cascades < 50);
+    }
+
+    #[test]
+    fn test_large_range_recomputes_without_expanding_edges() {
+        let sheet = Spreadsheet::new();
+        let inside = CellIdentifier { col: 50, row: 50 }; // AY51
+        let reader = CellIdentifier { col: 101, row: 0 }; // CX1
+
+        // Index into a 100x100 block so its empty cells don't matter
+        sheet.set(inside, "2".to_string()).unwrap();
+        sheet
+            .set(reader, "A1_CV100[50][50] + 1".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&reader), CellValue::Int(3));
+        assert_eq!(sheet.dependency_edges(), 100);
+
+        // Changing a cell in the middle of the block still reaches the reader
+        sheet.set(inside, "5".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&reader), CellValue::Int(6));
+    }
+
+    #[test]
+    fn test_worker_backlog_drains() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        // Every cascade from A1 keeps the worker busy for a while
+        sheet.set(b1, "sleep_then(100, A1)".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.worker_backlogs(), vec![0]);
+
+        for i in 0..3 {
+            sheet.set(a1, i.to_string()).unwrap();
+        }
+        assert!(sheet.worker_backlogs()[0] >= 1);
+
+        sheet.flush();
+        assert_eq!(sheet.worker_backlogs(), vec![0]);
+        assert_eq!(sheet.get(&b1), CellValue::Int(2));
+    }
+
+    #[test]
+    fn test_self_reference() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let a2 = CellIdentifier { col: 0, row: 1 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let self_reference = CellValue::Error("SelfReference".into());
+
+        // Scalar case
+        sheet.set(a1, "A1".to_string()).unwrap();
+        assert_eq!(sheet.get(&a1), self_reference);
+        sheet.set(a1, "A1 + 1".to_string()).unwrap();
+        assert_eq!(sheet.get(&a1), self_reference);
+
+        // Range case, where the target sits inside the summed range
+        sheet.set(a2, "2".to_string()).unwrap();
+        sheet.set(a1, "sum(A1_A3)".to_string()).unwrap();
+        assert_eq!(sheet.get(&a1), self_reference);
+
+        // It stays an error when a cell in the range changes
+        sheet.set(a2, "3".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&a1), self_reference);
+
+        // Dependents see an error, and a fixed formula recovers
+        sheet.set(b1, "A1 + 1".to_string()).unwrap();
+        assert!(matches!(sheet.get(&b1), CellValue::Error(_)));
+        sheet.set(a1, "A2 * 2".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&a1), CellValue::Int(6));
+        assert_eq!(sheet.get(&b1), CellValue::Int(7));
+    }
+
+    #[test]
+    fn test_error_propagates_down_long_chains() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+        let d1 = CellIdentifier { col: 3, row: 0 };
+        let depends_on_error = CellValue::Error("VariableDependsOnError".into());
+
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(b1, "A1 + 1".to_string()).unwrap();
+        sheet.set(c1, "B1 * 2".to_string()).unwrap();
+        sheet.set(d1, "C1 - 1".to_string()).unwrap();
+
+        // Breaking A1 reaches every level of the chain
+        sheet.set(a1, "1 +".to_string()).unwrap();
+        sheet.flush();
+        assert!(matches!(sheet.get(&a1), CellValue::Error(_)));
+        assert_eq!(sheet.get(&b1), depends_on_error);
+        assert_eq!(sheet.get(&c1), depends_on_error);
+        assert_eq!(sheet.get(&d1), depends_on_error);
+
+        // Fixing A1 brings the whole chain back
+        sheet.set(a1, "3".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(4));
+        assert_eq!(sheet.get(&c1), CellValue::Int(8));
+        assert_eq!(sheet.get(&d1), CellValue::Int(7));
+    }
+
+    #[test]
+    fn test_error_propagates_through_ranges() {
+        let sheet = Spreadsheet::new();
+        let a2 = CellIdentifier { col: 0, row: 1 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
+            .unwrap();
+        sheet.set(a2, "2".to_string()).unwrap();
+        sheet.set(b1, "sum(A1_A2)".to_string()).unwrap();
+        sheet.set(c1, "B1 * 10".to_string()).unwrap();
+        assert_eq!(sheet.get(&c1), CellValue::Int(30));
+
+        sheet.set(a2, "1 +".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(
+            sheet.get(&b1),
+            CellValue::Error("VariableDependsOnError".into())
+        );
+        assert_eq!(
+            sheet.get(&c1),
+            CellValue::Error("VariableDependsOnError".into())
+        );
+
+        sheet.set(a2, "5".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(6));
+        assert_eq!(sheet.get(&c1), CellValue::Int(60));
+    }
+
+    #[test]
+    fn test_stats() {
+        let sheet = Spreadsheet::new();
+        let stats = sheet.stats();
+        assert_eq!(
+            stats,
+            SheetStats {
+                uptime: stats.uptime,
+                ..SheetStats::default()
+            }
+        );
+
+        sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 0, row: 1 }, "2".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 1, row: 0 }, "sum(A1_A2)".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 2, row: 0 }, "B1 + A1".to_string())
+            .unwrap();
+        sheet.flush();
+
+        let stats = sheet.stats();
+        assert_eq!(
+            stats,
+            SheetStats {
+                cells: 4,
+                errors: 0,
+                edges: 3, // One span for A1_A2, plus B1 and A1 named by C1
+                max_chain_depth: 2,
+                queue_depth: 0,
+                evaluations: stats.evaluations,
+                evaluator_threads: 0, // No eval_timeout, so formulas run where they're set
+                abandoned_evaluations: 0,
+                uptime: stats.uptime,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stats_counters_move() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let before = sheet.stats();
+
+        sheet.set_and_wait(a1, "1".to_string()).unwrap();
+        sheet.set_and_wait(b1, "A1 + 1".to_string()).unwrap();
+        let set_up = sheet.stats();
+        assert_eq!(set_up.evaluations, before.evaluations + 2);
+        assert_eq!(set_up.errors, 0);
+
+        // Changing A1 evaluates it and then its dependent B1
+        sheet.set(a1, "1 / 0".to_string()).unwrap();
+        sheet.flush();
+        let after = sheet.stats();
+        assert_eq!(after.evaluations, set_up.evaluations + 2);
+        assert_eq!(after.errors, 2); // A1's own error and B1's dependency error
+        assert!(after.uptime >= set_up.uptime);
+
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.stats().errors, 0);
+    }
+
+    #[test]
+    fn test_string_in_numeric_range() {
+        let sheet = Spreadsheet::new();
+        let d1 = CellIdentifier { col: 3, row: 0 };
+
+        sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 0, row: 1 }, "\"two\"".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 0, row: 2 }, "3".to_string())
+            .unwrap();
+
+        sheet.set(d1, "sum(A1_A3)".to_string()).unwrap();
+        assert_eq!(
+            sheet.get(&d1),
+            CellValue::Error("TypeError in range A1_A3: A2 is not a number".into())
+        );
+
+        // Matrices name the offending cell by row and column too
+        sheet
+            .set(CellIdentifier { col: 1, row: 1 }, "\"x\"".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 0, row: 1 }, "2".to_string())
+            .unwrap();
+        sheet.set(d1, "sum(A1_B2)".to_string()).unwrap();
+        assert_eq!(
+            sheet.get(&d1),
+            CellValue::Error("TypeError in range A1_B2: B2 is not a number".into())
+        );
+
+        // Open-ended ranges are checked once bounded
+        sheet.set(d1, "sum(B1_B)".to_string()).unwrap();
+        assert_eq!(
+            sheet.get(&d1),
+            CellValue::Error("TypeError in range B1_B: B2 is not a number".into())
+        );
+
+        // The worker applies the same check when the range changes later
+        sheet.set(d1, "sum(A1_A3)".to_string()).unwrap();
+        assert_eq!(sheet.get(&d1), CellValue::Int(6));
+        sheet
+            .set(CellIdentifier { col: 0, row: 2 }, "\"three\"".to_string())
+            .unwrap();
+        sheet.flush();
+        assert_eq!(
+            sheet.get(&d1),
+            CellValue::Error("TypeError in range A1_A3: A3 is not a number".into())
+        );
+    }
+
+    #[test]
+    fn test_error_source() {
+        let sheet = Spreadsheet::new();
+        let name = |col, row| CellIdentifier { col, row };
+
+        // Two broken cells feed B1 through a range; C1 reads B1
+        sheet.set(name(0, 1), "1 +".to_string()).unwrap();
+        sheet.set(name(0, 0), "2 *".to_string()).unwrap();
+        sheet.set(name(1, 0), "sum(A1_A2)".to_string()).unwrap();
+        sheet.set(name(2, 0), "B1 * 2".to_string()).unwrap();
+        sheet.flush();
+
+        // The smallest genuine error wins, however far down the chain
+        let (source, message) = sheet.error_source(&name(2, 0)).unwrap();
+        assert_eq!(source, name(0, 0));
+        assert_eq!(CellValue::Error(message), sheet.get(&name(0, 0)));
+        assert_eq!(sheet.error_source(&name(1, 0)).unwrap().0, name(0, 0));
+
+        // Once A1 is fixed, the remaining broken cell is reported
+        sheet.set(name(0, 0), "1".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.error_source(&name(2, 0)).unwrap().0, name(0, 1));
+
+        // Cells without an inherited error have no source
+        sheet.set(name(0, 1), "2".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.error_source(&name(2, 0)), None);
+    }
+
+    #[test]
+    fn test_sumif() {
+        let sheet = Spreadsheet::new();
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        for (row, value) in ["1", "5", "\"text\"", "8", "3"].iter().enumerate() {
+            sheet
+                .set(
+                    CellIdentifier {
+                        col: 0,
+                        row: row as u32,
+                    },
+                    value.to_string(),
+                )
+                .unwrap();
+        }
+
+        // Only 5 and 8 are above 3; the string and the unset A6 are skipped
+        sheet
+            .set(CellIdentifier { col: 1, row: 1 }, "1 +".to_string())
+            .unwrap();
+        sheet.set(c1, "sumif(A1_A6, 3)".to_string()).unwrap();
+        assert_eq!(sheet.get(&c1), CellValue::Int(13));
+
+        // Matrices are filtered element by element
+        sheet
+            .set(CellIdentifier { col: 1, row: 0 }, "10".to_string())
+            .unwrap();
+        sheet.set(c1, "sumif(A1_B2, 4) * 2".to_string()).unwrap();
+        assert_eq!(sheet.get(&c1), CellValue::Int(30));
+
+        // Changes inside the range are picked up by the worker
+        sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "6".to_string())
+            .unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&c1), CellValue::Int(42));
+    }
+
+    #[test]
+    fn test_if() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "5".to_string()).unwrap();
+        sheet.set(cell("B1"), "10".to_string()).unwrap();
+        sheet.set(cell("C1"), "1 / 0".to_string()).unwrap();
+        sheet
+            .set(cell("D1"), "if(A1 > 3, B1 * 2, C1)".to_string())
+            .unwrap();
+
+        // The untaken branch's error doesn't matter
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(20));
+
+        // Every branch is still a dependency, so either one can take over
+        sheet.set(cell("C1"), "7".to_string()).unwrap();
+        sheet.set(cell("A1"), "0".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(7));
+        sheet.set(cell("C1"), "8".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("D1")), CellValue::Int(8));
+
+        // A non-zero integer condition takes the first branch
+        sheet
+            .set(cell("D1"), "if(B1, \"yes\", \"no\")".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&cell("D1")), CellValue::String("yes".into()));
+    }
+
+    #[test]
+    fn test_fixing_an_error_recovers_dependents() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        sheet.set(a1, "1 +".to_string()).unwrap();
+        sheet.set(b1, "A1 + 1".to_string()).unwrap();
+        sheet.set(c1, "B1 * 2".to_string()).unwrap();
+        sheet.flush();
+        assert!(Spreadsheet::is_dependency_error(&sheet.get(&c1)));
+
+        sheet.set(a1, "7".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(8));
+        assert_eq!(sheet.get(&c1), CellValue::Int(16));
+    }
+
+    #[test]
+    fn test_error_set_racing_a_fix_recovers() {
+        let sheet = Arc::new(Spreadsheet::new());
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        // Keep setting B1 while A1 flips from broken to fixed, so some of
+        // B1's set-time evaluations see the broken A1 and land late
+        sheet.set(a1, "1 +".to_string()).unwrap();
+        let setter = {
+            let sheet = Arc::clone(&sheet);
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    sheet.set(b1, "A1 + 1".to_string()).unwrap();
+                }
+            })
+        };
+        sheet.set(a1, "7".to_string()).unwrap();
+        setter.join().unwrap();
+        sheet.flush();
+
+        assert_eq!(sheet.get(&b1), CellValue::Int(8));
+    }
+
+    #[test]
+    fn test_health() {
+        let sheet = Arc::new(Spreadsheet::new());
+        sheet
+            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
+            .unwrap();
+        sheet.flush();
+
+        let report = sheet.health();
+        assert!(report.is_ok());
+        assert!(report.worker_alive);
+        assert_eq!(report.pending_updates, 0);
+
+        // Poison the cells lock by panicking while holding it
+        let poisoner = Arc::clone(&sheet);
+        let _ = thread::spawn(move || {
+            let _cells = poisoner.cells.lock().unwrap();
+            panic!("poisoning the cells lock");
+        })
+        .join();
+
+        let report = sheet.health();
+        assert!(report.cells_poisoned);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn test_poisoned_locks_keep_working() {
+        let sheet = Arc::new(Spreadsheet::new());
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(b1, "A1 * 10".to_string()).unwrap();
+        sheet.flush();
+
+        // Poison both locks by panicking while holding them
+        let poisoner = Arc::clone(&sheet);
+        let _ = thread::spawn(move || {
+            let _graph = poisoner.graph.lock().unwrap();
+            panic!("poisoning the graph lock");
+        })
+        .join();
+        let poisoner = Arc::clone(&sheet);
+        let _ = thread::spawn(move || {
+            let _cells = poisoner.cells.lock().unwrap();
+            panic!("poisoning the cells lock");
+        })
+        .join();
+
+        // Reads, sets and the worker's cascades carry on
+        assert_eq!(sheet.get(&b1), CellValue::Int(10));
+        sheet.set(a1, "2".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(20));
+        assert_eq!(sheet.force_recompute_value(&b1), Ok(CellValue::Int(20)));
+
+        let report = sheet.health();
+        assert!(report.worker_alive);
+        assert!(report.cells_poisoned && report.graph_poisoned);
+    }
+
+    #[test]
+    fn test_bounded_queue_applies_backpressure() {
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            queue_capacity: 4,
+            ..Default::default()
+        });
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        // Each cascade is slower than a set, so the queue fills up
+        sheet.set(b1, "sleep_then(2, A1)".to_string()).unwrap();
+
+        let mut largest_backlog = 0;
+        for i in 0..100 {
+            sheet.set(a1, i.to_string()).unwrap();
+            largest_backlog = largest_backlog.max(sheet.worker_backlogs()[0]);
+        }
+
+        // At most the queue's capacity plus the one message being sent
+        assert!(largest_backlog <= 5, "backlog reached {largest_backlog}");
+
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(99));
+    }
+
+    #[test]
+    fn test_rapid_sets_coalesce_into_few_passes() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        // A slow dependent lets updates pile up behind each pass
+        sheet.set(b1, "sleep_then(50, A1)".to_string()).unwrap();
+        sheet.set(c1, "B1 + 1".to_string()).unwrap();
+        sheet.flush();
+        let before = sheet.recompute_passes();
+
+        let started = Instant::now();
+        for i in 0..100 {
+            sheet.set(a1, i.to_string()).unwrap();
+        }
+        let slow_passes = (started.elapsed().as_millis() / 50) as usize;
+        sheet.flush();
+
+        // Same result as one pass per set, from far fewer passes: one for
+        // each pass that ran while the sets streamed in, plus the last one
+        assert_eq!(sheet.get(&b1), CellValue::Int(99));
+        assert_eq!(sheet.get(&c1), CellValue::Int(100));
+        let passes = sheet.recompute_passes() - before;
+        assert!(passes <= slow_passes + 2, "{passes} passes");
+        assert!(passes < 50, "{passes} passes");
+    }
+
+    #[test]
+    fn test_range_is_read_in_one_snapshot() {
+        let sheet = Arc::new(Spreadsheet::new());
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+
+        // A2 and A3 always change together, in a single cascade from A1
+        sheet.set(a1, "0".to_string()).unwrap();
+        sheet
+            .set(CellIdentifier { col: 0, row: 1 }, "A1".to_string())
+            .unwrap();
+        sheet
+            .set(CellIdentifier { col: 0, row: 2 }, "A1".to_string())
+            .unwrap();
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let sheet = Arc::clone(&sheet);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut i = 0;
+                while !done.load(Ordering::SeqCst) {
+                    i += 1;
+                    sheet.set(a1, i.to_string()).unwrap();
+                }
+            })
+        };
+
+        for _ in 0..200 {
+            sheet.set(c1, "sum(A2_A3)".to_string()).unwrap();
+            match sheet.get(&c1) {
+                CellValue::Int(sum) => assert_eq!(sum % 2, 0, "A2 and A3 were read apart"),
+                other => panic!("Expected Int, got {:?}", other),
+            }
+        }
+        done.store(true, Ordering::SeqCst);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_force_recompute_value() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        sheet.set(a1, "4".to_string()).unwrap();
+        sheet.set(b1, "A1 * 3".to_string()).unwrap();
+        sheet.flush();
+
+        // Corrupt B1's cached value behind the sheet's back
+        sheet.cells.lock().unwrap().get_mut(&b1).unwrap().value = CellValue::Int(-1);
+
+        assert_eq!(sheet.force_recompute_value(&b1), Ok(CellValue::Int(12)));
+        assert_eq!(sheet.get(&b1), CellValue::Int(-1));
+        assert_eq!(
+            sheet.force_recompute_value(&CellIdentifier { col: 5, row: 5 }),
+            Err(SpreadsheetError::CellNotSet(CellIdentifier {
+                col: 5,
+                row: 5
+            }))
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, expression) in [
+            ("A1", "1"),
+            ("A2", "2"),
+            ("A3", "3"),
+            ("B1", "sum(A1_A3)"),
+            ("C1", "B1 * A2"),
+            ("D1", "1 +"),
+            ("D2", "\"hi\""),
+        ] {
+            sheet.set(cell(name), expression.to_string()).unwrap();
+        }
+        sheet.flush();
+
+        let json = sheet.to_json();
+        assert!(json.starts_with(
+            r#"[{"cell":"A1","expr":"1","value":{"type":"int","value":1}},{"cell":"A2""#
+        ));
+        assert!(
+            json.contains(r#"{"cell":"C1","expr":"B1 * A2","value":{"type":"int","value":12}}"#)
+        );
+        assert!(json.contains(r#""expr":"1 +","value":{"type":"error","#));
+        assert!(json.contains(r#""expr":"\"hi\"","value":{"type":"string","value":"hi"}"#));
+
+        // Expressions come back as they were, and values are recomputed
+        let imported = Spreadsheet::from_json(&json).unwrap();
+        assert_eq!(imported.to_json(), json);
+        assert_eq!(imported.content_hash(), sheet.content_hash());
+        imported.set(cell("A2"), "5".to_string()).unwrap();
+        imported.flush();
+        assert_eq!(imported.get(&cell("B1")), CellValue::Int(9));
+        assert_eq!(imported.get(&cell("C1")), CellValue::Int(45));
+
+        assert!(Spreadsheet::from_json("{").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("rsheet-snapshot-{}.json", std::process::id()));
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+        let c1 = CellIdentifier { col: 2, row: 0 };
+        let d1 = CellIdentifier { col: 3, row: 0 };
+
+        // Written with forward references and an error cell, as a user might
+        std::fs::write(
+            &path,
+            r#"{"cells": [
+                {"cell": "C1", "expression": "B1 * 2"},
+                {"cell": "D1", "expression": "1 +"},
+                {"cell": "B1", "expression": "A1 + 1"},
+                {"cell": "A1", "expression": "1"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let sheet = Spreadsheet::load_from_path(&path).unwrap();
+        assert_eq!(sheet.get(&c1), CellValue::Int(4));
+        assert!(matches!(sheet.get(&d1), CellValue::Error(_)));
+
+        // Dependents are rebuilt, not just values
+        sheet.set(a1, "10".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::Int(11));
+        assert_eq!(sheet.get(&c1), CellValue::Int(22));
+
+        // Saving and loading again gives the same sheet
+        sheet.save_to_path(&path).unwrap();
+        let reloaded = Spreadsheet::load_from_path(&path).unwrap();
+        assert_eq!(reloaded.content_hash(), sheet.content_hash());
+        assert_eq!(reloaded.get(&c1), CellValue::Int(22));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(Spreadsheet::load_from_path(&path).is_err());
+    }
+
+    #[test]
+    fn test_find_by_value_and_expression() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, expression) in [
+            ("B1", "42"),
+            ("A1", "6"),
+            ("A2", "A1 * 7"),
+            ("C1", "A10 + 1"),
+        ] {
+            sheet
+                .set_and_wait(cell(name), expression.to_string())
+                .unwrap();
+        }
+        let names = |cells: Vec<CellIdentifier>| {
+            cells
+                .iter()
+                .map(references::a1_name)
+                .collect::<Vec<String>>()
+        };
+
+        // A2 matches only once the worker computes it, and rows come first
+        assert_eq!(
+            names(sheet.find_by_value(&CellValue::Int(42))),
+            ["B1", "A2"]
+        );
+        sheet.set_and_wait(cell("A1"), "5".to_string()).unwrap();
+        assert_eq!(names(sheet.find_by_value(&CellValue::Int(42))), ["B1"]);
+        assert_eq!(names(sheet.find_by_value(&CellValue::Int(35))), ["A2"]);
+
+        // Expressions are matched as text
+        assert_eq!(names(sheet.find_by_expression("A1")), ["C1", "A2"]);
+        assert!(sheet.find_by_expression("sum").is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_column_with_holes_and_errors() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, expression) in [
+            ("B1", "4"),
+            ("B2", "-3"),
+            ("B4", "10"),
+            ("B5", "\"text\""),
+            ("B7", "1"),
+            ("C1", "100"),
+        ] {
+            sheet
+                .set_and_wait(cell(name), expression.to_string())
+                .unwrap();
+        }
+        let column = AggTarget::Column(1);
+        let aggregate = |target: AggTarget, kind: AggKind| sheet.aggregate(target, kind, false);
+
+        // Holes and text are skipped, and the mean rounds toward zero
+        assert_eq!(aggregate(column, AggKind::Sum), Ok(CellValue::Int(12)));
+        assert_eq!(
+            sheet.aggregate_many(
+                column,
+                &[AggKind::Min, AggKind::Max, AggKind::Avg, AggKind::Count],
+                false
+            ),
+            Ok(vec![
+                CellValue::Int(-3),
+                CellValue::Int(10),
+                CellValue::Int(3),
+                CellValue::Int(4),
+            ])
+        );
+        assert_eq!(
+            aggregate(AggTarget::Row(0), AggKind::Sum),
+            Ok(CellValue::Int(104))
+        );
+        assert_eq!(
+            aggregate(AggTarget::Range(cell("C2"), cell("B1")), AggKind::Sum),
+            Ok(CellValue::Int(101))
+        );
+        assert_eq!(
+            aggregate(AggTarget::Column(5), AggKind::Avg),
+            Ok(CellValue::None)
+        );
+
+        // An error fails the query unless errors are skipped
+        sheet.set_and_wait(cell("B6"), "1 / 0".to_string()).unwrap();
+        sheet.set_and_wait(cell("B3"), "B6".to_string()).unwrap();
+        assert_eq!(
+            aggregate(column, AggKind::Sum),
+            Err(AggError::ErrorCell(cell("B3")))
+        );
+        assert_eq!(
+            sheet.aggregate(column, AggKind::Sum, true),
+            Ok(CellValue::Int(12))
+        );
+        assert_eq!(
+            aggregate(AggTarget::Column(2), AggKind::Count),
+            Ok(CellValue::Int(1))
+        );
+
+        // A sum too large for a cell is an error rather than wrapping
+        sheet
+            .set_and_wait(cell("D1"), i64::MAX.to_string())
+            .unwrap();
+        sheet.set_and_wait(cell("D2"), "1".to_string()).unwrap();
+        assert_eq!(
+            aggregate(AggTarget::Column(3), AggKind::Sum),
+            Err(AggError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_error_cells_in_range() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        sheet.set(cell("A1"), "1 +".to_string()).unwrap();
+        sheet.set(cell("B2"), "A1".to_string()).unwrap();
+        sheet.set(cell("C3"), "7".to_string()).unwrap();
+        sheet.set(cell("Z9"), "1 +".to_string()).unwrap();
+        sheet.flush();
+
+        // Only errors inside the region, whichever corner comes first
+        let errors = sheet.error_cells_in_range(cell("C3"), cell("A1"));
+        let names: Vec<String> = errors
+            .iter()
+            .map(|(id, _)| references::a1_name(id))
+            .collect();
+        assert_eq!(names, vec!["A1", "B2"]);
+        assert_eq!(errors[1].1, "VariableDependsOnError");
+
+        assert!(sheet
+            .error_cells_in_range(cell("C3"), cell("Y50"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_export_csv_sparse_sheet() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        // B2 to D4, with gaps and a string needing quotes
+        sheet.set(cell("B2"), "1".to_string()).unwrap();
+        sheet
+            .set(cell("D2"), "\"a, \\\"b\\\"\"".to_string())
+            .unwrap();
+        sheet.set(cell("C4"), "B2 + 1".to_string()).unwrap();
+        sheet.flush();
+
+        let mut csv = Vec::new();
+        assert_eq!(sheet.write_csv(&mut csv).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "1,,\"a, \"\"b\"\"\"\n,,\n,2,\n"
+        );
+    }
+
+    #[test]
+    fn test_export_csv_with_error_cell() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        sheet.set(cell("A1"), "1 +".to_string()).unwrap();
+        sheet.set(cell("B1"), "5".to_string()).unwrap();
+
+        let mut csv = Vec::new();
+        sheet.export_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "#ERROR,5\n");
+
+        let mut empty = Vec::new();
+        assert_eq!(Spreadsheet::new().write_csv(&mut empty).unwrap(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_get_with_presence() {
+        let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        sheet.set(a1, "5".to_string()).unwrap();
+        assert_eq!(sheet.get_with_presence(&a1), (true, CellValue::Int(5)));
+
+        // Cleared and never-set cells look the same to get, but not here
+        sheet.set(a1, "".to_string()).unwrap();
+        assert_eq!(sheet.get(&a1), sheet.get(&b1));
+        assert_eq!(sheet.get_with_presence(&a1), (true, CellValue::None));
+        assert_eq!(sheet.get_with_presence(&b1), (false, CellValue::None));
+    }
+
+    #[test]
+    fn test_dependency_exports_keep_ranges_compact() {
+        let sheet = Spreadsheet::new();
+        let reader = CellIdentifier { col: 26, row: 0 }; // AA1
+        sheet.set(reader, "A1_Z1000[0][0]".to_string()).unwrap();
+
+        assert_eq!(
+            sheet.dependencies_json(),
+            r#"{"cells":[{"cell":"AA1","reads":["A1_Z1000"]}]}"#
+        );
+        let dot = sheet.dependencies_dot();
+        assert_eq!(dot.matches("->").count(), 1);
+        assert!(dot.contains("\"A1_Z1000\" -> \"AA1\";"));
+    }
+
+    #[test]
+    fn test_import_csv_under_sum() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        // The formula exists before the block it sums
+        sheet.set(cell("E1"), "sum(B2_D4)".to_string()).unwrap();
+        let failures = sheet
+            .import_csv(cell("B2"), "1,2,3\n4,5,6\n7,8,9\n".as_bytes())
+            .unwrap();
+
+        assert!(failures.is_empty());
+        assert_eq!(sheet.get(&cell("C3")), CellValue::Int(5));
+        assert_eq!(sheet.get(&cell("E1")), CellValue::Int(45));
+    }
+
+    #[test]
+    fn test_import_csv_strings_and_failures() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+
+        // Text is stored as strings, even when it looks like an expression
+        let failures = sheet
+            .import_csv(cell("A1"), "A2 + 1,\"say \"\"hi\"\"\",\n,\\\n".as_bytes())
+            .unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(sheet.get(&cell("A1")), CellValue::String("A2 + 1".into()));
+        assert_eq!(
+            sheet.get(&cell("B1")),
+            CellValue::String("say \"hi\"".into())
+        );
+        assert_eq!(sheet.get(&cell("B2")), CellValue::String("\\".into()));
+        assert!(!sheet.get_with_presence(&cell("A2")).0);
+
+        // A malformed file sets nothing
+        assert!(sheet.import_csv(cell("D1"), "1,\"2".as_bytes()).is_err());
+        assert!(!sheet.get_with_presence(&cell("D1")).0);
+
+        // Fields past the sheet's edge fail, even where the column overflows
+        let edge = CellIdentifier {
+            col: u32::MAX,
+            row: 0,
+        };
+        assert_eq!(
+            sheet.import_csv(edge, "1,2".as_bytes()).unwrap(),
+            [
+                (
+                    0,
+                    0,
+                    "cell MWLQKWV1 is outside the sheet bounds (max XFD1048576)".to_string()
+                ),
+                (
+                    0,
+                    1,
+                    "cell is outside the sheet bounds (max XFD1048576)".to_string()
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clear_all() {
+        let sheet = Spreadsheet::new();
         let a1 = CellIdentifier { col: 0, row: 0 };
         let b1 = CellIdentifier { col: 1, row: 0 };
         let c1 = CellIdentifier { col: 2, row: 0 };
 
-        // Set up chain: C1 depends on B1 depends on A1
-        assert!(sheet.set(a1, "5".to_string()).is_ok());
-        assert!(sheet.set(b1, "A1 + 1".to_string()).is_ok());
-        assert!(sheet.set(c1, "B1 * 2".to_string()).is_ok());
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(b1, "sleep_then(50, A1 + 1)".to_string()).unwrap();
+        sheet.set(c1, "B1 * 2".to_string()).unwrap();
+        sheet.define_name("first", "A1_B1").unwrap();
 
-        sleep(Duration::from_millis(50)); // Allow time for processing
+        // Clear while A1's cascade is still pending or running
+        sheet.set(a1, "2".to_string()).unwrap();
+        sheet.clear_all();
+        sheet.flush();
 
-        assert_eq!(sheet.get(&a1), CellValue::Int(5));
-        assert_eq!(sheet.get(&b1), CellValue::Int(6));
-        assert_eq!(sheet.get(&c1), CellValue::Int(12));
+        for cell_id in [a1, b1, c1] {
+            assert_eq!(sheet.get_with_presence(&cell_id), (false, CellValue::None));
+        }
+        assert!(sheet.list_cells().is_empty());
+        assert_eq!(sheet.dependency_edges(), 0);
+        assert_eq!(sheet.version(), 0);
 
-        // Update A1 and verify cascade
-        assert!(sheet.set(a1, "10".to_string()).is_ok());
+        // Names are wiped with the cells
+        assert_eq!(
+            sheet.remove_name("first"),
+            Err(SpreadsheetError::UnknownName("first".to_string()))
+        );
+
+        // Setting A1 again doesn't bring its old dependents back, and
+        // versions count up from the start again
+        sheet.set(a1, "3".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&b1), CellValue::None);
+        assert_eq!(sheet.get(&c1), CellValue::None);
+        assert_eq!(sheet.changed_since(0), [(a1, CellValue::Int(3), 1)]);
+    }
+
+    #[test]
+    fn test_recover_from_write_ahead_log() {
+        let path = std::env::temp_dir().join(format!("rsheet-wal-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let cells: Vec<CellIdentifier> = ["A1", "A2", "B1", "C1", "D1"]
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+
+        // A handful of sets, including an overwrite and an error
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            wal_path: Some(path.clone()),
+            ..Default::default()
+        });
+        sheet.set(cells[3], "B1 * 2".to_string()).unwrap();
+        sheet.set(cells[0], "1".to_string()).unwrap();
+        sheet
+            .set(cells[1], "\"two\tlines\nhere\"".to_string())
+            .unwrap();
+        sheet.set(cells[2], "A1 + 10".to_string()).unwrap();
+        sheet.set(cells[0], "5".to_string()).unwrap();
+        sheet.set(cells[4], "1 +".to_string()).unwrap();
+        sheet.flush();
+        let expected: Vec<CellValue> = cells.iter().map(|id| sheet.get(id)).collect();
+
+        // Simulate a crash that left half a line behind
+        drop(sheet);
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        log.write_all(b"7\tA1\t99").unwrap();
+
+        let recovered = Spreadsheet::recover(&path).unwrap();
+        let values: Vec<CellValue> = cells.iter().map(|id| recovered.get(id)).collect();
+        assert_eq!(values, expected);
+        assert_eq!(recovered.get(&cells[3]), CellValue::Int(30));
+
+        // The log was compacted, and later sets are still logged
+        recovered.set(cells[0], "6".to_string()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 6);
+        drop(recovered);
+        let recovered = Spreadsheet::recover(&path).unwrap();
+        assert_eq!(recovered.get(&cells[3]), CellValue::Int(32));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_autosave() {
+        let dir = std::env::temp_dir().join(format!("rsheet-autosave-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sheet.json");
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
+
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            autosave: Some(Autosave {
+                path: path.clone(),
+                interval: Duration::from_millis(20),
+            }),
+            ..Default::default()
+        });
+
+        // Nothing is written while nothing has changed
+        sleep(Duration::from_millis(60));
+        assert!(!path.exists());
+
+        sheet.set(a1, "1".to_string()).unwrap();
+        sheet.set(b1, "A1 + 1".to_string()).unwrap();
         sleep(Duration::from_millis(100));
+        let saved = Spreadsheet::load_from_path(&path).unwrap();
+        assert_eq!(saved.get(&b1), CellValue::Int(2));
 
-        assert_eq!(sheet.get(&a1), CellValue::Int(10));
-        assert_eq!(sheet.get(&b1), CellValue::Int(11));
-        assert_eq!(sheet.get(&c1), CellValue::Int(22));
+        // Shutting down saves the last change without waiting an interval
+        sheet.set(a1, "5".to_string()).unwrap();
+        drop(sheet);
+        assert_eq!(
+            Spreadsheet::load_from_path(&path).unwrap().get(&b1),
+            CellValue::Int(6)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_vector_and_matrix() {
+    fn test_get_expression() {
         let sheet = Spreadsheet::new();
+        let a1 = CellIdentifier { col: 0, row: 0 };
+        let b1 = CellIdentifier { col: 1, row: 0 };
 
-        // Set up a 2x2 matrix
-        assert!(sheet
-            .set(CellIdentifier { col: 0, row: 0 }, "1".to_string())
-            .is_ok()); // A1
-        assert!(sheet
-            .set(CellIdentifier { col: 1, row: 0 }, "2".to_string())
-            .is_ok()); // B1
-        assert!(sheet
-            .set(CellIdentifier { col: 0, row: 1 }, "3".to_string())
-            .is_ok()); // A2
-        assert!(sheet
-            .set(CellIdentifier { col: 1, row: 1 }, "4".to_string())
-            .is_ok()); // B2
+        // Ranges and spacing come back exactly as typed
+        let formula = "sum(A2_A10)  *  2 + A1".to_string();
+        sheet.set(b1, formula.clone()).unwrap();
+        assert_eq!(sheet.get_expression(&b1), Some(formula));
+        assert_eq!(sheet.get_expression(&a1), None);
+    }
 
-        // Test horizontal vector sum
-        assert!(sheet
-            .set(
-                CellIdentifier { col: 2, row: 0 }, // C1
-                "sum(A1_B1)".to_string()
-            )
-            .is_ok());
+    #[test]
+    fn test_get_range() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let names = |values: &[(CellIdentifier, CellValue)]| -> Vec<String> {
+            values
+                .iter()
+                .map(|(id, _)| references::a1_name(id))
+                .collect()
+        };
 
-        // Test vertical vector sum
-        assert!(sheet
-            .set(
-                CellIdentifier { col: 2, row: 1 }, // C2
-                "sum(A1_A2)".to_string()
-            )
-            .is_ok());
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "2".to_string()).unwrap();
+        sheet.set(cell("A2"), "3".to_string()).unwrap();
+        sheet.set(cell("B2"), "A1 + B1 + A2".to_string()).unwrap();
 
-        // Test matrix sum
-        assert!(sheet
-            .set(
-                CellIdentifier { col: 2, row: 2 }, // C3
-                "sum(A1_B2)".to_string()
-            )
-            .is_ok());
+        // Horizontal and vertical
+        let row = sheet.get_range(cell("A1"), cell("B1"));
+        assert_eq!(names(&row), vec!["A1", "B1"]);
+        let column = sheet.get_range(cell("A1"), cell("A2"));
+        assert_eq!(column[1].1, CellValue::Int(3));
 
-        sleep(Duration::from_millis(50));
+        // Matrix, row-major, from either diagonal, with an unset column
+        let matrix = sheet.get_range(cell("C2"), cell("A1"));
+        assert_eq!(names(&matrix), vec!["A1", "B1", "C1", "A2", "B2", "C2"]);
+        let values: Vec<CellValue> = matrix.into_iter().map(|(_, value)| value).collect();
+        assert_eq!(
+            values,
+            vec![
+                CellValue::Int(1),
+                CellValue::Int(2),
+                CellValue::None,
+                CellValue::Int(3),
+                CellValue::Int(6),
+                CellValue::None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dependency_introspection() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let cells =
+            |names: &[&str]| -> Vec<CellIdentifier> { names.iter().map(|n| cell(n)).collect() };
+
+        // D1 reads B1 and C1, which both read A1
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+        sheet.set(cell("C1"), "A1 * 2".to_string()).unwrap();
+        sheet.set(cell("D1"), "B1 + C1".to_string()).unwrap();
 
+        assert_eq!(sheet.dependencies_of(&cell("A1")), cells(&[]));
         assert_eq!(
-            sheet.get(&CellIdentifier { col: 2, row: 0 }),
-            CellValue::Int(3)
-        ); // C1 = 1 + 2
+            sheet.dependents_of(&cell("A1"), false),
+            cells(&["B1", "C1"])
+        );
+        for name in ["B1", "C1"] {
+            assert_eq!(sheet.dependencies_of(&cell(name)), cells(&["A1"]));
+            assert_eq!(sheet.dependents_of(&cell(name), false), cells(&["D1"]));
+        }
+        assert_eq!(sheet.dependencies_of(&cell("D1")), cells(&["B1", "C1"]));
+        assert_eq!(sheet.dependents_of(&cell("D1"), false), cells(&[]));
+
+        // Ranges list each covered cell once
+        sheet
+            .set(cell("E1"), "sum(A1_B1) + A1".to_string())
+            .unwrap();
+        assert_eq!(sheet.dependencies_of(&cell("E1")), cells(&["A1", "B1"]));
         assert_eq!(
-            sheet.get(&CellIdentifier { col: 2, row: 1 }),
-            CellValue::Int(4)
-        ); // C2 = 1 + 3
+            sheet.dependents_of(&cell("B1"), false),
+            cells(&["D1", "E1"])
+        );
+
+        // Transitive dependents follow every chain, ranges included
+        sheet.set(cell("F1"), "E1 + D1".to_string()).unwrap();
+        assert_eq!(
+            sheet.dependents_of(&cell("A1"), true),
+            cells(&["B1", "C1", "D1", "E1", "F1"])
+        );
+        assert_eq!(sheet.dependents_of(&cell("C1"), true), cells(&["D1", "F1"]));
+        assert_eq!(sheet.dependents_of(&cell("F1"), true), cells(&[]));
+
+        // A cell nobody has set or read has neither
+        assert_eq!(sheet.dependencies_of(&cell("Z9")), cells(&[]));
+        assert_eq!(sheet.dependents_of(&cell("Z9"), true), cells(&[]));
+    }
+
+    #[test]
+    fn test_set_batch_recomputes_dependents_once() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "2".to_string()).unwrap();
+        sheet.set(cell("C1"), "A1 + B1".to_string()).unwrap();
+        sheet.flush();
+
+        let seen: Arc<Mutex<Vec<(CellIdentifier, CellValue)>>> = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&seen);
+        sheet.add_observer(move |cell_id, value| {
+            observed.lock().unwrap().push((cell_id, value.clone()));
+        });
+
+        // C1 must only ever see both new values, once the batch is reported
+        sheet
+            .set_batch(vec![
+                (cell("A1"), "10".to_string()),
+                (cell("B1"), "20".to_string()),
+            ])
+            .unwrap();
+        sheet.flush();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (cell("A1"), CellValue::Int(10)),
+                (cell("B1"), CellValue::Int(20)),
+                (cell("C1"), CellValue::Int(30)),
+            ]
+        );
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(30));
+
+        // One bad assignment rejects the whole batch
+        assert_eq!(
+            sheet.set_batch(vec![
+                (cell("A1"), "5".to_string()),
+                (cell("B1"), "Q + 1".to_string()),
+            ]),
+            Err(SpreadsheetError::InvalidReference("Q".to_string()))
+        );
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(10));
+    }
+
+    #[test]
+    fn test_evaluate_adhoc() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "2".to_string()).unwrap();
+        sheet.set(cell("A2"), "3".to_string()).unwrap();
+        sheet.set(cell("A3"), "A1 * 5".to_string()).unwrap();
+        sheet.set(cell("B1"), "1 / 0".to_string()).unwrap();
+        sheet.set(cell("B2"), "B1 + 1".to_string()).unwrap();
+        sheet.flush();
+        let cells_before = sheet.list_cells();
+
+        assert_eq!(sheet.evaluate_adhoc("A1 + A3"), CellValue::Int(12));
+        assert_eq!(sheet.evaluate_adhoc("sum(A1_A3) * 2"), CellValue::Int(30));
+        assert_eq!(sheet.evaluate_adhoc("  "), CellValue::None);
+        assert_eq!(
+            sheet.evaluate_adhoc("Q + 1"),
+            CellValue::Error("Q is not a valid cell or range reference".into())
+        );
+
+        // An error anywhere up the chain is reported as a stored formula's would be
+        assert_eq!(
+            sheet.evaluate_adhoc("B2 + A1"),
+            CellValue::Error("VariableDependsOnError".into())
+        );
+
+        // Nothing was stored, and nothing depends on the expressions
+        assert_eq!(sheet.list_cells(), cells_before);
+        assert_eq!(sheet.dependents_of(&cell("A1"), false), vec![cell("A3")]);
+    }
+
+    #[test]
+    fn test_evaluate_expr_is_a_dry_run() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "2".to_string()).unwrap();
+        sheet.set_and_wait(cell("B1"), "5".to_string()).unwrap();
+        let passes = sheet.counters.passes.load(Ordering::SeqCst);
+
+        assert_eq!(sheet.evaluate_expr("A1 + B1"), Ok(CellValue::Int(7)));
+        assert!(sheet.evaluate_expr("A1 / 0").is_err());
+        assert_eq!(
+            sheet.evaluate_expr("Q"),
+            Err("Q is not a valid cell or range reference".into())
+        );
+        sheet.flush();
+
+        // No cell was created and the worker had nothing to recompute
+        assert_eq!(sheet.count_nonempty(), 2);
+        assert_eq!(
+            sheet.get_with_presence(&cell("C1")),
+            (false, CellValue::None)
+        );
+        assert_eq!(sheet.counters.passes.load(Ordering::SeqCst), passes);
+    }
+
+    #[test]
+    fn test_contains_and_len_follow_sets_and_clears() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        assert!(sheet.is_empty());
+        assert_eq!(sheet.len(), 0);
+
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B2"), "A1 + 1".to_string()).unwrap();
+        assert!(sheet.contains(&cell("A1")));
+        assert!(!sheet.contains(&cell("C3")));
+        assert_eq!(sheet.len(), 2);
+        assert!(!sheet.is_empty());
+
+        // Setting a cell again doesn't add to the count
+        sheet.set(cell("A1"), "5".to_string()).unwrap();
+        assert_eq!(sheet.len(), 2);
+
+        // Clearing removes a cell from the count, down to an empty sheet
+        sheet.set(cell("A1"), "".to_string()).unwrap();
+        assert!(!sheet.contains(&cell("A1")));
+        assert_eq!(sheet.len(), 1);
+        sheet.set(cell("B2"), "".to_string()).unwrap();
+        assert_eq!(sheet.len(), 0);
+        assert!(sheet.is_empty());
+    }
+
+    #[test]
+    fn test_extent() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        assert_eq!(sheet.extent(), None);
+
+        // A single cell is a 1x1 box
+        sheet.set(cell("C3"), "1".to_string()).unwrap();
+        assert_eq!(sheet.extent(), Some((cell("C3"), cell("C3"))));
+
+        // Scattered cells span their extremes in each direction
+        sheet.set(cell("E2"), "2".to_string()).unwrap();
+        sheet.set(cell("B7"), "C3 + 1".to_string()).unwrap();
+        assert_eq!(sheet.extent(), Some((cell("B2"), cell("E7"))));
+        assert_eq!(sheet.extents(), sheet.extent());
+
+        // Cleared cells don't count
+        sheet.set(cell("B7"), "".to_string()).unwrap();
+        sheet.set(cell("E2"), "".to_string()).unwrap();
+        assert_eq!(sheet.extent(), Some((cell("C3"), cell("C3"))));
+        sheet.set(cell("C3"), "".to_string()).unwrap();
+        assert_eq!(sheet.extent(), None);
+    }
+
+    #[test]
+    fn test_sparse_sheet_listing() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("B2"), "1".to_string()).unwrap();
+        sheet
+            .set_and_wait(cell("ZZ999"), "B2 + 1".to_string())
+            .unwrap();
+        sheet.set(cell("C1"), "3".to_string()).unwrap();
+        sheet.set(cell("D4"), "".to_string()).unwrap();
+
+        // The far cell stretches the extent; the cleared D4 doesn't count
+        assert_eq!(sheet.extent(), Some((cell("B1"), cell("ZZ999"))));
+        assert_eq!(sheet.count_nonempty(), 3);
+        let names = |listed: Vec<(CellIdentifier, String, CellValue)>| {
+            listed
+                .iter()
+                .map(|(cell_id, _, _)| references::a1_name(cell_id))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(names(sheet.list_cells()), ["C1", "B2", "ZZ999"]);
+
+        // A window lists only its own cells, whichever corners it is given
+        assert_eq!(
+            names(sheet.list_cells_in_range(cell("D5"), cell("A1"))),
+            ["C1", "B2"]
+        );
+        assert_eq!(
+            sheet.list_cells_in_range(cell("ZZ999"), cell("ZZ999")),
+            vec![(cell("ZZ999"), "B2 + 1".to_string(), CellValue::Int(2))]
+        );
+        assert!(sheet.list_cells_in_range(cell("D4"), cell("D4")).is_empty());
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        let sheet = Arc::new(Spreadsheet::new());
+        let a1 = "A1".parse::<CellIdentifier>().unwrap();
+
+        // A cell that was never set holds None
+        assert_eq!(
+            sheet.compare_and_set(a1, &CellValue::Int(0), "1".to_string()),
+            Ok(false)
+        );
+        assert_eq!(
+            sheet.compare_and_set(a1, &CellValue::None, "0".to_string()),
+            Ok(true)
+        );
+        assert_eq!(sheet.get(&a1), CellValue::Int(0));
+        assert_eq!(
+            sheet.compare_and_set(a1, &CellValue::Int(0), "Q + 1".to_string()),
+            Err(SpreadsheetError::InvalidReference("Q".to_string()))
+        );
+
+        // Two clients incrementing the same counter, retrying whenever the
+        // other got in first, land every increment exactly once
+        let clients: Vec<thread::JoinHandle<()>> = (0..2)
+            .map(|_| {
+                let sheet = Arc::clone(&sheet);
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        while let CellValue::Int(count) = sheet.get(&a1) {
+                            let next = (count + 1).to_string();
+                            if sheet
+                                .compare_and_set(a1, &CellValue::Int(count), next)
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(sheet.get(&a1), CellValue::Int(50));
+    }
+
+    #[test]
+    fn test_undo_redo_round_trip() {
+        let sheet = Spreadsheet::new();
+        let a1 = "A1".parse::<CellIdentifier>().unwrap();
+        for expression in ["1", "2", "3"] {
+            sheet.set(a1, expression.to_string()).unwrap();
+        }
+
+        // Undo walks back to before the first set, which left A1 blank
+        sheet.undo(a1).unwrap();
+        assert_eq!(sheet.get(&a1), CellValue::Int(2));
+        sheet.undo(a1).unwrap();
+        sheet.undo(a1).unwrap();
+        assert_eq!(sheet.get(&a1), CellValue::None);
+        assert_eq!(sheet.undo(a1), Err(SpreadsheetError::NothingToUndo(a1)));
+
+        // Redo walks forward again
+        sheet.redo(a1).unwrap();
+        sheet.redo(a1).unwrap();
+        assert_eq!(sheet.get_expression(&a1), Some("2".to_string()));
+
+        // A fresh set drops what was left to redo
+        sheet.set(a1, "9".to_string()).unwrap();
+        assert_eq!(sheet.redo(a1), Err(SpreadsheetError::NothingToRedo(a1)));
+        sheet.undo(a1).unwrap();
+        assert_eq!(sheet.get(&a1), CellValue::Int(2));
+
+        // Only the last HISTORY_LIMIT expressions are kept
+        let b1 = "B1".parse::<CellIdentifier>().unwrap();
+        for value in 0..HISTORY_LIMIT + 8 {
+            sheet.set(b1, value.to_string()).unwrap();
+        }
+        for _ in 0..HISTORY_LIMIT {
+            sheet.undo(b1).unwrap();
+        }
+        assert_eq!(sheet.get(&b1), CellValue::Int(7));
+        assert_eq!(sheet.undo(b1), Err(SpreadsheetError::NothingToUndo(b1)));
+    }
+
+    #[test]
+    fn test_max_cells_limits_new_cells() {
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            max_cells: Some(3),
+            ..SpreadsheetOptions::default()
+        });
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, expression) in [("A1", "1"), ("A2", "2"), ("A3", "A1 + A2")] {
+            sheet.set(cell(name), expression.to_string()).unwrap();
+        }
+
+        // A fourth cell is rejected, alone or in a batch, but a full sheet
+        // can still change the cells it holds
+        assert_eq!(
+            sheet.set(cell("A4"), "4".to_string()),
+            Err(SpreadsheetError::CellLimitReached(3))
+        );
+        assert_eq!(
+            sheet.set_batch(vec![
+                (cell("A1"), "10".to_string()),
+                (cell("B1"), "5".to_string()),
+            ]),
+            Err(SpreadsheetError::CellLimitReached(3))
+        );
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(1));
+        sheet.set_and_wait(cell("A1"), "5".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("A3")), CellValue::Int(7));
+
+        // Clearing a cell frees its slot, even within the same batch
+        sheet.set(cell("A2"), String::new()).unwrap();
+        sheet.set(cell("A4"), "4".to_string()).unwrap();
+        sheet
+            .set_batch(vec![
+                (cell("A4"), String::new()),
+                (cell("B1"), "6".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(6));
+        assert_eq!(sheet.get(&cell("A4")), CellValue::None);
+    }
+
+    #[test]
+    fn test_history_keeps_the_latest_sets() {
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            edit_log_length: 3,
+            ..SpreadsheetOptions::default()
+        });
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let expressions = |entries: Vec<(SystemTime, String)>| {
+            entries
+                .into_iter()
+                .map(|(_, expression)| expression)
+                .collect::<Vec<String>>()
+        };
+        let before = SystemTime::now();
+        for expression in ["1", "2", "B1 * 2", "B1 * 3"] {
+            sheet.set(cell("A1"), expression.to_string()).unwrap();
+        }
+
+        // Only the last three sets are kept, oldest first, with their times
+        let entries = sheet.history(&cell("A1"), usize::MAX);
+        assert!(entries.iter().all(|(time, _)| *time >= before));
+        assert!(entries.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert_eq!(expressions(entries), vec!["2", "B1 * 2", "B1 * 3"]);
+        assert_eq!(expressions(sheet.history(&cell("A1"), 1)), vec!["B1 * 3"]);
+
+        // Recomputing A1 isn't a set, but an undo is
+        sheet.set_and_wait(cell("B1"), "5".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(15));
+        assert_eq!(expressions(sheet.history(&cell("A1"), 1)), vec!["B1 * 3"]);
+        sheet.undo(cell("A1")).unwrap();
+        assert_eq!(
+            expressions(sheet.history(&cell("A1"), usize::MAX)),
+            vec!["B1 * 2", "B1 * 3", "B1 * 2"]
+        );
+        assert!(sheet.history(&cell("C1"), usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_undo_recomputes_dependents() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "2".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1 * 10".to_string()).unwrap();
+        sheet.set(cell("C1"), "B1 + 1".to_string()).unwrap();
+        sheet
+            .set_and_wait(cell("B1"), "A1 * 100".to_string())
+            .unwrap();
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(201));
+
+        // The restored formula cascades like any other set
+        sheet.undo(cell("B1")).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(20));
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(21));
+
+        // And its dependency edges are restored, so A1 still reaches C1
+        sheet.set_and_wait(cell("A1"), "3".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(31));
+
+        sheet.redo(cell("B1")).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(301));
+    }
+
+    #[test]
+    fn test_evaluate_with_overrides() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, expression) in [
+            ("A1", "1"),
+            ("A2", "2"),
+            ("B1", "sum(A1_A2)"),
+            ("C1", "B1 * 10"),
+            ("D1", "C1 + A2"),
+        ] {
+            sheet
+                .set_and_wait(cell(name), expression.to_string())
+                .unwrap();
+        }
+
+        // An override reaches the target through a range and a chain
+        let overrides = HashMap::from([(cell("A1"), CellValue::Int(100))]);
+        assert_eq!(
+            sheet.evaluate_with_overrides(cell("D1"), &overrides),
+            CellValue::Int(1022)
+        );
+        let overrides = HashMap::from([(cell("B1"), CellValue::Int(5))]);
+        assert_eq!(
+            sheet.evaluate_with_overrides(cell("D1"), &overrides),
+            CellValue::Int(52)
+        );
+        assert_eq!(
+            sheet.evaluate_with_overrides(cell("D1"), &HashMap::new()),
+            CellValue::Int(32)
+        );
+
+        // The sheet itself is untouched
+        for (name, value) in [("A1", 1), ("B1", 3), ("C1", 30), ("D1", 32)] {
+            assert_eq!(sheet.get(&cell(name)), CellValue::Int(value));
+        }
+
+        // A cycle is reported instead of followed, unless an override cuts it
+        sheet
+            .set_and_wait(cell("E1"), "F1 + 1".to_string())
+            .unwrap();
+        sheet
+            .set_and_wait(cell("F1"), "E1 + 1".to_string())
+            .unwrap();
+        assert_eq!(
+            sheet.evaluate_with_overrides(cell("E1"), &HashMap::new()),
+            CellValue::Error("CircularReference: E1 -> F1 -> E1".into())
+        );
+        let overrides = HashMap::from([(cell("F1"), CellValue::Int(7))]);
         assert_eq!(
-            sheet.get(&CellIdentifier { col: 2, row: 2 }),
-            CellValue::Int(10)
-        ); // C3 = 1 + 2 + 3 + 4
+            sheet.evaluate_with_overrides(cell("E1"), &overrides),
+            CellValue::Int(8)
+        );
     }
 
     #[test]
-    fn test_error_propagation() {
-        let sheet = Spreadsheet::new();
-        let a1 = CellIdentifier { col: 0, row: 0 };
-        let b1 = CellIdentifier { col: 1, row: 0 };
+    fn test_slow_formula_keeps_the_newest_input() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let seen: Arc<Mutex<Vec<CellValue>>> = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&seen);
+        let sheet = Arc::new(Spreadsheet::new().with_observer(move |cell_id, value| {
+            if cell_id == "A1".parse().unwrap() {
+                observed.lock().unwrap().push(value.clone());
+            }
+        }));
 
-        // Set A1 to an invalid expression
-        assert!(sheet.set(a1, "invalid + expression".to_string()).is_ok());
-        assert!(sheet.set(b1, "A1 + 1".to_string()).is_ok());
+        // The cascade from B1 = 2 is still evaluating when B1 = 3 lands, so
+        // its result is discarded rather than briefly committed
+        sheet
+            .set_and_wait(cell("A1"), "sleep_then(300, B1)".to_string())
+            .unwrap();
+        seen.lock().unwrap().clear();
+        sheet.set(cell("B1"), "2".to_string()).unwrap();
+        sleep(Duration::from_millis(50));
+        sheet.set_and_wait(cell("B1"), "3".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(3));
+        assert!(!seen.lock().unwrap().contains(&CellValue::Int(2)));
 
+        // A slow set that read B1 before it changed is re-evaluated, even
+        // though B1's cascade ran against the old formula
+        sheet.set_and_wait(cell("A1"), "B1".to_string()).unwrap();
+        sheet.set_and_wait(cell("B1"), "1".to_string()).unwrap();
+        let writer = Arc::clone(&sheet);
+        let slow = thread::spawn(move || {
+            writer
+                .set(cell("A1"), "sleep_then(300, B1)".to_string())
+                .unwrap()
+        });
         sleep(Duration::from_millis(50));
+        sheet.set(cell("B1"), "5".to_string()).unwrap();
+        slow.join().unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("A1")), CellValue::Int(5));
+    }
 
-        match sheet.get(&a1) {
-            CellValue::Error(_) => (), // Expected
-            other => panic!("Expected Error, got {:?}", other),
-        }
+    #[test]
+    fn test_observers_see_the_chain_in_order() {
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let events = |seen: &Arc<Mutex<Vec<(CellIdentifier, CellValue)>>>| {
+            std::mem::take(&mut *seen.lock().unwrap())
+        };
+        let first: Arc<Mutex<Vec<(CellIdentifier, CellValue)>>> = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&first);
+        let sheet = Spreadsheet::new().with_observer(move |cell_id, value| {
+            observed.lock().unwrap().push((cell_id, value.clone()));
+        });
 
-        match sheet.get(&b1) {
-            CellValue::Error(msg) if msg == "VariableDependsOnError" => (), // Expected
-            other => panic!("Expected VariableDependsOnError, got {:?}", other),
+        sheet.set_and_wait(cell("A1"), "1".to_string()).unwrap();
+        for (name, expression) in [("A2", "A1 + 1"), ("A3", "A2 + 1"), ("A4", "A3 + 1")] {
+            sheet
+                .set_and_wait(cell(name), expression.to_string())
+                .unwrap();
         }
+        assert_eq!(
+            events(&first),
+            vec![
+                (cell("A1"), CellValue::Int(1)),
+                (cell("A2"), CellValue::Int(2)),
+                (cell("A3"), CellValue::Int(3)),
+                (cell("A4"), CellValue::Int(4)),
+            ]
+        );
+
+        // A later observer only sees later commits, in cascade order
+        let second: Arc<Mutex<Vec<(CellIdentifier, CellValue)>>> = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&second);
+        sheet.add_observer(move |cell_id, value| {
+            observed.lock().unwrap().push((cell_id, value.clone()));
+        });
+        sheet.set_and_wait(cell("A1"), "7".to_string()).unwrap();
+
+        let cascade = vec![
+            (cell("A1"), CellValue::Int(7)),
+            (cell("A2"), CellValue::Int(8)),
+            (cell("A3"), CellValue::Int(9)),
+            (cell("A4"), CellValue::Int(10)),
+        ];
+        assert_eq!(events(&first), cascade);
+        assert_eq!(events(&second), cascade);
     }
 
     #[test]
-    fn test_range_sum_with_updates() {
-        let spreadsheet = Spreadsheet::new();
+    fn test_subscribers_see_the_cascade_in_order() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+        sheet.set(cell("C1"), "B1 * 10".to_string()).unwrap();
+        sheet.set(cell("D1"), "C1 + A1".to_string()).unwrap();
+        sheet.flush();
 
-        // Initial setup: Set values for A1, B1, C1
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "1".to_string(),
-            )
-            .unwrap();
+        let (first, second) = (sheet.subscribe(), sheet.subscribe());
+        drop(sheet.subscribe());
+        sheet.set_and_wait(cell("A1"), "2".to_string()).unwrap();
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 1, row: 0 }, // B1
-                "2".to_string(),
-            )
-            .unwrap();
+        // Every subscriber gets the root, then its dependents in order
+        let cascade = vec![
+            (cell("A1"), CellValue::Int(2)),
+            (cell("B1"), CellValue::Int(3)),
+            (cell("C1"), CellValue::Int(30)),
+            (cell("D1"), CellValue::Int(32)),
+        ];
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), cascade);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), cascade);
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 2, row: 0 }, // C1
-                "3".to_string(),
-            )
-            .unwrap();
+        // The dropped subscription is gone and the worker carries on
+        drop(first);
+        sheet.set_and_wait(cell("A1"), "3".to_string()).unwrap();
+        assert_eq!(second.try_iter().count(), 4);
+        assert_eq!(sheet.subscribers.lock().unwrap().watches.len(), 1);
+        assert!(sheet.health().worker_alive);
+    }
 
-        // Set D1 to sum of range A1_C1
-        spreadsheet
-            .set(
-                CellIdentifier { col: 3, row: 0 }, // D1
-                "sum(A1_C1)".to_string(),
-            )
-            .unwrap();
+    #[test]
+    fn test_watch_reports_changes_in_its_region() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1 * 2".to_string()).unwrap();
+        sheet.set(cell("C1"), "B1 + 1".to_string()).unwrap();
+        sheet.flush();
 
-        // Check initial sum (should be 6)
+        let seen: Arc<Mutex<Vec<(CellIdentifier, CellValue)>>> = Arc::new(Mutex::new(Vec::new()));
+        let watched = Arc::clone(&seen);
+        let id = sheet.watch(cell("B1"), cell("A1"), move |cell_id, value| {
+            watched.lock().unwrap().push((cell_id, value.clone()));
+            true
+        });
+        let once = sheet.watch(cell("C1"), cell("C1"), |_, _| false);
+
+        // The set cell comes first, then its dependents in commit order;
+        // C1 is outside the first watch
+        sheet.set(cell("A1"), "5".to_string()).unwrap();
+        sheet.flush();
         assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 3, row: 0 }), // D1
-            CellValue::Int(6)
+            *seen.lock().unwrap(),
+            vec![
+                (cell("A1"), CellValue::Int(5)),
+                (cell("B1"), CellValue::Int(10))
+            ]
         );
 
-        // Update A1 to 4
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "4".to_string(),
-            )
-            .unwrap();
+        // A watch that returned false is already gone
+        assert!(!sheet.unwatch(once));
+        assert!(sheet.unwatch(id));
+        sheet.set(cell("A1"), "6".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
 
-        // Wait for updates to propagate
-        thread::sleep(Duration::from_millis(2000));
+    #[test]
+    fn test_dropped_reference_leaves_no_stale_dependent() {
+        let sheet = Arc::new(Spreadsheet::new());
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        sheet.set(cell("A1"), "1".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1".to_string()).unwrap();
+        assert_eq!(sheet.dependents_of(&cell("A1"), false), vec![cell("B1")]);
 
-        // Check sum after A1 update (should be 9)
-        assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 3, row: 0 }), // D1
-            CellValue::Int(9)
-        );
+        sheet.set(cell("B1"), "5".to_string()).unwrap();
+        assert_eq!(sheet.dependents_of(&cell("A1"), false), vec![]);
 
-        // Update C1 to 10
-        spreadsheet
-            .set(
-                CellIdentifier { col: 2, row: 0 }, // C1
-                "10".to_string(),
-            )
-            .unwrap();
+        // Racing re-sets, with cascades in flight, must leave the edges of
+        // whichever formula each cell ends up holding
+        let handles: Vec<thread::JoinHandle<()>> = (0..4)
+            .map(|t| {
+                let sheet = Arc::clone(&sheet);
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let expression = if (i + t) % 2 == 0 { "A1 + 1" } else { "5" };
+                        sheet.set(cell("B1"), expression.to_string()).unwrap();
+                        sheet.set(cell("A1"), i.to_string()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        sheet.flush();
 
-        // Wait for updates to propagate
-        thread::sleep(Duration::from_millis(2000));
+        let expected = match sheet.get_expression(&cell("B1")).as_deref() {
+            Some("5") => vec![],
+            _ => vec![cell("B1")],
+        };
+        assert_eq!(sheet.dependents_of(&cell("A1"), false), expected);
 
-        // Check sum after C1 update (should be 16)
-        assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 3, row: 0 }), // D1
-            CellValue::Int(16)
-        );
+        sheet.set(cell("B1"), "5".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.dependents_of(&cell("A1"), false), vec![]);
+        assert_eq!(sheet.dependency_edges(), 0);
     }
 
     #[test]
-    fn test_range_sum_with_error() {
-        let spreadsheet = Spreadsheet::new();
+    fn test_copy_range_shifts_references() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (row, value) in [1, 2, 3, 4].iter().enumerate() {
+            sheet
+                .set(cell(&format!("A{}", row + 1)), value.to_string())
+                .unwrap();
+        }
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+        sheet.set(cell("C1"), "sum(A1_B1)".to_string()).unwrap();
 
-        // Set up initial values
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "1".to_string(),
-            )
+        // Down: B1_C1 copied to B2 reads row 2
+        sheet
+            .copy_range(cell("C1"), cell("B1"), cell("B2"))
             .unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get_expression(&cell("B2")).as_deref(), Some("A2 + 1"));
+        assert_eq!(
+            sheet.get_expression(&cell("C2")).as_deref(),
+            Some("sum(A2_B2)")
+        );
+        assert_eq!(sheet.get(&cell("C2")), CellValue::Int(5));
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 1, row: 0 }, // B1
-                "invalid_expression".to_string(),
-            )
+        // Right: B1_C2 copied to D3 moves two columns right and two rows down
+        sheet.set(cell("C3"), "7".to_string()).unwrap();
+        sheet.set(cell("C4"), "1".to_string()).unwrap();
+        sheet
+            .copy_range(cell("B1"), cell("C2"), cell("D3"))
             .unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get_expression(&cell("D3")).as_deref(), Some("C3 + 1"));
+        assert_eq!(
+            sheet.get_expression(&cell("E4")).as_deref(),
+            Some("sum(C4_D4)")
+        );
+        assert_eq!(sheet.get(&cell("D3")), CellValue::Int(8));
+        assert_eq!(sheet.get(&cell("E4")), CellValue::Int(3));
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 2, row: 0 }, // C1
-                "3".to_string(),
-            )
+        // A reference moved off the sheet becomes a reference error
+        sheet
+            .copy_range(cell("B2"), cell("B2"), cell("A2"))
             .unwrap();
+        assert_eq!(
+            sheet.get(&cell("A2")),
+            CellValue::Error("Reference error: A2 no longer points to a cell".to_string())
+        );
 
-        // Set D1 to sum of range A1_C1
-        spreadsheet
-            .set(
-                CellIdentifier { col: 3, row: 0 }, // D1
-                "sum(A1_C1)".to_string(),
-            )
-            .unwrap();
+        // Cells landing past the largest column fail the copy without a panic
+        let edge = CellIdentifier {
+            col: u32::MAX,
+            row: 0,
+        };
+        assert!(matches!(
+            sheet.copy_range(cell("B1"), cell("C1"), edge),
+            Err(SpreadsheetError::OutOfBounds(..))
+        ));
+        assert_eq!(sheet.get_expression(&cell("B1")).as_deref(), Some("A1 + 1"));
+    }
 
-        // D1 should have an error since B1 contains an invalid expression
-        match spreadsheet.get(&CellIdentifier { col: 3, row: 0 }) {
-            CellValue::Error(_) => (),
-            other => panic!("Expected Error, got {:?}", other),
-        }
+    #[test]
+    fn test_move_cell_rewrites_references() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let expression = |name: &str| sheet.get_expression(&cell(name)).unwrap_or_default();
+        sheet.set(cell("A1"), "5".to_string()).unwrap();
+        sheet.set(cell("B1"), "A1 * 2".to_string()).unwrap();
+        sheet.set(cell("C1"), "$A$1 + A2".to_string()).unwrap();
+        sheet.set(cell("A2"), "1".to_string()).unwrap();
+
+        // Both readers follow A1 to D4 and keep their values
+        sheet.move_cell(cell("A1"), cell("D4"), false).unwrap();
+        sheet.flush();
+        assert_eq!(expression("B1"), "D4 * 2");
+        assert_eq!(expression("C1"), "$D$4 + A2");
+        assert_eq!(expression("D4"), "5");
+        assert_eq!(sheet.get_expression(&cell("A1")), None);
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(10));
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(6));
+
+        // And are recomputed from the moved cell
+        assert_eq!(
+            sheet.dependents_of(&cell("D4"), false),
+            [cell("B1"), cell("C1")]
+        );
+        sheet.set_and_wait(cell("D4"), "7".to_string()).unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(14));
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(8));
+
+        // An occupied destination is only replaced when asked
+        assert_eq!(
+            sheet.move_cell(cell("D4"), cell("A2"), false),
+            Err(SpreadsheetError::CellOccupied(cell("A2")))
+        );
+        assert_eq!(
+            sheet.move_cell(cell("E9"), cell("E1"), false),
+            Err(SpreadsheetError::CellNotSet(cell("E9")))
+        );
+        sheet.move_cell(cell("D4"), cell("A2"), true).unwrap();
+        sheet.flush();
+        assert_eq!(expression("B1"), "A2 * 2");
+        assert_eq!(expression("C1"), "$A$2 + ref_error(\"A2\")");
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(14));
+        assert!(matches!(sheet.get(&cell("C1")), CellValue::Error(_)));
     }
 
     #[test]
-    fn test_long_dependency_chain() {
-        let spreadsheet = Spreadsheet::new();
+    fn test_insert_and_delete_rows_and_columns() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let expression = |name: &str| sheet.get_expression(&cell(name)).unwrap_or_default();
+        for (name, value) in [("A1", "1"), ("A2", "2"), ("A3", "3")] {
+            sheet.set(cell(name), value.to_string()).unwrap();
+        }
+        sheet.set(cell("B1"), "sum(A1_A3)".to_string()).unwrap();
+        sheet.set(cell("C1"), "A3 * 10".to_string()).unwrap();
 
-        // Set up chain A1 -> A2 -> A3 -> A4 -> A5
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "1".to_string(),
-            )
-            .unwrap();
+        // Inserting before row 2 grows the range over the new row
+        sheet.insert_row(1).unwrap();
+        assert_eq!(expression("B1"), "sum(A1_A4)");
+        assert_eq!(expression("C1"), "A4 * 10");
+        assert_eq!(expression("A4"), "3");
+        assert_eq!(sheet.get_expression(&cell("A2")), None);
+        sheet.set(cell("A2"), "10".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(16));
+        assert_eq!(sheet.dependents_of(&cell("A2"), false), vec![cell("B1")]);
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 1 }, // A2
-                "A1 + 1".to_string(),
-            )
-            .unwrap();
+        // Deleting that row shrinks it back
+        sheet.delete_row(1).unwrap();
+        sheet.flush();
+        assert_eq!(expression("B1"), "sum(A1_A3)");
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(6));
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(30));
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 2 }, // A3
-                "A2 + 1".to_string(),
-            )
-            .unwrap();
+        // A reference to a deleted cell becomes a reference error
+        sheet.delete_row(2).unwrap();
+        sheet.flush();
+        assert_eq!(expression("C1"), "ref_error(\"A3\") * 10");
+        assert!(matches!(sheet.get(&cell("C1")), CellValue::Error(_)));
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(3));
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 3 }, // A4
-                "A3 + 1".to_string(),
-            )
-            .unwrap();
+        // Columns work the same way
+        sheet.insert_col(0).unwrap();
+        sheet.flush();
+        assert_eq!(expression("C1"), "sum(B1_B2)");
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(3));
+        sheet.delete_col(1).unwrap();
+        sheet.flush();
+        assert_eq!(expression("B1"), "sum(ref_error(\"B1_B2\"))");
+        assert_eq!(expression("C1"), "ref_error(\"A3\") * 10");
+    }
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 4 }, // A5
-                "A4 + 1".to_string(),
-            )
-            .unwrap();
+    #[test]
+    fn test_insert_and_delete_stay_within_bounds() {
+        let sheet = Spreadsheet::with_options(SpreadsheetOptions {
+            max_rows: 3,
+            max_cols: 3,
+            ..Default::default()
+        });
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let expression = |name: &str| sheet.get_expression(&cell(name)).unwrap_or_default();
+        let outside = |name: &str| Err(SpreadsheetError::OutOfBounds(cell(name), cell("C3")));
+        sheet.set(cell("A3"), "3".to_string()).unwrap();
+        sheet.set(cell("B1"), "A3 + 1".to_string()).unwrap();
 
-        // Check initial value of A5
+        // A cell in the last row, or a reference to it, can't be pushed off
+        assert_eq!(sheet.insert_row(0), outside("A4"));
+        sheet.set(cell("A3"), String::new()).unwrap();
+        assert_eq!(sheet.insert_row(0), outside("A4"));
+        assert_eq!(expression("B1"), "A3 + 1");
+
+        // Nor a cell in the last column
+        sheet.set(cell("C2"), "1".to_string()).unwrap();
+        assert_eq!(sheet.insert_col(1), outside("D2"));
+        assert_eq!(expression("C2"), "1");
+
+        // Lines past the sheet are refused, and those within it still work
+        assert_eq!(sheet.insert_row(3), outside("A4"));
         assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 0, row: 4 }), // A5
-            CellValue::Int(5)                                    // 1 + 1 + 1 + 1 + 1 = 5
+            sheet.delete_row(u32::MAX),
+            Err(SpreadsheetError::OutOfBounds(
+                CellIdentifier {
+                    col: 0,
+                    row: u32::MAX
+                },
+                cell("C3")
+            ))
         );
+        assert_eq!(sheet.delete_col(3), outside("D1"));
+        sheet.set(cell("B1"), "A2 + 1".to_string()).unwrap();
+        sheet.set(cell("C2"), String::new()).unwrap();
+        sheet.insert_row(0).unwrap();
+        sheet.insert_col(0).unwrap();
+        assert_eq!(expression("C2"), "B3 + 1");
+        sheet.delete_row(2).unwrap();
+        assert_eq!(expression("C2"), "ref_error(\"B3\") + 1");
+    }
 
-        // Update A1 and check propagation
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "7".to_string(),
-            )
-            .unwrap();
+    #[test]
+    fn test_copy_cell_down_and_across() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let expression = |name: &str| sheet.get_expression(&cell(name)).unwrap_or_default();
+        for (name, value) in [("A1", "1"), ("A2", "2"), ("B2", "20")] {
+            sheet.set(cell(name), value.to_string()).unwrap();
+        }
+        sheet.set(cell("B1"), "A1 + 1".to_string()).unwrap();
+        sheet.set(cell("C1"), "sum(A1_B1)".to_string()).unwrap();
 
-        thread::sleep(Duration::from_millis(2000));
+        // A scalar formula, down and across
+        sheet.copy_cell(cell("B1"), cell("B2")).unwrap();
+        assert_eq!(expression("B2"), "A2 + 1");
+        sheet.copy_cell(cell("B1"), cell("D1")).unwrap();
+        assert_eq!(expression("D1"), "C1 + 1");
 
-        assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 0, row: 4 }), // A5
-            CellValue::Int(11)                                   // 7 + 1 + 1 + 1 + 1 = 11
-        );
+        // A range-summing formula, down and across
+        sheet.copy_cell(cell("C1"), cell("C2")).unwrap();
+        assert_eq!(expression("C2"), "sum(A2_B2)");
+        sheet.copy_cell(cell("C1"), cell("E1")).unwrap();
+        assert_eq!(expression("E1"), "sum(C1_D1)");
+
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("C2")), CellValue::Int(5));
+        assert_eq!(sheet.get(&cell("E1")), CellValue::Int(7));
+
+        // Copying left past column A is a reference error, not a panic
+        sheet.copy_cell(cell("B1"), cell("A1")).unwrap();
+        assert_eq!(expression("A1"), "ref_error(\"A1\") + 1");
     }
 
     #[test]
-    fn test_multi_level_dependency() {
-        let spreadsheet = Spreadsheet::new();
+    fn test_anchored_references_survive_copy() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let expression = |name: &str| sheet.get_expression(&cell(name)).unwrap_or_default();
+        for (name, value) in [("A1", "1"), ("B1", "2"), ("A2", "30"), ("B2", "40")] {
+            sheet.set(cell(name), value.to_string()).unwrap();
+        }
+        for (name, formula) in [("C1", "$A$1"), ("D1", "A$1"), ("E1", "$A1"), ("F1", "A1")] {
+            sheet.set(cell(name), formula.to_string()).unwrap();
+        }
+        assert_eq!(sheet.get(&cell("C1")), CellValue::Int(1));
 
-        // Initial setup
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "1".to_string(),
-            )
+        // One row down and one column right
+        sheet
+            .copy_range(cell("C1"), cell("F1"), cell("D2"))
             .unwrap();
+        assert_eq!(expression("D2"), "$A$1");
+        assert_eq!(expression("E2"), "B$1");
+        assert_eq!(expression("F2"), "$A2");
+        assert_eq!(expression("G2"), "B2");
+        sheet.flush();
+        let values: Vec<CellValue> = ["D2", "E2", "F2", "G2"]
+            .iter()
+            .map(|name| sheet.get(&cell(name)))
+            .collect();
+        assert_eq!(values, [1, 2, 30, 40].map(CellValue::Int).to_vec());
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 1 }, // A2
-                "A1 + 1".to_string(),
-            )
-            .unwrap();
+        // Anchors still track the cells they name through row changes
+        sheet.insert_row(0).unwrap();
+        assert_eq!(expression("D3"), "$A$2");
+        assert_eq!(sheet.dependents_of(&cell("A2"), false).len(), 5);
+    }
 
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 2 }, // A3
-                "A1 + A2 + 1".to_string(),
-            )
+    #[test]
+    fn test_defined_names() {
+        let sheet = Spreadsheet::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        for (name, value) in [("A1", "1"), ("A2", "2"), ("A3", "3"), ("C1", "100")] {
+            sheet.set(cell(name), value.to_string()).unwrap();
+        }
+
+        // A name over a range, and one over a cell
+        sheet.define_name("revenue", "A1_A2").unwrap();
+        sheet.define_name("bonus", "C1").unwrap();
+        sheet
+            .set(cell("B1"), "sum(revenue) + bonus".to_string())
             .unwrap();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(103));
 
-        // Check initial value of A3
-        assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 0, row: 2 }), // A3
-            CellValue::Int(4)                                    // 1 + 2 + 1 = 4
-        );
+        // Updates inside the named cells reach formulas using the name
+        sheet.set(cell("A2"), "20".to_string()).unwrap();
+        sheet.set(cell("C1"), "200".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(221));
 
-        // Update A1 and check propagation
-        spreadsheet
-            .set(
-                CellIdentifier { col: 0, row: 0 }, // A1
-                "2".to_string(),
-            )
-            .unwrap();
+        // Redefining re-evaluates users and moves their edges
+        sheet.define_name("revenue", "A1_A3").unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(224));
+        sheet.set(cell("A3"), "30".to_string()).unwrap();
+        sheet.flush();
+        assert_eq!(sheet.get(&cell("B1")), CellValue::Int(251));
 
-        thread::sleep(Duration::from_millis(2000));
+        // Removing a name leaves its users in error
+        sheet.remove_name("bonus").unwrap();
+        assert!(matches!(sheet.get(&cell("B1")), CellValue::Error(_)));
+        assert_eq!(
+            sheet.remove_name("bonus"),
+            Err(SpreadsheetError::UnknownName("bonus".to_string()))
+        );
+        assert_eq!(
+            sheet.set(cell("D1"), "bonus".to_string()),
+            Err(SpreadsheetError::InvalidReference("bonus".to_string()))
+        );
 
+        // Names can't shadow references or keywords
+        for bad in ["B2", "A1_A3", "true", "2x", "my-name"] {
+            assert_eq!(
+                sheet.define_name(bad, "A1"),
+                Err(SpreadsheetError::InvalidName(bad.to_string()))
+            );
+        }
         assert_eq!(
-            spreadsheet.get(&CellIdentifier { col: 0, row: 2 }), // A3
-            CellValue::Int(6)                                    // 2 + 3 + 1 = 6
+            sheet.define_name("total", "A1_"),
+            Err(SpreadsheetError::InvalidReference("A1_".to_string()))
         );
     }
 }
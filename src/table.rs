@@ -0,0 +1,125 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::CellIdentifier;
+
+use crate::references;
+
+/**
+ * HELPER FUNCTION
+ * Renders a rectangle of values as an aligned text table, e.g.
+ *
+ *   | A | B
+ * 1 | 5 | hi
+ * 2 |   | #ERR
+ *
+ * Procedure:
+ * 1. Shows integers and strings as they are, errors as #ERR and cells
+ *    without a value as blanks, cutting any text longer than max_width
+ *    characters short with an ellipsis
+ * 2. Sizes each column to its widest entry, header included, and the row
+ *    number column to the widest row number
+ * 3. Writes a header of column names, then one line per row of the grid,
+ *    starting at start's row, with each entry padded on the right and
+ *    trailing blanks removed
+ */
+pub fn render(start: CellIdentifier, grid: &[Vec<CellValue>], max_width: usize) -> String {
+    let columns = grid.first().map_or(0, Vec::len) as u32;
+    let names: Vec<String> = (start.col..start.col + columns)
+        .map(references::column_name)
+        .collect();
+
+    // Step 1: Text of every entry
+    let rows: Vec<Vec<String>> = grid
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|value| cell_text(value, max_width))
+                .collect()
+        })
+        .collect();
+
+    // Step 2: Column widths
+    let widths: Vec<usize> = names
+        .iter()
+        .enumerate()
+        .map(|(col, name)| {
+            rows.iter()
+                .map(|row| row[col].chars().count())
+                .fold(name.len(), usize::max)
+        })
+        .collect();
+    let label_width = (u64::from(start.row) + rows.len() as u64).to_string().len();
+
+    // Step 3: Header and rows
+    let line = |label: String, entries: &[String]| {
+        let mut line = format!("{label:>label_width$}");
+        let used = entries
+            .iter()
+            .rposition(|entry| !entry.is_empty())
+            .map_or(0, |i| i + 1);
+        for (entry, width) in entries[..used].iter().zip(&widths) {
+            let padding = width - entry.chars().count();
+            line.push_str(&format!(" | {}{}", entry, " ".repeat(padding)));
+        }
+        line.trim_end().to_string()
+    };
+    let mut lines = vec![line(String::new(), &names)];
+    for (row, entries) in (u64::from(start.row) + 1..).zip(&rows) {
+        lines.push(line(row.to_string(), entries));
+    }
+    lines.join("\n")
+}
+
+/**
+ * HELPER FUNCTION
+ * Shows one value in a table cell, at most max_width characters long
+ */
+fn cell_text(value: &CellValue, max_width: usize) -> String {
+    let text = match value {
+        CellValue::None => String::new(),
+        CellValue::Int(number) => number.to_string(),
+        CellValue::String(text) => text.clone(),
+        CellValue::Error(_) => "#ERR".to_string(),
+    };
+    if text.chars().count() <= max_width {
+        return text;
+    }
+    let mut cut: String = text.chars().take(max_width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_small_grid() {
+        let grid = vec![
+            vec![CellValue::Int(5), CellValue::String("hi".into())],
+            vec![CellValue::None, CellValue::Error("1 / 0".into())],
+            vec![CellValue::Int(-120), CellValue::None],
+        ];
+        assert_eq!(
+            render(CellIdentifier { col: 0, row: 0 }, &grid, 12),
+            [
+                "  | A    | B",
+                "1 | 5    | hi",
+                "2 |      | #ERR",
+                "3 | -120"
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_truncates_wide_values() {
+        let grid = vec![
+            vec![CellValue::String("a rather long string".into())],
+            vec![CellValue::Int(7)],
+        ];
+        assert_eq!(
+            render(CellIdentifier { col: 2, row: 8 }, &grid, 8),
+            ["   | C", " 9 | a rathe…", "10 | 7"].join("\n")
+        );
+    }
+}
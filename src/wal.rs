@@ -0,0 +1,225 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use rsheet_lib::command::CellIdentifier;
+
+use crate::references::a1_name;
+
+/**
+ * An append-only log of every successful set, one line per set:
+ *
+ *   seq<TAB>cell<TAB>expression
+ *
+ * where seq counts up from 1, cell is an A1 name, and backslashes, tabs and
+ * line breaks in the expression are escaped as \\, \t, \n and \r
+ */
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,    // Log file, opened for appending
+    path: PathBuf, // Where the log lives, for compaction
+    next_seq: u64, // Sequence number of the next entry
+}
+
+impl WriteAheadLog {
+    /**
+     * Public Function
+     * Opens a log for appending, creating it if needed and continuing after
+     * the last sequence number already in it
+     */
+    pub fn open(path: &Path) -> io::Result<WriteAheadLog> {
+        let last_seq = match fs::read_to_string(path) {
+            Ok(contents) => parse_entries(&contents)
+                .last()
+                .map_or(0, |(seq, _, _)| *seq),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        Ok(WriteAheadLog {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            path: path.to_path_buf(),
+            next_seq: last_seq + 1,
+        })
+    }
+
+    /**
+     * Public Function
+     * Appends one set to the log, flushing it before returning
+     * The line is written in a single call so a crash leaves at most one
+     * partial line at the end
+     */
+    pub fn append(&mut self, cell_id: &CellIdentifier, expression: &str) -> io::Result<()> {
+        let line = format!(
+            "{}\t{}\t{}\n",
+            self.next_seq,
+            a1_name(cell_id),
+            escape(expression)
+        );
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /**
+     * Public Function
+     * Replaces the whole log with one entry per given cell
+     *
+     * Procedure:
+     * 1. Writes the entries, numbered from 1, to a temporary file beside the log
+     * 2. Renames it over the log, so a crash keeps either log whole
+     * 3. Reopens the new log for appending
+     */
+    pub fn compact(&mut self, entries: &[(CellIdentifier, String)]) -> io::Result<()> {
+        let contents: String = (1u64..)
+            .zip(entries)
+            .map(|(seq, (cell_id, expression))| {
+                format!("{}\t{}\t{}\n", seq, a1_name(cell_id), escape(expression))
+            })
+            .collect();
+
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".tmp");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.next_seq = entries.len() as u64 + 1;
+        Ok(())
+    }
+
+    /**
+     * Public Function
+     * Reads the sets recorded in a log, in order
+     * A missing log holds no sets
+     */
+    pub fn read(path: &Path) -> io::Result<Vec<(CellIdentifier, String)>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(parse_entries(&contents)
+                .into_iter()
+                .map(|(_, cell_id, expression)| (cell_id, expression))
+                .collect()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/**
+ * HELPER FUNCTION
+ * Parses the lines of a log
+ *
+ * Procedure:
+ * 1. Splits the log into lines, treating text after the last line break as
+ *    a partial write
+ * 2. Parses each line's sequence number, cell name and escaped expression
+ * 3. Skips, with a warning, partial or malformed lines and lines whose
+ *    sequence number doesn't follow the one before
+ */
+fn parse_entries(contents: &str) -> Vec<(u64, CellIdentifier, String)> {
+    let mut entries: Vec<(u64, CellIdentifier, String)> = Vec::new();
+    let mut lines: Vec<&str> = contents.split('\n').collect();
+
+    // Step 1: A complete log ends with a line break, leaving "" here
+    if let Some(partial) = lines.pop().filter(|line| !line.is_empty()) {
+        warn!(
+            "event=wal_skipped line={} reason=partial {:?}",
+            lines.len() + 1,
+            partial
+        );
+    }
+
+    for (number, line) in (1..).zip(lines) {
+        // Step 2: Parse the line
+        let parsed = line.splitn(3, '\t').collect::<Vec<&str>>();
+        let entry = match parsed.as_slice() {
+            [seq, cell, expression] => seq
+                .parse::<u64>()
+                .ok()
+                .zip(cell.parse::<CellIdentifier>().ok())
+                .zip(unescape(expression))
+                .map(|((seq, cell_id), expression)| (seq, cell_id, expression)),
+            _ => None,
+        };
+
+        // Step 3: Keep only well-formed lines in sequence
+        match entry {
+            Some(entry) if entries.last().is_none_or(|(last, _, _)| entry.0 > *last) => {
+                entries.push(entry)
+            }
+            _ => warn!(
+                "event=wal_skipped line={} reason=malformed {:?}",
+                number, line
+            ),
+        }
+    }
+
+    entries
+}
+
+/**
+ * HELPER FUNCTION
+ * Escapes an expression so it fits on one line of the log
+ */
+fn escape(expression: &str) -> String {
+    let mut escaped = String::with_capacity(expression.len());
+    for c in expression.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/**
+ * HELPER FUNCTION
+ * Reverses escape, returning None for an unknown or unfinished escape
+ */
+fn unescape(escaped: &str) -> Option<String> {
+    let mut expression = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            expression.push(c);
+            continue;
+        }
+        expression.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(expression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_round_trips() {
+        let expression = "\"a\\tb\"\t+\n\"c\"\\";
+        assert!(!escape(expression).contains(['\t', '\n']));
+        assert_eq!(unescape(&escape(expression)).unwrap(), expression);
+        assert_eq!(unescape("bad\\q"), None);
+    }
+
+    #[test]
+    fn test_parse_skips_corrupt_lines() {
+        let entries =
+            parse_entries("1\tA1\t5\n2\tnot a cell\t1\n3\tB1\tA1 + 1\n2\tC1\t9\n4\tC1\tB1 *");
+        let cells: Vec<String> = entries.iter().map(|(_, id, _)| a1_name(id)).collect();
+
+        // The bad name, the out-of-order line and the partial line are skipped
+        assert_eq!(cells, vec!["A1", "B1"]);
+        assert_eq!(entries[1], (3, "B1".parse().unwrap(), "A1 + 1".to_string()));
+    }
+}
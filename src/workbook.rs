@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::spreadsheet::{Spreadsheet, SpreadsheetOptions};
+
+// Sheet used by commands that don't name one
+pub const DEFAULT_SHEET: &str = "main";
+
+/**
+ * A set of independent sheets addressed by name, e.g. "Sheet2" in
+ * "get Sheet2!A1"
+ * Each sheet has its own cells and worker; formulas can't read other sheets
+ */
+#[derive(Debug, Default)]
+pub struct Workbook {
+    sheets: Mutex<HashMap<String, Arc<Spreadsheet>>>, // Sheets created so far, by name
+    options: SpreadsheetOptions,                      // Options each new sheet is created with
+}
+
+impl Workbook {
+    /**
+     * HELPER FUNCTION
+     * Creates a workbook with no sheets; each is created on first use
+     */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Creates a workbook with no sheets, each created on first use with the
+     * given options, e.g. the limits a shared server needs
+     */
+    pub fn with_options(options: SpreadsheetOptions) -> Self {
+        Self {
+            sheets: Mutex::new(HashMap::new()),
+            options,
+        }
+    }
+
+    /**
+     * HELPER FUNCTION
+     * Creates a workbook whose default sheet is the given one, e.g. a sheet
+     * loaded from a snapshot, creating the others with the given options
+     */
+    pub fn with_default_sheet(sheet: Spreadsheet, options: SpreadsheetOptions) -> Self {
+        Self {
+            sheets: Mutex::new(HashMap::from([(
+                DEFAULT_SHEET.to_string(),
+                Arc::new(sheet),
+            )])),
+            options,
+        }
+    }
+
+    /**
+     * Public Function
+     * Returns the sheet with the given name, creating an empty one with the
+     * workbook's options if it doesn't exist yet
+     */
+    pub fn sheet(&self, name: &str) -> Arc<Spreadsheet> {
+        let mut sheets = self.sheets.lock().unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(
+            sheets
+                .entry(name.to_string())
+                .or_insert_with(|| Arc::new(Spreadsheet::with_options(self.options.clone()))),
+        )
+    }
+
+    /**
+     * Public Function
+     * Returns the name of every sheet created so far, sorted
+     */
+    pub fn sheet_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .sheets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /**
+     * Public Function
+     * Removes a sheet, returning whether it existed
+     *
+     * Procedure:
+     * 1. Takes the sheet out of the workbook under the lock
+     * 2. Drops it after releasing the lock, which waits for its worker to
+     *    finish, so other sheets stay usable meanwhile; a command still
+     *    running on the sheet keeps it alive until that command returns
+     */
+    pub fn drop_sheet(&self, name: &str) -> bool {
+        let removed = self
+            .sheets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name);
+        removed.is_some()
+    }
+
+    /**
+     * Public Function
+     * Removes every sheet, dropping each after releasing the lock, which
+     * stops its worker and waits for it to finish; a sheet still held
+     * elsewhere stops once its last handle is dropped
+     */
+    pub fn close(&self) {
+        let sheets =
+            std::mem::take(&mut *self.sheets.lock().unwrap_or_else(PoisonError::into_inner));
+        drop(sheets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsheet_lib::cell_value::CellValue;
+    use rsheet_lib::command::CellIdentifier;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_sheets_are_independent() {
+        let workbook = Workbook::new();
+        let cell = |name: &str| name.parse::<CellIdentifier>().unwrap();
+        let (first, second) = (workbook.sheet("main"), workbook.sheet("Sheet2"));
+
+        first.set(cell("A1"), "1".to_string()).unwrap();
+        first.set(cell("B1"), "A1 * 10".to_string()).unwrap();
+        second.set(cell("A1"), "2".to_string()).unwrap();
+        second.set(cell("B1"), "A1 * 10".to_string()).unwrap();
+
+        // Formulas cascade within their own sheet only
+        workbook
+            .sheet("Sheet2")
+            .set(cell("A1"), "5".to_string())
+            .unwrap();
+        first.flush();
+        second.flush();
+        assert_eq!(first.get(&cell("B1")), CellValue::Int(10));
+        assert_eq!(second.get(&cell("B1")), CellValue::Int(50));
+        assert_eq!(workbook.sheet_names(), vec!["Sheet2", "main"]);
+    }
+
+    #[test]
+    fn test_drop_sheet_stops_its_worker() {
+        let workbook = Workbook::new();
+        let (alive, stopped) = mpsc::channel::<()>();
+        workbook.sheet("scratch").add_observer(move |_, _| {
+            let _ = alive.send(());
+        });
+
+        // The observer, and its sender, go away only with the worker
+        assert!(workbook.drop_sheet("scratch"));
+        assert_eq!(stopped.recv(), Err(mpsc::RecvError));
+        assert!(!workbook.drop_sheet("scratch"));
+        assert!(workbook.sheet_names().is_empty());
+    }
+}